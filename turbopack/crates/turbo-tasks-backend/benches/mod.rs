@@ -0,0 +1,62 @@
+use std::{borrow::Cow, time::Duration};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use turbo_tasks_backend::database::{
+    key_value_database::{KeySpace, KeyValueDatabase, WriteBatch},
+    LmbdKeyValueDatabase,
+};
+
+fn bench_large_values(c: &mut Criterion) {
+    let mut g = c.benchmark_group("turbo-tasks-backend");
+    g.sample_size(10);
+    g.measurement_time(Duration::from_secs(10));
+
+    let temp = tempfile::TempDir::new().unwrap();
+    let database = LmbdKeyValueDatabase::new(temp.path()).unwrap();
+    let key = 1u32.to_le_bytes();
+
+    for size in [16 * 1024 * 1024, 256 * 1024 * 1024] {
+        let fill = |buffer: &mut [u8]| {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+        };
+
+        g.bench_with_input(BenchmarkId::new("put", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut value = vec![0; size];
+                fill(&mut value);
+                let mut batch = database.write_batch().unwrap();
+                batch
+                    .put(KeySpace::TaskData, Cow::Borrowed(&key), Cow::Owned(value))
+                    .unwrap();
+                batch.commit().unwrap();
+            })
+        });
+
+        g.bench_with_input(BenchmarkId::new("put_with", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut batch = database.write_batch().unwrap();
+                batch
+                    .put_with(
+                        KeySpace::TaskData,
+                        Cow::Borrowed(&key),
+                        size,
+                        &mut |buffer| {
+                            fill(buffer);
+                            Ok(())
+                        },
+                    )
+                    .unwrap();
+                batch.commit().unwrap();
+            })
+        });
+    }
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default();
+    targets = bench_large_values
+);
+criterion_main!(benches);
@@ -0,0 +1,288 @@
+use std::{mem::transmute, path::Path, sync::Arc};
+
+use anyhow::Result;
+use turbo_tasks::{backend::CachedTaskType, SessionId, TaskId};
+
+use crate::{
+    backend::{AnyOperation, TaskDataCategory},
+    backing_storage::{BackingStorage, SnapshotCostEstimate},
+    data::{CachedDataItem, CachedDataUpdate},
+    database::NoopKvDb,
+    utils::chunked_vec::ChunkedVec,
+    BackingStorageOptions, KeyValueDatabaseBackingStorage, NoopBackingStorage,
+};
+
+/// The backing storage implementations that can be selected at runtime with
+/// [`open_backing_storage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackingStorageKind {
+    /// Doesn't persist anything. All task data only lives in memory.
+    Noop,
+    #[cfg(feature = "lmdb")]
+    Lmdb,
+    #[cfg(feature = "rocksdb")]
+    RocksDb,
+}
+
+/// A [`BackingStorage`] that is selected at runtime.
+///
+/// [`BackingStorage`] isn't object safe because of its generic read transaction, so this
+/// dispatches to the selected implementation instead of a `Box<dyn BackingStorage>`.
+pub enum AnyBackingStorage {
+    Noop(NoopBackingStorage),
+    #[cfg(feature = "lmdb")]
+    Lmdb(crate::LmdbBackingStorage),
+    #[cfg(feature = "rocksdb")]
+    RocksDb(crate::RocksDBBackingStorage),
+}
+
+/// Opens the backing storage of the given `kind` at `path`.
+pub fn open_backing_storage(
+    kind: BackingStorageKind,
+    path: &Path,
+    options: BackingStorageOptions,
+) -> Result<AnyBackingStorage> {
+    Ok(match kind {
+        BackingStorageKind::Noop => {
+            let _ = path;
+            AnyBackingStorage::Noop(KeyValueDatabaseBackingStorage::with_options(
+                NoopKvDb, options,
+            )?)
+        }
+        #[cfg(feature = "lmdb")]
+        BackingStorageKind::Lmdb => AnyBackingStorage::Lmdb(
+            crate::lmdb_backing_storage_with_options(path, Default::default(), options)?,
+        ),
+        #[cfg(feature = "rocksdb")]
+        BackingStorageKind::RocksDb => {
+            let path = crate::database::handle_db_versioning(path)?;
+            let database = crate::database::RocksDbKeyValueDatabase::new(&path)?;
+            AnyBackingStorage::RocksDb(KeyValueDatabaseBackingStorage::with_options(
+                database, options,
+            )?)
+        }
+    })
+}
+
+pub enum AnyReadTransaction<'l> {
+    Noop(<NoopBackingStorage as BackingStorage>::ReadTransaction<'l>),
+    #[cfg(feature = "lmdb")]
+    Lmdb(<crate::LmdbBackingStorage as BackingStorage>::ReadTransaction<'l>),
+    #[cfg(feature = "rocksdb")]
+    RocksDb(<crate::RocksDBBackingStorage as BackingStorage>::ReadTransaction<'l>),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $storage:ident => $body:expr) => {
+        match $self {
+            AnyBackingStorage::Noop($storage) => $body,
+            #[cfg(feature = "lmdb")]
+            AnyBackingStorage::Lmdb($storage) => $body,
+            #[cfg(feature = "rocksdb")]
+            AnyBackingStorage::RocksDb($storage) => $body,
+        }
+    };
+}
+
+/// Like `dispatch!`, but also unwraps the transaction of the selected implementation.
+macro_rules! dispatch_tx {
+    ($self:expr, $tx:expr, ($storage:ident, $inner_tx:ident) => $body:expr) => {
+        match $self {
+            AnyBackingStorage::Noop($storage) => {
+                let $inner_tx = $tx.map(|tx| match tx {
+                    AnyReadTransaction::Noop(tx) => tx,
+                    #[allow(unreachable_patterns)]
+                    _ => unreachable!("Transaction of a different backing storage"),
+                });
+                $body
+            }
+            #[cfg(feature = "lmdb")]
+            AnyBackingStorage::Lmdb($storage) => {
+                let $inner_tx = $tx.map(|tx| match tx {
+                    AnyReadTransaction::Lmdb(tx) => tx,
+                    _ => unreachable!("Transaction of a different backing storage"),
+                });
+                $body
+            }
+            #[cfg(feature = "rocksdb")]
+            AnyBackingStorage::RocksDb($storage) => {
+                let $inner_tx = $tx.map(|tx| match tx {
+                    AnyReadTransaction::RocksDb(tx) => tx,
+                    _ => unreachable!("Transaction of a different backing storage"),
+                });
+                $body
+            }
+        }
+    };
+}
+
+impl BackingStorage for AnyBackingStorage {
+    type ReadTransaction<'l> = AnyReadTransaction<'l>;
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        // Safety: Every variant contains the transaction of an implementation that supports
+        // lowering it with its own lower_read_transaction.
+        unsafe { transmute::<&'r Self::ReadTransaction<'l>, &'r Self::ReadTransaction<'i>>(tx) }
+    }
+
+    fn next_free_task_id(&self) -> TaskId {
+        dispatch!(self, storage => storage.next_free_task_id())
+    }
+
+    fn content_addressed_task_id(&self, task_type: &CachedTaskType) -> Option<TaskId> {
+        dispatch!(self, storage => storage.content_addressed_task_id(task_type))
+    }
+
+    fn next_session_id(&self) -> SessionId {
+        dispatch!(self, storage => storage.next_session_id())
+    }
+
+    fn uncompleted_operations(&self) -> Vec<AnyOperation> {
+        dispatch!(self, storage => storage.uncompleted_operations())
+    }
+
+    fn save_snapshot(
+        &self,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+        data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+    ) -> Result<()> {
+        dispatch!(self, storage => storage.save_snapshot(
+            session_id,
+            operations,
+            task_cache_updates,
+            meta_updates,
+            data_updates,
+        ))
+    }
+
+    fn estimate_snapshot_cost(
+        &self,
+        task_cache_len: usize,
+        data_updates_len: usize,
+    ) -> SnapshotCostEstimate {
+        dispatch!(self, storage => storage.estimate_snapshot_cost(task_cache_len, data_updates_len))
+    }
+
+    fn start_read_transaction(&self) -> Option<Self::ReadTransaction<'_>> {
+        Some(match self {
+            AnyBackingStorage::Noop(storage) => {
+                AnyReadTransaction::Noop(storage.start_read_transaction()?)
+            }
+            #[cfg(feature = "lmdb")]
+            AnyBackingStorage::Lmdb(storage) => {
+                AnyReadTransaction::Lmdb(storage.start_read_transaction()?)
+            }
+            #[cfg(feature = "rocksdb")]
+            AnyBackingStorage::RocksDb(storage) => {
+                AnyReadTransaction::RocksDb(storage.start_read_transaction()?)
+            }
+        })
+    }
+
+    unsafe fn forward_lookup_task_cache(
+        &self,
+        tx: Option<&Self::ReadTransaction<'_>>,
+        key: &CachedTaskType,
+    ) -> Option<TaskId> {
+        // Safety: The transaction is a transaction of the selected storage.
+        dispatch_tx!(self, tx, (storage, tx) => unsafe {
+            storage.forward_lookup_task_cache(tx, key)
+        })
+    }
+
+    unsafe fn reverse_lookup_task_cache(
+        &self,
+        tx: Option<&Self::ReadTransaction<'_>>,
+        task_id: TaskId,
+    ) -> Option<Arc<CachedTaskType>> {
+        // Safety: The transaction is a transaction of the selected storage.
+        dispatch_tx!(self, tx, (storage, tx) => unsafe {
+            storage.reverse_lookup_task_cache(tx, task_id)
+        })
+    }
+
+    unsafe fn lookup_data(
+        &self,
+        tx: Option<&Self::ReadTransaction<'_>>,
+        task_id: TaskId,
+        category: TaskDataCategory,
+    ) -> Arc<Vec<CachedDataItem>> {
+        // Safety: The transaction is a transaction of the selected storage.
+        dispatch_tx!(self, tx, (storage, tx) => unsafe {
+            storage.lookup_data(tx, task_id, category)
+        })
+    }
+
+    fn contains_tasks(&self, task_ids: &[TaskId]) -> Vec<bool> {
+        dispatch!(self, storage => storage.contains_tasks(task_ids))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{CachedDataItemKey, CachedDataItemValue},
+        kv_backing_storage::test_utils::with_turbo_tasks,
+    };
+
+    fn save_and_lookup(storage: &AnyBackingStorage) -> Result<Arc<Vec<CachedDataItem>>> {
+        let task = TaskId::from(1);
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                vec![updates],
+            )
+        })?;
+        let tx = storage.start_read_transaction();
+        // Safety: The transaction is a transaction of the storage.
+        Ok(unsafe { storage.lookup_data(tx.as_ref(), task, TaskDataCategory::Data) })
+    }
+
+    #[test]
+    fn noop_storage_keeps_nothing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = open_backing_storage(
+            BackingStorageKind::Noop,
+            dir.path(),
+            BackingStorageOptions::default(),
+        )?;
+        assert_eq!(storage.next_free_task_id(), TaskId::from(1));
+        assert!(save_and_lookup(&storage)?.is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lmdb_storage_persists_data() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = open_backing_storage(
+            BackingStorageKind::Lmdb,
+            dir.path(),
+            BackingStorageOptions::default(),
+        )?;
+        let data = save_and_lookup(&storage)?;
+        assert_eq!(data.len(), 1);
+        assert!(matches!(
+            data[0],
+            CachedDataItem::ChildrenCount { value: 7 }
+        ));
+        assert_eq!(storage.next_session_id(), SessionId::from(2));
+        Ok(())
+    }
+}
@@ -59,6 +59,10 @@ const BACKEND_JOB_FOLLOW_UP_SNAPSHOT: BackendJobId = unsafe { BackendJobId::new_
 
 const SNAPSHOT_REQUESTED_BIT: usize = 1 << (usize::BITS - 1);
 
+/// The number of taken ids that are skipped when looking for a free content addressed task id,
+/// before falling back to sequential allocation.
+const MAX_CONTENT_ADDRESSED_PROBES: u32 = 64;
+
 struct SnapshotRequest {
     snapshot_requested: bool,
     suspended_operations: HashSet<PtrEqArc<AnyOperation>>,
@@ -727,17 +731,26 @@ impl<B: BackingStorage> TurboTasksBackendInner<B> {
                 task_id
             } else {
                 let task_type = Arc::new(task_type);
-                let task_id = self.persisted_task_id_factory.get();
-                let task_id = if let Err(existing_task_id) =
-                    self.task_cache.try_insert(task_type.clone(), task_id)
+                let task_id = if let Some(task_id) =
+                    self.backing_storage.content_addressed_task_id(&task_type)
                 {
-                    // Safety: We just created the id and failed to insert it.
+                    // Safety: `tx` is a valid transaction from `self.backend.backing_storage`.
                     unsafe {
-                        self.persisted_task_id_factory.reuse(task_id);
+                        self.insert_content_addressed_task(tx.as_ref(), task_type.clone(), task_id)
                     }
-                    existing_task_id
                 } else {
-                    task_id
+                    let task_id = self.persisted_task_id_factory.get();
+                    if let Err(existing_task_id) =
+                        self.task_cache.try_insert(task_type.clone(), task_id)
+                    {
+                        // Safety: We just created the id and failed to insert it.
+                        unsafe {
+                            self.persisted_task_id_factory.reuse(task_id);
+                        }
+                        existing_task_id
+                    } else {
+                        task_id
+                    }
                 };
                 self.persisted_task_cache_log
                     .lock(task_id)
@@ -752,6 +765,66 @@ impl<B: BackingStorage> TurboTasksBackendInner<B> {
         task_id
     }
 
+    /// Inserts a new task type into the task cache with a content addressed id. Starting at
+    /// `task_id` all ids that are already used by another task type are skipped. After
+    /// [`MAX_CONTENT_ADDRESSED_PROBES`] taken ids the id is allocated sequentially instead.
+    ///
+    /// # Safety
+    ///
+    /// `tx` must be a transaction from this TurboTasksBackendInner instance.
+    unsafe fn insert_content_addressed_task(
+        &self,
+        tx: Option<&B::ReadTransaction<'_>>,
+        task_type: Arc<CachedTaskType>,
+        mut task_id: TaskId,
+    ) -> TaskId {
+        for _ in 0..MAX_CONTENT_ADDRESSED_PROBES {
+            // Safety: `tx` is a valid transaction from `self.backend.backing_storage`.
+            if let Some(task_id) =
+                unsafe { self.try_insert_unused_task_id(tx, &task_type, task_id) }
+            {
+                return task_id;
+            }
+            task_id = TaskId::from(*task_id % (TRANSIENT_TASK_BIT - 1) + 1);
+        }
+        loop {
+            // Sequential ids can collide with content addressed ids. Taken ids are not reused.
+            let task_id = self.persisted_task_id_factory.get();
+            // Safety: `tx` is a valid transaction from `self.backend.backing_storage`.
+            if let Some(task_id) =
+                unsafe { self.try_insert_unused_task_id(tx, &task_type, task_id) }
+            {
+                return task_id;
+            }
+        }
+    }
+
+    /// Inserts `task_type` with `task_id` into the task cache unless the id is already used by
+    /// another task type. Returns the id of the task type when it's in the task cache afterwards.
+    ///
+    /// # Safety
+    ///
+    /// `tx` must be a transaction from this TurboTasksBackendInner instance.
+    unsafe fn try_insert_unused_task_id(
+        &self,
+        tx: Option<&B::ReadTransaction<'_>>,
+        task_type: &Arc<CachedTaskType>,
+        task_id: TaskId,
+    ) -> Option<TaskId> {
+        // Safety: `tx` is a valid transaction from `self.backend.backing_storage`.
+        if unsafe { self.backing_storage.reverse_lookup_task_cache(tx, task_id) }.is_some() {
+            return None;
+        }
+        match self
+            .task_cache
+            .try_insert_unique(task_type.clone(), task_id)
+        {
+            Ok(()) => Some(task_id),
+            Err(Some(existing_task_id)) => Some(existing_task_id),
+            Err(None) => None,
+        }
+    }
+
     fn get_or_create_transient_task(
         &self,
         task_type: CachedTaskType,
@@ -9,6 +9,7 @@ mod update_output;
 use std::{
     fmt::{Debug, Formatter},
     mem::{take, transmute},
+    sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
@@ -160,11 +161,13 @@ where
         category: TaskDataCategory,
     ) -> Vec<CachedDataItem> {
         // Safety: `transaction` is a valid transaction from `self.backend.backing_storage`.
-        unsafe {
+        let data = unsafe {
             self.backend
                 .backing_storage
                 .lookup_data(self.transaction(), task_id, category)
-        }
+        };
+        // The items are moved into the task, so only data that is shared with a cache is copied
+        Arc::unwrap_or_clone(data)
     }
 }
 
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use turbo_tasks::{backend::CachedTaskType, SessionId, TaskId};
@@ -9,12 +9,27 @@ use crate::{
     utils::chunked_vec::ChunkedVec,
 };
 
+/// The predicted cost of a snapshot, see [`BackingStorage::estimate_snapshot_cost`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotCostEstimate {
+    /// Approximate number of bytes of task data written.
+    pub bytes: u64,
+    /// Approximate time until the snapshot is committed.
+    pub duration: Duration,
+}
+
 pub trait BackingStorage: 'static + Send + Sync {
     type ReadTransaction<'l>;
     fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
         tx: &'r Self::ReadTransaction<'l>,
     ) -> &'r Self::ReadTransaction<'i>;
     fn next_free_task_id(&self) -> TaskId;
+    /// Returns the preferred id for a new task when the storage is configured to derive task ids
+    /// from the task type instead of allocating them sequentially. The caller need to probe
+    /// following ids when the preferred id is already taken by another task type.
+    fn content_addressed_task_id(&self, _task_type: &CachedTaskType) -> Option<TaskId> {
+        None
+    }
     fn next_session_id(&self) -> SessionId;
     fn uncompleted_operations(&self) -> Vec<AnyOperation>;
     fn save_snapshot(
@@ -25,6 +40,18 @@ pub trait BackingStorage: 'static + Send + Sync {
         meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
         data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
     ) -> Result<()>;
+    /// Predicts the cost of a `save_snapshot` call with `task_cache_len` task cache updates and
+    /// `data_updates_len` task meta and data updates from recent snapshots, so the caller can
+    /// pick a good time for it. Storages that don't track their snapshots estimate zero.
+    fn estimate_snapshot_cost(
+        &self,
+        _task_cache_len: usize,
+        _data_updates_len: usize,
+    ) -> SnapshotCostEstimate {
+        SnapshotCostEstimate::default()
+    }
+    /// Starts a read transaction that observes a consistent view of the storage. Read
+    /// transactions never block on or wait for a concurrent `save_snapshot`.
     fn start_read_transaction(&self) -> Option<Self::ReadTransaction<'_>>;
     /// # Safety
     ///
@@ -42,6 +69,9 @@ pub trait BackingStorage: 'static + Send + Sync {
         tx: Option<&Self::ReadTransaction<'_>>,
         task_id: TaskId,
     ) -> Option<Arc<CachedTaskType>>;
+    /// Returns the data of the task. Storages that cache the data return it shared instead of a
+    /// copy.
+    ///
     /// # Safety
     ///
     /// `tx` must be a transaction from this BackingStorage instance.
@@ -50,5 +80,21 @@ pub trait BackingStorage: 'static + Send + Sync {
         tx: Option<&Self::ReadTransaction<'_>>,
         task_id: TaskId,
         category: TaskDataCategory,
-    ) -> Vec<CachedDataItem>;
+    ) -> Arc<Vec<CachedDataItem>>;
+    /// Returns for each of `task_ids` whether the storage has meta or data of the task, in the
+    /// same order. Storages can answer this without deserializing the data.
+    fn contains_tasks(&self, task_ids: &[TaskId]) -> Vec<bool> {
+        let tx = self.start_read_transaction();
+        task_ids
+            .iter()
+            .map(|&task_id| {
+                [TaskDataCategory::Meta, TaskDataCategory::Data]
+                    .into_iter()
+                    .any(|category| {
+                        // Safety: The transaction is a transaction of this storage.
+                        !unsafe { self.lookup_data(tx.as_ref(), task_id, category) }.is_empty()
+                    })
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,112 @@
+use std::borrow::{Borrow, Cow};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    database::key_value_database::{KeySpace, KeyValueDatabase, WriteBatch},
+    value_codec::is_encoded,
+};
+
+/// Infra values with this prefix are split into chunks. The prefix is followed by the number of
+/// chunks as little-endian u32. Chunk `i` is stored under the infra key `chunk_keys + i`.
+/// Unchunked values are stored without a header, see
+/// [`ENCODED_VALUE_MARKER`](crate::value_codec::ENCODED_VALUE_MARKER).
+const CHUNKED_PREFIX: &[u8] = b"\0chunks";
+
+fn chunk_count(value: &[u8]) -> Result<Option<u32>> {
+    if !is_encoded(value) {
+        return Ok(None);
+    }
+    let Some(count) = value.strip_prefix(CHUNKED_PREFIX) else {
+        return Ok(None);
+    };
+    let count = count
+        .try_into()
+        .context("Invalid header of a chunked value")?;
+    Ok(Some(u32::from_le_bytes(count)))
+}
+
+/// Writes `value` under the infra key `key`. When it's larger than `chunk_size` it's split into
+/// chunks stored under the infra keys starting at `chunk_keys`. Chunks of the previous value that
+/// are no longer needed are deleted. Returns the number of database operations.
+pub(crate) fn write_chunked<'a>(
+    batch: &mut impl WriteBatch<'a>,
+    key: u32,
+    chunk_keys: u32,
+    value: Vec<u8>,
+    chunk_size: Option<usize>,
+) -> Result<usize> {
+    let old_chunks = match batch.get(KeySpace::Infra, &key.to_le_bytes())? {
+        Some(old) => {
+            let old: &[u8] = old.borrow();
+            chunk_count(old)?.unwrap_or(0)
+        }
+        None => 0,
+    };
+    let mut op_count = 0;
+    let chunks = match chunk_size {
+        Some(chunk_size) if value.len() > chunk_size => {
+            let mut chunks = 0;
+            for chunk in value.chunks(chunk_size) {
+                batch.put(
+                    KeySpace::Infra,
+                    Cow::Borrowed(&(chunk_keys + chunks).to_le_bytes()),
+                    Cow::Borrowed(chunk),
+                )?;
+                chunks += 1;
+                op_count += 1;
+            }
+            let mut header = CHUNKED_PREFIX.to_vec();
+            header.extend_from_slice(&chunks.to_le_bytes());
+            batch.put(
+                KeySpace::Infra,
+                Cow::Borrowed(&key.to_le_bytes()),
+                Cow::Owned(header),
+            )?;
+            chunks
+        }
+        _ => {
+            batch.put(
+                KeySpace::Infra,
+                Cow::Borrowed(&key.to_le_bytes()),
+                Cow::Owned(value),
+            )?;
+            0
+        }
+    };
+    op_count += 1;
+    for chunk in chunks..old_chunks {
+        batch.delete(
+            KeySpace::Infra,
+            Cow::Borrowed(&(chunk_keys + chunk).to_le_bytes()),
+        )?;
+        op_count += 1;
+    }
+    Ok(op_count)
+}
+
+/// Reads the value written by [`write_chunked`] and reassembles its chunks.
+pub(crate) fn read_chunked<D: KeyValueDatabase>(
+    database: &D,
+    tx: &D::ReadTransaction<'_>,
+    key: u32,
+    chunk_keys: u32,
+) -> Result<Option<Vec<u8>>> {
+    let Some(value) = database.get(tx, KeySpace::Infra, &key.to_le_bytes())? else {
+        return Ok(None);
+    };
+    let value: &[u8] = value.borrow();
+    let Some(chunks) = chunk_count(value)? else {
+        return Ok(Some(value.to_vec()));
+    };
+    let mut result = Vec::new();
+    for chunk in 0..chunks {
+        let Some(bytes) = database.get(tx, KeySpace::Infra, &(chunk_keys + chunk).to_le_bytes())?
+        else {
+            bail!("Chunk {chunk} of {chunks} of the value of infra key {key} is missing");
+        };
+        let bytes: &[u8] = bytes.borrow();
+        result.extend_from_slice(bytes);
+    }
+    Ok(Some(result))
+}
@@ -688,7 +688,7 @@ impl CachedDataItemValue {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CachedDataUpdate {
     pub task: TaskId,
     // TODO generate CachedDataItemUpdate to avoid repeating the variant field 3 times
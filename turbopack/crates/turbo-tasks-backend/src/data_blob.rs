@@ -0,0 +1,112 @@
+use std::borrow::{Borrow, Cow};
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use turbo_tasks_hash::hash_xxh3_hash128;
+
+use crate::database::key_value_database::{KeySpace, WriteBatch};
+
+/// Task data values with this prefix reference a blob in [`KeySpace::DataBlob`] by content hash
+/// instead of containing the data, see
+/// [`ENCODED_VALUE_MARKER`](crate::value_codec::ENCODED_VALUE_MARKER).
+const BLOB_REFERENCE_PREFIX: &[u8] = b"\0blob";
+
+/// The kind byte of a data blob key that stores the content.
+const BLOB_CONTENT: u8 = 0;
+/// The kind byte of a data blob key that stores the number of references as little-endian u32.
+const BLOB_REF_COUNT: u8 = 1;
+/// The kind byte of a data blob key that stores the baseline of delta encoded task data. The
+/// task id takes the place of the hash.
+const TASK_BASELINE: u8 = 2;
+/// The kind byte of a data blob key that stores the label of the task type of a task. The task id
+/// takes the place of the hash.
+const TASK_LABEL: u8 = 3;
+
+fn blob_key(kind: u8, hash: u128) -> [u8; 17] {
+    let mut key = [0; 17];
+    key[0] = kind;
+    key[1..].copy_from_slice(&hash.to_le_bytes());
+    key
+}
+
+pub(crate) fn blob_content_key(hash: u128) -> [u8; 17] {
+    blob_key(BLOB_CONTENT, hash)
+}
+
+pub(crate) fn task_baseline_key(task_id: u32) -> [u8; 17] {
+    blob_key(TASK_BASELINE, task_id as u128)
+}
+
+pub(crate) fn task_label_key(task_id: u32) -> [u8; 17] {
+    blob_key(TASK_LABEL, task_id as u128)
+}
+
+/// Returns the content hash if `value` is a blob reference.
+pub(crate) fn blob_reference(value: &[u8]) -> Option<u128> {
+    let hash = value.strip_prefix(BLOB_REFERENCE_PREFIX)?;
+    Some(u128::from_le_bytes(hash.try_into().ok()?))
+}
+
+/// Collects the reference count changes of data blobs during a write batch and applies them at
+/// the end, so the batch doesn't need to see its own writes.
+#[derive(Default)]
+pub(crate) struct BlobUpdates {
+    ref_counts: FxHashMap<u128, i64>,
+    contents: FxHashMap<u128, Vec<u8>>,
+}
+
+impl BlobUpdates {
+    /// Adds a reference to a blob with `value` as content and returns the reference that should
+    /// be stored instead of `value`.
+    pub fn add(&mut self, value: Vec<u8>) -> Vec<u8> {
+        let hash = hash_xxh3_hash128(&value[..]);
+        *self.ref_counts.entry(hash).or_default() += 1;
+        self.contents.entry(hash).or_insert(value);
+        let mut reference = BLOB_REFERENCE_PREFIX.to_vec();
+        reference.extend_from_slice(&hash.to_le_bytes());
+        reference
+    }
+
+    /// Removes a reference to the blob with the content hash `hash`.
+    pub fn remove(&mut self, hash: u128) {
+        *self.ref_counts.entry(hash).or_default() -= 1;
+    }
+
+    /// Writes the changed reference counts, stores new blobs and deletes blobs that are no
+    /// longer referenced.
+    pub fn write<'a>(mut self, batch: &mut impl WriteBatch<'a>) -> Result<()> {
+        for (hash, delta) in self.ref_counts {
+            if delta == 0 {
+                continue;
+            }
+            let ref_count_key = blob_key(BLOB_REF_COUNT, hash);
+            let old = match batch.get(KeySpace::DataBlob, &ref_count_key)? {
+                Some(bytes) => u32::from_le_bytes(bytes.borrow().try_into()?) as i64,
+                None => 0,
+            };
+            let new = old + delta;
+            if new <= 0 {
+                batch.delete(KeySpace::DataBlob, Cow::Borrowed(&ref_count_key))?;
+                batch.delete(KeySpace::DataBlob, Cow::Borrowed(&blob_content_key(hash)))?;
+                continue;
+            }
+            batch.put(
+                KeySpace::DataBlob,
+                Cow::Borrowed(&ref_count_key),
+                Cow::Borrowed(&(new as u32).to_le_bytes()),
+            )?;
+            if old == 0 {
+                let content = self
+                    .contents
+                    .remove(&hash)
+                    .context("Content of a new data blob is missing")?;
+                batch.put(
+                    KeySpace::DataBlob,
+                    Cow::Borrowed(&blob_content_key(hash)),
+                    Cow::Owned(content),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
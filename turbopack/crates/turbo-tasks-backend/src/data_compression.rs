@@ -0,0 +1,53 @@
+use std::borrow::Cow;
+
+use anyhow::{Context, Result};
+
+/// Task data values with this prefix are compressed with LZ4. The prefix is followed by the
+/// uncompressed length as little-endian u32 and the compressed block. Uncompressed values are
+/// stored without a header, see [`ENCODED_VALUE_MARKER`](crate::value_codec::ENCODED_VALUE_MARKER).
+const COMPRESSED_PREFIX: &[u8] = b"\0lz4";
+
+/// Compresses `value` if it has at least `min_bytes` bytes and compression makes it smaller.
+pub(crate) fn compress(value: Vec<u8>, min_bytes: usize) -> Vec<u8> {
+    if value.len() < min_bytes {
+        return value;
+    }
+    let compressed = lz4_flex::compress_prepend_size(&value);
+    if COMPRESSED_PREFIX.len() + compressed.len() >= value.len() {
+        return value;
+    }
+    let mut result = Vec::with_capacity(COMPRESSED_PREFIX.len() + compressed.len());
+    result.extend_from_slice(COMPRESSED_PREFIX);
+    result.extend_from_slice(&compressed);
+    result
+}
+
+/// Returns the uncompressed data of `value`. It's borrowed if `value` isn't compressed.
+pub(crate) fn decompress(value: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let Some(compressed) = value.strip_prefix(COMPRESSED_PREFIX) else {
+        return Ok(Cow::Borrowed(value));
+    };
+    let value = lz4_flex::decompress_size_prepended(compressed)
+        .context("Unable to decompress task data")?;
+    Ok(Cow::Owned(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_large_values_are_compressed() -> Result<()> {
+        let small = b"small value".to_vec();
+        let stored = compress(small.clone(), 64);
+        assert_eq!(stored, small);
+        assert!(matches!(decompress(&stored)?, Cow::Borrowed(value) if value == small));
+
+        let large = b"a large value that repeats ".repeat(100);
+        let stored = compress(large.clone(), 64);
+        assert!(stored.starts_with(COMPRESSED_PREFIX));
+        assert!(stored.len() < large.len());
+        assert_eq!(decompress(&stored)?, large);
+        Ok(())
+    }
+}
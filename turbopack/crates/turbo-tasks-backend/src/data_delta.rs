@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+
+/// Task data values with this prefix are a [`Delta`] against the baseline of the task, which is
+/// stored at [`task_baseline_key`](crate::data_blob::task_baseline_key), see
+/// [`ENCODED_VALUE_MARKER`](crate::value_codec::ENCODED_VALUE_MARKER).
+const DELTA_PREFIX: &[u8] = b"\0delta";
+const HEADER_SIZE: usize = DELTA_PREFIX.len() + 12;
+
+/// The difference of task data to its baseline. The data shares a prefix and a suffix with the
+/// baseline and only the bytes in between are stored. This is efficient for data that changes
+/// slightly between snapshots.
+pub(crate) struct Delta<'a> {
+    /// The number of snapshots since the baseline was written.
+    pub generation: u32,
+    prefix: usize,
+    suffix: usize,
+    middle: &'a [u8],
+}
+
+impl<'a> Delta<'a> {
+    /// Returns the delta if `value` is delta encoded.
+    pub fn decode(value: &'a [u8]) -> Option<Self> {
+        let header = value.strip_prefix(DELTA_PREFIX)?;
+        if header.len() < 12 {
+            return None;
+        }
+        let read_u32 = |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+        Some(Self {
+            generation: read_u32(0),
+            prefix: read_u32(4) as usize,
+            suffix: read_u32(8) as usize,
+            middle: &header[12..],
+        })
+    }
+
+    /// Encodes the difference of `value` to `baseline`.
+    pub fn encode(generation: u32, baseline: &[u8], value: &[u8]) -> Vec<u8> {
+        let prefix = baseline
+            .iter()
+            .zip(value)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let suffix = baseline[prefix..]
+            .iter()
+            .rev()
+            .zip(value[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let middle = &value[prefix..value.len() - suffix];
+        let mut result = Vec::with_capacity(HEADER_SIZE + middle.len());
+        result.extend_from_slice(DELTA_PREFIX);
+        result.extend_from_slice(&generation.to_le_bytes());
+        result.extend_from_slice(&(prefix as u32).to_le_bytes());
+        result.extend_from_slice(&(suffix as u32).to_le_bytes());
+        result.extend_from_slice(middle);
+        result
+    }
+
+    /// Reconstructs the data from the `baseline` the delta was encoded against.
+    pub fn apply(&self, baseline: &[u8]) -> Result<Vec<u8>> {
+        if self.prefix + self.suffix > baseline.len() {
+            bail!(
+                "Delta doesn't fit the baseline: {} + {} shared bytes, but the baseline has {} \
+                 bytes",
+                self.prefix,
+                self.suffix,
+                baseline.len()
+            );
+        }
+        let mut result = Vec::with_capacity(self.prefix + self.middle.len() + self.suffix);
+        result.extend_from_slice(&baseline[..self.prefix]);
+        result.extend_from_slice(self.middle);
+        result.extend_from_slice(&baseline[baseline.len() - self.suffix..]);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incremental_changes_round_trip() -> Result<()> {
+        let baseline = b"the quick brown fox jumps over the lazy dog".to_vec();
+        let versions: [&[u8]; 5] = [
+            b"the quick brown fox jumps over the lazy dog",
+            b"the quick red fox jumps over the lazy dog",
+            b"the quick red fox jumps over the lazy dogs",
+            b"a quick red fox jumps over the lazy dogs",
+            b"",
+        ];
+        for (generation, version) in versions.into_iter().enumerate() {
+            let encoded = Delta::encode(generation as u32, &baseline, version);
+            let delta = Delta::decode(&encoded).unwrap();
+            assert_eq!(delta.generation, generation as u32);
+            assert_eq!(delta.apply(&baseline)?, version);
+        }
+        // Only the changed bytes are stored
+        let encoded = Delta::encode(1, &baseline, versions[1]);
+        assert_eq!(encoded.len(), HEADER_SIZE + b"red".len());
+        assert!(Delta::decode(&baseline).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::num::NonZeroUsize;
+
+use anyhow::{bail, Result};
+
+/// Task data values with this prefix are framed. The prefix is followed by a version byte, the
+/// length of the serialized data as little-endian u32 and the serialized data, see
+/// [`ENCODED_VALUE_MARKER`](crate::value_codec::ENCODED_VALUE_MARKER). In version 2
+/// the version byte is followed by a byte that identifies the writer, see
+/// [`BackingStorageOptions::writer_version`](crate::BackingStorageOptions::writer_version).
+/// [`PADDED`] is set in the version byte when the data is followed by zero bytes.
+const FRAME_PREFIX: &[u8] = b"\0frame";
+const FRAME_VERSION: u8 = 1;
+const FRAME_VERSION_WITH_WRITER: u8 = 2;
+const PADDED: u8 = 0x80;
+const HEADER_SIZE: usize = FRAME_PREFIX.len() + 5;
+
+/// Prefixes the serialized data `value` with its length and the `writer` if any. With an
+/// `alignment` the framed value is padded with zero bytes to a multiple of it, see
+/// [`BackingStorageOptions::pad_values_to`](crate::BackingStorageOptions::pad_values_to).
+pub(crate) fn frame(
+    value: Vec<u8>,
+    writer: Option<u8>,
+    alignment: Option<NonZeroUsize>,
+) -> Vec<u8> {
+    let len = HEADER_SIZE + usize::from(writer.is_some()) + value.len();
+    let padded_len = alignment.map_or(len, |alignment| len.next_multiple_of(alignment.get()));
+    let mut result = Vec::with_capacity(padded_len);
+    result.extend_from_slice(FRAME_PREFIX);
+    let padded = if padded_len > len { PADDED } else { 0 };
+    match writer {
+        Some(writer) => result.extend_from_slice(&[FRAME_VERSION_WITH_WRITER | padded, writer]),
+        None => result.push(FRAME_VERSION | padded),
+    }
+    result.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    result.extend_from_slice(&value);
+    result.resize(padded_len, 0);
+    result
+}
+
+/// Returns the serialized data of `value` and its writer, if it was recorded. Unframed values are
+/// returned as is. Fails with a precise error when a framed value doesn't have the length it was
+/// written with.
+pub(crate) fn unframe(value: &[u8]) -> Result<(&[u8], Option<u8>)> {
+    let Some(framed) = value.strip_prefix(FRAME_PREFIX) else {
+        return Ok((value, None));
+    };
+    let Some((&version, framed)) = framed.split_first() else {
+        bail!("Truncated value: the frame header is incomplete");
+    };
+    let padded = version & PADDED != 0;
+    let (writer, framed) = match version & !PADDED {
+        FRAME_VERSION => (None, framed),
+        FRAME_VERSION_WITH_WRITER => {
+            let Some((&writer, framed)) = framed.split_first() else {
+                bail!("Truncated value: the frame header is incomplete");
+            };
+            (Some(writer), framed)
+        }
+        _ => bail!("Unsupported frame version {version}"),
+    };
+    let Some((len, data)) = framed.split_first_chunk::<4>() else {
+        bail!("Truncated value: the frame header is incomplete");
+    };
+    let len = u32::from_le_bytes(*len) as usize;
+    if data.len() < len {
+        bail!(
+            "Truncated value: expected {len} bytes, but only {} bytes are stored",
+            data.len()
+        );
+    }
+    let (data, padding) = data.split_at(len);
+    if !padding.is_empty() && !(padded && padding.iter().all(|&byte| byte == 0)) {
+        bail!(
+            "Corrupted value: expected {len} bytes, but {} bytes are stored",
+            len + padding.len()
+        );
+    }
+    Ok((data, writer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framed_values_round_trip() -> Result<()> {
+        let value = b"serialized data".to_vec();
+        assert_eq!(unframe(&value)?, (&value[..], None));
+        let framed = frame(value.clone(), None, None);
+        assert_eq!(framed.len(), HEADER_SIZE + value.len());
+        assert_eq!(unframe(&framed)?, (&value[..], None));
+        let framed = frame(value.clone(), Some(7), None);
+        assert_eq!(framed.len(), HEADER_SIZE + 1 + value.len());
+        assert_eq!(unframe(&framed)?, (&value[..], Some(7)));
+        assert!(unframe(&framed[..HEADER_SIZE - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn padded_values_round_trip() -> Result<()> {
+        let alignment = NonZeroUsize::new(64).unwrap();
+        for len in [0, 1, 52, 53, 200] {
+            let value = vec![1; len];
+            for writer in [None, Some(7)] {
+                let framed = frame(value.clone(), writer, Some(alignment));
+                assert_eq!(framed.len() % 64, 0);
+                assert!(framed.len() - value.len() < HEADER_SIZE + 1 + 64);
+                assert_eq!(unframe(&framed)?, (&value[..], writer));
+            }
+        }
+        // Padding must be zero bytes
+        let mut framed = frame(vec![1; 10], None, Some(alignment));
+        *framed.last_mut().unwrap() = 1;
+        assert!(unframe(&framed).is_err());
+        Ok(())
+    }
+}
@@ -6,6 +6,8 @@ pub struct ByKeySpace<T> {
     task_data: T,
     forward_task_cache: T,
     reverse_task_cache: T,
+    data_blob: T,
+    task_tags: T,
 }
 
 impl<T> ByKeySpace<T> {
@@ -16,6 +18,8 @@ impl<T> ByKeySpace<T> {
             task_data: factory(KeySpace::TaskData),
             forward_task_cache: factory(KeySpace::ForwardTaskCache),
             reverse_task_cache: factory(KeySpace::ReverseTaskCache),
+            data_blob: factory(KeySpace::DataBlob),
+            task_tags: factory(KeySpace::TaskTags),
         }
     }
 
@@ -26,6 +30,8 @@ impl<T> ByKeySpace<T> {
             KeySpace::TaskData => &self.task_data,
             KeySpace::ForwardTaskCache => &self.forward_task_cache,
             KeySpace::ReverseTaskCache => &self.reverse_task_cache,
+            KeySpace::DataBlob => &self.data_blob,
+            KeySpace::TaskTags => &self.task_tags,
         }
     }
 
@@ -36,6 +42,8 @@ impl<T> ByKeySpace<T> {
             KeySpace::TaskData => &mut self.task_data,
             KeySpace::ForwardTaskCache => &mut self.forward_task_cache,
             KeySpace::ReverseTaskCache => &mut self.reverse_task_cache,
+            KeySpace::DataBlob => &mut self.data_blob,
+            KeySpace::TaskTags => &mut self.task_tags,
         }
     }
 
@@ -46,6 +54,8 @@ impl<T> ByKeySpace<T> {
             (KeySpace::TaskData, &self.task_data),
             (KeySpace::ForwardTaskCache, &self.forward_task_cache),
             (KeySpace::ReverseTaskCache, &self.reverse_task_cache),
+            (KeySpace::DataBlob, &self.data_blob),
+            (KeySpace::TaskTags, &self.task_tags),
         ]
         .into_iter()
     }
@@ -63,6 +63,27 @@ impl<T: KeyValueDatabase> KeyValueDatabase for FreshDbOptimization<T> {
         self.database.get(transaction, key_space, key)
     }
 
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        if self.fresh_db.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        self.database.iterate(transaction, key_space, start, f)
+    }
+
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        self.database.used_capacity()
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.database.is_immutable()
+    }
+
     type WriteBatch<'l>
         = FreshDbOptimizationWriteBatch<'l, T>
     where
@@ -105,10 +126,29 @@ where
         self.write_batch.put(key_space, key, value)
     }
 
+    fn put_with(
+        &mut self,
+        key_space: KeySpace,
+        key: Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.write_batch.put_with(key_space, key, len, write)
+    }
+
     fn delete(&mut self, key_space: KeySpace, key: Cow<[u8]>) -> Result<()> {
         self.write_batch.delete(key_space, key)
     }
 
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.write_batch.iterate(key_space, start, f)
+    }
+
     fn commit(self) -> Result<()> {
         self.fresh_db.store(false, Ordering::Release);
         self.write_batch.commit()
@@ -1,14 +1,67 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+};
 
 use anyhow::Result;
 
-#[derive(Debug, Clone, Copy)]
+/// The error returned when a write fails because the filesystem of the database is read-only,
+/// e.g. after it was remounted read-only because the disk is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOnlyFilesystem;
+
+impl Display for ReadOnlyFilesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The database is on a read-only filesystem")
+    }
+}
+
+impl std::error::Error for ReadOnlyFilesystem {}
+
+/// The error returned when a database call was interrupted by a signal and can be retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The database operation was interrupted by a signal")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// The error returned when a write doesn't fit into the write transaction, e.g. because LMDB's
+/// limit of dirty pages (`MDB_TXN_FULL`) is exceeded. Committing the writes in smaller
+/// transactions avoids it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionFull {
+    /// Whether the write was rejected without affecting the transaction, so the writes before it
+    /// can still be committed. LMDB aborts the transaction when it exceeds its own limit.
+    pub committable: bool,
+}
+
+impl Display for TransactionFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The write transaction is full")
+    }
+}
+
+impl std::error::Error for TransactionFull {}
+
+/// How often an operation that is [`Interrupted`] is retried before the error is returned.
+pub(crate) const MAX_INTERRUPTED_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeySpace {
     Infra,
     TaskMeta,
     TaskData,
     ForwardTaskCache,
     ReverseTaskCache,
+    /// Task data shared by multiple tasks, keyed by content hash.
+    DataBlob,
+    /// The ids of the tasks with a tag, keyed by tag.
+    TaskTags,
 }
 
 pub trait WriteBatch<'a> {
@@ -21,7 +74,31 @@ pub trait WriteBatch<'a> {
     where
         'a: 'l;
     fn put(&mut self, key_space: KeySpace, key: Cow<[u8]>, value: Cow<[u8]>) -> Result<()>;
+    /// Stores a value of `len` bytes that is written by `write`. Databases that support it let
+    /// `write` fill the stored value in place, which avoids an intermediate buffer for large
+    /// values. `write` must fill the whole buffer.
+    fn put_with(
+        &mut self,
+        key_space: KeySpace,
+        key: Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        let mut value = vec![0; len];
+        write(&mut value)?;
+        self.put(key_space, key, Cow::Owned(value))
+    }
     fn delete(&mut self, key_space: KeySpace, key: Cow<[u8]>) -> Result<()>;
+    /// Calls `f` for the entries of a key space like [`KeyValueDatabase::iterate`], but within
+    /// the batch. Databases with write transactions read them in the transaction, so nothing
+    /// else can write the entries before the batch is committed. Batches that defer their writes
+    /// to the commit don't see their own writes, so entries should be iterated before writing.
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()>;
     fn commit(self) -> Result<()>;
 }
 
@@ -47,8 +124,31 @@ pub trait KeyValueDatabase {
         key: &[u8],
     ) -> Result<Option<Self::ValueBuffer<'l>>>;
 
+    /// Calls `f` for the entries of a key space, starting at the first key that is not less than
+    /// `start`. Databases with ordered keys visit entries in ascending key order. Integer keys,
+    /// i.e. little-endian task ids, are ordered by their value, so tasks are visited in ascending
+    /// task id order. The iteration stops when `f` returns `false`.
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()>;
+
     type WriteBatch<'l>: WriteBatch<'l>
     where
         Self: 'l;
     fn write_batch(&self) -> Result<Self::WriteBatch<'_>>;
+
+    /// Returns the fraction of the capacity of the database that is in use, e.g. of LMDB's map,
+    /// or `None` when the capacity isn't bounded.
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        Ok(None)
+    }
+
+    /// Returns whether the database was opened immutable, so every write fails.
+    fn is_immutable(&self) -> bool {
+        false
+    }
 }
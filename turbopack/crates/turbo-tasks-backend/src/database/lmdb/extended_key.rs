@@ -1,10 +1,9 @@
-use std::hash::{Hash, Hasher};
-
 use byteorder::ByteOrder;
 use lmdb::{Database, RwTransaction, Transaction, WriteFlags};
-use rustc_hash::FxHasher;
 
-const MAX_KEY_SIZE: usize = 511;
+use crate::utils::stable_hash::stable_hash;
+
+pub const MAX_KEY_SIZE: usize = 511;
 const SHARED_KEY: usize = MAX_KEY_SIZE - 8;
 
 pub fn get<'tx, T: Transaction>(
@@ -102,11 +101,77 @@ pub fn delete(
     }
 }
 
+/// Calls `f` for every logical entry stored under a raw database entry. Extended keys are
+/// expanded to the original key. Returns `false` when `f` stopped the iteration.
+pub fn visit_entries(
+    key: &[u8],
+    value: &[u8],
+    f: &mut dyn FnMut(&[u8], &[u8]) -> anyhow::Result<bool>,
+) -> anyhow::Result<bool> {
+    if key.len() != MAX_KEY_SIZE {
+        return f(key, value);
+    }
+    let mut full_key = Vec::with_capacity(MAX_KEY_SIZE * 2);
+    for (suffix, value) in ExtendedValueIter::new(value) {
+        full_key.clear();
+        full_key.extend_from_slice(&key[8..]);
+        full_key.extend_from_slice(suffix);
+        if !f(&full_key, value)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Returns the hashed key a long `key` is stored under and the key bytes that identify its record
+/// in that entry, or `None` when `key` is short enough to be stored directly.
+pub fn layout(key: &[u8]) -> Option<([u8; MAX_KEY_SIZE], &[u8])> {
+    if key.len() > MAX_KEY_SIZE - 1 {
+        Some((hashed_key(key), &key[SHARED_KEY..]))
+    } else {
+        None
+    }
+}
+
+/// Checks an entry stored under the hashed `key`. Every record must be complete and hash to
+/// `key`. Returns the reason when the entry is malformed, together with the well-formed records
+/// that can be stored to repair the entry.
+pub fn verify_entry(key: &[u8], value: &[u8]) -> Option<(&'static str, Vec<u8>)> {
+    if value.is_empty() {
+        return Some(("the entry has no records", Vec::new()));
+    }
+    let mut reason = None;
+    let mut valid = Vec::with_capacity(value.len());
+    let mut full_key = Vec::with_capacity(MAX_KEY_SIZE * 2);
+    let mut pos = 0;
+    while pos < value.len() {
+        let Some(header) = value.get(pos..pos + 8) else {
+            reason = Some("the last record has an incomplete header");
+            break;
+        };
+        let key_len = byteorder::BigEndian::read_u32(&header[..4]) as usize;
+        let value_len = byteorder::BigEndian::read_u32(&header[4..]) as usize;
+        let end = pos + 8 + key_len + value_len;
+        let Some(record) = value.get(pos + 8..end) else {
+            reason = Some("the last record is incomplete");
+            break;
+        };
+        full_key.clear();
+        full_key.extend_from_slice(&key[8..]);
+        full_key.extend_from_slice(&record[..key_len]);
+        if hashed_key(&full_key)[..] == *key {
+            valid.extend_from_slice(&value[pos..end]);
+        } else {
+            reason.get_or_insert("a record doesn't belong to the key");
+        }
+        pos = end;
+    }
+    reason.map(|reason| (reason, valid))
+}
+
 fn hashed_key(key: &[u8]) -> [u8; MAX_KEY_SIZE] {
     let mut result = [0; MAX_KEY_SIZE];
-    let mut hash = FxHasher::default();
-    key.hash(&mut hash);
-    byteorder::BigEndian::write_u64(&mut result, hash.finish());
+    byteorder::BigEndian::write_u64(&mut result, stable_hash(key));
     result[8..].copy_from_slice(&key[0..SHARED_KEY]);
     result
 }
@@ -1,60 +1,1033 @@
-use std::{borrow::Cow, fs::create_dir_all, path::Path, thread::available_parallelism};
+use std::{
+    borrow::Cow,
+    ffi::{c_char, c_int, c_void, CStr},
+    fs::{create_dir_all, File},
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread::available_parallelism,
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use lmdb::{
-    Database, DatabaseFlags, Environment, EnvironmentFlags, RoTransaction, RwTransaction,
+    Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, RoTransaction, RwTransaction,
     Transaction, WriteFlags,
 };
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+use rustc_hash::FxHashMap;
 
-use crate::database::key_value_database::{KeySpace, KeyValueDatabase, WriteBatch};
+use crate::database::key_value_database::{
+    Interrupted, KeySpace, KeyValueDatabase, ReadOnlyFilesystem, TransactionFull, WriteBatch,
+    MAX_INTERRUPTED_RETRIES,
+};
 
 mod extended_key;
 
+/// The number of named databases of one storage.
+const DBS_PER_NAMESPACE: u32 = 7;
+/// The number of storages with different namespaces that can share an environment.
+const MAX_NAMESPACES: u32 = 8;
+const MAX_DBS: u32 = DBS_PER_NAMESPACE * MAX_NAMESPACES;
+
+/// Converts a key between the little-endian integer keys of the key spaces and the native byte
+/// order that databases with `INTEGER_KEY` compare their 4 byte keys in, so they are iterated in
+/// ascending task id order on every platform. Range scans rely on this order. It's a no-op on
+/// little-endian platforms and its own inverse, so it converts in both directions.
+fn native_key(key_space: KeySpace, key: &[u8]) -> Cow<'_, [u8]> {
+    match key_space {
+        KeySpace::Infra | KeySpace::TaskMeta | KeySpace::TaskData | KeySpace::ReverseTaskCache
+            if cfg!(target_endian = "big") =>
+        {
+            Cow::Owned(key.iter().rev().copied().collect())
+        }
+        _ => Cow::Borrowed(key),
+    }
+}
+
+/// The environments that are currently open, keyed by canonicalized path. LMDB doesn't allow to
+/// open the same environment twice in one process, so all instances for a path share one
+/// environment. It's closed when the last instance is dropped.
+static ENVIRONMENTS: Lazy<Mutex<FxHashMap<PathBuf, Weak<SharedEnvironment>>>> =
+    Lazy::new(Default::default);
+/// Notified when an environment in [`ENVIRONMENTS`] was closed.
+static ENVIRONMENT_CLOSED: Condvar = Condvar::new();
+
+/// An environment in [`ENVIRONMENTS`]. It's closed and removed while the registry is locked, so
+/// its path can't be opened again before it's closed.
+struct SharedEnvironment {
+    env: ManuallyDrop<Environment>,
+    path: PathBuf,
+    /// The flags the environment was opened with.
+    flags: EnvironmentFlags,
+    /// The maximum number of named databases the environment was opened with. LMDB has no
+    /// getter for it.
+    max_dbs: u32,
+}
+
+impl Deref for SharedEnvironment {
+    type Target = Environment;
+
+    fn deref(&self) -> &Environment {
+        &self.env
+    }
+}
+
+impl Drop for SharedEnvironment {
+    fn drop(&mut self) {
+        let mut environments = ENVIRONMENTS.lock();
+        // The entry was replaced when the environment was forgotten
+        if environments
+            .get(&self.path)
+            .is_some_and(|env| std::ptr::eq(env.as_ptr(), self))
+        {
+            environments.remove(&self.path);
+        }
+        // Safety: The environment isn't used after this.
+        unsafe { ManuallyDrop::drop(&mut self.env) };
+        drop(environments);
+        ENVIRONMENT_CLOSED.notify_all();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LmdbOptions {
+    /// The flags the environment is opened with. `NO_TLS` is removed with
+    /// [`LmdbOptions::thread_local_readers`].
+    pub flags: EnvironmentFlags,
+    pub map_size: usize,
+    pub max_readers: u32,
+    /// Stores forward task cache keys directly instead of via `extended_key`. Keys longer than
+    /// LMDB's key size limit are rejected with an error. This must not be changed for an existing
+    /// database, as keys written in the other mode are not found.
+    pub short_keys_only: bool,
+    /// Fails write batches with [`TimedOut`](crate::database::TimedOut) when they take longer
+    /// than this to commit. It's applied by `lmdb_backing_storage_with_options` via the
+    /// [`OperationTimeout`](crate::database::OperationTimeout) layer.
+    pub operation_timeout: Option<Duration>,
+    /// Prefixes the names of the databases in the environment, so multiple storages or other
+    /// data can share one environment. A key prefix can't be used, since integer keys must all
+    /// have the same size.
+    pub namespace: Option<&'static str>,
+    /// What to do when the map size exceeds the free disk space. The data file is sparse, so the
+    /// environment can be opened, but writes fail once the disk is full.
+    pub map_size_check: MapSizeCheck,
+    /// Releases the reader slots of processes that exited without closing the environment when
+    /// the database is opened. Otherwise they stay occupied, and opening read transactions fails
+    /// with `ReadersFull` when all slots are occupied.
+    pub clear_stale_readers: bool,
+    /// Fails writes with [`TransactionFull`] once a write transaction wrote more than this many
+    /// bytes of keys and values. The rejected write isn't applied, so the transaction can still
+    /// be committed. LMDB fails with `MDB_TXN_FULL` by itself when a transaction exceeds its
+    /// dirty page limit, but only without `WRITE_MAP`, and the transaction can't be committed
+    /// then.
+    pub max_transaction_bytes: Option<usize>,
+    /// Opens the environment without `NO_TLS`, so LMDB binds reader slots to threads and reuses
+    /// a thread's slot for its next read transaction, which makes read transactions slightly
+    /// cheaper. Only for embedders that use the database from a single thread: a read
+    /// transaction must then be used and dropped on the thread that began it, and a thread can
+    /// only have one read transaction at a time. Beginning a second one, e.g. a lookup while a
+    /// [`StoreSnapshot`](crate::StoreSnapshot) is alive, fails with `BadRslot`. The
+    /// multi-threaded engine moves read transactions between threads via
+    /// [`ReadTransactionCache`](crate::database::ReadTransactionCache), so it requires `NO_TLS`.
+    pub thread_local_readers: bool,
+}
+
+/// How to handle a map size that exceeds the free disk space when opening a database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapSizeCheck {
+    /// Doesn't check the free disk space.
+    Off,
+    /// Logs a warning.
+    Warn,
+    /// Fails to open the database.
+    Error,
+}
+
+impl Default for LmdbOptions {
+    fn default() -> Self {
+        #[cfg(target_arch = "x86")]
+        const MAP_SIZE: usize = usize::MAX;
+        #[cfg(not(target_arch = "x86"))]
+        const MAP_SIZE: usize = 40 * 1024 * 1024 * 1024;
+
+        Self {
+            flags: EnvironmentFlags::WRITE_MAP
+                | EnvironmentFlags::NO_META_SYNC
+                | EnvironmentFlags::NO_TLS,
+            map_size: MAP_SIZE,
+            max_readers: (available_parallelism().map_or(16, |v| v.get()) * 8) as u32,
+            short_keys_only: false,
+            operation_timeout: None,
+            namespace: None,
+            map_size_check: MapSizeCheck::Warn,
+            clear_stale_readers: true,
+            max_transaction_bytes: None,
+            thread_local_readers: false,
+        }
+    }
+}
+
+/// The configuration the environment was actually opened with, as reported by LMDB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveConfig {
+    pub flags: EnvironmentFlags,
+    pub map_size: usize,
+    pub max_readers: u32,
+    pub max_dbs: u32,
+}
+
+/// The state of the environment, as reported by [`LmbdKeyValueDatabase::environment_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvInfo {
+    pub map_size: usize,
+    /// The number of the last page that was ever used. The data file contains this many pages
+    /// plus one.
+    pub last_page: usize,
+    /// The number of reader slots in use. LMDB doesn't release slots, but reuses the slots of
+    /// finished read transactions, so this is the most read transactions that were open at the
+    /// same time.
+    pub readers: u32,
+    pub max_readers: u32,
+}
+
+/// Statistics about the stored values, as reported by [`LmbdKeyValueDatabase::db_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DbStats {
+    pub page_size: u32,
+    /// The number of entries of the task data database.
+    pub data_entries: u64,
+    /// The entries of the task data database whose value doesn't fit into a leaf page and is
+    /// stored on overflow pages instead.
+    pub data_overflow_entries: u64,
+    /// The number of overflow pages used by the task data database. A high count compared to the
+    /// number of entries indicates that compression or a larger page size might help.
+    pub data_overflow_pages: u64,
+}
+
+/// How much of the file is used by stored data, as reported by
+/// [`LmbdKeyValueDatabase::fragmentation`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FragmentationReport {
+    pub page_size: u32,
+    /// The sum of the key and value bytes of all entries.
+    pub logical_bytes: u64,
+    /// The bytes of all pages that were ever used. LMDB reuses freed pages, but never shrinks
+    /// the file.
+    pub physical_bytes: u64,
+    /// The share of the physical bytes that don't hold stored data, between 0 and 1. Page and
+    /// node headers and partially filled pages make it non-zero for a compact database, too.
+    pub ratio: f64,
+    /// Whether compacting the database would reclaim a significant amount of space.
+    pub compaction_recommended: bool,
+}
+
+/// Compaction is recommended when more than this share of the file doesn't hold stored data...
+const COMPACTION_RATIO: f64 = 0.5;
+/// ...and compaction would reclaim at least this many bytes.
+const COMPACTION_MIN_RECLAIMABLE_BYTES: u64 = 1024 * 1024;
+
+/// The smallest map size [`LmbdKeyValueDatabase::shrink_to_fit`] shrinks the map to.
+const MIN_SHRUNK_MAP_SIZE: usize = 64 * 1024 * 1024;
+
+/// A malformed entry of an extended key, as reported by
+/// [`LmbdKeyValueDatabase::verify_extended_keys`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidExtendedKey {
+    /// The hashed key the entry is stored under.
+    pub key: Vec<u8>,
+    pub reason: &'static str,
+}
+
+/// The raw LMDB key a key is stored under, as returned by [`raw_key_layout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawKeyLayout {
+    /// The key is stored as is.
+    Direct(Vec<u8>),
+    /// The key exceeds LMDB's key size limit. It's stored under `hashed_key`, the big-endian 64
+    /// bit xxh3 hash (seed 0) of the key followed by its leading bytes. Keys with the same
+    /// hashed key share one entry, whose value is a list of records. Each record is the
+    /// big-endian u32 length of the remaining key bytes, the big-endian u32 length of the
+    /// value, the remaining key bytes and the value. The record of this key is the one whose
+    /// remaining key bytes are `record_key`.
+    Extended {
+        hashed_key: Vec<u8>,
+        record_key: Vec<u8>,
+    },
+}
+
+/// Returns how `key` is stored in a database opened with `options`. This allows inspecting the
+/// raw LMDB databases without this crate.
+pub fn raw_key_layout(key: &[u8], options: &LmdbOptions) -> RawKeyLayout {
+    if options.short_keys_only {
+        return RawKeyLayout::Direct(key.to_vec());
+    }
+    match extended_key::layout(key) {
+        Some((hashed_key, record_key)) => RawKeyLayout::Extended {
+            hashed_key: hashed_key.to_vec(),
+            record_key: record_key.to_vec(),
+        },
+        None => RawKeyLayout::Direct(key.to_vec()),
+    }
+}
+
+/// Compares the map size with the free disk space at `path`. The space of an existing data file
+/// is already allocated, so it counts as available.
+fn check_map_size(path: &Path, options: &LmdbOptions) -> Result<()> {
+    if options.map_size_check == MapSizeCheck::Off {
+        return Ok(());
+    }
+    let Some(available) = available_disk_space(path)? else {
+        return Ok(());
+    };
+    let allocated = path
+        .join("data.mdb")
+        .metadata()
+        .map_or(0, |metadata| metadata.len());
+    let map_size = options.map_size as u64;
+    if map_size <= available.saturating_add(allocated) {
+        return Ok(());
+    }
+    let message = format!(
+        "The LMDB map size of {map_size} bytes exceeds the {available} bytes of free disk space \
+         at {}. The data file is sparse, so the database opens, but writes will fail once the \
+         disk is full.",
+        path.display()
+    );
+    if options.map_size_check == MapSizeCheck::Error {
+        bail!(message);
+    }
+    tracing::warn!("{message}");
+    Ok(())
+}
+
+/// Releases the reader slots of processes that no longer exist and returns their number.
+fn clear_stale_readers(env: &Environment) -> Result<usize> {
+    let mut dead = 0;
+    // Safety: The environment is open for the lifetime of the reference
+    let code = unsafe { lmdb_sys::mdb_reader_check(env.env(), &mut dead) };
+    if code != 0 {
+        return Err(anyhow::Error::new(lmdb::Error::from_err_code(code))
+            .context("Unable to check for stale readers"));
+    }
+    Ok(dead as usize)
+}
+
+/// Returns the ids of the processes other than this one that have a reader registered in the
+/// reader table of `env`, after releasing the slots of exited processes.
+fn other_process_readers(env: &Environment) -> Result<Vec<u32>> {
+    unsafe extern "C" fn collect(msg: *const c_char, ctx: *mut c_void) -> c_int {
+        // Safety: LMDB passes a nul terminated line of the reader table and the `ctx` below
+        let (msg, pids) = unsafe { (CStr::from_ptr(msg), &mut *ctx.cast::<Vec<u32>>()) };
+        // Lines start with the process id, the header and the empty table message don't parse
+        pids.extend(
+            msg.to_string_lossy()
+                .split_whitespace()
+                .next()
+                .and_then(|pid| pid.parse::<u32>().ok()),
+        );
+        0
+    }
+
+    clear_stale_readers(env)?;
+    let mut pids = Vec::new();
+    // Safety: The environment is open for the lifetime of the reference and `pids` outlives
+    // the call
+    let code = unsafe {
+        lmdb_sys::mdb_reader_list(
+            env.env(),
+            Some(collect),
+            (&mut pids as *mut Vec<u32>).cast(),
+        )
+    };
+    if code < 0 {
+        return Err(anyhow::Error::new(lmdb::Error::from_err_code(code))
+            .context("Unable to list the readers"));
+    }
+    let own = std::process::id();
+    pids.retain(|&pid| pid != own);
+    pids.sort_unstable();
+    pids.dedup();
+    Ok(pids)
+}
+
+/// Returns the flags of the environment as reported by LMDB.
+fn environment_flags(env: &Environment) -> Result<EnvironmentFlags> {
+    let mut flags = 0;
+    // Safety: The environment is open for the lifetime of the reference
+    let code = unsafe { lmdb_sys::mdb_env_get_flags(env.env(), &mut flags) };
+    if code != 0 {
+        return Err(anyhow::Error::new(lmdb::Error::from_err_code(code))
+            .context("Unable to read the environment flags"));
+    }
+    Ok(EnvironmentFlags::from_bits_truncate(flags))
+}
+
+/// Returns the disk space at `path` that is available to unprivileged users, if it can be
+/// determined on this platform.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn available_disk_space(path: &Path) -> Result<Option<u64>> {
+    let stat = nix::sys::statvfs::statvfs(path)
+        .with_context(|| format!("Unable to query free disk space at {}", path.display()))?;
+    Ok(Some(
+        (stat.blocks_available() as u64).saturating_mul(stat.fragment_size() as u64),
+    ))
+}
+
+#[cfg(not(unix))]
+fn available_disk_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// The size of a page header in LMDB.
+const PAGE_HEADER_SIZE: usize = 16;
+/// The size of a node header in a leaf page in LMDB.
+const NODE_HEADER_SIZE: usize = 8;
+
+/// Returns the number of overflow pages LMDB uses for an entry, or `None` when the entry is stored
+/// in a leaf page. This mirrors `mdb_leaf_size`: a node that exceeds half of the usable page size
+/// stores its value on overflow pages. Like LMDB's `me_nodemax`, the limit leaves room for the
+/// node's index in the page.
+fn overflow_pages(page_size: usize, key_len: usize, value_len: usize) -> Option<usize> {
+    let max_node_size = (((page_size - PAGE_HEADER_SIZE) / 2) & !1) - size_of::<u16>();
+    if NODE_HEADER_SIZE + key_len + value_len <= max_node_size {
+        return None;
+    }
+    Some((PAGE_HEADER_SIZE - 1 + value_len) / page_size + 1)
+}
+
+fn os_error_kind(err: &lmdb::Error) -> Option<std::io::ErrorKind> {
+    match err {
+        lmdb::Error::Other(code) => Some(std::io::Error::from_raw_os_error(*code).kind()),
+        _ => None,
+    }
+}
+
+/// Reports writes that fail because the filesystem is read-only as [`ReadOnlyFilesystem`],
+/// writes that were interrupted by a signal as [`Interrupted`] and writes that exceed the dirty
+/// page limit as [`TransactionFull`].
+fn map_write_error(err: lmdb::Error) -> anyhow::Error {
+    if err == lmdb::Error::TxnFull {
+        return anyhow::Error::new(err).context(TransactionFull { committable: false });
+    }
+    match os_error_kind(&err) {
+        Some(std::io::ErrorKind::ReadOnlyFilesystem) => {
+            anyhow::Error::new(err).context(ReadOnlyFilesystem)
+        }
+        Some(std::io::ErrorKind::Interrupted) => anyhow::Error::new(err).context(Interrupted),
+        _ => err.into(),
+    }
+}
+
+/// Calls `f` again when it was interrupted by a signal, up to [`MAX_INTERRUPTED_RETRIES`] times.
+/// LMDB retries interrupted reads and writes itself, but e.g. waiting for a lock can be
+/// interrupted.
+fn retry_interrupted<R>(mut f: impl FnMut() -> lmdb::Result<R>) -> lmdb::Result<R> {
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(err)
+                if os_error_kind(&err) == Some(std::io::ErrorKind::Interrupted)
+                    && retries < MAX_INTERRUPTED_RETRIES =>
+            {
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Makes the entries of the directory at `path` durable, e.g. of newly created files.
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Unable to sync directory {}", path.display()))
+}
+
+/// Directories can't be opened as files on this platform. Directory entries are durable without
+/// syncing them on NTFS.
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
 pub struct LmbdKeyValueDatabase {
-    env: Environment,
+    env: Arc<SharedEnvironment>,
+    config: EffectiveConfig,
+    short_keys_only: bool,
+    max_transaction_bytes: Option<usize>,
+    immutable: bool,
+    /// Whether the store was created by this instance and its directory entries were synced.
+    #[cfg_attr(not(test), allow(dead_code))]
+    synced_directories: bool,
     infra_db: Database,
     data_db: Database,
     meta_db: Database,
     forward_task_cache_db: Database,
     reverse_task_cache_db: Database,
+    data_blob_db: Database,
+    task_tags_db: Database,
+    /// Set when a forward task cache key was found stored as a plain key, see
+    /// [`LmbdKeyValueDatabase::uses_legacy_forward_keys`].
+    legacy_forward_keys: AtomicBool,
+    /// The number of stale reader slots released when the database was opened, see
+    /// [`LmdbOptions::clear_stale_readers`].
+    cleared_stale_readers: usize,
+    /// The number of following commits that fail as if they were interrupted by a signal.
+    #[cfg(all(test, unix))]
+    pub(crate) interrupted_commits: std::sync::atomic::AtomicUsize,
+    /// The number of milliseconds each commit blocks before it's applied.
+    #[cfg(test)]
+    pub(crate) commit_delay: std::sync::atomic::AtomicU64,
 }
 
 impl LmbdKeyValueDatabase {
     pub fn new(path: &Path) -> Result<Self> {
+        Self::with_options(path, LmdbOptions::default())
+    }
+
+    pub fn with_options(path: &Path, options: LmdbOptions) -> Result<Self> {
         create_dir_all(path).context("Creating database directory failed")?;
+        Self::open(path, options, false)
+    }
 
-        #[cfg(target_arch = "x86")]
-        const MAP_SIZE: usize = usize::MAX;
-        #[cfg(not(target_arch = "x86"))]
-        const MAP_SIZE: usize = 40 * 1024 * 1024 * 1024;
+    /// Opens an existing database that is never written, e.g. a cache that is shipped as a
+    /// read-only layer of a container image. No lock file is used, so the files must not be
+    /// modified by another process while the database is open. Write batches fail.
+    pub fn open_immutable(path: &Path) -> Result<Self> {
+        let mut options = LmdbOptions::default();
+        options.flags.remove(EnvironmentFlags::WRITE_MAP);
+        options.flags |= EnvironmentFlags::READ_ONLY | EnvironmentFlags::NO_LOCK;
+        Self::open(path, options, true)
+    }
 
-        let env = Environment::new()
-            .set_flags(
-                EnvironmentFlags::WRITE_MAP
-                    | EnvironmentFlags::NO_META_SYNC
-                    | EnvironmentFlags::NO_TLS,
-            )
-            .set_max_readers((available_parallelism().map_or(16, |v| v.get()) * 8) as u32)
-            .set_max_dbs(5)
-            .set_map_size(MAP_SIZE)
-            .open(path)?;
-        let infra_db = env.create_db(Some("infra"), DatabaseFlags::INTEGER_KEY)?;
-        let data_db = env.create_db(Some("data"), DatabaseFlags::INTEGER_KEY)?;
-        let meta_db = env.create_db(Some("meta"), DatabaseFlags::INTEGER_KEY)?;
-        let forward_task_cache_db =
-            env.create_db(Some("forward_task_cache"), DatabaseFlags::empty())?;
-        let reverse_task_cache_db =
-            env.create_db(Some("reverse_task_cache"), DatabaseFlags::INTEGER_KEY)?;
+    fn open(path: &Path, mut options: LmdbOptions, immutable: bool) -> Result<Self> {
+        // A smaller map than the data file can't map all pages, e.g. when the store was created
+        // with a larger map size
+        let data_file_size = path
+            .join("data.mdb")
+            .metadata()
+            .map_or(0, |metadata| metadata.len());
+        if data_file_size > options.map_size as u64 {
+            tracing::info!(
+                "Increasing the LMDB map size from {} bytes to the {data_file_size} bytes of the \
+                 data file at {}",
+                options.map_size,
+                path.display()
+            );
+            options.map_size = usize::try_from(data_file_size)?;
+        }
+        if !immutable {
+            check_map_size(path, &options)?;
+        }
+        if options.thread_local_readers {
+            options.flags.remove(EnvironmentFlags::NO_TLS);
+        }
+        let created = !immutable && !path.join("data.mdb").exists();
+        let env = Self::shared_environment(path, options)?;
+        // Without a lock file, readers aren't tracked
+        let cleared_stale_readers = if options.clear_stale_readers && !immutable {
+            let cleared = clear_stale_readers(&env)?;
+            if cleared > 0 {
+                tracing::info!("Cleared {cleared} stale LMDB readers at {}", path.display());
+            }
+            cleared
+        } else {
+            0
+        };
+        // Commits are only durable once the directory entries of a new store are durable, which
+        // is pointless to ensure when commits aren't synced anyway
+        let synced_directories = created && !options.flags.contains(EnvironmentFlags::NO_SYNC);
+        if synced_directories {
+            sync_directory(path)?;
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                sync_directory(parent)?;
+            }
+        }
+        let info = env.info()?;
+        let config = EffectiveConfig {
+            flags: environment_flags(&env)?,
+            map_size: info.map_size(),
+            max_readers: info.max_readers(),
+            max_dbs: env.max_dbs,
+        };
+        tracing::debug!(
+            flags = ?config.flags,
+            map_size = config.map_size,
+            max_readers = config.max_readers,
+            max_dbs = config.max_dbs,
+            "opened LMDB environment at {}",
+            path.display()
+        );
+        let open_db = |name: &str, flags| {
+            let name = match options.namespace {
+                Some(namespace) => format!("{namespace}/{name}"),
+                None => name.to_string(),
+            };
+            if immutable {
+                env.open_db(Some(&name))
+                    .with_context(|| format!("Unable to open database {name}"))
+            } else {
+                Ok(env.create_db(Some(&name), flags)?)
+            }
+        };
+        // Integer keys are ordered by their value, not by their bytes
+        let infra_db = open_db("infra", DatabaseFlags::INTEGER_KEY)?;
+        let data_db = open_db("data", DatabaseFlags::INTEGER_KEY)?;
+        let meta_db = open_db("meta", DatabaseFlags::INTEGER_KEY)?;
+        let forward_task_cache_db = open_db("forward_task_cache", DatabaseFlags::empty())?;
+        let reverse_task_cache_db = open_db("reverse_task_cache", DatabaseFlags::INTEGER_KEY)?;
+        let data_blob_db = open_db("data_blob", DatabaseFlags::empty())?;
+        let task_tags_db = open_db("task_tags", DatabaseFlags::empty())?;
         Ok(LmbdKeyValueDatabase {
             env,
+            config,
+            short_keys_only: options.short_keys_only,
+            max_transaction_bytes: options.max_transaction_bytes,
+            immutable,
+            synced_directories,
             infra_db,
             data_db,
             meta_db,
             forward_task_cache_db,
             reverse_task_cache_db,
+            data_blob_db,
+            task_tags_db,
+            legacy_forward_keys: AtomicBool::new(false),
+            cleared_stale_readers,
+            #[cfg(all(test, unix))]
+            interrupted_commits: Default::default(),
+            #[cfg(test)]
+            commit_delay: Default::default(),
         })
     }
 
+    /// Returns the open environment for `path` or opens a new one. When the environment is
+    /// already open, the options it was opened with apply.
+    fn shared_environment(path: &Path, options: LmdbOptions) -> Result<Arc<SharedEnvironment>> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve database path {}", path.display()))?;
+        let mut environments = ENVIRONMENTS.lock();
+        while let Some(env) = environments.get(&path) {
+            let Some(env) = env.upgrade() else {
+                // The last instance was dropped, but the environment isn't closed yet
+                ENVIRONMENT_CLOSED.wait(&mut environments);
+                continue;
+            };
+            if env.flags != options.flags {
+                let flags = env.flags;
+                // Dropping the last instance locks the registry
+                drop(environments);
+                drop(env);
+                bail!(
+                    "The database at {} is already open with the flags {flags:?}, but {:?} were \
+                     requested",
+                    path.display(),
+                    options.flags
+                );
+            }
+            return Ok(env);
+        }
+        let env = Arc::new(SharedEnvironment {
+            env: ManuallyDrop::new(
+                Environment::new()
+                    .set_flags(options.flags)
+                    .set_max_readers(options.max_readers)
+                    .set_max_dbs(MAX_DBS)
+                    .set_map_size(options.map_size)
+                    .open(&path)?,
+            ),
+            path: path.clone(),
+            flags: options.flags,
+            max_dbs: MAX_DBS,
+        });
+        environments.insert(path, Arc::downgrade(&env));
+        Ok(env)
+    }
+
+    /// Forgets the open environment for `path`, so the next open creates a new environment even
+    /// while instances of the previous one are alive, e.g. after the files at `path` were
+    /// replaced. The previous instances keep using the files they opened.
+    pub(crate) fn forget_environment(path: &Path) -> Result<()> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve database path {}", path.display()))?;
+        ENVIRONMENTS.lock().remove(&path);
+        Ok(())
+    }
+
+    /// Fails when another process has a reader registered on the environment at `path`, e.g.
+    /// before its files are replaced. Processes that have the environment open without a read
+    /// transaction aren't registered.
+    pub(crate) fn ensure_no_other_process_readers(path: &Path) -> Result<()> {
+        let canonical_path = path
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve database path {}", path.display()))?;
+        let environments = ENVIRONMENTS.lock();
+        let env = environments.get(&canonical_path).and_then(Weak::upgrade);
+        let pids = match env {
+            Some(env) => {
+                drop(environments);
+                other_process_readers(&env)?
+            }
+            None => {
+                // The registry stays locked, so the path isn't opened twice in this process
+                let env = Environment::new()
+                    .set_max_dbs(MAX_DBS)
+                    .open(&canonical_path)?;
+                let pids = other_process_readers(&env)?;
+                drop(env);
+                drop(environments);
+                pids
+            }
+        };
+        if !pids.is_empty() {
+            bail!(
+                "The database at {} is in use by the processes {pids:?}",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn effective_config(&self) -> EffectiveConfig {
+        self.config
+    }
+
+    /// Returns the number of reader slots of exited processes that were released when the
+    /// database was opened, see [`LmdbOptions::clear_stale_readers`].
+    pub fn cleared_stale_readers(&self) -> usize {
+        self.cleared_stale_readers
+    }
+
+    /// Releases the reader slots of processes that exited without closing the environment, like
+    /// [`LmdbOptions::clear_stale_readers`] does on open, and returns their number. Long-lived
+    /// processes can call it periodically, since other processes can exit at any time.
+    pub fn check_stale_readers(&self) -> Result<usize> {
+        if self.immutable {
+            return Ok(0);
+        }
+        clear_stale_readers(&self.env)
+    }
+
+    /// Returns whether a forward task cache key was found in the legacy format, i.e. stored as a
+    /// plain key although it's an extended key in the current format.
+    pub fn uses_legacy_forward_keys(&self) -> bool {
+        self.legacy_forward_keys.load(Ordering::Relaxed)
+    }
+
+    /// Returns the map size, the used pages and the reader slots of the environment. This doesn't
+    /// need a transaction or a scan, so it's cheap enough to be polled.
+    pub fn environment_info(&self) -> Result<EnvInfo> {
+        let info = self.env.info()?;
+        Ok(EnvInfo {
+            map_size: info.map_size(),
+            last_page: info.last_pgno(),
+            readers: info.num_readers(),
+            max_readers: info.max_readers(),
+        })
+    }
+
+    /// Reduces the map size to twice the used pages, but at least 64 MiB, to release reserved
+    /// address space, e.g. after most of the data was deleted. Returns the new map size. LMDB
+    /// never releases used pages, so a database that was large once needs to be compacted
+    /// before it can shrink. The map size isn't persisted, the next open uses the configured
+    /// map size again.
+    ///
+    /// The map size can only be changed while the process has no transactions in the
+    /// environment. Taking `&mut self` ensures that for this database. Environments that are
+    /// shared with other databases of the process can't be shrunk.
+    pub fn shrink_to_fit(&mut self) -> Result<usize> {
+        if Arc::strong_count(&self.env) > 1 {
+            bail!("The map size can't be changed while the environment is shared");
+        }
+        let page_size = self.env.stat()?.page_size() as usize;
+        let used = (self.env.info()?.last_pgno() + 1) * page_size;
+        let map_size = used
+            .saturating_mul(2)
+            .max(MIN_SHRUNK_MAP_SIZE)
+            .next_multiple_of(page_size);
+        if map_size < self.config.map_size {
+            self.env.set_map_size(map_size)?;
+            self.config.map_size = self.env.info()?.map_size();
+        }
+        Ok(self.config.map_size)
+    }
+
+    /// Scans the task data database and counts the entries that are stored on overflow pages.
+    pub fn db_stats(&self) -> Result<DbStats> {
+        let page_size = self.env.stat()?.page_size();
+        let mut stats = DbStats {
+            page_size,
+            ..Default::default()
+        };
+        let tx = self.env.begin_ro_txn()?;
+        let mut cursor = tx.open_ro_cursor(self.data_db)?;
+        for entry in cursor.iter_start() {
+            let (key, value) = entry?;
+            stats.data_entries += 1;
+            if let Some(pages) = overflow_pages(page_size as usize, key.len(), value.len()) {
+                stats.data_overflow_entries += 1;
+                stats.data_overflow_pages += pages as u64;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Compares the bytes stored in all databases with the pages used by the environment. Freed
+    /// pages are only reused for new writes, so many overwrites and deletes leave the file
+    /// fragmented.
+    pub fn fragmentation(&self) -> Result<FragmentationReport> {
+        let page_size = self.env.stat()?.page_size();
+        let physical_bytes = (self.env.info()?.last_pgno() as u64 + 1) * page_size as u64;
+        let tx = self.env.begin_ro_txn()?;
+        let mut logical_bytes = 0;
+        for db in [
+            self.infra_db,
+            self.data_db,
+            self.meta_db,
+            self.forward_task_cache_db,
+            self.reverse_task_cache_db,
+            self.data_blob_db,
+            self.task_tags_db,
+        ] {
+            let mut cursor = tx.open_ro_cursor(db)?;
+            for entry in cursor.iter_start() {
+                let (key, value) = entry?;
+                logical_bytes += (key.len() + value.len()) as u64;
+            }
+        }
+        let reclaimable_bytes = physical_bytes.saturating_sub(logical_bytes);
+        let ratio = reclaimable_bytes as f64 / physical_bytes as f64;
+        Ok(FragmentationReport {
+            page_size,
+            logical_bytes,
+            physical_bytes,
+            ratio,
+            compaction_recommended: ratio > COMPACTION_RATIO
+                && reclaimable_bytes >= COMPACTION_MIN_RECLAIMABLE_BYTES,
+        })
+    }
+
+    /// Checks that all extended keys of the forward task cache are well-formed. Writes are
+    /// transactional, so malformed entries indicate a bug in the `extended_key` encoding. With
+    /// `repair` the well-formed records of malformed entries are kept and the rest is removed.
+    /// Keys of the maximum size in the legacy plain format look like extended keys and are
+    /// reported as malformed.
+    pub fn verify_extended_keys(&self, repair: bool) -> Result<Vec<InvalidExtendedKey>> {
+        if self.short_keys_only {
+            return Ok(Vec::new());
+        }
+        let mut invalid = Vec::new();
+        let mut repaired = Vec::new();
+        {
+            let tx = self.env.begin_ro_txn()?;
+            let mut cursor = tx.open_ro_cursor(self.forward_task_cache_db)?;
+            for entry in cursor.iter_start() {
+                let (key, value) = entry?;
+                if key.len() != extended_key::MAX_KEY_SIZE {
+                    continue;
+                }
+                if let Some((reason, valid)) = extended_key::verify_entry(key, value) {
+                    invalid.push(InvalidExtendedKey {
+                        key: key.to_vec(),
+                        reason,
+                    });
+                    repaired.push(valid);
+                }
+            }
+        }
+        if repair && !invalid.is_empty() {
+            if self.immutable {
+                bail!("The database was opened immutable and can't be repaired");
+            }
+            let mut tx = AbortOnDrop::new(self.env.begin_rw_txn()?);
+            for (entry, valid) in invalid.iter().zip(repaired) {
+                if valid.is_empty() {
+                    tx.del(self.forward_task_cache_db, &entry.key, None)?;
+                } else {
+                    tx.put(
+                        self.forward_task_cache_db,
+                        &entry.key,
+                        &valid,
+                        WriteFlags::empty(),
+                    )?;
+                }
+            }
+            tx.commit()?;
+        }
+        Ok(invalid)
+    }
+
+    /// Checks that `key` is a legal key for the database of `key_space`. Integer key spaces
+    /// require keys of exactly 4 bytes. Data blob keys are a kind byte followed by a 128 bit
+    /// content hash. Variable-length keys are stored via `extended_key`, which
+    /// lifts LMDB's key size limit, unless `short_keys_only` is set. They must not be empty.
+    /// Tags are stored directly and must fit into LMDB's key size limit.
+    fn check_key(&self, key_space: KeySpace, key: &[u8]) -> Result<()> {
+        match key_space {
+            KeySpace::Infra
+            | KeySpace::TaskMeta
+            | KeySpace::TaskData
+            | KeySpace::ReverseTaskCache => {
+                if key.len() != 4 {
+                    bail!(
+                        "Invalid key for {key_space:?}: integer keys must be 4 bytes, but got {} \
+                         bytes",
+                        key.len()
+                    );
+                }
+            }
+            KeySpace::ForwardTaskCache => {
+                if key.is_empty() {
+                    bail!("Invalid key for {key_space:?}: keys must not be empty");
+                }
+                if self.short_keys_only && key.len() > extended_key::MAX_KEY_SIZE {
+                    bail!(
+                        "Invalid key for {key_space:?}: the key has {} bytes, but only keys up to \
+                         {} bytes are allowed when short_keys_only is set",
+                        key.len(),
+                        extended_key::MAX_KEY_SIZE
+                    );
+                }
+            }
+            KeySpace::TaskTags => {
+                if key.is_empty() || key.len() > extended_key::MAX_KEY_SIZE {
+                    bail!(
+                        "Invalid key for {key_space:?}: tags must have 1 to {} bytes, but got {} \
+                         bytes",
+                        extended_key::MAX_KEY_SIZE,
+                        key.len()
+                    );
+                }
+            }
+            KeySpace::DataBlob => {
+                if key.len() != 17 {
+                    bail!(
+                        "Invalid key for {key_space:?}: keys must be 17 bytes, but got {} bytes",
+                        key.len()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterates the entries of a key space in `tx`, see [`KeyValueDatabase::iterate`].
+    fn iterate_values(
+        &self,
+        tx: &impl Transaction,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        let mut cursor = tx.open_ro_cursor(self.db(key_space))?;
+        let start = start.map(|start| native_key(key_space, start));
+        let iter = match &start {
+            Some(start) => cursor.iter_from(&**start),
+            None => cursor.iter_start(),
+        };
+        let mut visit = |key: &[u8], value: &[u8]| f(&native_key(key_space, key), value);
+        for entry in iter {
+            let (key, value) = entry?;
+            let more = if self.short_keys_only {
+                visit(key, value)?
+            } else {
+                extended_key::visit_entries(key, value, &mut visit)?
+            };
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_value<'tx>(
+        &self,
+        tx: &'tx impl Transaction,
+        key_space: KeySpace,
+        key: &[u8],
+    ) -> Result<Option<&'tx [u8]>> {
+        self.check_key(key_space, key)?;
+        let key = &*native_key(key_space, key);
+        let db = self.db(key_space);
+        let mut result = if self.short_keys_only {
+            tx.get(db, &key)
+        } else {
+            extended_key::get(tx, db, key)
+        };
+        // TODO: Remove this fallback after the next release. Stores written before
+        // `extended_key` stored keys of the maximum size as plain keys.
+        if matches!(result, Err(lmdb::Error::NotFound))
+            && key_space == KeySpace::ForwardTaskCache
+            && !self.short_keys_only
+            && key.len() <= extended_key::MAX_KEY_SIZE
+        {
+            result = tx.get(db, &key);
+            if result.is_ok() && !self.legacy_forward_keys.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "The forward task cache contains keys in the legacy plain format. They are \
+                     still read, but support for them will be removed."
+                );
+            }
+        }
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put_value(
+        &self,
+        tx: &mut RwTransaction<'_>,
+        key_space: KeySpace,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        self.check_key(key_space, key)?;
+        let key = &*native_key(key_space, key);
+        let db = self.db(key_space);
+        if self.short_keys_only {
+            tx.put(db, &key, &value, WriteFlags::empty())
+        } else {
+            extended_key::put(tx, db, key, value, WriteFlags::empty())
+        }
+        .map_err(map_write_error)
+    }
+
+    fn delete_value(
+        &self,
+        tx: &mut RwTransaction<'_>,
+        key_space: KeySpace,
+        key: &[u8],
+    ) -> Result<()> {
+        self.check_key(key_space, key)?;
+        let key = &*native_key(key_space, key);
+        let db = self.db(key_space);
+        let result = if self.short_keys_only {
+            tx.del(db, &key, None)
+        } else {
+            extended_key::delete(tx, db, key, WriteFlags::empty())
+        };
+        match result {
+            // Deleting a missing key is a no-op, like in the other databases
+            Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+            Err(err) => Err(map_write_error(err)),
+        }
+    }
+
     fn db(&self, key_space: KeySpace) -> Database {
         match key_space {
             KeySpace::Infra => self.infra_db,
@@ -62,6 +1035,8 @@ impl LmbdKeyValueDatabase {
             KeySpace::TaskData => self.data_db,
             KeySpace::ForwardTaskCache => self.forward_task_cache_db,
             KeySpace::ReverseTaskCache => self.reverse_task_cache_db,
+            KeySpace::DataBlob => self.data_blob_db,
+            KeySpace::TaskTags => self.task_tags_db,
         }
     }
 }
@@ -79,7 +1054,7 @@ impl KeyValueDatabase for LmbdKeyValueDatabase {
     }
 
     fn begin_read_transaction(&self) -> Result<Self::ReadTransaction<'_>> {
-        Ok(self.env.begin_ro_txn()?)
+        Ok(retry_interrupted(|| self.env.begin_ro_txn())?)
     }
 
     type ValueBuffer<'l> = &'l [u8];
@@ -90,25 +1065,30 @@ impl KeyValueDatabase for LmbdKeyValueDatabase {
         key_space: super::key_value_database::KeySpace,
         key: &[u8],
     ) -> Result<Option<Self::ValueBuffer<'l>>> {
-        let db = match key_space {
-            KeySpace::Infra => self.infra_db,
-            KeySpace::TaskMeta => self.meta_db,
-            KeySpace::TaskData => self.data_db,
-            KeySpace::ForwardTaskCache => self.forward_task_cache_db,
-            KeySpace::ReverseTaskCache => self.reverse_task_cache_db,
-        };
+        self.get_value(transaction, key_space, key)
+    }
 
-        let value = match extended_key::get(transaction, db, key) {
-            Ok(result) => result,
-            Err(err) => {
-                if err == lmdb::Error::NotFound {
-                    return Ok(None);
-                } else {
-                    return Err(err.into());
-                }
-            }
-        };
-        Ok(Some(value))
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.iterate_values(transaction, key_space, start, f)
+    }
+
+    /// Returns the fraction of the map that is used by pages. Writes fail with `MapFull` when
+    /// the map is full.
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        let info = self.env.info()?;
+        let page_size = self.env.stat()?.page_size() as usize;
+        let used = (info.last_pgno() + 1) * page_size;
+        Ok(Some(used as f64 / info.map_size() as f64))
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.immutable
     }
 
     type WriteBatch<'l>
@@ -117,38 +1097,131 @@ impl KeyValueDatabase for LmbdKeyValueDatabase {
         Self: 'l;
 
     fn write_batch(&self) -> Result<Self::WriteBatch<'_>> {
+        if self.immutable {
+            bail!("The database was opened immutable and can't be written");
+        }
         Ok(LmbdWriteBatch {
-            tx: self.env.begin_rw_txn()?,
+            tx: AbortOnDrop::new(
+                retry_interrupted(|| self.env.begin_rw_txn()).map_err(map_write_error)?,
+            ),
             this: self,
+            written_bytes: 0,
         })
     }
 }
 
+/// A write transaction that is explicitly aborted when it's dropped without being committed. LMDB
+/// aborts transactions on drop as well, but with `WRITE_MAP` the pages are modified in place, so
+/// the abort is made explicit and logged when a panic unwinds through the owner of the
+/// transaction.
+struct AbortOnDrop<'env> {
+    tx: Option<RwTransaction<'env>>,
+}
+
+impl<'env> AbortOnDrop<'env> {
+    fn new(tx: RwTransaction<'env>) -> Self {
+        Self { tx: Some(tx) }
+    }
+
+    fn commit(mut self) -> Result<()> {
+        let tx = self
+            .tx
+            .take()
+            .expect("the transaction is only taken on commit");
+        tx.commit().map_err(map_write_error)?;
+        Ok(())
+    }
+}
+
+impl<'env> Deref for AbortOnDrop<'env> {
+    type Target = RwTransaction<'env>;
+
+    fn deref(&self) -> &Self::Target {
+        self.tx
+            .as_ref()
+            .expect("the transaction is only taken on commit")
+    }
+}
+
+impl DerefMut for AbortOnDrop<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.tx
+            .as_mut()
+            .expect("the transaction is only taken on commit")
+    }
+}
+
+impl Drop for AbortOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            if std::thread::panicking() {
+                tracing::error!("Aborting an LMDB write transaction because of a panic");
+            }
+            tx.abort();
+        }
+    }
+}
+
 pub struct LmbdWriteBatch<'l> {
-    tx: RwTransaction<'l>,
+    tx: AbortOnDrop<'l>,
     this: &'l LmbdKeyValueDatabase,
+    /// The bytes of keys and values written so far, see [`LmdbOptions::max_transaction_bytes`].
+    written_bytes: usize,
+}
+
+impl LmbdWriteBatch<'_> {
+    /// Accounts for a write of `len` bytes. Fails without accounting for it when the transaction
+    /// would exceed [`LmdbOptions::max_transaction_bytes`].
+    fn add_written_bytes(&mut self, len: usize) -> Result<()> {
+        let written_bytes = self.written_bytes + len;
+        if self
+            .this
+            .max_transaction_bytes
+            .is_some_and(|max| written_bytes > max)
+        {
+            return Err(anyhow::Error::new(lmdb::Error::TxnFull)
+                .context(TransactionFull { committable: true }));
+        }
+        self.written_bytes = written_bytes;
+        Ok(())
+    }
 }
 
 impl<'a> WriteBatch<'a> for LmbdWriteBatch<'a> {
     fn put(&mut self, key_space: KeySpace, key: Cow<[u8]>, value: Cow<[u8]>) -> Result<()> {
-        extended_key::put(
-            &mut self.tx,
-            self.this.db(key_space),
-            &key,
-            &value,
-            WriteFlags::empty(),
-        )?;
-        Ok(())
+        self.add_written_bytes(key.len() + value.len())?;
+        self.this.put_value(&mut self.tx, key_space, &key, &value)
+    }
+
+    fn put_with(
+        &mut self,
+        key_space: KeySpace,
+        key: Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        if matches!(key_space, KeySpace::ForwardTaskCache) && !self.this.short_keys_only {
+            // Extended keys pack the value together with the key, so it can't be reserved
+            let mut value = vec![0; len];
+            write(&mut value)?;
+            return self.put(key_space, key, Cow::Owned(value));
+        }
+        self.this.check_key(key_space, &key)?;
+        self.add_written_bytes(key.len() + len)?;
+        let buffer = self
+            .tx
+            .reserve(
+                self.this.db(key_space),
+                &native_key(key_space, &key),
+                len,
+                WriteFlags::empty(),
+            )
+            .map_err(map_write_error)?;
+        write(buffer)
     }
 
     fn delete(&mut self, key_space: KeySpace, key: Cow<[u8]>) -> Result<()> {
-        extended_key::delete(
-            &mut self.tx,
-            self.this.db(key_space),
-            &key,
-            WriteFlags::empty(),
-        )?;
-        Ok(())
+        self.this.delete_value(&mut self.tx, key_space, &key)
     }
 
     type ValueBuffer<'l>
@@ -161,20 +1234,1129 @@ impl<'a> WriteBatch<'a> for LmbdWriteBatch<'a> {
     where
         'a: 'l,
     {
-        match extended_key::get(&self.tx, self.this.db(key_space), key) {
-            Ok(value) => Ok(Some(value)),
-            Err(err) => {
-                if err == lmdb::Error::NotFound {
-                    Ok(None)
-                } else {
-                    Err(err.into())
-                }
+        self.this.get_value(&*self.tx, key_space, key)
+    }
+
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.this.iterate_values(&*self.tx, key_space, start, f)
+    }
+
+    fn commit(self) -> Result<()> {
+        #[cfg(all(test, unix))]
+        if self
+            .this
+            .interrupted_commits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            // A failed commit aborts the transaction. EINTR has the same value on Linux and
+            // macOS.
+            drop(self.tx);
+            return Err(map_write_error(lmdb::Error::Other(4)));
+        }
+        #[cfg(test)]
+        std::thread::sleep(std::time::Duration::from_millis(
+            self.this.commit_delay.load(Ordering::Relaxed),
+        ));
+        self.tx.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread::{scope, sleep},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn overflow_starts_at_the_node_size_limit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let page_size = database.db_stats()?.page_size as usize;
+        let key_len = 4;
+        let max_inline = (((page_size - PAGE_HEADER_SIZE) / 2) & !1)
+            - size_of::<u16>()
+            - NODE_HEADER_SIZE
+            - key_len;
+        assert_eq!(overflow_pages(page_size, key_len, max_inline), None);
+        assert_eq!(overflow_pages(page_size, key_len, max_inline + 1), Some(1));
+
+        let mut batch = database.write_batch()?;
+        for (key, len) in [(1u32, max_inline - 2), (2, max_inline), (3, max_inline + 1)] {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(&key.to_le_bytes()),
+                Cow::Owned(vec![42; len]),
+            )?;
+        }
+        batch.commit()?;
+        // Matches LMDB's own accounting
+        let stats = database.db_stats()?;
+        assert_eq!(stats.data_overflow_entries, 1);
+        let tx = database.env.begin_ro_txn()?;
+        assert_eq!(tx.stat(database.data_db)?.overflow_pages(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn large_values_are_counted_as_overflow() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let page_size = database.db_stats()?.page_size as usize;
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(&1u32.to_le_bytes()),
+            Cow::Borrowed(b"small"),
+        )?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(&2u32.to_le_bytes()),
+            Cow::Owned(vec![42; page_size * 3]),
+        )?;
+        batch.commit()?;
+
+        let stats = database.db_stats()?;
+        assert_eq!(stats.data_entries, 2);
+        assert_eq!(stats.data_overflow_entries, 1);
+        assert_eq!(stats.data_overflow_pages, 4);
+        // Matches LMDB's own accounting
+        let tx = database.env.begin_ro_txn()?;
+        assert_eq!(
+            tx.stat(database.data_db)?.overflow_pages() as u64,
+            stats.data_overflow_pages
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overwrites_and_deletes_fragment_the_database() -> Result<()> {
+        const ENTRIES: u32 = 100;
+
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        // Values on two overflow pages make the physical size predictable
+        let value_len = database.db_stats()?.page_size as usize * 2 - 100;
+        let write = |fill: u8| -> Result<()> {
+            let mut batch = database.write_batch()?;
+            for key in 0..ENTRIES {
+                batch.put(
+                    KeySpace::TaskData,
+                    Cow::Borrowed(&key.to_le_bytes()),
+                    Cow::Owned(vec![fill; value_len]),
+                )?;
             }
+            batch.commit()
+        };
+        write(1)?;
+        let compact = database.fragmentation()?;
+        assert_eq!(
+            compact.logical_bytes,
+            ENTRIES as u64 * (4 + value_len as u64)
+        );
+        assert!(!compact.compaction_recommended, "{compact:?}");
+
+        write(2)?;
+        write(3)?;
+        let mut batch = database.write_batch()?;
+        for key in 10..ENTRIES {
+            batch.delete(KeySpace::TaskData, Cow::Borrowed(&key.to_le_bytes()))?;
         }
+        batch.commit()?;
+        let fragmented = database.fragmentation()?;
+        assert_eq!(fragmented.logical_bytes, 10 * (4 + value_len as u64));
+        assert!(fragmented.ratio > compact.ratio);
+        assert!(fragmented.compaction_recommended, "{fragmented:?}");
+        Ok(())
     }
 
-    fn commit(self) -> Result<()> {
-        self.tx.commit()?;
+    #[test]
+    fn integer_keys_are_iterated_in_ascending_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        // Lexicographic order of the little-endian bytes differs from the numeric order
+        let task_ids = [65536u32, 2, 256, 1, 0x01000000, 255, 257];
+        let mut batch = database.write_batch()?;
+        for task_id in task_ids {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(&task_id.to_le_bytes()),
+                Cow::Borrowed(b"data"),
+            )?;
+        }
+        batch.commit()?;
+
+        let mut expected = task_ids.to_vec();
+        expected.sort_unstable();
+        let iterate = |start: Option<u32>| -> Result<Vec<u32>> {
+            let tx = database.begin_read_transaction()?;
+            let mut iterated = Vec::new();
+            database.iterate(
+                &tx,
+                KeySpace::TaskData,
+                start.map(u32::to_le_bytes).as_ref().map(|start| &start[..]),
+                &mut |key: &[u8], _: &[u8]| {
+                    iterated.push(u32::from_le_bytes(key.try_into()?));
+                    Ok(true)
+                },
+            )?;
+            Ok(iterated)
+        };
+        assert_eq!(iterate(None)?, expected);
+        assert_eq!(iterate(Some(256))?, [256, 257, 65536, 0x01000000]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupted_calls_are_retried() {
+        // EINTR has the same value on Linux and macOS
+        let interrupted = || lmdb::Error::Other(4);
+        let mut calls = 0;
+        let result = retry_interrupted(|| {
+            calls += 1;
+            if calls <= MAX_INTERRUPTED_RETRIES {
+                Err(interrupted())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(MAX_INTERRUPTED_RETRIES + 1));
+        // The retries are bounded
+        calls = 0;
+        let result = retry_interrupted(|| -> lmdb::Result<()> {
+            calls += 1;
+            Err(interrupted())
+        });
+        assert_eq!(result, Err(interrupted()));
+        assert_eq!(calls, MAX_INTERRUPTED_RETRIES + 1);
+        assert!(map_write_error(interrupted()).is::<Interrupted>());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_filesystem_errors_are_detected() {
+        // EROFS has the same value on Linux and macOS
+        let err = map_write_error(lmdb::Error::Other(30));
+        assert!(err.is::<ReadOnlyFilesystem>());
+        let err = map_write_error(lmdb::Error::MapFull);
+        assert!(!err.is::<ReadOnlyFilesystem>());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_store_directory_is_synced() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("store");
+        let database = LmbdKeyValueDatabase::new(&path)?;
+        assert!(database.synced_directories);
+        drop(database);
+        // An existing store was already synced when it was created
+        assert!(!LmbdKeyValueDatabase::new(&path)?.synced_directories);
+
+        let unsynced = LmbdKeyValueDatabase::with_options(
+            &dir.path().join("unsynced"),
+            LmdbOptions {
+                flags: LmdbOptions::default().flags | EnvironmentFlags::NO_SYNC,
+                ..Default::default()
+            },
+        )?;
+        assert!(!unsynced.synced_directories);
+        Ok(())
+    }
+
+    #[test]
+    fn effective_config_reports_options() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = LmdbOptions {
+            flags: EnvironmentFlags::NO_TLS | EnvironmentFlags::NO_SYNC,
+            map_size: 64 * 1024 * 1024,
+            max_readers: 42,
+            short_keys_only: false,
+            operation_timeout: None,
+            namespace: None,
+            map_size_check: MapSizeCheck::Off,
+            clear_stale_readers: false,
+            max_transaction_bytes: None,
+            thread_local_readers: false,
+        };
+        let database = LmbdKeyValueDatabase::with_options(dir.path(), options)?;
+        let config = database.effective_config();
+        assert_eq!(config.flags, options.flags);
+        assert_eq!(config.map_size, options.map_size);
+        assert_eq!(config.max_readers, options.max_readers);
+        assert_eq!(config.max_dbs, MAX_DBS);
+        Ok(())
+    }
+
+    #[test]
+    fn effective_config_reads_flags_from_the_environment() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = LmbdKeyValueDatabase::new(dir.path())?;
+        assert!(!first
+            .effective_config()
+            .flags
+            .contains(EnvironmentFlags::NO_META_SYNC));
+        // Safety: The environment is open
+        let code = unsafe {
+            lmdb_sys::mdb_env_set_flags(first.env.env(), EnvironmentFlags::NO_META_SYNC.bits(), 1)
+        };
+        assert_eq!(code, 0);
+        // The second instance shares the environment and requests the default flags
+        let second = LmbdKeyValueDatabase::new(dir.path())?;
+        assert!(second
+            .effective_config()
+            .flags
+            .contains(EnvironmentFlags::NO_META_SYNC));
+        assert_eq!(second.effective_config().max_dbs, MAX_DBS);
+        Ok(())
+    }
+
+    #[test]
+    fn environment_info_reports_map_size_and_readers() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = LmdbOptions {
+            map_size: 64 * 1024 * 1024,
+            max_readers: 42,
+            ..Default::default()
+        };
+        let database = LmbdKeyValueDatabase::with_options(dir.path(), options)?;
+        let info = database.environment_info()?;
+        assert_eq!(info.map_size, options.map_size);
+        assert_eq!(info.max_readers, options.max_readers);
+        assert_eq!(info.readers, 0);
+
+        let key = 1u32.to_le_bytes();
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(&key),
+            Cow::Borrowed(&[0; 8192]),
+        )?;
+        batch.commit()?;
+        assert!(database.environment_info()?.last_page > info.last_page);
+
+        let tx = database.begin_read_transaction()?;
+        let other_tx = database.begin_read_transaction()?;
+        assert_eq!(database.environment_info()?.readers, 2);
+        drop((tx, other_tx));
+        // The slots of finished readers are reused
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(database.environment_info()?.readers, 2);
+        drop(tx);
+        Ok(())
+    }
+
+    #[test]
+    fn stores_larger_than_the_map_size_are_opened() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = LmdbOptions {
+            map_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
+        let database = LmbdKeyValueDatabase::with_options(dir.path(), options)?;
+        let value = [7; 1024];
+        let mut batch = database.write_batch()?;
+        for key in 0..1000u32 {
+            batch.put(
+                KeySpace::Infra,
+                Cow::Borrowed(&key.to_le_bytes()),
+                Cow::Borrowed(&value),
+            )?;
+        }
+        batch.commit()?;
+        drop(database);
+        let data_file_size = dir.path().join("data.mdb").metadata()?.len();
+        assert!(data_file_size > 1024 * 1024);
+
+        let database = LmbdKeyValueDatabase::with_options(
+            dir.path(),
+            LmdbOptions {
+                map_size: 1024 * 1024,
+                ..options
+            },
+        )?;
+        assert!(database.effective_config().map_size as u64 >= data_file_size);
+        let tx = database.begin_read_transaction()?;
+        for key in 0..1000u32 {
+            let stored = database.get(&tx, KeySpace::Infra, &key.to_le_bytes())?;
+            assert_eq!(stored, Some(&value[..]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shrink_to_fit_reduces_the_map_size() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let options = LmdbOptions {
+            map_size: 1024 * 1024 * 1024,
+            ..Default::default()
+        };
+        let mut database = LmbdKeyValueDatabase::with_options(dir.path(), options)?;
+        let value = [7; 1024];
+        let mut batch = database.write_batch()?;
+        for key in 0..1000u32 {
+            batch.put(
+                KeySpace::Infra,
+                Cow::Borrowed(&key.to_le_bytes()),
+                Cow::Borrowed(&value),
+            )?;
+        }
+        batch.commit()?;
+        let mut batch = database.write_batch()?;
+        for key in 0..1000u32 {
+            batch.delete(KeySpace::Infra, Cow::Borrowed(&key.to_le_bytes()))?;
+        }
+        batch.commit()?;
+
+        let map_size = database.shrink_to_fit()?;
+        assert!(map_size < options.map_size);
+        assert_eq!(database.environment_info()?.map_size, map_size);
+        assert_eq!(database.effective_config().map_size, map_size);
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(&1u32.to_le_bytes()),
+            Cow::Borrowed(&value),
+        )?;
+        batch.commit()?;
+
+        // Another database might have transactions in a shared environment
+        let _shared = LmbdKeyValueDatabase::with_options(dir.path(), options)?;
+        assert!(database.shrink_to_fit().is_err());
+        Ok(())
+    }
+
+    const ABANDONED_READER_PATH: &str = "TURBO_TASKS_ABANDONED_READER_PATH";
+
+    /// Opens a read transaction in the database at `ABANDONED_READER_PATH` and exits without
+    /// closing it, when started by `stale_readers_are_cleared_on_open`.
+    #[test]
+    fn abandoned_reader() -> Result<()> {
+        let Some(path) = std::env::var_os(ABANDONED_READER_PATH) else {
+            return Ok(());
+        };
+        let database = LmbdKeyValueDatabase::new(Path::new(&path))?;
+        let tx = database.begin_read_transaction()?;
+        std::mem::forget(tx);
+        std::process::exit(0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_readers_are_cleared_on_open() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        drop(LmbdKeyValueDatabase::new(dir.path())?);
+        let status = std::process::Command::new(std::env::current_exe()?)
+            .args(["--exact", "database::lmdb::tests::abandoned_reader"])
+            .env(ABANDONED_READER_PATH, dir.path())
+            .stdout(std::process::Stdio::null())
+            .status()?;
+        assert!(status.success());
+
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        assert_eq!(database.cleared_stale_readers(), 1);
+        let key = 1u32.to_le_bytes();
+        let mut batch = database.write_batch()?;
+        batch.put(KeySpace::Infra, Cow::Borrowed(&key), Cow::Borrowed(b"1"))?;
+        batch.commit()?;
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(database.get(&tx, KeySpace::Infra, &key)?, Some(&b"1"[..]));
+        Ok(())
+    }
+
+    const ACTIVE_READER_PATH: &str = "TURBO_TASKS_ACTIVE_READER_PATH";
+
+    /// Opens a read transaction in the database at `ACTIVE_READER_PATH`, creates a `ready` file
+    /// next to it and keeps the transaction until stdin is closed, when started by
+    /// `other_process_readers_are_detected`.
+    #[test]
+    fn active_reader() -> Result<()> {
+        let Some(path) = std::env::var_os(ACTIVE_READER_PATH) else {
+            return Ok(());
+        };
+        let path = Path::new(&path);
+        let database = LmbdKeyValueDatabase::new(path)?;
+        let tx = database.begin_read_transaction()?;
+        File::create(path.join("ready"))?;
+        std::io::copy(&mut std::io::stdin(), &mut std::io::sink())?;
+        drop(tx);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_process_readers_are_detected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        // Readers of this process don't count
+        let _tx = database.begin_read_transaction()?;
+        LmbdKeyValueDatabase::ensure_no_other_process_readers(dir.path())?;
+
+        let mut reader = std::process::Command::new(std::env::current_exe()?)
+            .args(["--exact", "database::lmdb::tests::active_reader"])
+            .env(ACTIVE_READER_PATH, dir.path())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .spawn()?;
+        while !dir.path().join("ready").exists() {
+            sleep(Duration::from_millis(10));
+        }
+        let err = LmbdKeyValueDatabase::ensure_no_other_process_readers(dir.path())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(&reader.id().to_string()), "{err}");
+
+        drop(reader.stdin.take());
+        assert!(reader.wait()?.success());
+        LmbdKeyValueDatabase::ensure_no_other_process_readers(dir.path())?;
+        Ok(())
+    }
+
+    #[test]
+    fn raw_key_layout_locates_stored_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let short_key = vec![3; 16];
+        let long_key = (0..2000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut batch = database.write_batch()?;
+        for key in [&short_key, &long_key] {
+            batch.put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(key),
+                Cow::Borrowed(b"value"),
+            )?;
+        }
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        let db = database.forward_task_cache_db;
+        let options = LmdbOptions::default();
+        assert_eq!(
+            raw_key_layout(&short_key, &options),
+            RawKeyLayout::Direct(short_key.clone())
+        );
+        assert_eq!(tx.get(db, &short_key)?, &b"value"[..]);
+        let RawKeyLayout::Extended {
+            hashed_key,
+            record_key,
+        } = raw_key_layout(&long_key, &options)
+        else {
+            panic!("a long key must be extended");
+        };
+        let mut record = Vec::new();
+        record.extend_from_slice(&(record_key.len() as u32).to_be_bytes());
+        record.extend_from_slice(&5u32.to_be_bytes());
+        record.extend_from_slice(&record_key);
+        record.extend_from_slice(b"value");
+        assert_eq!(tx.get(db, &hashed_key)?, &record[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn map_size_exceeding_free_disk_space_is_reported() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let Some(available) = available_disk_space(dir.path())? else {
+            return Ok(());
+        };
+        let options = LmdbOptions {
+            map_size: usize::try_from(available.saturating_mul(2)).unwrap_or(usize::MAX),
+            map_size_check: MapSizeCheck::Error,
+            ..Default::default()
+        };
+        let err = LmbdKeyValueDatabase::with_options(dir.path(), options)
+            .err()
+            .expect("opening must fail");
+        assert!(err.to_string().contains("exceeds the"));
+        assert!(err.to_string().contains("sparse"));
+
+        // A warning doesn't prevent opening the database
+        LmbdKeyValueDatabase::with_options(
+            dir.path(),
+            LmdbOptions {
+                map_size_check: MapSizeCheck::Warn,
+                ..options
+            },
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_plain_forward_keys_are_found() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // A key of the maximum size is extended in the current format, but was stored as a plain
+        // key before
+        let key = vec![7; extended_key::MAX_KEY_SIZE];
+        {
+            let legacy = LmbdKeyValueDatabase::with_options(
+                dir.path(),
+                LmdbOptions {
+                    short_keys_only: true,
+                    ..Default::default()
+                },
+            )?;
+            let mut batch = legacy.write_batch()?;
+            batch.put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(&key),
+                Cow::Borrowed(b"value"),
+            )?;
+            batch.commit()?;
+        }
+
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        assert!(!database.uses_legacy_forward_keys());
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(
+            database.get(&tx, KeySpace::ForwardTaskCache, &[8; 600])?,
+            None
+        );
+        assert!(!database.uses_legacy_forward_keys());
+        assert_eq!(
+            database.get(&tx, KeySpace::ForwardTaskCache, &key)?,
+            Some(&b"value"[..])
+        );
+        assert!(database.uses_legacy_forward_keys());
+        Ok(())
+    }
+
+    #[test]
+    fn short_keys_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::with_options(
+            dir.path(),
+            LmdbOptions {
+                short_keys_only: true,
+                ..Default::default()
+            },
+        )?;
+        let short_key = vec![7; extended_key::MAX_KEY_SIZE];
+        let long_key = vec![7; extended_key::MAX_KEY_SIZE + 1];
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::ForwardTaskCache,
+            Cow::Borrowed(&short_key),
+            Cow::Borrowed(b"value"),
+        )?;
+        let err = batch
+            .put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(&long_key),
+                Cow::Borrowed(b"value"),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("short_keys_only"));
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(
+            database.get(&tx, KeySpace::ForwardTaskCache, &short_key)?,
+            Some(&b"value"[..])
+        );
+        // The key is stored directly, without extended key chunking
+        assert_eq!(
+            tx.get(database.forward_task_cache_db, &short_key)?,
+            &b"value"[..]
+        );
+        let mut entries = Vec::new();
+        database.iterate(
+            &tx,
+            KeySpace::ForwardTaskCache,
+            None,
+            &mut |key: &[u8], _: &[u8]| {
+                entries.push(key.to_vec());
+                Ok(true)
+            },
+        )?;
+        assert_eq!(entries, vec![short_key]);
+        Ok(())
+    }
+
+    #[test]
+    fn put_with_round_trips() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let value = (0..3 * 1024 * 1024)
+            .map(|i: u32| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut batch = database.write_batch()?;
+        batch.put_with(
+            KeySpace::TaskData,
+            Cow::Borrowed(&1u32.to_le_bytes()),
+            value.len(),
+            &mut |buffer| {
+                buffer.copy_from_slice(&value);
+                Ok(())
+            },
+        )?;
+        // Falls back to buffering for extended keys
+        batch.put_with(
+            KeySpace::ForwardTaskCache,
+            Cow::Borrowed(&[1; 600]),
+            value.len(),
+            &mut |buffer| {
+                buffer.copy_from_slice(&value);
+                Ok(())
+            },
+        )?;
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        assert!(database.get(&tx, KeySpace::TaskData, &1u32.to_le_bytes())? == Some(&value[..]));
+        assert!(database.get(&tx, KeySpace::ForwardTaskCache, &[1; 600])? == Some(&value[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_extended_keys_reports_incomplete_records() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let long_key = vec![3; 600];
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::ForwardTaskCache,
+            Cow::Borrowed(&long_key),
+            Cow::Borrowed(b"value"),
+        )?;
+        batch.commit()?;
+        assert_eq!(database.verify_extended_keys(false)?, Vec::new());
+
+        // Append a record that claims more bytes than are stored
+        let mut tx = database.env.begin_rw_txn()?;
+        let (hashed_key, mut value) = {
+            let mut cursor = tx.open_ro_cursor(database.forward_task_cache_db)?;
+            let (key, value) = cursor.iter_start().next().context("entry is missing")??;
+            (key.to_vec(), value.to_vec())
+        };
+        value.extend_from_slice(&100u32.to_be_bytes());
+        value.extend_from_slice(&100u32.to_be_bytes());
+        value.extend_from_slice(b"partial");
+        tx.put(
+            database.forward_task_cache_db,
+            &hashed_key,
+            &value,
+            WriteFlags::empty(),
+        )?;
+        tx.commit()?;
+
+        let expected = vec![InvalidExtendedKey {
+            key: hashed_key,
+            reason: "the last record is incomplete",
+        }];
+        assert_eq!(database.verify_extended_keys(false)?, expected);
+        assert_eq!(database.verify_extended_keys(true)?, expected);
+        assert_eq!(database.verify_extended_keys(false)?, Vec::new());
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(
+            database.get(&tx, KeySpace::ForwardTaskCache, &long_key)?,
+            Some(&b"value"[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn instances_for_a_path_share_the_environment() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = LmbdKeyValueDatabase::new(dir.path())?;
+        let second = LmbdKeyValueDatabase::new(&dir.path().join("."))?;
+        assert!(Arc::ptr_eq(&first.env, &second.env));
+
+        let mut batch = first.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(&1u32.to_le_bytes()),
+            Cow::Borrowed(b"value"),
+        )?;
+        batch.commit()?;
+        let tx = second.begin_read_transaction()?;
+        assert_eq!(
+            second.get(&tx, KeySpace::TaskData, &1u32.to_le_bytes())?,
+            Some(&b"value"[..])
+        );
+        drop(tx);
+
+        let env = Arc::downgrade(&first.env);
+        drop(first);
+        assert!(env.upgrade().is_some());
+        drop(second);
+        assert!(env.upgrade().is_none());
+
+        assert!(ENVIRONMENTS
+            .lock()
+            .get(&dir.path().canonicalize()?)
+            .is_none());
+
+        // The environment is opened again after it was closed
+        let reopened = LmbdKeyValueDatabase::new(dir.path())?;
+        let tx = reopened.begin_read_transaction()?;
+        assert_eq!(
+            reopened.get(&tx, KeySpace::TaskData, &1u32.to_le_bytes())?,
+            Some(&b"value"[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_reopens_wait_for_the_close() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = LmbdKeyValueDatabase::with_options(
+            dir.path(),
+            LmdbOptions {
+                max_readers: 42,
+                ..Default::default()
+            },
+        )?;
+        // The options of the open environment apply
+        let second = LmbdKeyValueDatabase::new(dir.path())?;
+        assert_eq!(second.effective_config().max_readers, 42);
+        drop((first, second));
+
+        scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let database = LmbdKeyValueDatabase::new(dir.path()).unwrap();
+                        drop(database.begin_read_transaction().unwrap());
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn failed_write_batch_leaves_all_databases_untouched() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let key = 1u32.to_le_bytes();
+        let key_spaces = [
+            KeySpace::Infra,
+            KeySpace::TaskMeta,
+            KeySpace::TaskData,
+            KeySpace::ForwardTaskCache,
+            KeySpace::ReverseTaskCache,
+        ];
+        let write = |value: &[u8], fail: bool| -> Result<()> {
+            let mut batch = database.write_batch()?;
+            for key_space in key_spaces {
+                batch.put(key_space, Cow::Borrowed(&key), Cow::Borrowed(value))?;
+            }
+            if fail {
+                // An invalid key fails the batch after all other databases were written
+                batch.put(
+                    KeySpace::DataBlob,
+                    Cow::Borrowed(&key),
+                    Cow::Borrowed(value),
+                )?;
+            }
+            batch.commit()
+        };
+        write(b"old", false)?;
+        assert!(write(b"new", true).is_err());
+
+        let tx = database.begin_read_transaction()?;
+        for key_space in key_spaces {
+            assert_eq!(database.get(&tx, key_space, &key)?, Some(&b"old"[..]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_share_an_environment() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let open = |namespace| {
+            LmbdKeyValueDatabase::with_options(
+                dir.path(),
+                LmdbOptions {
+                    namespace: Some(namespace),
+                    ..Default::default()
+                },
+            )
+        };
+        let first = open("first")?;
+        let second = open("second")?;
+        assert!(Arc::ptr_eq(&first.env, &second.env));
+        for (database, value) in [(&first, b"first"), (&second, b"other")] {
+            let mut batch = database.write_batch()?;
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(&1u32.to_le_bytes()),
+                Cow::Borrowed(value),
+            )?;
+            batch.commit()?;
+        }
+        let mut batch = first.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(&2u32.to_le_bytes()),
+            Cow::Borrowed(b"only first"),
+        )?;
+        batch.commit()?;
+
+        let tx = second.begin_read_transaction()?;
+        assert_eq!(
+            second.get(&tx, KeySpace::TaskData, &1u32.to_le_bytes())?,
+            Some(&b"other"[..])
+        );
+        assert_eq!(
+            second.get(&tx, KeySpace::TaskData, &2u32.to_le_bytes())?,
+            None
+        );
+        let mut keys = Vec::new();
+        second.iterate(&tx, KeySpace::TaskData, None, &mut |key, _| {
+            keys.push(key.to_vec());
+            Ok(true)
+        })?;
+        assert_eq!(keys, vec![1u32.to_le_bytes().to_vec()]);
+        drop(tx);
+        let tx = first.begin_read_transaction()?;
+        assert_eq!(
+            first.get(&tx, KeySpace::TaskData, &1u32.to_le_bytes())?,
+            Some(&b"first"[..])
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_immutable_read_only_directory() -> Result<()> {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(&1u32.to_le_bytes()),
+            Cow::Borrowed(b"value"),
+        )?;
+        batch.commit()?;
+        drop(database);
+
+        let set_read_only = |read_only: bool| -> Result<()> {
+            for entry in fs::read_dir(dir.path())? {
+                let path = entry?.path();
+                fs::set_permissions(
+                    &path,
+                    fs::Permissions::from_mode(if read_only { 0o444 } else { 0o644 }),
+                )?;
+            }
+            fs::set_permissions(
+                dir.path(),
+                fs::Permissions::from_mode(if read_only { 0o555 } else { 0o755 }),
+            )?;
+            Ok(())
+        };
+        let files = || -> Result<Vec<_>> {
+            let mut files = fs::read_dir(dir.path())?
+                .map(|entry| Ok(entry?.file_name()))
+                .collect::<Result<Vec<_>>>()?;
+            files.sort();
+            Ok(files)
+        };
+        let files_before = files()?;
+        set_read_only(true)?;
+
+        let result = (|| -> Result<()> {
+            let database = LmbdKeyValueDatabase::open_immutable(dir.path())?;
+            let tx = database.begin_read_transaction()?;
+            assert_eq!(
+                database.get(&tx, KeySpace::TaskData, &1u32.to_le_bytes())?,
+                Some(&b"value"[..])
+            );
+            assert_eq!(
+                database.get(&tx, KeySpace::TaskData, &2u32.to_le_bytes())?,
+                None
+            );
+            drop(tx);
+            assert!(database.is_immutable());
+            assert!(database.write_batch().is_err());
+            Ok(())
+        })();
+        set_read_only(false)?;
+        result?;
+        // No lock file or other files were created
+        assert_eq!(files()?, files_before);
+        Ok(())
+    }
+
+    #[test]
+    fn readers_are_not_blocked_by_writes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let key = 1u32.to_le_bytes();
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(&key),
+            Cow::Borrowed(b"before"),
+        )?;
+        batch.commit()?;
+
+        let mut batch = database.write_batch()?;
+        for task_id in 0..10_000u32 {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(&task_id.to_le_bytes()),
+                Cow::Owned(vec![0; 1024]),
+            )?;
+        }
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(&key),
+            Cow::Borrowed(b"after"),
+        )?;
+
+        // Readers make progress while the write transaction is open and only see the state before
+        // the write
+        let stop = AtomicBool::new(false);
+        scope(|s| {
+            let readers = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut reads = 0;
+                        while !stop.load(Ordering::Relaxed) {
+                            let tx = database.begin_read_transaction()?;
+                            assert_eq!(
+                                database.get(&tx, KeySpace::Infra, &key)?,
+                                Some(&b"before"[..])
+                            );
+                            assert_eq!(
+                                database.get(&tx, KeySpace::TaskData, &5000u32.to_le_bytes())?,
+                                None
+                            );
+                            reads += 1;
+                        }
+                        anyhow::Ok(reads)
+                    })
+                })
+                .collect::<Vec<_>>();
+            sleep(Duration::from_millis(100));
+            stop.store(true, Ordering::Relaxed);
+            for reader in readers {
+                assert!(reader.join().unwrap()? > 0);
+            }
+            anyhow::Ok(())
+        })?;
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(
+            database.get(&tx, KeySpace::Infra, &key)?,
+            Some(&b"after"[..])
+        );
+        assert!(database
+            .get(&tx, KeySpace::TaskData, &5000u32.to_le_bytes())?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn boundary_length_keys() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let keys = [1, 509, 510, 511, 512, 1024]
+            .map(|len| (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+
+        let mut batch = database.write_batch()?;
+        for key in keys.iter() {
+            batch.put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(key),
+                Cow::Owned(key.len().to_le_bytes().to_vec()),
+            )?;
+        }
+        for len in [0, 3, 5, 511] {
+            let key = vec![1; len];
+            assert!(batch
+                .put(
+                    KeySpace::TaskData,
+                    Cow::Borrowed(&key),
+                    Cow::Borrowed(b"value")
+                )
+                .is_err());
+        }
+        assert!(batch
+            .put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(&[]),
+                Cow::Borrowed(b"value")
+            )
+            .is_err());
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        for key in keys.iter() {
+            let value = database.get(&tx, KeySpace::ForwardTaskCache, key)?;
+            assert_eq!(value, Some(&key.len().to_le_bytes()[..]));
+        }
+        drop(tx);
+
+        let mut batch = database.write_batch()?;
+        for key in keys.iter() {
+            batch.delete(KeySpace::ForwardTaskCache, Cow::Borrowed(key))?;
+        }
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        for key in keys.iter() {
+            assert_eq!(database.get(&tx, KeySpace::ForwardTaskCache, key)?, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn thread_local_readers_work_single_threaded() -> Result<()> {
+        for thread_local_readers in [false, true] {
+            let dir = tempfile::tempdir()?;
+            let database = LmbdKeyValueDatabase::with_options(
+                dir.path(),
+                LmdbOptions {
+                    thread_local_readers,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(
+                database
+                    .effective_config()
+                    .flags
+                    .contains(EnvironmentFlags::NO_TLS),
+                !thread_local_readers
+            );
+            for round in 0..3u32 {
+                let mut batch = database.write_batch()?;
+                batch.put(
+                    KeySpace::TaskData,
+                    Cow::Borrowed(&round.to_le_bytes()),
+                    Cow::Owned(round.to_le_bytes().to_vec()),
+                )?;
+                batch.commit()?;
+
+                // Each round begins a new read transaction after the last one was dropped, which
+                // reuses the thread's reader slot with thread local readers
+                let tx = database.begin_read_transaction()?;
+                let mut entries = 0;
+                database.iterate(&tx, KeySpace::TaskData, None, &mut |key, value| {
+                    assert_eq!(key, value);
+                    entries += 1;
+                    Ok(true)
+                })?;
+                assert_eq!(entries, round + 1);
+                assert_eq!(
+                    database.get(&tx, KeySpace::TaskData, &round.to_le_bytes())?,
+                    Some(&round.to_le_bytes()[..])
+                );
+            }
+        }
         Ok(())
     }
 }
@@ -5,18 +5,28 @@ pub mod key_value_database;
 #[cfg(feature = "lmdb")]
 pub mod lmdb;
 pub mod noop_kv;
+pub mod operation_timeout;
 pub mod read_transaction_cache;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb;
+pub mod sharded;
 mod startup_cache;
+pub mod swappable;
 
 pub use db_versioning::handle_db_versioning;
 pub use fresh_db_optimization::{is_fresh, FreshDbOptimization};
+pub use key_value_database::{Interrupted, ReadOnlyFilesystem, TransactionFull};
 #[cfg(feature = "lmdb")]
-pub use lmdb::LmbdKeyValueDatabase;
+pub use lmdb::{
+    raw_key_layout, DbStats, EffectiveConfig, EnvInfo, FragmentationReport, InvalidExtendedKey,
+    LmbdKeyValueDatabase, LmdbOptions, MapSizeCheck, RawKeyLayout,
+};
 #[allow(unused_imports)]
 pub use noop_kv::NoopKvDb;
+pub use operation_timeout::{OperationTimeout, TimedOut};
 pub use read_transaction_cache::ReadTransactionCache;
 #[cfg(feature = "rocksdb")]
 pub use rocksdb::RocksDbKeyValueDatabase;
+pub use sharded::ShardedKeyValueDatabase;
 pub use startup_cache::StartupCacheLayer;
+pub use swappable::SwappableKeyValueDatabase;
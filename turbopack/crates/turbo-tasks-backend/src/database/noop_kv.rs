@@ -36,6 +36,16 @@ impl KeyValueDatabase for NoopKvDb {
         Ok(None)
     }
 
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        _transaction: &'l Self::ReadTransaction<'db>,
+        _key_space: KeySpace,
+        _start: Option<&[u8]>,
+        _f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     type WriteBatch<'l>
         = NoopWriteBatch
     where
@@ -70,6 +80,15 @@ impl<'a> WriteBatch<'a> for NoopWriteBatch {
         Ok(())
     }
 
+    fn iterate(
+        &self,
+        _key_space: KeySpace,
+        _start: Option<&[u8]>,
+        _f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     fn commit(self) -> Result<()> {
         Ok(())
     }
@@ -0,0 +1,442 @@
+use std::{
+    borrow::{Borrow, Cow},
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc::{channel, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use rustc_hash::FxHashMap;
+
+use crate::database::key_value_database::{
+    Interrupted, KeySpace, KeyValueDatabase, WriteBatch, MAX_INTERRUPTED_RETRIES,
+};
+
+/// The error returned when a database operation exceeds the configured timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut {
+    pub timeout: Duration,
+    /// Whether the timeout elapsed while the transaction was being committed. A commit can't be
+    /// aborted, so it might still succeed and the outcome of the write batch is unknown.
+    /// Otherwise the transaction is aborted and none of its operations are applied.
+    pub committing: bool,
+}
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Database operation timed out after {:?}", self.timeout)?;
+        if self.committing {
+            write!(f, " while committing, the write might still be applied")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Applies a timeout to write batches, so a stuck database (e.g. on a flaky disk) results in a
+/// [`TimedOut`] error instead of a hang.
+///
+/// With a timeout, the operations of a write batch are collected in memory and applied and
+/// committed on a worker thread when the batch is committed. When the timeout elapses before the
+/// worker started committing, the transaction is aborted. A commit that is already in progress
+/// can't be aborted, so it might still be applied after the error was returned, see
+/// [`TimedOut::committing`]. Database calls can't be cancelled while they are blocked, so the
+/// timeout guarantees that the error is reported in time, but the worker only stops once the
+/// blocking call returns. The next write batch might wait for it.
+///
+/// The operations are kept until the commit succeeds, so a commit that is [`Interrupted`] by a
+/// signal is retried by applying them again. Without a timeout, interrupted commits fail.
+///
+/// Reads are not affected by the timeout.
+pub struct OperationTimeout<T: KeyValueDatabase> {
+    database: Arc<T>,
+    timeout: Option<Duration>,
+}
+
+impl<T: KeyValueDatabase> OperationTimeout<T> {
+    pub fn new(database: T, timeout: Option<Duration>) -> Self {
+        Self {
+            database: Arc::new(database),
+            timeout,
+        }
+    }
+
+    pub fn database(&self) -> &T {
+        &self.database
+    }
+}
+
+impl<T: KeyValueDatabase + Send + Sync + 'static> KeyValueDatabase for OperationTimeout<T> {
+    type ReadTransaction<'l>
+        = T::ReadTransaction<'l>
+    where
+        Self: 'l;
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        T::lower_read_transaction(tx)
+    }
+
+    fn begin_read_transaction(&self) -> Result<Self::ReadTransaction<'_>> {
+        self.database.begin_read_transaction()
+    }
+
+    type ValueBuffer<'l>
+        = T::ValueBuffer<'l>
+    where
+        Self: 'l;
+
+    fn get<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        key: &[u8],
+    ) -> Result<Option<Self::ValueBuffer<'l>>> {
+        self.database.get(transaction, key_space, key)
+    }
+
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.database.iterate(transaction, key_space, start, f)
+    }
+
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        self.database.used_capacity()
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.database.is_immutable()
+    }
+
+    type WriteBatch<'l>
+        = OperationTimeoutWriteBatch<'l, T>
+    where
+        Self: 'l;
+
+    fn write_batch(&self) -> Result<Self::WriteBatch<'_>> {
+        Ok(match self.timeout {
+            Some(timeout) => OperationTimeoutWriteBatch::Deferred {
+                database: &self.database,
+                timeout,
+                operations: FxHashMap::default(),
+            },
+            None => OperationTimeoutWriteBatch::Direct(self.database.write_batch()?),
+        })
+    }
+}
+
+type Operations = FxHashMap<(u8, Vec<u8>), (KeySpace, Option<Vec<u8>>)>;
+
+/// The states of a deferred write batch, shared between the caller and the worker thread.
+const APPLYING: u8 = 0;
+const ABORTED: u8 = 1;
+const COMMITTING: u8 = 2;
+
+pub enum OperationTimeoutWriteBatch<'a, T: KeyValueDatabase + 'a> {
+    Direct(T::WriteBatch<'a>),
+    Deferred {
+        database: &'a Arc<T>,
+        timeout: Duration,
+        /// The last operation for each key. `None` deletes the key.
+        operations: Operations,
+    },
+}
+
+impl<'a, T: KeyValueDatabase + Send + Sync + 'static> WriteBatch<'a>
+    for OperationTimeoutWriteBatch<'a, T>
+{
+    type ValueBuffer<'l>
+        = Cow<'l, [u8]>
+    where
+        Self: 'l,
+        'a: 'l;
+
+    fn get<'l>(&'l self, key_space: KeySpace, key: &[u8]) -> Result<Option<Self::ValueBuffer<'l>>>
+    where
+        'a: 'l,
+    {
+        match self {
+            OperationTimeoutWriteBatch::Direct(batch) => Ok(batch
+                .get(key_space, key)?
+                .map(|value| Cow::Owned(value.borrow().to_vec()))),
+            OperationTimeoutWriteBatch::Deferred {
+                database,
+                operations,
+                ..
+            } => {
+                if let Some((_, value)) = operations.get(&(key_space as u8, key.to_vec())) {
+                    return Ok(value.as_deref().map(Cow::Borrowed));
+                }
+                let tx = database.begin_read_transaction()?;
+                let value = database
+                    .get(&tx, key_space, key)?
+                    .map(|value| Cow::Owned(value.borrow().to_vec()));
+                Ok(value)
+            }
+        }
+    }
+
+    fn put(&mut self, key_space: KeySpace, key: Cow<[u8]>, value: Cow<[u8]>) -> Result<()> {
+        match self {
+            OperationTimeoutWriteBatch::Direct(batch) => batch.put(key_space, key, value),
+            OperationTimeoutWriteBatch::Deferred { operations, .. } => {
+                operations.insert(
+                    (key_space as u8, key.into_owned()),
+                    (key_space, Some(value.into_owned())),
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn put_with(
+        &mut self,
+        key_space: KeySpace,
+        key: Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        if let OperationTimeoutWriteBatch::Direct(batch) = self {
+            return batch.put_with(key_space, key, len, write);
+        }
+        let mut value = vec![0; len];
+        write(&mut value)?;
+        self.put(key_space, key, Cow::Owned(value))
+    }
+
+    fn delete(&mut self, key_space: KeySpace, key: Cow<[u8]>) -> Result<()> {
+        match self {
+            OperationTimeoutWriteBatch::Direct(batch) => batch.delete(key_space, key),
+            OperationTimeoutWriteBatch::Deferred { operations, .. } => {
+                operations.insert((key_space as u8, key.into_owned()), (key_space, None));
+                Ok(())
+            }
+        }
+    }
+
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        match self {
+            OperationTimeoutWriteBatch::Direct(batch) => batch.iterate(key_space, start, f),
+            OperationTimeoutWriteBatch::Deferred { database, .. } => {
+                let tx = database.begin_read_transaction()?;
+                database.iterate(&tx, key_space, start, f)
+            }
+        }
+    }
+
+    fn commit(self) -> Result<()> {
+        let (database, timeout, operations) = match self {
+            OperationTimeoutWriteBatch::Direct(batch) => return batch.commit(),
+            OperationTimeoutWriteBatch::Deferred {
+                database,
+                timeout,
+                operations,
+            } => (database.clone(), timeout, operations),
+        };
+        let state = Arc::new(AtomicU8::new(APPLYING));
+        let (sender, receiver) = channel();
+        thread::Builder::new()
+            .name("database write".to_string())
+            .spawn({
+                let state = state.clone();
+                move || {
+                    let mut result = apply(&*database, &operations, &state);
+                    let mut retries = 0;
+                    while retries < MAX_INTERRUPTED_RETRIES
+                        && result.as_ref().is_err_and(|err| err.is::<Interrupted>())
+                    {
+                        retries += 1;
+                        // An interrupted commit was rolled back, so the retry can be aborted
+                        let _ = state.compare_exchange(
+                            COMMITTING,
+                            APPLYING,
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        );
+                        result = apply(&*database, &operations, &state);
+                    }
+                    let _ = sender.send(result);
+                }
+            })?;
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                let committing = state
+                    .compare_exchange(APPLYING, ABORTED, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err();
+                Err(TimedOut {
+                    timeout,
+                    committing,
+                }
+                .into())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(anyhow!("Database write thread stopped unexpectedly"))
+            }
+        }
+    }
+}
+
+/// Applies the operations in a write batch and commits it, unless the operation was aborted in
+/// the meantime. Once the state is switched to committing the operation can't be aborted anymore.
+fn apply<T: KeyValueDatabase>(
+    database: &T,
+    operations: &Operations,
+    state: &AtomicU8,
+) -> Result<()> {
+    let mut batch = database.write_batch()?;
+    for ((_, key), (key_space, value)) in operations {
+        if state.load(Ordering::Acquire) == ABORTED {
+            // Dropping the batch aborts the transaction
+            return Ok(());
+        }
+        match value {
+            Some(value) => batch.put(*key_space, Cow::Borrowed(key), Cow::Borrowed(value))?,
+            None => batch.delete(*key_space, Cow::Borrowed(key))?,
+        }
+    }
+    if state
+        .compare_exchange(APPLYING, COMMITTING, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Ok(());
+    }
+    batch.commit()
+}
+
+#[cfg(all(test, feature = "lmdb"))]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::database::LmbdKeyValueDatabase;
+
+    #[test]
+    fn timeout_fires_on_stuck_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = OperationTimeout::new(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            Some(Duration::from_millis(100)),
+        );
+        let key = 1u32.to_le_bytes();
+
+        thread::scope(|s| -> Result<()> {
+            // Holding a write transaction blocks all other writers
+            let (locked_sender, locked_receiver) = channel();
+            s.spawn(|| {
+                let batch = database.database().write_batch().unwrap();
+                locked_sender.send(()).unwrap();
+                thread::sleep(Duration::from_millis(500));
+                drop(batch);
+            });
+            locked_receiver.recv()?;
+
+            let start = Instant::now();
+            let mut batch = database.write_batch()?;
+            batch.put(KeySpace::Infra, Cow::Borrowed(&key), Cow::Borrowed(b"1"))?;
+            let err = batch.commit().unwrap_err();
+            assert!(start.elapsed() < Duration::from_millis(400));
+            assert_eq!(
+                err.downcast_ref::<TimedOut>(),
+                Some(&TimedOut {
+                    timeout: Duration::from_millis(100),
+                    committing: false,
+                })
+            );
+            Ok(())
+        })?;
+
+        // The timed out transaction was aborted once the database was unblocked
+        thread::sleep(Duration::from_millis(100));
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(database.get(&tx, KeySpace::Infra, &key)?, None);
+        drop(tx);
+
+        let mut batch = database.write_batch()?;
+        batch.put(KeySpace::Infra, Cow::Borrowed(&key), Cow::Borrowed(b"2"))?;
+        batch.commit()?;
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(database.get(&tx, KeySpace::Infra, &key)?, Some(&b"2"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn timeout_while_committing_reports_unknown_outcome() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = OperationTimeout::new(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            Some(Duration::from_millis(100)),
+        );
+        database
+            .database()
+            .commit_delay
+            .store(500, Ordering::Relaxed);
+        let key = 1u32.to_le_bytes();
+
+        let start = Instant::now();
+        let mut batch = database.write_batch()?;
+        batch.put(KeySpace::Infra, Cow::Borrowed(&key), Cow::Borrowed(b"1"))?;
+        let err = batch.commit().unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(
+            err.downcast_ref::<TimedOut>(),
+            Some(&TimedOut {
+                timeout: Duration::from_millis(100),
+                committing: true,
+            })
+        );
+
+        // The commit wasn't aborted and is applied once it returns
+        database.database().commit_delay.store(0, Ordering::Relaxed);
+        let mut batch = database.write_batch()?;
+        batch.commit()?;
+        let tx = database.begin_read_transaction()?;
+        assert_eq!(database.get(&tx, KeySpace::Infra, &key)?, Some(&b"1"[..]));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupted_commit_is_retried() -> Result<()> {
+        let key = 1u32.to_le_bytes();
+        for timeout in [Some(Duration::from_secs(10)), None] {
+            let dir = tempfile::tempdir()?;
+            let database = OperationTimeout::new(LmbdKeyValueDatabase::new(dir.path())?, timeout);
+            database
+                .database()
+                .interrupted_commits
+                .store(MAX_INTERRUPTED_RETRIES, Ordering::Relaxed);
+            let mut batch = database.write_batch()?;
+            batch.put(KeySpace::Infra, Cow::Borrowed(&key), Cow::Borrowed(b"1"))?;
+            let result = batch.commit();
+            let tx = database.begin_read_transaction()?;
+            let value = database.get(&tx, KeySpace::Infra, &key)?;
+            if timeout.is_some() {
+                result?;
+                assert_eq!(value, Some(&b"1"[..]));
+            } else {
+                // A direct batch can't be replayed after its transaction was aborted
+                assert!(result.unwrap_err().is::<Interrupted>());
+                assert_eq!(value, None);
+            }
+        }
+        Ok(())
+    }
+}
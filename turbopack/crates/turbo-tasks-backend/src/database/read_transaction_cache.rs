@@ -86,6 +86,25 @@ impl<T: KeyValueDatabase + 'static> KeyValueDatabase for ReadTransactionCache<T>
             .get(transaction.tx.as_ref().unwrap(), key_space, key)
     }
 
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: super::key_value_database::KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.database
+            .iterate(transaction.tx.as_ref().unwrap(), key_space, start, f)
+    }
+
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        self.database.used_capacity()
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.database.is_immutable()
+    }
+
     type WriteBatch<'l> = ReadTransactionCacheWriteBatch<'l, T>;
 
     fn write_batch(&self) -> Result<Self::WriteBatch<'_>> {
@@ -148,6 +167,16 @@ impl<'a, T: KeyValueDatabase> WriteBatch<'a> for ReadTransactionCacheWriteBatch<
         self.write_batch.put(key_space, key, value)
     }
 
+    fn put_with(
+        &mut self,
+        key_space: super::key_value_database::KeySpace,
+        key: std::borrow::Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.write_batch.put_with(key_space, key, len, write)
+    }
+
     type ValueBuffer<'l>
         = <T::WriteBatch<'a> as WriteBatch<'a>>::ValueBuffer<'l>
     where
@@ -172,4 +201,13 @@ impl<'a, T: KeyValueDatabase> WriteBatch<'a> for ReadTransactionCacheWriteBatch<
     ) -> Result<()> {
         self.write_batch.delete(key_space, key)
     }
+
+    fn iterate(
+        &self,
+        key_space: super::key_value_database::KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.write_batch.iterate(key_space, start, f)
+    }
 }
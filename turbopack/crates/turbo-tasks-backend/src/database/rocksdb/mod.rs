@@ -6,8 +6,10 @@ use std::{
     time::Instant,
 };
 
-use anyhow::{Context, Result};
-use rocksdb::{ColumnFamily, Env, SliceTransform, WriteBatch as RdbWriteBack, WriteOptions, DB};
+use anyhow::{bail, Context, Result};
+use rocksdb::{
+    ColumnFamily, Env, IteratorMode, SliceTransform, WriteBatch as RdbWriteBack, WriteOptions, DB,
+};
 use rustc_hash::FxHasher;
 
 use crate::database::key_value_database::{KeySpace, KeyValueDatabase, WriteBatch};
@@ -33,6 +35,8 @@ make_names!(TASK_DATA, "task-data-");
 make_names!(TASK_META, "task-meta-");
 make_names!(FORWARD_TASK_CACHE, "forward-task-cache-");
 make_names!(REVERSE_TASK_CACHE, "reverse-task-cache-");
+make_names!(DATA_BLOB, "data-blob-");
+make_names!(TASK_TAGS, "task-tags-");
 
 pub struct RocksDbKeyValueDatabase {
     db: DB,
@@ -91,6 +95,8 @@ impl RocksDbKeyValueDatabase {
             .chain(TASK_META.iter().copied())
             .chain(FORWARD_TASK_CACHE.iter().copied())
             .chain(REVERSE_TASK_CACHE.iter().copied())
+            .chain(DATA_BLOB.iter().copied())
+            .chain(TASK_TAGS.iter().copied())
     }
 
     fn cf_handle(&self, key_space: KeySpace, key: &[u8]) -> Result<&ColumnFamily> {
@@ -108,6 +114,8 @@ impl RocksDbKeyValueDatabase {
                 KeySpace::TaskData => TASK_DATA[shard],
                 KeySpace::ForwardTaskCache => FORWARD_TASK_CACHE[shard],
                 KeySpace::ReverseTaskCache => REVERSE_TASK_CACHE[shard],
+                KeySpace::DataBlob => DATA_BLOB[shard],
+                KeySpace::TaskTags => TASK_TAGS[shard],
             })
             .context("Failed to get column family")
     }
@@ -144,6 +152,40 @@ impl KeyValueDatabase for RocksDbKeyValueDatabase {
         Ok(self.db.get_cf(cf, key)?)
     }
 
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        _transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        if start.is_some() {
+            bail!("Ordered iteration is not supported since keys are sharded");
+        }
+        let names: &[&str] = match key_space {
+            KeySpace::Infra => &["default"],
+            KeySpace::TaskMeta => &TASK_META,
+            KeySpace::TaskData => &TASK_DATA,
+            KeySpace::ForwardTaskCache => &FORWARD_TASK_CACHE,
+            KeySpace::ReverseTaskCache => &REVERSE_TASK_CACHE,
+            KeySpace::DataBlob => &DATA_BLOB,
+            KeySpace::TaskTags => &TASK_TAGS,
+        };
+        for name in names {
+            let cf = self
+                .db
+                .cf_handle(name)
+                .context("Failed to get column family")?;
+            for entry in self.db.iterator_cf(cf, IteratorMode::Start) {
+                let (key, value) = entry?;
+                if !f(&key, &value)? {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
     type WriteBatch<'l>
         = RocksDbWriteBatch<'l>
     where
@@ -176,6 +218,15 @@ impl<'a> WriteBatch<'a> for RocksDbWriteBatch<'a> {
         self.this.get(&(), key_space, key)
     }
 
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.this.iterate(&(), key_space, start, f)
+    }
+
     fn put(
         &mut self,
         key_space: KeySpace,
@@ -0,0 +1,350 @@
+use std::{
+    borrow::{Borrow, Cow},
+    mem::transmute,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rustc_hash::FxHashMap;
+
+use crate::database::key_value_database::{KeySpace, KeyValueDatabase, WriteBatch};
+
+/// Spreads task meta and data over multiple databases, e.g. one LMDB environment per shard
+/// directory, so write batches are written to the shards in parallel with one writer per shard.
+///
+/// Task meta and data of a task are stored in the shard `task_id % shards`. All other key spaces
+/// are stored in the first shard.
+///
+/// Consistency: The operations of a write batch are collected in memory. On commit every shard
+/// applies and commits its operations in its own transaction on the rayon thread pool. Each shard
+/// commits atomically, but a batch is not atomic across shards: when a shard fails to commit, the
+/// other shards might have committed, so task data can be newer than the task cache and the
+/// infra data in the first shard. The read transactions of the shards are started one after
+/// another, so a read might observe a commit in some shards but not yet in others.
+///
+/// Iterating from a start key is not supported, since keys are sharded.
+///
+/// The number of shards is stored in the first shard. Opening the database with another number
+/// of shards fails, since tasks would be looked up in the wrong shards.
+pub struct ShardedKeyValueDatabase<T: KeyValueDatabase> {
+    shards: Vec<T>,
+}
+
+/// The infra key of the number of shards as little-endian u32. It's above the keys used by the
+/// backing storage.
+const SHARD_COUNT_KEY: u32 = u32::MAX;
+
+impl<T: KeyValueDatabase> ShardedKeyValueDatabase<T> {
+    pub fn new(shards: Vec<T>) -> Result<Self> {
+        let Some(first) = shards.first() else {
+            bail!("A sharded database needs at least one shard");
+        };
+        let count = u32::try_from(shards.len())?;
+        let key = SHARD_COUNT_KEY.to_le_bytes();
+        let stored = {
+            let tx = first.begin_read_transaction()?;
+            first
+                .get(&tx, KeySpace::Infra, &key)?
+                .map(|value| value.borrow().to_vec())
+        };
+        match stored {
+            Some(stored) => {
+                let stored = u32::from_le_bytes(
+                    stored
+                        .try_into()
+                        .map_err(|_| anyhow!("The stored number of shards is invalid"))?,
+                );
+                if stored != count {
+                    bail!(
+                        "The database was created with {stored} shards, but is opened with \
+                         {count} shards"
+                    );
+                }
+            }
+            // Immutable databases can't be written, but are still usable
+            None if first.is_immutable() => {}
+            None => {
+                let mut batch = first.write_batch()?;
+                batch.put(
+                    KeySpace::Infra,
+                    Cow::Borrowed(&key),
+                    Cow::Borrowed(&count.to_le_bytes()),
+                )?;
+                batch
+                    .commit()
+                    .context("Unable to store the number of shards")?;
+            }
+        }
+        Ok(Self { shards })
+    }
+
+    pub fn shards(&self) -> &[T] {
+        &self.shards
+    }
+
+    fn shard(&self, key_space: KeySpace, key: &[u8]) -> usize {
+        match key_space {
+            KeySpace::TaskMeta | KeySpace::TaskData => match key.try_into() {
+                Ok(task_id) => u32::from_le_bytes(task_id) as usize % self.shards.len(),
+                // Malformed keys are rejected by the shard
+                Err(_) => 0,
+            },
+            _ => 0,
+        }
+    }
+}
+
+impl<T: KeyValueDatabase + Sync> KeyValueDatabase for ShardedKeyValueDatabase<T> {
+    type ReadTransaction<'l>
+        = Vec<T::ReadTransaction<'l>>
+    where
+        Self: 'l;
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        // Safety: The types only differ in the lifetime of the transactions, so they have the
+        // same layout. Viewing a transaction with a shorter lifetime is what
+        // `T::lower_read_transaction` does for a single transaction, and the `Vec` is only
+        // borrowed immutably, so no transaction of the shorter lifetime can be stored in it.
+        unsafe { transmute::<&'r Self::ReadTransaction<'l>, &'r Self::ReadTransaction<'i>>(tx) }
+    }
+
+    fn begin_read_transaction(&self) -> Result<Self::ReadTransaction<'_>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.begin_read_transaction())
+            .collect()
+    }
+
+    type ValueBuffer<'l>
+        = T::ValueBuffer<'l>
+    where
+        Self: 'l;
+
+    fn get<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        key: &[u8],
+    ) -> Result<Option<Self::ValueBuffer<'l>>> {
+        let shard = self.shard(key_space, key);
+        self.shards[shard].get(&transaction[shard], key_space, key)
+    }
+
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        if start.is_some() {
+            bail!("Ordered iteration is not supported since keys are sharded");
+        }
+        let shards = match key_space {
+            KeySpace::TaskMeta | KeySpace::TaskData => self.shards.len(),
+            _ => 1,
+        };
+        for (shard, tx) in self.shards.iter().zip(transaction).take(shards) {
+            let mut stopped = false;
+            shard.iterate(tx, key_space, None, &mut |key: &[u8], value: &[u8]| {
+                stopped = !f(key, value)?;
+                Ok(!stopped)
+            })?;
+            if stopped {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the highest used capacity of the shards, since a write fails when one of them is
+    /// full.
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        let mut max = None;
+        for shard in &self.shards {
+            if let Some(used) = shard.used_capacity()? {
+                max = Some(max.map_or(used, |max: f64| max.max(used)));
+            }
+        }
+        Ok(max)
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.shards.iter().any(|shard| shard.is_immutable())
+    }
+
+    type WriteBatch<'l>
+        = ShardedWriteBatch<'l, T>
+    where
+        Self: 'l;
+
+    fn write_batch(&self) -> Result<Self::WriteBatch<'_>> {
+        Ok(ShardedWriteBatch {
+            database: self,
+            operations: self.shards.iter().map(|_| Operations::default()).collect(),
+        })
+    }
+}
+
+/// The last operation for each key of a shard. `None` deletes the key.
+type Operations = FxHashMap<(u8, Vec<u8>), (KeySpace, Option<Vec<u8>>)>;
+
+pub struct ShardedWriteBatch<'a, T: KeyValueDatabase> {
+    database: &'a ShardedKeyValueDatabase<T>,
+    operations: Vec<Operations>,
+}
+
+impl<'a, T: KeyValueDatabase + Sync> WriteBatch<'a> for ShardedWriteBatch<'a, T> {
+    type ValueBuffer<'l>
+        = Cow<'l, [u8]>
+    where
+        Self: 'l,
+        'a: 'l;
+
+    fn get<'l>(&'l self, key_space: KeySpace, key: &[u8]) -> Result<Option<Self::ValueBuffer<'l>>>
+    where
+        'a: 'l,
+    {
+        let shard = self.database.shard(key_space, key);
+        if let Some((_, value)) = self.operations[shard].get(&(key_space as u8, key.to_vec())) {
+            return Ok(value.as_deref().map(Cow::Borrowed));
+        }
+        let database = &self.database.shards[shard];
+        let tx = database.begin_read_transaction()?;
+        let value = database
+            .get(&tx, key_space, key)?
+            .map(|value| Cow::Owned(value.borrow().to_vec()));
+        Ok(value)
+    }
+
+    fn put(&mut self, key_space: KeySpace, key: Cow<[u8]>, value: Cow<[u8]>) -> Result<()> {
+        let shard = self.database.shard(key_space, &key);
+        self.operations[shard].insert(
+            (key_space as u8, key.into_owned()),
+            (key_space, Some(value.into_owned())),
+        );
+        Ok(())
+    }
+
+    fn delete(&mut self, key_space: KeySpace, key: Cow<[u8]>) -> Result<()> {
+        let shard = self.database.shard(key_space, &key);
+        self.operations[shard].insert((key_space as u8, key.into_owned()), (key_space, None));
+        Ok(())
+    }
+
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        let tx = self.database.begin_read_transaction()?;
+        self.database.iterate(&tx, key_space, start, f)
+    }
+
+    fn commit(self) -> Result<()> {
+        let shards = &self.database.shards;
+        // Every shard is committed, even when another shard fails, to keep the shards as
+        // consistent as possible
+        let results = self
+            .operations
+            .into_par_iter()
+            .enumerate()
+            .filter(|(_, operations)| !operations.is_empty())
+            .map(|(shard, operations)| apply(&shards[shard], operations))
+            .collect::<Vec<_>>();
+        results.into_iter().collect()
+    }
+}
+
+/// Applies the operations of a shard in one write batch and commits it.
+fn apply<T: KeyValueDatabase>(database: &T, operations: Operations) -> Result<()> {
+    let mut batch = database.write_batch()?;
+    for ((_, key), (key_space, value)) in operations {
+        match value {
+            Some(value) => batch.put(key_space, Cow::Owned(key), Cow::Owned(value))?,
+            None => batch.delete(key_space, Cow::Owned(key))?,
+        }
+    }
+    batch.commit()
+}
+
+#[cfg(all(test, feature = "lmdb"))]
+mod tests {
+    use super::*;
+    use crate::database::LmbdKeyValueDatabase;
+
+    #[test]
+    fn writes_land_in_their_shards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let shards = (0..4)
+            .map(|shard| LmbdKeyValueDatabase::new(&dir.path().join(format!("shard-{shard}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let database = ShardedKeyValueDatabase::new(shards)?;
+
+        let mut batch = database.write_batch()?;
+        for task_id in 1u32..=100 {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(&task_id.to_le_bytes()),
+                Cow::Owned(format!("data {task_id}").into_bytes()),
+            )?;
+        }
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(&1u32.to_le_bytes()),
+            Cow::Borrowed(b"infra"),
+        )?;
+        assert_eq!(
+            batch
+                .get(KeySpace::TaskData, &7u32.to_le_bytes())?
+                .as_deref(),
+            Some(&b"data 7"[..])
+        );
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        for task_id in 1u32..=100 {
+            let key = task_id.to_le_bytes();
+            let expected = format!("data {task_id}").into_bytes();
+            assert_eq!(
+                database.get(&tx, KeySpace::TaskData, &key)?,
+                Some(&expected[..])
+            );
+            // The data is only stored in its own shard
+            for (shard, (shard_database, tx)) in database.shards().iter().zip(&tx).enumerate() {
+                let value = shard_database.get(tx, KeySpace::TaskData, &key)?;
+                assert_eq!(value.is_some(), shard == task_id as usize % 4);
+            }
+        }
+        assert_eq!(
+            database.shards()[0].get(&tx[0], KeySpace::Infra, &1u32.to_le_bytes())?,
+            Some(&b"infra"[..])
+        );
+
+        let mut count = 0;
+        database.iterate(&tx, KeySpace::TaskData, None, &mut |_: &[u8], _: &[u8]| {
+            count += 1;
+            Ok(true)
+        })?;
+        assert_eq!(count, 100);
+        Ok(())
+    }
+    #[test]
+    fn shard_count_is_checked() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let open = |shards: usize| {
+            let shards = (0..shards)
+                .map(|shard| LmbdKeyValueDatabase::new(&dir.path().join(format!("shard-{shard}"))))
+                .collect::<Result<Vec<_>>>()?;
+            ShardedKeyValueDatabase::new(shards)
+        };
+        drop(open(4)?);
+        drop(open(4)?);
+        let err = open(2).err().unwrap();
+        assert!(err.to_string().contains("created with 4 shards"), "{err}");
+        Ok(())
+    }
+}
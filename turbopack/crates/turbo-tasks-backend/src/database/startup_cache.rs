@@ -87,6 +87,8 @@ impl<T: KeyValueDatabase> StartupCacheLayer<T> {
                         KeySpace::TaskData => 1024 * 1024,
                         KeySpace::ForwardTaskCache => 1024 * 1024,
                         KeySpace::ReverseTaskCache => 1024 * 1024,
+                        KeySpace::DataBlob => 1024,
+                        KeySpace::TaskTags => 8,
                     },
                     Default::default(),
                 )
@@ -140,21 +142,44 @@ impl<T: KeyValueDatabase> KeyValueDatabase for StartupCacheLayer<T> {
             }
         };
         if let Some(value) = value.as_ref() {
-            let value: &[u8] = value.borrow();
-            let size = self.cache_size.fetch_add(
-                key.len() + value.len() + PAIR_HEADER_SIZE,
-                Ordering::Relaxed,
-            );
-            if size < CACHE_SIZE_LIMIT {
-                self.cache
-                    .get(key_space)
-                    .entry(key.to_vec())
-                    .or_insert_with(|| Some(value.to_vec()));
+            let cache = self.cache.get(key_space);
+            // Only take the write lock of the shard when the entry is not cached yet, so
+            // concurrent reads of the same keys don't serialize on the shard lock.
+            if !cache.contains_key(key) {
+                let value: &[u8] = value.borrow();
+                let size = self.cache_size.fetch_add(
+                    key.len() + value.len() + PAIR_HEADER_SIZE,
+                    Ordering::Relaxed,
+                );
+                if size < CACHE_SIZE_LIMIT {
+                    cache
+                        .entry(key.to_vec())
+                        .or_insert_with(|| Some(value.to_vec()));
+                }
             }
         }
         Ok(value)
     }
 
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        // The database is always up to date, the cache only mirrors a subset of it
+        self.database.iterate(transaction, key_space, start, f)
+    }
+
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        self.database.used_capacity()
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.database.is_immutable()
+    }
+
     type WriteBatch<'l>
         = StartupCacheWriteBatch<'l, T>
     where
@@ -182,6 +207,22 @@ impl<'a, T: KeyValueDatabase> WriteBatch<'a> for StartupCacheWriteBatch<'a, T> {
         self.batch.put(key_space, key, value)
     }
 
+    fn put_with(
+        &mut self,
+        key_space: KeySpace,
+        key: Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        if !self.this.fresh_db {
+            // Values written in place are usually large, so they aren't copied into the cache.
+            // The entry is invalidated instead, so a restored value isn't persisted again.
+            let cache = self.this.cache.get(key_space);
+            cache.insert(key.to_vec(), None);
+        }
+        self.batch.put_with(key_space, key, len, write)
+    }
+
     type ValueBuffer<'l>
         = <T::WriteBatch<'a> as WriteBatch<'a>>::ValueBuffer<'l>
     where
@@ -203,6 +244,15 @@ impl<'a, T: KeyValueDatabase> WriteBatch<'a> for StartupCacheWriteBatch<'a, T> {
         self.batch.delete(key_space, key)
     }
 
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.batch.iterate(key_space, start, f)
+    }
+
     fn commit(self) -> Result<()> {
         if !self.this.fresh_db {
             // Remove file before writing the new snapshot to database to avoid inconsistency
@@ -269,6 +319,8 @@ fn write_key_value_pair(
         KeySpace::TaskData => 2,
         KeySpace::ForwardTaskCache => 3,
         KeySpace::ReverseTaskCache => 4,
+        KeySpace::DataBlob => 5,
+        KeySpace::TaskTags => 6,
     })?;
     let key_len = key.len();
     size_buffer.copy_from_slice(&(key_len as u32).to_be_bytes());
@@ -291,6 +343,8 @@ fn read_key_value_pair<'l>(
         2 => KeySpace::TaskData,
         3 => KeySpace::ForwardTaskCache,
         4 => KeySpace::ReverseTaskCache,
+        5 => KeySpace::DataBlob,
+        6 => KeySpace::TaskTags,
         _ => return Err(anyhow::anyhow!("Invalid key space")),
     };
     *pos += 1;
@@ -0,0 +1,195 @@
+use std::{borrow::Cow, mem::transmute, path::Path, sync::Arc};
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+
+use crate::database::key_value_database::{KeySpace, KeyValueDatabase, WriteBatch};
+
+/// A database that can be replaced while it's in use, see
+/// [`SwappableKeyValueDatabase::swap`]. Transactions and write batches keep the database they
+/// began on alive, so they finish on it. New ones begin on the replacement.
+pub struct SwappableKeyValueDatabase<T: KeyValueDatabase + 'static> {
+    current: ArcSwap<T>,
+    replace: Option<Box<ReplaceFn<T>>>,
+}
+
+/// Replaces the files of the database with the ones of the database at the given path and opens
+/// the result. The validation is called with the database at the given path before any files are
+/// replaced.
+pub type ReplaceFn<T> = dyn Fn(&Path, &dyn Fn(&T) -> Result<()>) -> Result<T> + Send + Sync;
+
+impl<T: KeyValueDatabase + 'static> SwappableKeyValueDatabase<T> {
+    pub fn new(database: T) -> Self {
+        Self {
+            current: ArcSwap::new(Arc::new(database)),
+            replace: None,
+        }
+    }
+
+    /// Like [`SwappableKeyValueDatabase::new`], but the files of the database can be replaced
+    /// with `replace`, see
+    /// [`KeyValueDatabaseBackingStorage::swap_in`](crate::KeyValueDatabaseBackingStorage::swap_in).
+    pub fn with_replace(
+        database: T,
+        replace: impl Fn(&Path, &dyn Fn(&T) -> Result<()>) -> Result<T> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            current: ArcSwap::new(Arc::new(database)),
+            replace: Some(Box::new(replace)),
+        }
+    }
+
+    pub(crate) fn replace_fn(&self) -> Option<&ReplaceFn<T>> {
+        self.replace.as_deref()
+    }
+
+    /// Returns the current database.
+    pub fn current(&self) -> Arc<T> {
+        self.current.load_full()
+    }
+
+    /// Replaces the database. The previous database is dropped once the last transaction or
+    /// write batch that began on it is dropped.
+    pub fn swap(&self, database: T) {
+        self.current.store(Arc::new(database));
+    }
+}
+
+impl<T: KeyValueDatabase + 'static> KeyValueDatabase for SwappableKeyValueDatabase<T> {
+    type ReadTransaction<'l>
+        = SwappableReadTransaction<'l, T>
+    where
+        T: 'l;
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        // Safety: When T compiles fine and lower_read_transaction is implemented correctly this is
+        // safe to do.
+        unsafe { transmute::<&'r Self::ReadTransaction<'l>, &'r Self::ReadTransaction<'i>>(tx) }
+    }
+
+    fn begin_read_transaction<'l>(&'l self) -> Result<Self::ReadTransaction<'l>> {
+        let database = self.current.load_full();
+        let tx = database.begin_read_transaction()?;
+        // Safety: The transaction borrows the database, which is kept alive by the Arc next to
+        // it. The transaction is dropped first, see `SwappableReadTransaction`.
+        let tx = unsafe { transmute::<T::ReadTransaction<'_>, T::ReadTransaction<'l>>(tx) };
+        Ok(SwappableReadTransaction { tx, database })
+    }
+
+    type ValueBuffer<'l>
+        = T::ValueBuffer<'l>
+    where
+        Self: 'l;
+
+    fn get<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        key: &[u8],
+    ) -> Result<Option<Self::ValueBuffer<'l>>> {
+        transaction.database.get(&transaction.tx, key_space, key)
+    }
+
+    fn iterate<'l, 'db: 'l>(
+        &'l self,
+        transaction: &'l Self::ReadTransaction<'db>,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        transaction
+            .database
+            .iterate(&transaction.tx, key_space, start, f)
+    }
+
+    fn used_capacity(&self) -> Result<Option<f64>> {
+        self.current.load().used_capacity()
+    }
+
+    fn is_immutable(&self) -> bool {
+        self.current.load().is_immutable()
+    }
+
+    type WriteBatch<'l>
+        = SwappableWriteBatch<'l, T>
+    where
+        Self: 'l;
+
+    fn write_batch<'l>(&'l self) -> Result<Self::WriteBatch<'l>> {
+        let database = self.current.load_full();
+        let write_batch = database.write_batch()?;
+        // Safety: Like in `begin_read_transaction`, the Arc keeps the database alive and the
+        // write batch is dropped first.
+        let write_batch = unsafe { transmute::<T::WriteBatch<'_>, T::WriteBatch<'l>>(write_batch) };
+        Ok(SwappableWriteBatch {
+            write_batch,
+            database,
+        })
+    }
+}
+
+pub struct SwappableReadTransaction<'l, T: KeyValueDatabase + 'static> {
+    // Safety: `tx` needs to be dropped before `database`, since it borrows it.
+    tx: T::ReadTransaction<'l>,
+    database: Arc<T>,
+}
+
+pub struct SwappableWriteBatch<'l, T: KeyValueDatabase + 'static> {
+    // Safety: `write_batch` needs to be dropped before `database`, since it borrows it.
+    write_batch: T::WriteBatch<'l>,
+    database: Arc<T>,
+}
+
+impl<'a, T: KeyValueDatabase> WriteBatch<'a> for SwappableWriteBatch<'a, T> {
+    type ValueBuffer<'l>
+        = <T::WriteBatch<'a> as WriteBatch<'a>>::ValueBuffer<'l>
+    where
+        Self: 'l,
+        'a: 'l;
+
+    fn get<'l>(&'l self, key_space: KeySpace, key: &[u8]) -> Result<Option<Self::ValueBuffer<'l>>>
+    where
+        'a: 'l,
+    {
+        self.write_batch.get(key_space, key)
+    }
+
+    fn put(&mut self, key_space: KeySpace, key: Cow<[u8]>, value: Cow<[u8]>) -> Result<()> {
+        self.write_batch.put(key_space, key, value)
+    }
+
+    fn put_with(
+        &mut self,
+        key_space: KeySpace,
+        key: Cow<[u8]>,
+        len: usize,
+        write: &mut dyn FnMut(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.write_batch.put_with(key_space, key, len, write)
+    }
+
+    fn delete(&mut self, key_space: KeySpace, key: Cow<[u8]>) -> Result<()> {
+        self.write_batch.delete(key_space, key)
+    }
+
+    fn iterate(
+        &self,
+        key_space: KeySpace,
+        start: Option<&[u8]>,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        self.write_batch.iterate(key_space, start, f)
+    }
+
+    fn commit(self) -> Result<()> {
+        let Self {
+            write_batch,
+            database,
+        } = self;
+        write_batch.commit()?;
+        drop(database);
+        Ok(())
+    }
+}
@@ -1,26 +1,92 @@
 use std::{
     borrow::{Borrow, Cow},
     collections::hash_map::Entry,
-    sync::Arc,
+    fmt::{self, Display, Write as _},
+    fs,
+    hash::BuildHasherDefault,
+    io,
+    mem::take,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{available_parallelism, scope},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use dashmap::DashMap;
+use lru::LruCache;
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, ReentrantMutex, ReentrantMutexGuard};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use serde::{Deserialize, Serialize};
 use tracing::Span;
-use turbo_tasks::{backend::CachedTaskType, turbo_tasks_scope, KeyValuePair, SessionId, TaskId};
+use turbo_tasks::{
+    backend::CachedTaskType, turbo_tasks_scope, KeyValuePair, SessionId, TaskId, TRANSIENT_TASK_BIT,
+};
+use turbo_tasks_hash::hash_xxh3_hash128;
 
 use crate::{
     backend::{AnyOperation, TaskDataCategory},
-    backing_storage::BackingStorage,
+    backing_storage::{BackingStorage, SnapshotCostEstimate},
+    chunked_value::{read_chunked, write_chunked},
     data::{CachedDataItem, CachedDataItemKey, CachedDataItemValue, CachedDataUpdate},
-    database::key_value_database::{KeySpace, KeyValueDatabase, WriteBatch},
-    utils::chunked_vec::ChunkedVec,
+    data_blob::{blob_content_key, blob_reference, task_baseline_key, task_label_key, BlobUpdates},
+    data_compression::{compress, decompress},
+    data_delta::Delta,
+    data_framing::{frame, unframe},
+    database::{
+        key_value_database::{
+            KeySpace, KeyValueDatabase, ReadOnlyFilesystem, TransactionFull, WriteBatch,
+        },
+        swappable::SwappableKeyValueDatabase,
+    },
+    maintenance::MaintenanceThread,
+    manifest::Manifest,
+    task_cache_export,
+    task_index_cache::{self, Generation},
+    utils::{chunked_vec::ChunkedVec, stable_hash::stable_hash},
+    value_codec::{is_encoded, ValueCodec},
 };
 
 const META_KEY_OPERATIONS: u32 = 0;
 const META_KEY_NEXT_FREE_TASK_ID: u32 = 1;
 const META_KEY_SESSION_ID: u32 = 2;
+const META_KEY_VALUE_CODEC: u32 = 3;
+const META_KEY_MANIFEST: u32 = 4;
+const META_KEY_PINNED_TASKS: u32 = 5;
+/// The number of committed snapshots as little-endian u64, see
+/// [`KeyValueDatabaseBackingStorage::current_generation`].
+const META_KEY_GENERATION: u32 = 6;
+/// The number of task data lookups that found data over the life of the store as little-endian
+/// u64, see [`BackingStorageStats::lifetime_restored_tasks`].
+const META_KEY_LIFETIME_RESTORED_TASKS: u32 = 7;
+/// The number of task cache lookups that found a task over the life of the store as
+/// little-endian u64, see [`BackingStorageStats::lifetime_restored_cache_entries`].
+const META_KEY_LIFETIME_RESTORED_CACHE_ENTRIES: u32 = 8;
+/// The [`SCHEMA_VERSION`] the store was written with as little-endian u32.
+const META_KEY_SCHEMA_VERSION: u32 = 9;
+/// The number of committed write batches as little-endian u64. Every write to the store bumps
+/// it, so the task index cache is validated against it, see [`Generation`].
+const META_KEY_WRITE_COUNT: u32 = 10;
+/// The first infra key of the chunks of the operations, see
+/// [`BackingStorageOptions::operations_chunk_size`].
+const META_KEY_OPERATIONS_CHUNKS: u32 = 1 << 16;
+
+/// The version of the layout of the stored data. It's recorded with every snapshot and stores
+/// written with another version are rejected when they are opened. Stores that don't record a
+/// version have the layout of version 1.
+const SCHEMA_VERSION: u32 = 1;
+
+/// The number of buckets of [`BackingStorageStats::size_histogram`].
+pub const SIZE_BUCKETS: usize = 32;
+/// The number of task data sizes that need to be recorded before outliers are reported.
+const MIN_SIZE_SAMPLES: u64 = 100;
 
 struct IntKey([u8; 4]);
 
@@ -41,494 +107,7487 @@ fn as_u32(bytes: impl Borrow<[u8]>) -> Result<u32> {
     Ok(n)
 }
 
-pub struct KeyValueDatabaseBackingStorage<T: KeyValueDatabase> {
-    database: T,
+fn as_u64(bytes: impl Borrow<[u8]>) -> Result<u64> {
+    let n = u64::from_le_bytes(bytes.borrow().try_into()?);
+    Ok(n)
 }
 
-impl<T: KeyValueDatabase> KeyValueDatabaseBackingStorage<T> {
-    pub fn new(database: T) -> Self {
-        Self { database }
+/// Decodes a list of task ids, e.g. the pinned tasks, which is stored as sorted little-endian
+/// u32s.
+fn decode_task_id_list(bytes: &[u8]) -> Result<Vec<u32>> {
+    if bytes.len() % 4 != 0 {
+        bail!("Invalid task id list: {} bytes", bytes.len());
     }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| as_u32(chunk).unwrap())
+        .collect())
+}
 
-    fn with_tx<R>(
-        &self,
-        tx: Option<&T::ReadTransaction<'_>>,
-        f: impl FnOnce(&T::ReadTransaction<'_>) -> Result<R>,
-    ) -> Result<R> {
-        if let Some(tx) = tx {
-            f(tx)
-        } else {
-            let tx = self.database.begin_read_transaction()?;
-            let r = f(&tx)?;
-            drop(tx);
-            Ok(r)
-        }
+/// The error returned when a stored task id doesn't have the expected length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corrupt {
+    pub key_space: KeySpace,
+    /// The key of the entry. For keys that are task ids, the key itself is malformed.
+    pub key: Vec<u8>,
+    pub len: usize,
+}
+
+impl Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Corrupt entry in {:?} at key {:?}: expected a 4 byte task id, but found {} bytes",
+            self.key_space, self.key, self.len
+        )
     }
 }
 
-fn get_infra_u32(database: &impl KeyValueDatabase, key: u32) -> Option<u32> {
-    let tx = database.begin_read_transaction().ok()?;
-    let value = database
-        .get(&tx, KeySpace::Infra, IntKey::new(key).as_ref())
-        .ok()?
-        .map(as_u32)?
-        .ok()?;
-    Some(value)
+impl std::error::Error for Corrupt {}
+
+/// The error returned when a task id is persisted that leaves no free task id after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskIdSpaceExhausted {
+    pub task_id: u32,
 }
 
-impl<T: KeyValueDatabase + Send + Sync + 'static> BackingStorage
-    for KeyValueDatabaseBackingStorage<T>
-{
-    type ReadTransaction<'l> = T::ReadTransaction<'l>;
+impl Display for TaskIdSpaceExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Task id space exhausted: task id {} is the last representable task id",
+            self.task_id
+        )
+    }
+}
 
-    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
-        tx: &'r Self::ReadTransaction<'l>,
-    ) -> &'r Self::ReadTransaction<'i> {
-        T::lower_read_transaction(tx)
+impl std::error::Error for TaskIdSpaceExhausted {}
+
+/// The error returned by a snapshot that found another snapshot writing, see
+/// [`ConcurrentSnapshotPolicy::Fail`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotInProgress;
+
+impl Display for SnapshotInProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Another snapshot is being written")
     }
+}
 
-    fn next_free_task_id(&self) -> TaskId {
-        TaskId::from(get_infra_u32(&self.database, META_KEY_NEXT_FREE_TASK_ID).unwrap_or(1))
+impl std::error::Error for SnapshotInProgress {}
+
+/// Returns the next free task id after `task_id`.
+fn next_task_id_after(task_id: u32) -> Result<u32> {
+    Ok(task_id
+        .checked_add(1)
+        .ok_or(TaskIdSpaceExhausted { task_id })?)
+}
+
+/// Decodes a task id stored in the key or value of the entry at `key`, and reports a [`Corrupt`]
+/// error if it has the wrong length.
+fn decode_task_id(key_space: KeySpace, key: &[u8], bytes: &[u8]) -> Result<u32> {
+    let Ok(bytes) = bytes.try_into() else {
+        return Err(Corrupt {
+            key_space,
+            key: key.to_vec(),
+            len: bytes.len(),
+        }
+        .into());
+    };
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Number of processed items between two invocations of the progress callback.
+const PROGRESS_INTERVAL: usize = 4096;
+
+pub type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+pub type CommitHook = Arc<dyn Fn(&CommitInfo) + Send + Sync>;
+
+/// A periodic housekeeping task, see [`KeyValueDatabaseBackingStorage::start_maintenance`].
+pub type MaintenanceTask<T> =
+    Arc<dyn Fn(&KeyValueDatabaseBackingStorage<T>) -> Result<()> + Send + Sync>;
+
+/// Describes a committed snapshot, see [`BackingStorageOptions::on_commit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// The number of database operations of the snapshot.
+    pub op_count: usize,
+    /// The number of tasks whose meta data or data was written.
+    pub written_tasks: usize,
+    pub next_free_task_id: TaskId,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaskIdAllocation {
+    /// Task ids are allocated from a counter that continues from the persisted state.
+    #[default]
+    Sequential,
+    /// Task ids are derived from a hash of the serialized task type, so a given task type gets
+    /// the same id independent of the order in which tasks are discovered. Collisions are
+    /// resolved by probing the following ids.
+    ContentAddressed,
+}
+
+/// How a snapshot handles a task id that is mapped to different task types in the task cache
+/// updates. This indicates a bug in the backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateTaskIdPolicy {
+    /// Fails the snapshot.
+    Error,
+    /// Stores the last mapping and logs an error. The forward entries of all types point to the
+    /// task id.
+    #[default]
+    LastWins,
+    /// Stores the first mapping, ignores the following ones and logs an error.
+    FirstWins,
+}
+
+/// How [`KeyValueDatabaseBackingStorage::import_task_cache`] handles an imported task type that is
+/// stored under another task id, or an imported task id that is stored with another task type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TaskCacheImportConflictPolicy {
+    /// Fails the import. Nothing is imported.
+    #[default]
+    Error,
+    /// Keeps the stored mapping, skips the imported entry and logs a warning.
+    KeepExisting,
+}
+
+/// How snapshots are handled after writing failed with
+/// [`ReadOnlyFilesystem`](crate::database::ReadOnlyFilesystem).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadOnlyFilesystemPolicy {
+    /// Every snapshot tries to write and fails.
+    #[default]
+    Fail,
+    /// The failing snapshot returns the error. The storage is read-only for the remainder of the
+    /// process and following snapshots are skipped.
+    Downgrade,
+}
+
+/// How a snapshot handles another snapshot or a maintenance run that is writing. Writes are
+/// serialized by a lock of the storage, not by the write transaction of the database, so a
+/// waiting snapshot doesn't hold a database transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConcurrentSnapshotPolicy {
+    /// Blocks until the other write is done. The time is recorded in
+    /// [`BackingStorageStats::write_lock_wait_micros`].
+    #[default]
+    Wait,
+    /// Fails with [`SnapshotInProgress`] without writing anything.
+    Fail,
+}
+
+/// Options of [`KeyValueDatabaseBackingStorage`]. The options that encode task data, like
+/// [`Self::compress_min_bytes`] or [`Self::frame_values`], only affect how values are written.
+/// Values written with any of them can be read with all options, so they can be changed between
+/// runs. They don't apply to task data that is streamed into the database, see
+/// [`Self::streaming_threshold`].
+#[derive(Clone)]
+pub struct BackingStorageOptions {
+    /// Called with `(items_processed, total_items)` while `save_snapshot` processes the task
+    /// cache and data updates. It's invoked every few thousand items and once at the end.
+    pub progress: Option<ProgressCallback>,
+    pub task_id_allocation: TaskIdAllocation,
+    /// Number of threads used by [`KeyValueDatabaseBackingStorage::scan_task_index`]. Each thread
+    /// scans a sub-range of task ids in its own read transaction. `0` and `1` scan serially.
+    /// Parallel scanning requires a database with ordered keys.
+    pub startup_parallelism: usize,
+    /// The codec used for task data of new databases. Existing databases keep using the codec
+    /// they were created with.
+    pub value_codec: ValueCodec,
+    /// Task data that serializes to at least this many bytes is serialized directly into the
+    /// database during the write instead of into an intermediate buffer. This needs an extra
+    /// pass to compute the serialized size of every task. Streamed data is written as is, i.e.
+    /// it's not framed, padded, compressed, delta encoded or deduplicated. `None` disables it.
+    pub streaming_threshold: Option<usize>,
+    /// Maximum number of data item serialization failures that are logged individually per
+    /// snapshot. Further failures are only included in a summary.
+    pub serialization_failure_log_limit: usize,
+    /// Fails the snapshot when any data item fails to serialize, instead of dropping optional
+    /// items like cell data. This surfaces serialization bugs in tests that would otherwise only
+    /// show up as cells that are recomputed after a restart.
+    pub strict_serialization: bool,
+    /// Task data that serializes to at least this many bytes is stored once per content in a
+    /// reference counted blob, so tasks with identical data share storage. `None` disables it.
+    /// Blobs that were written before it was disabled are released when the data is rewritten.
+    pub data_deduplication_threshold: Option<usize>,
+    /// Stores task data as a delta against a baseline, which is rewritten after this many
+    /// snapshots. This saves writes and space for data that changes slightly between snapshots,
+    /// at the cost of reconstructing the data on lookup. Delta encoded data isn't deduplicated.
+    /// `None` disables it.
+    pub data_delta_baseline_interval: Option<u32>,
+    /// Logs a warning for task data that is larger than this factor times the 99th percentile of
+    /// all task data sizes, since such outliers often indicate a bug. This doesn't limit the size.
+    pub outlier_size_factor: Option<u64>,
+    /// The size budget that [`KeyValueDatabaseBackingStorage::enforce_budget`] enforces. When
+    /// it's set, accesses of tasks are tracked to evict the least recently accessed tasks.
+    pub max_store_bytes: Option<u64>,
+    /// Compresses task data that serializes to at least this many bytes with LZ4. Smaller data is
+    /// stored uncompressed, since compressing it costs CPU and rarely saves space. `None`
+    /// disables compression.
+    pub compress_min_bytes: Option<usize>,
+    /// Skips snapshots without operations and updates instead of committing a write transaction
+    /// that only updates the session id. The session id is then not persisted, which is fine
+    /// since nothing was written in the session. Clearing the stored operations is still
+    /// persisted.
+    pub skip_empty_snapshots: bool,
+    /// Splits the serialized uncompleted operations into chunks of this many bytes when they are
+    /// larger, so many pending operations don't end up in one oversized value. `None` stores them
+    /// in one value. Chunked operations can be read with any chunk size.
+    pub operations_chunk_size: Option<usize>,
+    pub duplicate_task_ids: DuplicateTaskIdPolicy,
+    /// A sidecar file that holds a copy of the task index, see
+    /// [`KeyValueDatabaseBackingStorage::scan_task_index`]. It's written by
+    /// [`KeyValueDatabaseBackingStorage::shutdown`] and loaded when the storage is opened, so a
+    /// warm start doesn't need to scan the database. A stale or missing file falls back to a
+    /// scan. `None` disables it.
+    pub task_index_cache: Option<PathBuf>,
+    /// Prefixes task data with its serialized length, so a truncated value is reported as such
+    /// instead of failing to deserialize.
+    pub frame_values: bool,
+    /// Records this byte in the frame of every written task data value, e.g. an epoch that is
+    /// bumped with format changes. When data fails to deserialize, the error names the version
+    /// that wrote it, which pinpoints stores written by mixed versions. Values are framed when
+    /// it's set, like with [`BackingStorageOptions::frame_values`], and the version adds one
+    /// byte to the frame.
+    pub writer_version: Option<u8>,
+    /// Experimental: pads every written task data value with zero bytes to a multiple of this
+    /// many bytes, so values that grow a little can be rewritten in place instead of moving to
+    /// other pages, at the cost of space. The real length is recorded in the frame. Values are
+    /// framed when it's set, like with [`BackingStorageOptions::frame_values`]. Compression with
+    /// [`BackingStorageOptions::compress_min_bytes`] removes the padding again.
+    pub pad_values_to: Option<NonZeroUsize>,
+    pub read_only_filesystem: ReadOnlyFilesystemPolicy,
+    /// Records [`BackingStorageStats::logical_update_bytes`] and
+    /// [`BackingStorageStats::written_task_bytes`]. This serializes every updated item on its
+    /// own, so it's disabled by default.
+    pub record_write_amplification: bool,
+    pub task_cache_import_conflicts: TaskCacheImportConflictPolicy,
+    /// The thread pool that serializes task data during snapshots. `None` uses a pool owned by
+    /// the storage with one thread per available core, so snapshots don't contend with the
+    /// global rayon pool.
+    pub serialization_pool: Option<Arc<rayon::ThreadPool>>,
+    /// Stores a short label of the task type next to the reverse task cache entry of every task,
+    /// e.g. the function name. Dumps and per-type metrics can use it via
+    /// [`KeyValueDatabaseBackingStorage::task_label`] without deserializing the task type.
+    pub store_task_labels: bool,
+    /// Called after a snapshot was committed, e.g. to notify an external system. It's only
+    /// called when the commit succeeded, so it never observes state that isn't persisted. The
+    /// snapshot is blocked until it returns.
+    pub on_commit: Option<CommitHook>,
+    /// Deserializes every task type again before its task cache entries are written and fails
+    /// the write when it doesn't round trip. A corrupted task type breaks resolving its task id,
+    /// and the check is cheap compared to the `verify_serialization` feature, which also checks
+    /// all data items.
+    pub verify_task_types: bool,
+    /// Emits a `tracing` event at trace level for every restore from the task cache and the
+    /// task data, with whether it was found, the task id and the label of the task type when
+    /// they are known. This allows correlating restores with the spans of the caller, but it
+    /// adds an event to a hot path, so it's disabled by default.
+    pub lookup_events: bool,
+    /// Logs the first this many task types that aren't in the task cache at debug level, i.e.
+    /// the tasks that are new compared to the store. This helps finding out why tasks aren't
+    /// restored. `0` logs none.
+    pub log_new_tasks_limit: usize,
+    /// Splits a snapshot that doesn't fit into one write transaction, i.e. that fails with
+    /// [`TransactionFull`], into multiple transactions. What was written so far is committed and
+    /// the remaining task data is committed in transactions of at most this many tasks. The
+    /// snapshot is then no longer committed atomically, so a crash in between leaves some tasks
+    /// with the data of the previous snapshot. `None` fails such snapshots. Snapshots aren't split
+    /// with [`Self::data_deduplication_threshold`] or [`Self::data_delta_baseline_interval`],
+    /// since their writes depend on each other. Only writes that are rejected before the
+    /// transaction is affected can be split, e.g. with `LmdbOptions::max_transaction_bytes`. LMDB
+    /// aborts the transaction when it exceeds its own limit.
+    pub commit_chunk_size: Option<usize>,
+    /// Keeps the deserialized data of this many recent `lookup_data` calls in memory, so
+    /// repeated lookups of hot tasks don't deserialize the data again. The data of a task is
+    /// dropped from the cache when the task is written. Cache misses read the latest committed
+    /// data instead of the data of the passed transaction. `None` disables the cache.
+    pub data_cache_capacity: Option<usize>,
+    /// Skips the data items of a task that can't be deserialized in `lookup_data`, e.g. after the
+    /// format of an item changed, and returns the remaining items instead of no data. Every
+    /// skipped item is logged. Items are only deserialized one by one when the task data as a
+    /// whole can't be deserialized.
+    pub skip_invalid_items: bool,
+    pub concurrent_snapshots: ConcurrentSnapshotPolicy,
+    /// Marks the storage as degraded once more than this many lookups failed, see
+    /// [`BackingStorageStats::degraded`]. Failed lookups are treated as missing data, so a broken
+    /// store would otherwise silently recompute every task. `None` never marks it degraded.
+    pub max_restore_errors: Option<u64>,
+    /// [`KeyValueDatabaseBackingStorage::health_check`] reports the storage as degraded once this
+    /// fraction of the database's capacity is used, e.g. of the LMDB map.
+    pub health_degraded_capacity: f64,
+    /// [`KeyValueDatabaseBackingStorage::health_check`] reports the storage as unhealthy once this
+    /// fraction of the database's capacity is used, since writes are about to fail.
+    pub health_unhealthy_capacity: f64,
+}
+
+impl Default for BackingStorageOptions {
+    fn default() -> Self {
+        Self {
+            progress: None,
+            task_id_allocation: TaskIdAllocation::default(),
+            startup_parallelism: 0,
+            value_codec: ValueCodec::default(),
+            streaming_threshold: None,
+            serialization_failure_log_limit: 10,
+            strict_serialization: false,
+            data_deduplication_threshold: None,
+            data_delta_baseline_interval: None,
+            outlier_size_factor: None,
+            max_store_bytes: None,
+            compress_min_bytes: None,
+            skip_empty_snapshots: true,
+            operations_chunk_size: Some(4 * 1024 * 1024),
+            duplicate_task_ids: DuplicateTaskIdPolicy::default(),
+            task_index_cache: None,
+            frame_values: false,
+            writer_version: None,
+            pad_values_to: None,
+            read_only_filesystem: ReadOnlyFilesystemPolicy::default(),
+            record_write_amplification: false,
+            task_cache_import_conflicts: TaskCacheImportConflictPolicy::default(),
+            serialization_pool: None,
+            store_task_labels: false,
+            on_commit: None,
+            verify_task_types: true,
+            lookup_events: false,
+            log_new_tasks_limit: 0,
+            commit_chunk_size: None,
+            data_cache_capacity: None,
+            skip_invalid_items: false,
+            concurrent_snapshots: ConcurrentSnapshotPolicy::default(),
+            max_restore_errors: None,
+            health_degraded_capacity: 0.9,
+            health_unhealthy_capacity: 0.98,
+        }
     }
+}
 
-    fn next_session_id(&self) -> SessionId {
-        SessionId::from(get_infra_u32(&self.database, META_KEY_SESSION_ID).unwrap_or(0) + 1)
+/// The result of [`KeyValueDatabaseBackingStorage::health_check`], e.g. for liveness and
+/// readiness probes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    /// The storage works, but needs attention. The reason explains why.
+    Degraded(String),
+    /// The storage can't be used. The reason explains why.
+    Unhealthy(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackingStorageStats {
+    /// Optional data items that were not persisted because they couldn't be serialized.
+    pub skipped_optional_items: u64,
+    /// Required data items that couldn't be serialized. Each of them fails the snapshot.
+    pub failed_required_items: u64,
+    /// The serialized sizes of all written task data. Bucket `i` counts sizes below `2^i` bytes
+    /// that don't fit into a lower bucket. The last bucket also counts all larger sizes.
+    pub size_histogram: [u64; SIZE_BUCKETS],
+    /// Task data that was reported as outlier, see
+    /// [`BackingStorageOptions::outlier_size_factor`].
+    pub outlier_tasks: u64,
+    /// Snapshots that were committed.
+    pub committed_snapshots: u64,
+    /// Snapshots that were skipped because they were empty, see
+    /// [`BackingStorageOptions::skip_empty_snapshots`].
+    pub skipped_empty_snapshots: u64,
+    /// Snapshots that were committed in multiple transactions, see
+    /// [`BackingStorageOptions::commit_chunk_size`].
+    pub split_snapshots: u64,
+    /// Lookups of task data that were served from the data cache, see
+    /// [`BackingStorageOptions::data_cache_capacity`].
+    pub data_cache_hits: u64,
+    /// Snapshots that waited for another write, see [`ConcurrentSnapshotPolicy::Wait`].
+    pub write_lock_waits: u64,
+    /// The time snapshots waited for other writes in microseconds.
+    pub write_lock_wait_micros: u64,
+    /// Lookups of task types or task data that failed and were treated as missing.
+    pub restore_errors: u64,
+    /// Lookups of task meta data or data that found persisted data.
+    pub restored_tasks: u64,
+    /// Lookups of task ids by task type that found a persisted task.
+    pub restored_cache_entries: u64,
+    /// Like [`BackingStorageStats::restored_tasks`], but over the life of the store. The count is
+    /// persisted with every snapshot, so it includes previous runs. Lookups since the last
+    /// snapshot are included, but are lost when the process exits before the next committed
+    /// snapshot or the snapshot fails. Snapshots that are skipped because they are empty don't
+    /// persist the count.
+    pub lifetime_restored_tasks: u64,
+    /// Like [`BackingStorageStats::restored_cache_entries`], but over the life of the store, see
+    /// [`BackingStorageStats::lifetime_restored_tasks`].
+    pub lifetime_restored_cache_entries: u64,
+    /// Whether more lookups failed than [`BackingStorageOptions::max_restore_errors`] allows.
+    pub degraded: bool,
+    /// Task index requests that scanned the database.
+    pub task_index_scans: u64,
+    /// Task index requests that were served from the task index cache, see
+    /// [`BackingStorageOptions::task_index_cache`].
+    pub task_index_cache_hits: u64,
+    /// The serialized sizes of the updated items handed to snapshots, see
+    /// [`BackingStorageOptions::record_write_amplification`].
+    pub logical_update_bytes: u64,
+    /// The bytes of task meta and data that snapshots wrote, including delta baselines. Every
+    /// update rewrites the whole data of its task, unless it's delta encoded. Shared data blobs
+    /// are not included.
+    pub written_task_bytes: u64,
+}
+
+impl BackingStorageStats {
+    /// Renders the stats in the Prometheus text exposition format, e.g. to serve them from a
+    /// `/metrics` endpoint.
+    pub fn to_prometheus(&self) -> String {
+        let mut output = String::new();
+        for (name, help, value) in [
+            (
+                "turbo_tasks_backend_skipped_optional_items_total",
+                "Optional data items that were not persisted because they couldn't be serialized.",
+                self.skipped_optional_items,
+            ),
+            (
+                "turbo_tasks_backend_failed_required_items_total",
+                "Required data items that couldn't be serialized.",
+                self.failed_required_items,
+            ),
+            (
+                "turbo_tasks_backend_outlier_tasks_total",
+                "Task data that was much larger than usual.",
+                self.outlier_tasks,
+            ),
+            (
+                "turbo_tasks_backend_committed_snapshots_total",
+                "Snapshots that were committed.",
+                self.committed_snapshots,
+            ),
+            (
+                "turbo_tasks_backend_skipped_empty_snapshots_total",
+                "Snapshots that were skipped because they were empty.",
+                self.skipped_empty_snapshots,
+            ),
+            (
+                "turbo_tasks_backend_split_snapshots_total",
+                "Snapshots that were committed in multiple transactions.",
+                self.split_snapshots,
+            ),
+            (
+                "turbo_tasks_backend_data_cache_hits_total",
+                "Lookups of task data that were served from the data cache.",
+                self.data_cache_hits,
+            ),
+            (
+                "turbo_tasks_backend_write_lock_waits_total",
+                "Snapshots that waited for another write.",
+                self.write_lock_waits,
+            ),
+            (
+                "turbo_tasks_backend_write_lock_wait_microseconds_total",
+                "Time snapshots waited for other writes.",
+                self.write_lock_wait_micros,
+            ),
+            (
+                "turbo_tasks_backend_restore_errors_total",
+                "Lookups of task types or task data that failed.",
+                self.restore_errors,
+            ),
+            (
+                "turbo_tasks_backend_restored_tasks_total",
+                "Lookups of task meta data or data that found persisted data.",
+                self.restored_tasks,
+            ),
+            (
+                "turbo_tasks_backend_restored_cache_entries_total",
+                "Lookups of task ids by task type that found a persisted task.",
+                self.restored_cache_entries,
+            ),
+            (
+                "turbo_tasks_backend_lifetime_restored_tasks_total",
+                "Lookups of task meta data or data that found persisted data over the life of the \
+                 store.",
+                self.lifetime_restored_tasks,
+            ),
+            (
+                "turbo_tasks_backend_lifetime_restored_cache_entries_total",
+                "Lookups of task ids by task type that found a persisted task over the life of \
+                 the store.",
+                self.lifetime_restored_cache_entries,
+            ),
+            (
+                "turbo_tasks_backend_task_index_scans_total",
+                "Task index requests that scanned the database.",
+                self.task_index_scans,
+            ),
+            (
+                "turbo_tasks_backend_task_index_cache_hits_total",
+                "Task index requests that were served from the task index cache.",
+                self.task_index_cache_hits,
+            ),
+            (
+                "turbo_tasks_backend_logical_update_bytes_total",
+                "Serialized sizes of the updated items handed to snapshots.",
+                self.logical_update_bytes,
+            ),
+            (
+                "turbo_tasks_backend_written_task_bytes_total",
+                "Bytes of task meta and data that snapshots wrote.",
+                self.written_task_bytes,
+            ),
+        ] {
+            writeln!(output, "# HELP {name} {help}").unwrap();
+            writeln!(output, "# TYPE {name} counter").unwrap();
+            writeln!(output, "{name} {value}").unwrap();
+        }
+        let name = "turbo_tasks_backend_degraded";
+        writeln!(
+            output,
+            "# HELP {name} Whether more lookups failed than the configured maximum."
+        )
+        .unwrap();
+        writeln!(output, "# TYPE {name} gauge").unwrap();
+        writeln!(output, "{name} {}", u8::from(self.degraded)).unwrap();
+        output
     }
 
-    fn uncompleted_operations(&self) -> Vec<AnyOperation> {
-        fn get(database: &impl KeyValueDatabase) -> Result<Vec<AnyOperation>> {
-            let tx = database.begin_read_transaction()?;
-            let Some(operations) = database.get(
-                &tx,
-                KeySpace::Infra,
-                IntKey::new(META_KEY_OPERATIONS).as_ref(),
-            )?
-            else {
-                return Ok(Vec::new());
-            };
-            let operations = pot::from_slice(operations.borrow())?;
-            Ok(operations)
+    /// Returns how many bytes were written per byte of updated items, or `None` when no updates
+    /// were recorded. A high value means that small updates rewrite large task data.
+    pub fn write_amplification(&self) -> Option<f64> {
+        (self.logical_update_bytes > 0)
+            .then(|| self.written_task_bytes as f64 / self.logical_update_bytes as f64)
+    }
+
+    /// Returns the exclusive upper bound of the task data sizes up to `percentile` (0 to 100),
+    /// or `None` when no sizes were recorded.
+    pub fn size_percentile(&self, percentile: u64) -> Option<u64> {
+        let total: u64 = self.size_histogram.iter().sum();
+        let target = (total * percentile).div_ceil(100).max(1);
+        let mut count = 0;
+        for (bucket, n) in self.size_histogram.iter().enumerate() {
+            count += n;
+            if count >= target {
+                return Some(1 << bucket);
+            }
         }
-        get(&self.database).unwrap_or_default()
+        None
     }
+}
 
-    fn save_snapshot(
-        &self,
-        session_id: SessionId,
-        operations: Vec<Arc<AnyOperation>>,
-        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
-        meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
-        data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
-    ) -> Result<()> {
-        let span = tracing::trace_span!("save snapshot", session_id = ?session_id, operations = operations.len(), db_operation_count = tracing::field::Empty);
-        let mut op_count = 0;
-        let mut batch = self.database.write_batch()?;
-        let mut task_meta_items_result = Ok(Vec::new());
-        let mut task_data_items_result = Ok(Vec::new());
+fn size_bucket(size: usize) -> usize {
+    ((usize::BITS - size.leading_zeros()) as usize).min(SIZE_BUCKETS - 1)
+}
 
-        turbo_tasks::scope(|s| {
-            // Start organizing the updates in parallel
-            s.spawn(|_| {
-                task_meta_items_result =
-                    process_task_data(&self.database, KeySpace::TaskMeta, meta_updates);
-            });
-            s.spawn(|_| {
-                task_data_items_result =
-                    process_task_data(&self.database, KeySpace::TaskData, data_updates);
-            });
+/// The result of [`KeyValueDatabaseBackingStorage::salvage_into`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Task meta and data entries that were copied.
+    pub recovered_entries: usize,
+    /// Task meta and data entries that were dropped because they couldn't be read.
+    pub dropped_entries: usize,
+    /// Task cache entries that were copied.
+    pub task_cache_entries: usize,
+}
 
-            {
-                let _span =
-                    tracing::trace_span!("update session id", session_id = ?session_id).entered();
-                batch
-                    .put(
-                        KeySpace::Infra,
-                        Cow::Borrowed(IntKey::new(META_KEY_SESSION_ID).as_ref()),
-                        Cow::Borrowed(&session_id.to_le_bytes()),
-                    )
-                    .with_context(|| anyhow!("Unable to write next session id"))?;
-            }
+pub struct KeyValueDatabaseBackingStorage<T: KeyValueDatabase> {
+    database: T,
+    options: BackingStorageOptions,
+    value_codec: ValueCodec,
+    skipped_optional_items: AtomicU64,
+    failed_required_items: AtomicU64,
+    size_histogram: [AtomicU64; SIZE_BUCKETS],
+    outlier_tasks: AtomicU64,
+    committed_snapshots: AtomicU64,
+    skipped_empty_snapshots: AtomicU64,
+    split_snapshots: AtomicU64,
+    data_cache_hits: AtomicU64,
+    write_lock_waits: AtomicU64,
+    write_lock_wait_micros: AtomicU64,
+    restore_errors: AtomicU64,
+    restored_tasks: AtomicU64,
+    restored_cache_entries: AtomicU64,
+    /// The restored tasks and cache entries since the last snapshot, which adds them to the
+    /// persisted lifetime counts.
+    unpersisted_restored_tasks: AtomicU64,
+    unpersisted_restored_cache_entries: AtomicU64,
+    /// The unpersisted restored tasks and cache entries that the snapshot that is being written
+    /// adds to the lifetime counts. They are only subtracted once it's committed, so a failed
+    /// snapshot doesn't lose them.
+    uncommitted_restored_counts: Mutex<(u64, u64)>,
+    degraded: AtomicBool,
+    /// The new task types that were logged, see [`BackingStorageOptions::log_new_tasks_limit`].
+    logged_new_tasks: AtomicUsize,
+    task_index_scans: AtomicU64,
+    task_index_cache_hits: AtomicU64,
+    logical_update_bytes: AtomicU64,
+    written_task_bytes: AtomicU64,
+    /// The task index loaded from the task index cache. It's dropped when task data is written,
+    /// since it's stale then.
+    cached_task_index: Mutex<Option<Vec<TaskId>>>,
+    snapshot_cost: Mutex<SnapshotCostModel>,
+    /// The logical time of the last access of each task since the storage was opened. Only
+    /// tracked when `max_store_bytes` is set.
+    access_stamps: DashMap<TaskId, u64, BuildHasherDefault<FxHasher>>,
+    access_clock: AtomicU64,
+    /// Set when the store was downgraded to read-only, see [`ReadOnlyFilesystemPolicy`].
+    read_only: AtomicBool,
+    /// The pool used when no [`BackingStorageOptions::serialization_pool`] is configured. It's
+    /// created on first use.
+    serialization_pool: OnceCell<rayon::ThreadPool>,
+    /// See [`BackingStorageOptions::data_cache_capacity`].
+    data_cache: Option<Mutex<DataCache>>,
+    /// Held while a snapshot or a maintenance run writes, so they don't compete for the write
+    /// transaction, see [`KeyValueDatabaseBackingStorage::start_maintenance`] and
+    /// [`BackingStorageOptions::concurrent_snapshots`]. It's reentrant, so maintenance tasks can
+    /// call methods that take it, e.g. [`KeyValueDatabaseBackingStorage::enforce_budget`].
+    write_lock: ReentrantMutex<()>,
+    /// The manifest for this opening of the store. It's written with the next snapshot, so
+    /// opening a store doesn't write to it.
+    pending_manifest: Mutex<Option<Manifest>>,
+}
 
-            let mut next_task_id = match batch.get(
-                KeySpace::Infra,
-                IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref(),
-            )? {
-                Some(bytes) => u32::from_le_bytes(bytes.borrow().try_into()?),
-                None => 1,
-            };
-            {
-                let _span = tracing::trace_span!(
-                    "update task cache",
-                    items = task_cache_updates.iter().map(|m| m.len()).sum::<usize>()
-                )
-                .entered();
-                for (task_type, task_id) in task_cache_updates.into_iter().flatten() {
-                    let task_id = *task_id;
-                    let task_type_bytes = pot::to_vec(&*task_type).with_context(|| {
-                        anyhow!("Unable to serialize task cache key {task_type:?}")
-                    })?;
-                    #[cfg(feature = "verify_serialization")]
-                    {
-                        let deserialize: Result<CachedTaskType, _> =
-                            serde_path_to_error::deserialize(
-                                &mut pot::de::SymbolList::new()
-                                    .deserializer_for_slice(&task_type_bytes)?,
-                            );
-                        if let Err(err) = deserialize {
-                            println!(
-                                "Task type would not be deserializable {task_id}: \
-                                 {err:?}\n{task_type:#?}"
-                            );
-                            panic!("Task type would not be deserializable {task_id}: {err:?}");
-                        }
-                    }
+/// The deserialized data of recently looked up tasks.
+struct DataCache {
+    entries: LruCache<(TaskId, TaskDataCategory), Arc<Vec<CachedDataItem>>>,
+    /// Incremented by every invalidation, so a lookup that raced with a write doesn't cache the
+    /// data it read before the write.
+    generation: u64,
+}
 
-                    batch
-                        .put(
-                            KeySpace::ForwardTaskCache,
-                            Cow::Borrowed(&task_type_bytes),
-                            Cow::Borrowed(&task_id.to_le_bytes()),
-                        )
-                        .with_context(|| {
-                            anyhow!("Unable to write task cache {task_type:?} => {task_id}")
-                        })?;
-                    batch
-                        .put(
-                            KeySpace::ReverseTaskCache,
-                            Cow::Borrowed(IntKey::new(task_id).as_ref()),
-                            Cow::Borrowed(&task_type_bytes),
-                        )
-                        .with_context(|| {
-                            anyhow!("Unable to write task cache {task_id} => {task_type:?}")
-                        })?;
-                    op_count += 2;
-                    next_task_id = next_task_id.max(task_id + 1);
-                }
-                batch
-                    .put(
-                        KeySpace::Infra,
-                        Cow::Borrowed(IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref()),
-                        Cow::Borrowed(&next_task_id.to_le_bytes()),
-                    )
-                    .with_context(|| anyhow!("Unable to write next free task id"))?;
-            }
-            {
-                let _span =
-                    tracing::trace_span!("update operations", operations = operations.len())
-                        .entered();
-                let operations = pot::to_vec(&operations)
-                    .with_context(|| anyhow!("Unable to serialize operations"))?;
-                batch
-                    .put(
-                        KeySpace::Infra,
-                        Cow::Borrowed(IntKey::new(META_KEY_OPERATIONS).as_ref()),
-                        operations.into(),
-                    )
-                    .with_context(|| anyhow!("Unable to write operations"))?;
-                op_count += 2;
+impl<T: KeyValueDatabase> KeyValueDatabaseBackingStorage<T> {
+    pub fn new(database: T) -> Result<Self> {
+        Self::with_options(database, BackingStorageOptions::default())
+    }
+
+    pub fn with_options(database: T, options: BackingStorageOptions) -> Result<Self> {
+        let schema_version =
+            get_infra_u32(&database, META_KEY_SCHEMA_VERSION).unwrap_or(SCHEMA_VERSION);
+        if schema_version != SCHEMA_VERSION {
+            bail!(
+                "The database was written with schema version {schema_version}, but only version \
+                 {SCHEMA_VERSION} can be read. It needs to be deleted or recreated."
+            );
+        }
+        let value_codec = match get_infra_u32(&database, META_KEY_VALUE_CODEC) {
+            Some(id) => ValueCodec::from_id(id)?,
+            // Stores that were written before the codec was recorded use plain `pot`. The
+            // configured codec only applies to new stores.
+            None if get_infra_u32(&database, META_KEY_SESSION_ID).is_some() => ValueCodec::Pot,
+            None => options.value_codec,
+        };
+        let data_cache = options
+            .data_cache_capacity
+            .and_then(NonZeroUsize::new)
+            .map(|capacity| {
+                Mutex::new(DataCache {
+                    entries: LruCache::new(capacity),
+                    generation: 0,
+                })
+            });
+        let this = Self {
+            database,
+            options,
+            value_codec,
+            skipped_optional_items: AtomicU64::new(0),
+            failed_required_items: AtomicU64::new(0),
+            size_histogram: std::array::from_fn(|_| AtomicU64::new(0)),
+            outlier_tasks: AtomicU64::new(0),
+            committed_snapshots: AtomicU64::new(0),
+            skipped_empty_snapshots: AtomicU64::new(0),
+            split_snapshots: AtomicU64::new(0),
+            data_cache_hits: AtomicU64::new(0),
+            write_lock_waits: AtomicU64::new(0),
+            write_lock_wait_micros: AtomicU64::new(0),
+            restore_errors: AtomicU64::new(0),
+            restored_tasks: AtomicU64::new(0),
+            restored_cache_entries: AtomicU64::new(0),
+            unpersisted_restored_tasks: AtomicU64::new(0),
+            unpersisted_restored_cache_entries: AtomicU64::new(0),
+            uncommitted_restored_counts: Mutex::new((0, 0)),
+            degraded: AtomicBool::new(false),
+            logged_new_tasks: AtomicUsize::new(0),
+            task_index_scans: AtomicU64::new(0),
+            task_index_cache_hits: AtomicU64::new(0),
+            logical_update_bytes: AtomicU64::new(0),
+            written_task_bytes: AtomicU64::new(0),
+            cached_task_index: Mutex::new(None),
+            snapshot_cost: Mutex::new(SnapshotCostModel::default()),
+            access_stamps: DashMap::default(),
+            access_clock: AtomicU64::new(0),
+            read_only: AtomicBool::new(false),
+            serialization_pool: OnceCell::new(),
+            data_cache,
+            write_lock: ReentrantMutex::new(()),
+            pending_manifest: Mutex::new(None),
+        };
+        if let Some(path) = &this.options.task_index_cache {
+            match this.load_task_index_cache(path) {
+                Ok(task_index) => *this.cached_task_index.lock() = task_index,
+                Err(err) => tracing::warn!("Ignoring task index cache: {err:?}"),
             }
+        }
+        // Immutable databases can't be written, but are still usable
+        if !this.database.is_immutable() {
+            *this.pending_manifest.lock() = Some(this.opened_manifest());
+        }
+        Ok(this)
+    }
 
-            anyhow::Ok(())
-        })?;
+    pub fn database(&self) -> &T {
+        &self.database
+    }
 
-        for (key_space, task_items) in [
-            (KeySpace::TaskMeta, task_meta_items_result?),
-            (KeySpace::TaskData, task_data_items_result?),
-        ] {
-            {
-                let _span =
-                    tracing::trace_span!("update task data", tasks = task_items.len()).entered();
-                for (task_id, value) in task_items.into_iter().flatten() {
-                    batch
-                        .put(
-                            key_space,
-                            Cow::Borrowed(IntKey::new(*task_id).as_ref()),
-                            value.into(),
-                        )
-                        .with_context(|| anyhow!("Unable to write data items for {task_id}"))?;
-                    op_count += 1;
+    /// Runs `tasks` every `interval` on a background thread, e.g. to evict tasks with
+    /// [`Self::enforce_budget`] or to release stale LMDB reader slots. Runs wait for a running
+    /// snapshot and snapshots wait for a running maintenance, so the tasks never compete with a
+    /// snapshot for the write transaction. Tasks must not save snapshots themselves. Errors of
+    /// tasks are logged. The thread stops when the returned handle or the storage is dropped.
+    pub fn start_maintenance(
+        self: &Arc<Self>,
+        interval: Duration,
+        tasks: Vec<MaintenanceTask<T>>,
+    ) -> Result<MaintenanceThread>
+    where
+        T: Send + Sync + 'static,
+    {
+        let storage = Arc::downgrade(self);
+        MaintenanceThread::spawn(interval, move || {
+            let Some(storage) = storage.upgrade() else {
+                return false;
+            };
+            let _span = tracing::trace_span!("maintenance").entered();
+            let _write_lock = storage.write_lock.lock();
+            for task in tasks.iter() {
+                if let Err(err) = task(&storage) {
+                    tracing::error!("Maintenance task failed: {err:?}");
                 }
             }
+            true
+        })
+    }
+
+    /// Returns the thread pool that serializes task data, see
+    /// [`BackingStorageOptions::serialization_pool`].
+    fn serialization_pool(&self) -> Result<&rayon::ThreadPool> {
+        if let Some(pool) = &self.options.serialization_pool {
+            return Ok(pool);
         }
+        self.serialization_pool
+            .get_or_try_init(|| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(available_parallelism().map_or(1, |n| n.get()))
+                    .thread_name(|index| format!("turbo-tasks-serialization-{index}"))
+                    .build()
+            })
+            .context("Unable to create the serialization thread pool")
+    }
+
+    /// Returns whether the store was downgraded to read-only because its filesystem is
+    /// read-only. Snapshots are skipped then.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Downgrades the store to read-only when `err` is a [`ReadOnlyFilesystem`] error and the
+    /// options ask for it.
+    fn handle_write_error(&self, err: anyhow::Error) -> anyhow::Error {
+        if self.options.read_only_filesystem == ReadOnlyFilesystemPolicy::Downgrade
+            && err.is::<ReadOnlyFilesystem>()
+            && !self.read_only.swap(true, Ordering::Relaxed)
         {
-            let _span = tracing::trace_span!("commit").entered();
-            batch
-                .commit()
-                .with_context(|| anyhow!("Unable to commit operations"))?;
+            tracing::warn!(
+                "The filesystem of the store is read-only, following snapshots are skipped"
+            );
         }
-        span.record("db_operation_count", op_count);
-        Ok(())
+        err
     }
 
-    fn start_read_transaction(&self) -> Option<Self::ReadTransaction<'_>> {
-        self.database.begin_read_transaction().ok()
+    fn record_restored_task(&self) {
+        self.restored_tasks.fetch_add(1, Ordering::Relaxed);
+        self.unpersisted_restored_tasks
+            .fetch_add(1, Ordering::Relaxed);
     }
 
-    unsafe fn forward_lookup_task_cache(
-        &self,
-        tx: Option<&T::ReadTransaction<'_>>,
-        task_type: &CachedTaskType,
-    ) -> Option<TaskId> {
-        fn lookup<D: KeyValueDatabase>(
-            database: &D,
-            tx: &D::ReadTransaction<'_>,
-            task_type: &CachedTaskType,
-        ) -> Result<Option<TaskId>> {
-            let task_type = pot::to_vec(task_type)?;
-            let Some(bytes) = database.get(tx, KeySpace::ForwardTaskCache, &task_type)? else {
-                return Ok(None);
-            };
-            let bytes = bytes.borrow().try_into()?;
-            let id = TaskId::from(u32::from_le_bytes(bytes));
-            Ok(Some(id))
+    fn record_restored_cache_entry(&self) {
+        self.restored_cache_entries.fetch_add(1, Ordering::Relaxed);
+        self.unpersisted_restored_cache_entries
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Logs a task type that isn't in the task cache, until
+    /// [`BackingStorageOptions::log_new_tasks_limit`] task types were logged.
+    fn log_new_task(&self, task_type: &CachedTaskType) {
+        let limit = self.options.log_new_tasks_limit;
+        if limit == 0 {
+            return;
+        }
+        let index = self.logged_new_tasks.fetch_add(1, Ordering::Relaxed);
+        if index < limit {
+            tracing::debug!(
+                new_task = index + 1,
+                task_type = %task_type.get_name(),
+                "new task"
+            );
         }
-        let id = self
-            .with_tx(tx, |tx| lookup(&self.database, tx, task_type))
-            .inspect_err(|err| println!("Looking up task id for {task_type:?} failed: {err:?}"))
-            .ok()??;
-        Some(id)
     }
 
-    unsafe fn reverse_lookup_task_cache(
+    /// Counts a failed lookup and marks the storage as degraded when there are too many, see
+    /// [`BackingStorageOptions::max_restore_errors`].
+    fn record_restore_error(&self) {
+        let errors = self.restore_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if self
+            .options
+            .max_restore_errors
+            .is_some_and(|max_errors| errors > max_errors)
+            && !self.degraded.swap(true, Ordering::Relaxed)
+        {
+            tracing::error!(
+                "{errors} lookups failed, the store is degraded and tasks will be recomputed"
+            );
+        }
+    }
+
+    /// Takes the write lock for a snapshot according to
+    /// [`BackingStorageOptions::concurrent_snapshots`].
+    fn lock_for_snapshot(&self) -> Result<ReentrantMutexGuard<'_, ()>> {
+        // The lock is reentrant, so a snapshot on a thread that holds it, e.g. from a maintenance
+        // task, would otherwise write concurrently to what the holder writes
+        if self.write_lock.is_owned_by_current_thread() {
+            return Err(SnapshotInProgress.into());
+        }
+        if let Some(guard) = self.write_lock.try_lock() {
+            return Ok(guard);
+        }
+        if self.options.concurrent_snapshots == ConcurrentSnapshotPolicy::Fail {
+            return Err(SnapshotInProgress.into());
+        }
+        let start = Instant::now();
+        let guard = self.write_lock.lock();
+        self.write_lock_waits.fetch_add(1, Ordering::Relaxed);
+        self.write_lock_wait_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(guard)
+    }
+
+    /// Commits the part of a snapshot that was written to `batch` and begins a new transaction
+    /// for the rest, see [`BackingStorageOptions::commit_chunk_size`].
+    fn commit_split_snapshot<'a>(&'a self, batch: T::WriteBatch<'a>) -> Result<T::WriteBatch<'a>> {
+        batch
+            .commit()
+            .map_err(|err| self.handle_write_error(err))
+            .with_context(|| anyhow!("Unable to commit a part of the snapshot"))?;
+        self.snapshot_infra_committed();
+        self.clear_cached_data();
+        self.write_batch()
+            .map_err(|err| self.handle_write_error(err))
+    }
+
+    /// Looks up task data via the data cache. Misses read the data with a new transaction, so a
+    /// write that is committed in the meantime is guaranteed to invalidate the cached data.
+    fn lookup_cached_data(
         &self,
-        tx: Option<&T::ReadTransaction<'_>>,
+        cache: &Mutex<DataCache>,
         task_id: TaskId,
-    ) -> Option<Arc<CachedTaskType>> {
-        fn lookup<D: KeyValueDatabase>(
-            database: &D,
-            tx: &D::ReadTransaction<'_>,
-            task_id: TaskId,
-        ) -> Result<Option<Arc<CachedTaskType>>> {
-            let Some(bytes) = database.get(
-                tx,
-                KeySpace::ReverseTaskCache,
-                IntKey::new(*task_id).as_ref(),
-            )?
-            else {
-                return Ok(None);
-            };
-            Ok(Some(pot::from_slice(bytes.borrow())?))
+        category: TaskDataCategory,
+    ) -> Result<Arc<Vec<CachedDataItem>>> {
+        let generation = {
+            let mut cache = cache.lock();
+            if let Some(data) = cache.entries.get(&(task_id, category)) {
+                self.data_cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(data.clone());
+            }
+            cache.generation
+        };
+        let tx = self.database.begin_read_transaction()?;
+        let data = lookup_task_data(
+            &self.database,
+            self.value_codec,
+            self.options.skip_invalid_items,
+            &tx,
+            task_id,
+            category,
+        )?;
+        drop(tx);
+        let data = Arc::new(data);
+        let mut cache = cache.lock();
+        if cache.generation == generation {
+            cache.entries.put((task_id, category), data.clone());
         }
-        let result = self
-            .with_tx(tx, |tx| lookup(&self.database, tx, task_id))
-            .inspect_err(|err| println!("Looking up task type for {task_id} failed: {err:?}"))
-            .ok()??;
-        Some(result)
+        Ok(data)
     }
 
-    unsafe fn lookup_data(
+    /// Looks up task data through the data cache when it's enabled.
+    fn lookup_shared_data(
         &self,
         tx: Option<&T::ReadTransaction<'_>>,
         task_id: TaskId,
         category: TaskDataCategory,
-    ) -> Vec<CachedDataItem> {
-        fn lookup<D: KeyValueDatabase>(
-            database: &D,
-            tx: &D::ReadTransaction<'_>,
-            task_id: TaskId,
-            category: TaskDataCategory,
-        ) -> Result<Vec<CachedDataItem>> {
-            let Some(bytes) = database.get(
-                tx,
-                match category {
-                    TaskDataCategory::Meta => KeySpace::TaskMeta,
-                    TaskDataCategory::Data => KeySpace::TaskData,
-                    TaskDataCategory::All => unreachable!(),
-                },
-                IntKey::new(*task_id).as_ref(),
-            )?
-            else {
-                return Ok(Vec::new());
-            };
-            let result: Vec<CachedDataItem> = pot::from_slice(bytes.borrow())?;
-            Ok(result)
+    ) -> Result<Arc<Vec<CachedDataItem>>> {
+        match &self.data_cache {
+            Some(cache) => self.lookup_cached_data(cache, task_id, category),
+            None => self
+                .with_tx(tx, |tx| {
+                    lookup_task_data(
+                        &self.database,
+                        self.value_codec,
+                        self.options.skip_invalid_items,
+                        tx,
+                        task_id,
+                        category,
+                    )
+                })
+                .map(Arc::new),
         }
-        self.with_tx(tx, |tx| lookup(&self.database, tx, task_id, category))
-            .inspect_err(|err| println!("Looking up data for {task_id} failed: {err:?}"))
-            .unwrap_or_default()
     }
-}
 
-type SerializedTasks = Vec<Vec<(TaskId, Vec<u8>)>>;
-
-fn process_task_data(
+    /// Like [`BackingStorage::lookup_data`], but returns the data shared instead of a copy. With
+    /// [`BackingStorageOptions::data_cache_capacity`] repeated lookups of a task return the same
+    /// allocation until the task is written, without deserializing it again.
+    pub fn lookup_data_arc(
+        &self,
+        task_id: TaskId,
+        category: TaskDataCategory,
+    ) -> Result<Arc<Vec<CachedDataItem>>> {
+        self.touch(task_id);
+        self.lookup_shared_data(None, task_id, category)
+    }
+
+    /// Drops the cached data of the tasks. Must be called after writes of the tasks were
+    /// committed, see [`BackingStorageOptions::data_cache_capacity`].
+    fn invalidate_cached_data(&self, task_ids: impl IntoIterator<Item = TaskId>) {
+        if let Some(cache) = &self.data_cache {
+            let mut cache = cache.lock();
+            cache.generation += 1;
+            for task_id in task_ids {
+                for category in [
+                    TaskDataCategory::Meta,
+                    TaskDataCategory::Data,
+                    TaskDataCategory::All,
+                ] {
+                    cache.entries.pop(&(task_id, category));
+                }
+            }
+        }
+    }
+
+    /// Drops all cached data, e.g. after a part of a snapshot was committed.
+    fn clear_cached_data(&self) {
+        if let Some(cache) = &self.data_cache {
+            let mut cache = cache.lock();
+            cache.generation += 1;
+            cache.entries.clear();
+        }
+    }
+
+    /// Returns the manifest for opening the store now. A missing or unreadable manifest is
+    /// replaced.
+    fn opened_manifest(&self) -> Manifest {
+        let previous = match self.read_manifest() {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                tracing::warn!("Replacing unreadable manifest: {err:?}");
+                None
+            }
+        };
+        Manifest::opened(previous, &self.options, self.value_codec)
+    }
+
+    /// Called once the infra values of a snapshot are committed, see
+    /// [`Self::write_snapshot_infra`].
+    fn snapshot_infra_committed(&self) {
+        self.pending_manifest.lock().take();
+        let (tasks, cache_entries) = take(&mut *self.uncommitted_restored_counts.lock());
+        self.unpersisted_restored_tasks
+            .fetch_sub(tasks, Ordering::Relaxed);
+        self.unpersisted_restored_cache_entries
+            .fetch_sub(cache_entries, Ordering::Relaxed);
+    }
+
+    fn read_manifest(&self) -> Result<Option<Manifest>> {
+        let tx = self.database.begin_read_transaction()?;
+        let Some(bytes) = self.database.get(
+            &tx,
+            KeySpace::Infra,
+            IntKey::new(META_KEY_MANIFEST).as_ref(),
+        )?
+        else {
+            return Ok(None);
+        };
+        let manifest = serde_json::from_slice(bytes.borrow())
+            .with_context(|| anyhow!("Unable to parse manifest"))?;
+        Ok(Some(manifest))
+    }
+
+    /// Metadata about the store, like when and by which version it was created. It's written
+    /// with the first snapshot after the store is opened.
+    pub fn manifest(&self) -> Result<Manifest> {
+        if let Some(manifest) = &*self.pending_manifest.lock() {
+            return Ok(manifest.clone());
+        }
+        self.read_manifest()?
+            .context("The database doesn't contain a manifest")
+    }
+
+    /// The codec used for task data, either read from the database or the configured default for
+    /// new databases.
+    pub fn value_codec(&self) -> ValueCodec {
+        self.value_codec
+    }
+
+    /// Statistics accumulated since the storage was opened.
+    pub fn stats(&self) -> BackingStorageStats {
+        BackingStorageStats {
+            skipped_optional_items: self.skipped_optional_items.load(Ordering::Relaxed),
+            failed_required_items: self.failed_required_items.load(Ordering::Relaxed),
+            size_histogram: self
+                .size_histogram
+                .each_ref()
+                .map(|n| n.load(Ordering::Relaxed)),
+            outlier_tasks: self.outlier_tasks.load(Ordering::Relaxed),
+            committed_snapshots: self.committed_snapshots.load(Ordering::Relaxed),
+            skipped_empty_snapshots: self.skipped_empty_snapshots.load(Ordering::Relaxed),
+            split_snapshots: self.split_snapshots.load(Ordering::Relaxed),
+            data_cache_hits: self.data_cache_hits.load(Ordering::Relaxed),
+            write_lock_waits: self.write_lock_waits.load(Ordering::Relaxed),
+            write_lock_wait_micros: self.write_lock_wait_micros.load(Ordering::Relaxed),
+            restore_errors: self.restore_errors.load(Ordering::Relaxed),
+            restored_tasks: self.restored_tasks.load(Ordering::Relaxed),
+            restored_cache_entries: self.restored_cache_entries.load(Ordering::Relaxed),
+            lifetime_restored_tasks: get_infra_u64(
+                &self.database,
+                META_KEY_LIFETIME_RESTORED_TASKS,
+            )
+            .unwrap_or(0)
+                + self.unpersisted_restored_tasks.load(Ordering::Relaxed),
+            lifetime_restored_cache_entries: get_infra_u64(
+                &self.database,
+                META_KEY_LIFETIME_RESTORED_CACHE_ENTRIES,
+            )
+            .unwrap_or(0)
+                + self
+                    .unpersisted_restored_cache_entries
+                    .load(Ordering::Relaxed),
+            degraded: self.degraded.load(Ordering::Relaxed),
+            task_index_scans: self.task_index_scans.load(Ordering::Relaxed),
+            task_index_cache_hits: self.task_index_cache_hits.load(Ordering::Relaxed),
+            logical_update_bytes: self.logical_update_bytes.load(Ordering::Relaxed),
+            written_task_bytes: self.written_task_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Checks whether the storage can be used, cheap enough to be called from a health endpoint.
+    /// It reads the next free task id in a read transaction and compares the used capacity of
+    /// the database with [`BackingStorageOptions::health_degraded_capacity`] and
+    /// [`BackingStorageOptions::health_unhealthy_capacity`]. The storage is also degraded when
+    /// too many lookups failed or when it was downgraded to read-only.
+    pub fn health_check(&self) -> Result<HealthStatus> {
+        let read = || -> Result<()> {
+            let tx = self.database.begin_read_transaction()?;
+            self.database.get(
+                &tx,
+                KeySpace::Infra,
+                IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref(),
+            )?;
+            Ok(())
+        };
+        if let Err(err) = read() {
+            return Ok(HealthStatus::Unhealthy(format!(
+                "The database doesn't respond: {err:#}"
+            )));
+        }
+        let used_capacity = match self.database.used_capacity() {
+            Ok(used_capacity) => used_capacity,
+            Err(err) => {
+                return Ok(HealthStatus::Unhealthy(format!(
+                    "Unable to read the used capacity: {err:#}"
+                )));
+            }
+        };
+        if let Some(used) =
+            used_capacity.filter(|used| *used >= self.options.health_unhealthy_capacity)
+        {
+            return Ok(HealthStatus::Unhealthy(format!(
+                "{:.0}% of the database capacity is used",
+                used * 100.0
+            )));
+        }
+        if self.degraded.load(Ordering::Relaxed) {
+            return Ok(HealthStatus::Degraded(format!(
+                "{} lookups failed",
+                self.restore_errors.load(Ordering::Relaxed)
+            )));
+        }
+        if self.is_read_only() {
+            return Ok(HealthStatus::Degraded(
+                "The store is read-only, snapshots are skipped".to_string(),
+            ));
+        }
+        if let Some(used) =
+            used_capacity.filter(|used| *used >= self.options.health_degraded_capacity)
+        {
+            return Ok(HealthStatus::Degraded(format!(
+                "{:.0}% of the database capacity is used",
+                used * 100.0
+            )));
+        }
+        Ok(HealthStatus::Healthy)
+    }
+
+    /// Adds the serialized size of the updated item to the logical update bytes, when
+    /// [`BackingStorageOptions::record_write_amplification`] is set.
+    fn record_logical_update(&self, update: &CachedDataUpdate) {
+        if !self.options.record_write_amplification {
+            return;
+        }
+        let Some(value) = &update.value else {
+            return;
+        };
+        let item = CachedDataItem::from_key_and_value(update.key.clone(), value.clone());
+        // Items that can't be serialized aren't written either
+        if let Ok(size) = self.value_codec.serialized_size(&item) {
+            self.logical_update_bytes
+                .fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the sizes of the serialized task data in the histogram and reports outliers.
+    fn record_task_sizes(&self, task_sizes: &[(TaskId, usize)]) {
+        for (_, size) in task_sizes {
+            self.size_histogram[size_bucket(*size)].fetch_add(1, Ordering::Relaxed);
+        }
+        let Some(factor) = self.options.outlier_size_factor else {
+            return;
+        };
+        let stats = self.stats();
+        if stats.size_histogram.iter().sum::<u64>() < MIN_SIZE_SAMPLES {
+            return;
+        }
+        let Some(p99) = stats.size_percentile(99) else {
+            return;
+        };
+        for &(task_id, size) in task_sizes {
+            if size as u64 > p99 * factor {
+                self.outlier_tasks.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    task = %task_id,
+                    size,
+                    p99,
+                    "Task data is much larger than usual, which can indicate a bug"
+                );
+            }
+        }
+    }
+
+    /// Writes the session id, the value codec, the pending manifest, the task cache and the
+    /// operations of a snapshot. Returns the number of database operations. Once they are
+    /// committed, [`Self::snapshot_infra_committed`] needs to be called.
+    fn write_snapshot_infra<'a>(
+        &self,
+        batch: &mut impl WriteBatch<'a>,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        progress: &SnapshotProgress<'_>,
+    ) -> Result<usize> {
+        {
+            let _span =
+                tracing::trace_span!("update session id", session_id = ?session_id).entered();
+            batch
+                .put(
+                    KeySpace::Infra,
+                    Cow::Borrowed(IntKey::new(META_KEY_SESSION_ID).as_ref()),
+                    Cow::Borrowed(&session_id.to_le_bytes()),
+                )
+                .with_context(|| anyhow!("Unable to write next session id"))?;
+            batch
+                .put(
+                    KeySpace::Infra,
+                    Cow::Borrowed(IntKey::new(META_KEY_VALUE_CODEC).as_ref()),
+                    Cow::Borrowed(&self.value_codec.id().to_le_bytes()),
+                )
+                .with_context(|| anyhow!("Unable to write value codec"))?;
+            batch
+                .put(
+                    KeySpace::Infra,
+                    Cow::Borrowed(IntKey::new(META_KEY_SCHEMA_VERSION).as_ref()),
+                    Cow::Borrowed(&SCHEMA_VERSION.to_le_bytes()),
+                )
+                .with_context(|| anyhow!("Unable to write schema version"))?;
+            add_infra_u64(batch, META_KEY_GENERATION, 1)
+                .with_context(|| anyhow!("Unable to update generation"))?;
+            let restored_tasks = self.unpersisted_restored_tasks.load(Ordering::Relaxed);
+            let restored_cache_entries = self
+                .unpersisted_restored_cache_entries
+                .load(Ordering::Relaxed);
+            add_infra_u64(batch, META_KEY_LIFETIME_RESTORED_TASKS, restored_tasks)
+                .with_context(|| anyhow!("Unable to update lifetime restored tasks"))?;
+            add_infra_u64(
+                batch,
+                META_KEY_LIFETIME_RESTORED_CACHE_ENTRIES,
+                restored_cache_entries,
+            )
+            .with_context(|| anyhow!("Unable to update lifetime restored cache entries"))?;
+            *self.uncommitted_restored_counts.lock() = (restored_tasks, restored_cache_entries);
+            if let Some(manifest) = &*self.pending_manifest.lock() {
+                batch
+                    .put(
+                        KeySpace::Infra,
+                        Cow::Borrowed(IntKey::new(META_KEY_MANIFEST).as_ref()),
+                        Cow::Owned(serde_json::to_vec(manifest)?),
+                    )
+                    .with_context(|| anyhow!("Unable to write manifest"))?;
+            }
+        }
+
+        let mut op_count = self.write_task_labels(
+            batch,
+            task_cache_updates
+                .iter()
+                .flat_map(|m| m.iter())
+                .map(|(task_type, task_id)| (&**task_type, *task_id)),
+        )?;
+        op_count += write_task_cache(
+            batch,
+            task_cache_updates.iter().map(|m| m.len()).sum(),
+            task_cache_updates
+                .into_iter()
+                .flatten()
+                .map(|(task_type, task_id)| {
+                    Ok((
+                        serialize_task_type(&task_type, self.options.verify_task_types)?,
+                        task_id,
+                    ))
+                }),
+            self.options.duplicate_task_ids,
+            progress,
+        )?;
+        {
+            let _span =
+                tracing::trace_span!("update operations", operations = operations.len()).entered();
+            let operations = self
+                .value_codec
+                .serialize(&operations)
+                .with_context(|| anyhow!("Unable to serialize operations"))?;
+            // The session id and the operations
+            op_count += 1 + write_chunked(
+                batch,
+                META_KEY_OPERATIONS,
+                META_KEY_OPERATIONS_CHUNKS,
+                operations,
+                self.options.operations_chunk_size,
+            )
+            .with_context(|| anyhow!("Unable to write operations"))?;
+        }
+        Ok(op_count)
+    }
+
+    /// Writes the labels of the task types when [`BackingStorageOptions::store_task_labels`] is
+    /// set. Returns the number of database operations.
+    fn write_task_labels<'a, 't>(
+        &self,
+        batch: &mut impl WriteBatch<'a>,
+        task_types: impl IntoIterator<Item = (&'t CachedTaskType, TaskId)>,
+    ) -> Result<usize> {
+        if !self.options.store_task_labels {
+            return Ok(0);
+        }
+        let mut op_count = 0;
+        for (task_type, task_id) in task_types {
+            batch
+                .put(
+                    KeySpace::DataBlob,
+                    Cow::Borrowed(&task_label_key(*task_id)),
+                    Cow::Borrowed(task_type.get_name().as_bytes()),
+                )
+                .with_context(|| anyhow!("Unable to write task label for {task_id}"))?;
+            op_count += 1;
+        }
+        Ok(op_count)
+    }
+
+    /// Returns the label of the task type of `task_id`, see
+    /// [`BackingStorageOptions::store_task_labels`]. Tasks that were stored while the option was
+    /// disabled have no label.
+    pub fn task_label(&self, task_id: TaskId) -> Result<Option<String>> {
+        let tx = self.database.begin_read_transaction()?;
+        self.database
+            .get(&tx, KeySpace::DataBlob, &task_label_key(*task_id))?
+            .map(|bytes| {
+                String::from_utf8(bytes.borrow().to_vec())
+                    .with_context(|| anyhow!("Invalid task label of {task_id}"))
+            })
+            .transpose()
+    }
+
+    /// Writes the serialized data of a task in a snapshot, deduplicating or delta encoding it when
+    /// enabled.
+    fn write_task_data<'a>(
+        &self,
+        batch: &mut impl WriteBatch<'a>,
+        blobs: &mut BlobUpdates,
+        key_space: KeySpace,
+        task_id: TaskId,
+        value: SerializedTaskData,
+    ) -> Result<()> {
+        let value = self.encode_task_data(batch, blobs, key_space, task_id, value)?;
+        self.put_task_data(batch, key_space, task_id, &value)
+    }
+
+    /// Frames, compresses, deduplicates and delta encodes the serialized data of a task as
+    /// enabled, i.e. returns the value that is stored for the task.
+    fn encode_task_data<'a>(
+        &self,
+        batch: &mut impl WriteBatch<'a>,
+        blobs: &mut BlobUpdates,
+        key_space: KeySpace,
+        task_id: TaskId,
+        mut value: SerializedTaskData,
+    ) -> Result<SerializedTaskData> {
+        let deduplication_threshold = self
+            .options
+            .data_deduplication_threshold
+            .filter(|_| key_space == KeySpace::TaskData);
+        let delta_baseline_interval = self
+            .options
+            .data_delta_baseline_interval
+            .filter(|_| key_space == KeySpace::TaskData);
+        if self.options.frame_values
+            || self.options.writer_version.is_some()
+            || self.options.pad_values_to.is_some()
+        {
+            if let SerializedTaskData::Buffered(bytes) = &mut value {
+                *bytes = frame(
+                    take(bytes),
+                    self.options.writer_version,
+                    self.options.pad_values_to,
+                );
+            }
+        }
+        if let (Some(min_bytes), SerializedTaskData::Buffered(bytes)) =
+            (self.options.compress_min_bytes, &mut value)
+        {
+            *bytes = compress(take(bytes), min_bytes);
+        }
+        let key = IntKey::new(*task_id);
+        let mut written_bytes = 0;
+        // The old value is checked regardless of the options, so a blob or a baseline that was
+        // written before the option was disabled is still released when the data is rewritten
+        if key_space == KeySpace::TaskData {
+            let old = batch
+                .get(key_space, key.as_ref())?
+                .map(|old| old.borrow().to_vec());
+            let old = old.as_deref();
+            if let Some(hash) = old.and_then(blob_reference) {
+                blobs.remove(hash);
+            }
+            let generation = old
+                .and_then(Delta::decode)
+                .map(|delta| delta.generation + 1);
+            if let Some(interval) = delta_baseline_interval {
+                written_bytes += encode_delta(batch, *task_id, generation, interval, &mut value)?;
+            } else if generation.is_some() {
+                batch.delete(
+                    KeySpace::DataBlob,
+                    Cow::Borrowed(&task_baseline_key(*task_id)),
+                )?;
+            }
+            if let Some(threshold) = deduplication_threshold {
+                if let SerializedTaskData::Buffered(bytes) = &mut value {
+                    if bytes.len() >= threshold && Delta::decode(bytes).is_none() {
+                        *bytes = blobs.add(take(bytes));
+                    }
+                }
+            }
+        }
+        written_bytes += match &value {
+            SerializedTaskData::Buffered(value) => value.len(),
+            SerializedTaskData::Streamed { len, .. } => *len,
+        };
+        if self.options.record_write_amplification {
+            self.written_task_bytes
+                .fetch_add(written_bytes as u64, Ordering::Relaxed);
+        }
+        Ok(value)
+    }
+
+    /// Stores the encoded data of a task, see [`Self::encode_task_data`]. The value is kept, so a
+    /// write that doesn't fit into the transaction can be retried in the next one.
+    fn put_task_data<'a>(
+        &self,
+        batch: &mut impl WriteBatch<'a>,
+        key_space: KeySpace,
+        task_id: TaskId,
+        value: &SerializedTaskData,
+    ) -> Result<()> {
+        let key = IntKey::new(*task_id);
+        match value {
+            SerializedTaskData::Buffered(value) => {
+                batch.put(key_space, Cow::Borrowed(key.as_ref()), Cow::Borrowed(value))
+            }
+            SerializedTaskData::Streamed { len, data } => batch.put_with(
+                key_space,
+                Cow::Borrowed(key.as_ref()),
+                *len,
+                &mut |buffer| self.value_codec.serialize_into(data, buffer),
+            ),
+        }
+        .with_context(|| anyhow!("Unable to write data items for {task_id}"))?;
+        Ok(())
+    }
+
+    /// Like [`BackingStorage::save_snapshot`], but takes the updates of task meta and data as
+    /// iterators that are sorted by task id. The updates are applied one task at a time, so only
+    /// the data of a single task is held in memory instead of the updates of all tasks. The
+    /// updates are processed on the calling thread and don't advance the progress callback.
+    pub fn save_snapshot_streaming(
+        &self,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        meta_updates: impl IntoIterator<Item = CachedDataUpdate>,
+        data_updates: impl IntoIterator<Item = CachedDataUpdate>,
+    ) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let _write_lock = self.lock_for_snapshot()?;
+        let _span =
+            tracing::trace_span!("save snapshot streaming", session_id = ?session_id).entered();
+        let progress = SnapshotProgress::new(
+            self.options.progress.as_deref(),
+            task_cache_updates.iter().map(|m| m.len()).sum(),
+        );
+        let tx = self.database.begin_read_transaction()?;
+        let mut batch = self
+            .write_batch()
+            .map_err(|err| self.handle_write_error(err))?;
+        let infra_op_count = self.write_snapshot_infra(
+            &mut batch,
+            session_id,
+            operations,
+            task_cache_updates,
+            &progress,
+        )?;
+        let mut blobs = BlobUpdates::default();
+        let failures = SerializationFailures::new(
+            self.options.serialization_failure_log_limit,
+            self.options.strict_serialization,
+        );
+        let result = self
+            .write_sorted_task_updates(
+                &mut batch,
+                &tx,
+                &mut blobs,
+                KeySpace::TaskMeta,
+                meta_updates
+                    .into_iter()
+                    .inspect(|update| self.record_logical_update(update)),
+                &failures,
+            )
+            .and_then(|meta_sizes| {
+                let data_sizes = self.write_sorted_task_updates(
+                    &mut batch,
+                    &tx,
+                    &mut blobs,
+                    KeySpace::TaskData,
+                    data_updates
+                        .into_iter()
+                        .inspect(|update| self.record_logical_update(update)),
+                    &failures,
+                )?;
+                Ok((meta_sizes, data_sizes))
+            });
+        failures.finish(&self.skipped_optional_items, &self.failed_required_items);
+        let (meta_sizes, data_sizes) = result?;
+        self.record_task_sizes(&data_sizes);
+        drop(tx);
+        blobs
+            .write(&mut batch)
+            .with_context(|| anyhow!("Unable to write data blobs"))?;
+        batch
+            .commit()
+            .map_err(|err| self.handle_write_error(err))
+            .with_context(|| anyhow!("Unable to commit operations"))?;
+        self.snapshot_infra_committed();
+        self.committed_snapshots.fetch_add(1, Ordering::Relaxed);
+        self.cached_task_index.lock().take();
+        self.invalidate_cached_data(
+            meta_sizes
+                .iter()
+                .chain(data_sizes.iter())
+                .map(|(task_id, _)| *task_id),
+        );
+        let written_tasks = meta_sizes.len() + data_sizes.len();
+        self.notify_commit(infra_op_count + written_tasks, written_tasks);
+        Ok(())
+    }
+
+    /// Calls the [`BackingStorageOptions::on_commit`] hook after a snapshot was committed.
+    fn notify_commit(&self, op_count: usize, written_tasks: usize) {
+        if let Some(on_commit) = &self.options.on_commit {
+            on_commit(&CommitInfo {
+                op_count,
+                written_tasks,
+                next_free_task_id: TaskId::from(
+                    get_infra_u32(&self.database, META_KEY_NEXT_FREE_TASK_ID).unwrap_or(1),
+                ),
+            });
+        }
+    }
+
+    /// Returns whether uncompleted operations are stored, which an empty snapshot must clear.
+    fn has_stored_operations(&self) -> Result<bool> {
+        let tx = self.database.begin_read_transaction()?;
+        let Some(operations) = read_chunked(
+            &self.database,
+            &tx,
+            META_KEY_OPERATIONS,
+            META_KEY_OPERATIONS_CHUNKS,
+        )?
+        else {
+            return Ok(false);
+        };
+        let no_operations = self
+            .value_codec
+            .serialize(&Vec::<Arc<AnyOperation>>::new())?;
+        Ok(operations != no_operations)
+    }
+
+    /// Returns the generation of the store, which is the number of committed snapshots. It's 0
+    /// for a new store. Snapshots that are skipped because nothing changed don't count.
+    pub fn current_generation(&self) -> Result<u64> {
+        let tx = self.database.begin_read_transaction()?;
+        let generation = self
+            .database
+            .get(
+                &tx,
+                KeySpace::Infra,
+                IntKey::new(META_KEY_GENERATION).as_ref(),
+            )?
+            .map(as_u64)
+            .transpose()
+            .with_context(|| anyhow!("Unable to read generation"))?;
+        Ok(generation.unwrap_or(0))
+    }
+
+    /// Returns the number of stored uncompleted operations. The operations are only skipped over,
+    /// not decoded into operations, e.g. to check that the log was drained.
+    pub fn operations_len(&self) -> Result<usize> {
+        let tx = self.database.begin_read_transaction()?;
+        let Some(operations) = read_chunked(
+            &self.database,
+            &tx,
+            META_KEY_OPERATIONS,
+            META_KEY_OPERATIONS_CHUNKS,
+        )?
+        else {
+            return Ok(0);
+        };
+        let operations: Vec<serde::de::IgnoredAny> = self
+            .value_codec
+            .deserialize(&operations)
+            .with_context(|| anyhow!("Unable to read stored operations"))?;
+        Ok(operations.len())
+    }
+
+    /// Removes the stored uncompleted operations and keeps all task data, e.g. for recovery
+    /// tooling when the operations can't be resumed.
+    pub fn clear_operations(&self) -> Result<()> {
+        let _lock = self.write_lock.lock();
+        let mut batch = self
+            .write_batch()
+            .map_err(|err| self.handle_write_error(err))?;
+        write_chunked(
+            &mut batch,
+            META_KEY_OPERATIONS,
+            META_KEY_OPERATIONS_CHUNKS,
+            self.value_codec
+                .serialize(&Vec::<Arc<AnyOperation>>::new())?,
+            self.options.operations_chunk_size,
+        )?;
+        batch
+            .commit()
+            .map_err(|err| self.handle_write_error(err))
+            .with_context(|| anyhow!("Unable to clear operations"))
+    }
+
+    /// Applies updates that are sorted by task id one task at a time and writes the new data of
+    /// each task. Returns the sizes of the new data.
+    fn write_sorted_task_updates<'a>(
+        &self,
+        batch: &mut impl WriteBatch<'a>,
+        tx: &T::ReadTransaction<'_>,
+        blobs: &mut BlobUpdates,
+        key_space: KeySpace,
+        updates: impl IntoIterator<Item = CachedDataUpdate>,
+        failures: &SerializationFailures,
+    ) -> Result<Vec<(TaskId, usize)>> {
+        let mut task_sizes = Vec::new();
+        let mut task_updates: FxHashMap<
+            CachedDataItemKey,
+            (Option<CachedDataItemValue>, Option<CachedDataItemValue>),
+        > = FxHashMap::default();
+        let mut map = FxHashMap::default();
+        let mut previous_task = None;
+        let mut updates = updates.into_iter().peekable();
+        while let Some(CachedDataUpdate {
+            task,
+            key,
+            value,
+            old_value,
+        }) = updates.next()
+        {
+            if let Some(previous) = previous_task.filter(|previous| *previous > task) {
+                bail!("Task updates must be sorted by task id, but task {task} follows {previous}");
+            }
+            previous_task = Some(task);
+            match task_updates.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    entry.get_mut().1 = value;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((old_value, value));
+                }
+            }
+            if updates.peek().is_some_and(|next| next.task == task) {
+                continue;
+            }
+
+            // All updates of the task are collected
+            task_updates.retain(|_, (old_value, value)| *old_value != *value);
+            if task_updates.is_empty() {
+                continue;
+            }
+            restore_task_data(
+                &self.database,
+                tx,
+                self.value_codec,
+                key_space,
+                task,
+                &mut map,
+            )?;
+            for (key, (_, value)) in task_updates.drain() {
+                if let Some(value) = value {
+                    map.insert(key, value);
+                } else {
+                    map.remove(&key);
+                }
+            }
+            let data = map
+                .drain()
+                .map(|(key, value)| CachedDataItem::from_key_and_value(key, value))
+                .collect::<Vec<_>>();
+            let value = serialize_task_data(
+                task,
+                data,
+                self.value_codec,
+                self.options.streaming_threshold,
+                failures,
+            )?;
+            task_sizes.push((task, value.len()));
+            self.write_task_data(batch, blobs, key_space, task, value)?;
+        }
+        Ok(task_sizes)
+    }
+
+    /// Renders [`stats`](Self::stats) in the Prometheus text exposition format.
+    pub fn stats_prometheus(&self) -> String {
+        self.stats().to_prometheus()
+    }
+
+    /// Calls `f` with the serialized task data of `task_id` without deserializing it. The slice
+    /// borrows from the read transaction, so it's only valid during the call. Returns `None` when
+    /// the task has no data.
+    pub fn lookup_raw<R>(&self, task_id: TaskId, f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>> {
+        self.touch(task_id);
+        let tx = self.database.begin_read_transaction()?;
+        with_task_data(&self.database, &tx, KeySpace::TaskData, task_id, |bytes| {
+            Ok(f(bytes))
+        })
+    }
+
+    /// Rebuilds the forward task cache from the reverse task cache and resets the next free task
+    /// id to follow the highest task id found in the database.
+    ///
+    /// Task data doesn't contain the task type, so tasks without a reverse task cache entry can't
+    /// be restored into the caches. They are left untouched and will be recomputed under a new
+    /// task id.
+    pub fn rebuild_caches(&self) -> Result<()> {
+        let _span = tracing::trace_span!("rebuild caches").entered();
+        // The caches are scanned within the write batch under the write lock, so a concurrent
+        // snapshot can't add entries or use task ids in between
+        let _write_lock = self.write_lock.lock();
+        let mut batch = self.write_batch()?;
+        let mut forward_keys = Vec::new();
+        batch.iterate(
+            KeySpace::ForwardTaskCache,
+            None,
+            &mut |key: &[u8], _: &[u8]| {
+                forward_keys.push(key.to_vec());
+                Ok(true)
+            },
+        )?;
+        let mut reverse_entries = Vec::new();
+        let mut max_task_id = 0;
+        batch.iterate(
+            KeySpace::ReverseTaskCache,
+            None,
+            &mut |key: &[u8], value: &[u8]| {
+                let task_id = decode_task_id(KeySpace::ReverseTaskCache, key, key)?;
+                max_task_id = max_task_id.max(task_id);
+                reverse_entries.push((task_id, value.to_vec()));
+                Ok(true)
+            },
+        )?;
+        for key_space in [KeySpace::TaskMeta, KeySpace::TaskData] {
+            batch.iterate(key_space, None, &mut |key: &[u8], _: &[u8]| {
+                max_task_id = max_task_id.max(decode_task_id(key_space, key, key)?);
+                Ok(true)
+            })?;
+        }
+
+        for key in forward_keys {
+            batch.delete(KeySpace::ForwardTaskCache, Cow::Owned(key))?;
+        }
+        for (task_id, task_type_bytes) in reverse_entries.iter() {
+            batch.put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(task_type_bytes),
+                Cow::Borrowed(&task_id.to_le_bytes()),
+            )?;
+        }
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref()),
+            Cow::Borrowed(&next_task_id_after(max_task_id)?.to_le_bytes()),
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit rebuilt caches"))?;
+        Ok(())
+    }
+
+    /// Scans the database for all task ids that have persisted data. The returned ids are sorted
+    /// ascending. The first call is served from the task index cache when one was loaded and no
+    /// task data was written since.
+    pub fn scan_task_index(&self) -> Result<Vec<TaskId>>
+    where
+        T: Sync,
+    {
+        if let Some(task_index) = self.cached_task_index.lock().take() {
+            self.task_index_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(task_index);
+        }
+        self.task_index_scans.fetch_add(1, Ordering::Relaxed);
+        let _span = tracing::trace_span!(
+            "scan task index",
+            parallelism = self.options.startup_parallelism
+        )
+        .entered();
+        let parallelism = self.options.startup_parallelism;
+        if parallelism <= 1 {
+            return self.scan_task_index_range(None);
+        }
+        let end = get_infra_u32(&self.database, META_KEY_NEXT_FREE_TASK_ID).unwrap_or(1);
+        let chunk_size = end.div_ceil(parallelism as u32).max(1);
+        let chunks = scope(|s| {
+            let handles = (0..end)
+                .step_by(chunk_size as usize)
+                .map(|start| {
+                    let range = start..end.min(start.saturating_add(chunk_size));
+                    s.spawn(move || self.scan_task_index_range(Some(range)))
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    /// Writes the task index cache for the next start, when
+    /// [`BackingStorageOptions::task_index_cache`] is set. It must be called on a clean shutdown
+    /// after the last write.
+    pub fn shutdown(&self) -> Result<()>
+    where
+        T: Sync,
+    {
+        let Some(path) = &self.options.task_index_cache else {
+            return Ok(());
+        };
+        let generation = self.generation();
+        let task_index = self.scan_task_index()?;
+        let temp_path = path.with_extension("tmp");
+        fs::write(
+            &temp_path,
+            task_index_cache::encode(generation, &task_index),
+        )
+        .with_context(|| anyhow!("Unable to write task index cache {}", temp_path.display()))?;
+        fs::rename(&temp_path, path)
+            .with_context(|| anyhow!("Unable to write task index cache {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Reads and removes the task index cache. Once the store is written the cache would be
+    /// stale, so it must not survive an unclean shutdown.
+    fn load_task_index_cache(&self, path: &Path) -> Result<Option<Vec<TaskId>>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        fs::remove_file(path)?;
+        Ok(Some(task_index_cache::decode(&bytes, self.generation())?))
+    }
+
+    fn generation(&self) -> Generation {
+        Generation {
+            session_id: get_infra_u32(&self.database, META_KEY_SESSION_ID).unwrap_or(0),
+            next_free_task_id: get_infra_u32(&self.database, META_KEY_NEXT_FREE_TASK_ID)
+                .unwrap_or(1),
+            write_count: get_infra_u64(&self.database, META_KEY_WRITE_COUNT).unwrap_or(0),
+        }
+    }
+
+    /// Begins a write batch that bumps [`META_KEY_WRITE_COUNT`]. All writes need to use it, so
+    /// the task index cache is invalidated by any change of the store.
+    fn write_batch(&self) -> Result<T::WriteBatch<'_>> {
+        let mut batch = self.database.write_batch()?;
+        add_infra_u64(&mut batch, META_KEY_WRITE_COUNT, 1)
+            .with_context(|| anyhow!("Unable to update write count"))?;
+        Ok(batch)
+    }
+
+    /// Copies all task data that can be read into the empty storage `dst`, as a last resort to
+    /// recover a corrupted storage. Entries that can't be read are dropped instead of failing the
+    /// whole operation. Deduplicated and delta encoded data is stored in full in `dst`.
+    pub fn salvage_into<D: KeyValueDatabase>(
+        &self,
+        dst: &KeyValueDatabaseBackingStorage<D>,
+    ) -> Result<SalvageReport> {
+        let _span = tracing::trace_span!("salvage").entered();
+        let mut report = SalvageReport::default();
+        let tx = self.database.begin_read_transaction()?;
+        let mut batch = dst.write_batch()?;
+        for key_space in [KeySpace::TaskMeta, KeySpace::TaskData] {
+            let mut task_ids = Vec::new();
+            self.database
+                .iterate(&tx, key_space, None, &mut |key: &[u8], _: &[u8]| {
+                    match decode_task_id(key_space, key, key) {
+                        Ok(task_id) => task_ids.push(TaskId::from(task_id)),
+                        Err(_) => report.dropped_entries += 1,
+                    }
+                    Ok(true)
+                })?;
+            for task_id in task_ids {
+                let value = with_task_data(&self.database, &tx, key_space, task_id, |bytes| {
+                    let data = deserialize_task_data(self.value_codec, task_id, bytes)?;
+                    dst.value_codec.serialize(&data)
+                });
+                match value {
+                    Ok(Some(value)) => {
+                        batch.put(
+                            key_space,
+                            Cow::Borrowed(IntKey::new(*task_id).as_ref()),
+                            Cow::Owned(value),
+                        )?;
+                        report.recovered_entries += 1;
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::warn!("Dropping {key_space:?} of {task_id}: {err:?}");
+                        report.dropped_entries += 1;
+                    }
+                }
+            }
+        }
+        let mut task_cache = Vec::new();
+        self.database.iterate(
+            &tx,
+            KeySpace::ReverseTaskCache,
+            None,
+            &mut |key: &[u8], value: &[u8]| {
+                if let Ok(task_id) = decode_task_id(KeySpace::ReverseTaskCache, key, key) {
+                    task_cache.push(Ok((value.to_vec(), TaskId::from(task_id))));
+                }
+                Ok(true)
+            },
+        )?;
+        drop(tx);
+        report.task_cache_entries = task_cache.len();
+        let progress = SnapshotProgress::new(None, task_cache.len());
+        write_task_cache(
+            &mut batch,
+            task_cache.len(),
+            task_cache,
+            dst.options.duplicate_task_ids,
+            &progress,
+        )?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_VALUE_CODEC).as_ref()),
+            Cow::Borrowed(&dst.value_codec.id().to_le_bytes()),
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit salvaged data"))?;
+        dst.cached_task_index.lock().take();
+        dst.clear_cached_data();
+        Ok(report)
+    }
+
+    /// Copies the meta data, the data and the task cache entries of `task_id` into `dst` under the
+    /// same task id, e.g. to extract a problematic task into a store for reproduction. Stored data
+    /// of the task in `dst` is replaced. Tasks without data are copied with their task cache
+    /// entries only. Fails when the task is stored neither with data nor in the task cache.
+    pub fn copy_task_to<D: KeyValueDatabase>(
+        &self,
+        task_id: TaskId,
+        dst: &KeyValueDatabaseBackingStorage<D>,
+    ) -> Result<()> {
+        let _span = tracing::trace_span!("copy task", task_id = *task_id).entered();
+        let tx = self.database.begin_read_transaction()?;
+        let mut values = Vec::new();
+        for key_space in [KeySpace::TaskMeta, KeySpace::TaskData] {
+            let value = with_task_data(&self.database, &tx, key_space, task_id, |bytes| {
+                let data = deserialize_task_data(self.value_codec, task_id, bytes)?;
+                dst.value_codec.serialize(&data)
+            })?;
+            values.push((key_space, value));
+        }
+        let task_type = self
+            .database
+            .get(
+                &tx,
+                KeySpace::ReverseTaskCache,
+                IntKey::new(*task_id).as_ref(),
+            )?
+            .map(|bytes| bytes.borrow().to_vec());
+        drop(tx);
+        if task_type.is_none() && values.iter().all(|(_, value)| value.is_none()) {
+            bail!("{task_id} is not stored");
+        }
+
+        let mut batch = dst.write_batch()?;
+        let key = IntKey::new(*task_id);
+        for (key_space, value) in values {
+            match value {
+                Some(value) => {
+                    batch.put(key_space, Cow::Borrowed(key.as_ref()), Cow::Owned(value))?
+                }
+                None => batch.delete(key_space, Cow::Borrowed(key.as_ref()))?,
+            }
+        }
+        if let Some(task_type) = task_type {
+            write_task_cache(
+                &mut batch,
+                1,
+                [Ok((task_type, task_id))],
+                dst.options.duplicate_task_ids,
+                &SnapshotProgress::new(None, 1),
+            )?;
+        }
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_VALUE_CODEC).as_ref()),
+            Cow::Borrowed(&dst.value_codec.id().to_le_bytes()),
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit copy of {task_id}"))?;
+        dst.cached_task_index.lock().take();
+        dst.invalidate_cached_data([task_id]);
+        Ok(())
+    }
+
+    /// Returns the keys of all infra entries, like the session id, the operations and the
+    /// manifest, together with the byte length of their values, sorted by key.
+    pub fn meta_entries(&self) -> Result<Vec<(u32, usize)>> {
+        let tx = self.database.begin_read_transaction()?;
+        let mut entries = Vec::new();
+        self.database.iterate(
+            &tx,
+            KeySpace::Infra,
+            None,
+            &mut |key: &[u8], value: &[u8]| {
+                entries.push((as_u32(key)?, value.len()));
+                Ok(true)
+            },
+        )?;
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    /// Returns the ids of tasks that are in the task cache but have no task data, sorted
+    /// ascending. This is expected for tasks that never produced data, but can also indicate an
+    /// incomplete restore.
+    pub fn orphan_cache_entries(&self) -> Result<Vec<TaskId>> {
+        let tx = self.database.begin_read_transaction()?;
+        let mut with_data = FxHashSet::default();
+        self.database.iterate(
+            &tx,
+            KeySpace::TaskData,
+            None,
+            &mut |key: &[u8], _: &[u8]| {
+                with_data.insert(decode_task_id(KeySpace::TaskData, key, key)?);
+                Ok(true)
+            },
+        )?;
+        let mut orphans = Vec::new();
+        self.database.iterate(
+            &tx,
+            KeySpace::ReverseTaskCache,
+            None,
+            &mut |key: &[u8], _: &[u8]| {
+                let task_id = decode_task_id(KeySpace::ReverseTaskCache, key, key)?;
+                if !with_data.contains(&task_id) {
+                    orphans.push(TaskId::from(task_id));
+                }
+                Ok(true)
+            },
+        )?;
+        orphans.sort_unstable();
+        Ok(orphans)
+    }
+
+    /// Pins a task, so [`delete_task_range`](Self::delete_task_range) never deletes it.
+    pub fn pin_task(&self, task_id: TaskId) -> Result<()> {
+        self.update_pinned_tasks(|pinned| {
+            if let Err(index) = pinned.binary_search(&*task_id) {
+                pinned.insert(index, *task_id);
+            }
+        })
+    }
+
+    pub fn unpin_task(&self, task_id: TaskId) -> Result<()> {
+        self.update_pinned_tasks(|pinned| {
+            if let Ok(index) = pinned.binary_search(&*task_id) {
+                pinned.remove(index);
+            }
+        })
+    }
+
+    /// Returns the pinned task ids, sorted ascending.
+    pub fn pinned_tasks(&self) -> Result<Vec<TaskId>> {
+        let tx = self.database.begin_read_transaction()?;
+        let pinned = match self.database.get(
+            &tx,
+            KeySpace::Infra,
+            IntKey::new(META_KEY_PINNED_TASKS).as_ref(),
+        )? {
+            Some(bytes) => decode_task_id_list(bytes.borrow())?,
+            None => Vec::new(),
+        };
+        Ok(pinned.into_iter().map(TaskId::from).collect())
+    }
+
+    fn update_pinned_tasks(&self, f: impl FnOnce(&mut Vec<u32>)) -> Result<()> {
+        let key = IntKey::new(META_KEY_PINNED_TASKS);
+        let mut batch = self.write_batch()?;
+        let mut pinned = match batch.get(KeySpace::Infra, key.as_ref())? {
+            Some(bytes) => decode_task_id_list(bytes.borrow())?,
+            None => Vec::new(),
+        };
+        f(&mut pinned);
+        let value = pinned
+            .iter()
+            .flat_map(|task_id| task_id.to_le_bytes())
+            .collect::<Vec<_>>();
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(key.as_ref()),
+            Cow::Owned(value),
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit pinned tasks"))?;
+        Ok(())
+    }
+
+    /// Deletes all persisted data of the tasks with ids in `start..end`, including their task
+    /// cache entries, in a single write batch. Pinned tasks are kept. Returns the number of
+    /// deleted tasks.
+    ///
+    /// This requires a database with ordered keys.
+    pub fn delete_task_range(&self, start: TaskId, end: TaskId) -> Result<usize> {
+        let _span = tracing::trace_span!("delete task range", start = *start, end = *end).entered();
+        let range = *start..*end;
+        // A snapshot must not add tasks to the range between the scan and the deletion
+        let _write_lock = self.write_lock.lock();
+        let pinned = self.pinned_tasks()?;
+        let mut task_ids = self.scan_task_index_range(Some(range.clone()))?;
+        let tx = self.database.begin_read_transaction()?;
+        self.database.iterate(
+            &tx,
+            KeySpace::ReverseTaskCache,
+            Some(IntKey::new(range.start).as_ref()),
+            &mut |key: &[u8], _: &[u8]| {
+                let task_id = decode_task_id(KeySpace::ReverseTaskCache, key, key)?;
+                if task_id >= range.end {
+                    return Ok(false);
+                }
+                task_ids.push(TaskId::from(task_id));
+                Ok(true)
+            },
+        )?;
+        drop(tx);
+        task_ids.retain(|task_id| pinned.binary_search(task_id).is_err());
+        self.delete_tasks(task_ids)
+            .with_context(|| anyhow!("Unable to delete tasks {range:?}"))
+    }
+
+    /// Adds `tag` to a task, so groups of tasks can be found with
+    /// [`tasks_with_tag`](Self::tasks_with_tag) and deleted with
+    /// [`delete_tasks_with_tag`](Self::delete_tasks_with_tag). Tags are stored apart from the
+    /// task data and aren't touched by snapshots.
+    pub fn tag_task(&self, task_id: TaskId, tag: &str) -> Result<()> {
+        self.update_task_tag(tag, |task_ids| {
+            if let Err(index) = task_ids.binary_search(&*task_id) {
+                task_ids.insert(index, *task_id);
+            }
+        })
+    }
+
+    pub fn untag_task(&self, task_id: TaskId, tag: &str) -> Result<()> {
+        self.update_task_tag(tag, |task_ids| {
+            if let Ok(index) = task_ids.binary_search(&*task_id) {
+                task_ids.remove(index);
+            }
+        })
+    }
+
+    /// Returns the ids of the tasks with `tag`, sorted ascending. Tasks that were deleted by other
+    /// means than [`delete_tasks_with_tag`](Self::delete_tasks_with_tag) keep their tags.
+    pub fn tasks_with_tag(&self, tag: &str) -> Result<Vec<TaskId>> {
+        let tx = self.database.begin_read_transaction()?;
+        let task_ids = match self.database.get(&tx, KeySpace::TaskTags, tag.as_bytes())? {
+            Some(bytes) => decode_task_id_list(bytes.borrow())?,
+            None => Vec::new(),
+        };
+        Ok(task_ids.into_iter().map(TaskId::from).collect())
+    }
+
+    fn update_task_tag(&self, tag: &str, f: impl FnOnce(&mut Vec<u32>)) -> Result<()> {
+        let mut batch = self.write_batch()?;
+        let mut task_ids = match batch.get(KeySpace::TaskTags, tag.as_bytes())? {
+            Some(bytes) => decode_task_id_list(bytes.borrow())?,
+            None => Vec::new(),
+        };
+        f(&mut task_ids);
+        if task_ids.is_empty() {
+            batch.delete(KeySpace::TaskTags, Cow::Borrowed(tag.as_bytes()))?;
+        } else {
+            let value = task_ids
+                .iter()
+                .flat_map(|task_id| task_id.to_le_bytes())
+                .collect::<Vec<_>>();
+            batch.put(
+                KeySpace::TaskTags,
+                Cow::Borrowed(tag.as_bytes()),
+                Cow::Owned(value),
+            )?;
+        }
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit tag {tag}"))?;
+        Ok(())
+    }
+
+    /// Deletes all persisted data of the tasks with `tag` like
+    /// [`delete_task_range`](Self::delete_task_range) and removes the tag. Pinned tasks are kept
+    /// and keep the tag. Returns the number of deleted tasks.
+    pub fn delete_tasks_with_tag(&self, tag: &str) -> Result<usize> {
+        let _span = tracing::trace_span!("delete tasks with tag", tag).entered();
+        let _write_lock = self.write_lock.lock();
+        let pinned = self.pinned_tasks()?;
+        let mut task_ids = self.tasks_with_tag(tag)?;
+        task_ids.retain(|task_id| pinned.binary_search(task_id).is_err());
+        let deleted = self
+            .delete_tasks(task_ids.clone())
+            .with_context(|| anyhow!("Unable to delete tasks with tag {tag}"))?;
+        self.update_task_tag(tag, |tagged| {
+            tagged.retain(|task_id| task_ids.binary_search(&TaskId::from(*task_id)).is_err())
+        })?;
+        Ok(deleted)
+    }
+
+    /// Deletes all persisted data of the tasks, including their task cache entries, in a single
+    /// write batch. Returns the number of deleted tasks.
+    fn delete_tasks(&self, mut task_ids: Vec<TaskId>) -> Result<usize> {
+        task_ids.sort_unstable();
+        task_ids.dedup();
+        // Everything is read within the write batch under the write lock like in
+        // `write_task_data`, so a concurrent snapshot can't change the cache entries or the data
+        // before the deletion is committed. Otherwise the blob references could be released for
+        // data that was already rewritten.
+        let _write_lock = self.write_lock.lock();
+        let mut batch = self.write_batch()?;
+        let mut task_types = Vec::new();
+        let mut blobs = BlobUpdates::default();
+        let mut baselines = Vec::new();
+        for task_id in task_ids.iter() {
+            let key = IntKey::new(**task_id);
+            if let Some(task_type) = batch.get(KeySpace::ReverseTaskCache, key.as_ref())? {
+                let task_type: &[u8] = task_type.borrow();
+                task_types.push(task_type.to_vec());
+            }
+            if let Some(value) = batch.get(KeySpace::TaskData, key.as_ref())? {
+                let value: &[u8] = value.borrow();
+                if let Some(hash) = blob_reference(value) {
+                    blobs.remove(hash);
+                }
+                if Delta::decode(value).is_some() {
+                    baselines.push(task_baseline_key(**task_id));
+                }
+            }
+        }
+        blobs.write(&mut batch)?;
+        for baseline_key in baselines.iter() {
+            batch.delete(KeySpace::DataBlob, Cow::Borrowed(baseline_key))?;
+        }
+        for task_id in task_ids.iter() {
+            let key = IntKey::new(**task_id);
+            batch.delete(
+                KeySpace::DataBlob,
+                Cow::Borrowed(&task_label_key(**task_id)),
+            )?;
+            for key_space in [
+                KeySpace::TaskMeta,
+                KeySpace::TaskData,
+                KeySpace::ReverseTaskCache,
+            ] {
+                batch.delete(key_space, Cow::Borrowed(key.as_ref()))?;
+            }
+        }
+        for task_type in task_types {
+            batch.delete(KeySpace::ForwardTaskCache, Cow::Owned(task_type))?;
+        }
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit deletion of tasks"))?;
+        self.cached_task_index.lock().take();
+        self.invalidate_cached_data(task_ids.iter().copied());
+        for task_id in task_ids.iter() {
+            self.access_stamps.remove(task_id);
+        }
+        Ok(task_ids.len())
+    }
+
+    /// Returns the number of bytes of all stored task meta and data entries. Shared data blobs
+    /// are not included.
+    pub fn stored_task_bytes(&self) -> Result<u64> {
+        Ok(self.stored_task_sizes()?.values().sum())
+    }
+
+    fn stored_task_sizes(&self) -> Result<FxHashMap<TaskId, u64>> {
+        let tx = self.database.begin_read_transaction()?;
+        let mut sizes = FxHashMap::default();
+        for key_space in [KeySpace::TaskMeta, KeySpace::TaskData] {
+            self.database
+                .iterate(&tx, key_space, None, &mut |key: &[u8], value: &[u8]| {
+                    let task_id = decode_task_id(key_space, key, key)?;
+                    *sizes.entry(TaskId::from(task_id)).or_default() +=
+                        (key.len() + value.len()) as u64;
+                    Ok(true)
+                })?;
+        }
+        Ok(sizes)
+    }
+
+    /// Records an access of the task for [`enforce_budget`](Self::enforce_budget).
+    fn touch(&self, task_id: TaskId) {
+        if self.options.max_store_bytes.is_some() {
+            let stamp = self.access_clock.fetch_add(1, Ordering::Relaxed) + 1;
+            self.access_stamps.insert(task_id, stamp);
+        }
+    }
+
+    /// Evicts the least recently accessed tasks until the stored task bytes are within
+    /// `max_store_bytes`. Tasks that were not accessed since the storage was opened are evicted
+    /// first. Pinned tasks are never evicted. Returns the number of evicted tasks.
+    pub fn enforce_budget(&self) -> Result<usize> {
+        let Some(budget) = self.options.max_store_bytes else {
+            return Ok(0);
+        };
+        let _span = tracing::trace_span!("enforce budget", budget).entered();
+        let sizes = self.stored_task_sizes()?;
+        let mut total: u64 = sizes.values().sum();
+        if total <= budget {
+            return Ok(0);
+        }
+        let pinned = self.pinned_tasks()?;
+        let mut candidates = sizes
+            .into_iter()
+            .filter(|(task_id, _)| pinned.binary_search(task_id).is_err())
+            .map(|(task_id, size)| {
+                let stamp = self.access_stamps.get(&task_id).map_or(0, |stamp| *stamp);
+                (stamp, task_id, size)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        let mut evicted = Vec::new();
+        for (_, task_id, size) in candidates {
+            if total <= budget {
+                break;
+            }
+            evicted.push(task_id);
+            total -= size;
+        }
+        self.delete_tasks(evicted)
+    }
+
+    /// Sets the next free task id, e.g. to reserve a range of task ids. Moving it backward would
+    /// hand out ids that might already be in use, so that is an error unless `force` is set.
+    pub fn set_next_free_task_id(&self, id: TaskId, force: bool) -> Result<()> {
+        let key = IntKey::new(META_KEY_NEXT_FREE_TASK_ID);
+        let mut batch = self.write_batch()?;
+        let current = batch
+            .get(KeySpace::Infra, key.as_ref())?
+            .map(as_u32)
+            .transpose()?;
+        if let Some(current) = current {
+            if *id < current && !force {
+                bail!(
+                    "Next free task id can't move backward from {current} to {}",
+                    *id
+                );
+            }
+        }
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(key.as_ref()),
+            Cow::Borrowed(&id.to_le_bytes()),
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit next free task id"))?;
+        Ok(())
+    }
+
+    /// Persists only the task cache and the next free task id, without touching task data. This
+    /// allows to checkpoint task id allocations without rewriting task data.
+    pub fn save_task_cache_only(
+        &self,
+        updates: ChunkedVec<(Arc<CachedTaskType>, TaskId)>,
+    ) -> Result<()> {
+        let items = updates.len();
+        let _span = tracing::trace_span!("save task cache", items).entered();
+        let mut batch = self.write_batch()?;
+        self.write_task_labels(
+            &mut batch,
+            updates
+                .iter()
+                .map(|(task_type, task_id)| (&**task_type, *task_id)),
+        )?;
+        write_task_cache(
+            &mut batch,
+            items,
+            updates.into_iter().map(|(task_type, task_id)| {
+                Ok((
+                    serialize_task_type(&task_type, self.options.verify_task_types)?,
+                    task_id,
+                ))
+            }),
+            self.options.duplicate_task_ids,
+            &SnapshotProgress::new(None, items),
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit task cache"))?;
+        Ok(())
+    }
+
+    /// Writes the task cache and the next free task id to `w`, without task data. This allows
+    /// to align task ids of stores on different machines with
+    /// [`import_task_cache`](Self::import_task_cache).
+    pub fn export_task_cache(&self, mut w: impl io::Write) -> Result<()> {
+        let _span = tracing::trace_span!("export task cache").entered();
+        let tx = self.database.begin_read_transaction()?;
+        let next_free_task_id = self
+            .database
+            .get(
+                &tx,
+                KeySpace::Infra,
+                IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref(),
+            )?
+            .map(as_u32)
+            .transpose()?
+            .unwrap_or(1);
+        task_cache_export::write_header(&mut w, next_free_task_id)?;
+        self.database.iterate(
+            &tx,
+            KeySpace::ReverseTaskCache,
+            None,
+            &mut |key: &[u8], value: &[u8]| {
+                let task_id = decode_task_id(KeySpace::ReverseTaskCache, key, key)?;
+                task_cache_export::write_entry(&mut w, task_id, value)?;
+                Ok(true)
+            },
+        )?;
+        task_cache_export::write_end(&mut w)
+            .with_context(|| anyhow!("Unable to write task cache export"))
+    }
+
+    /// Imports a task cache that was written by [`export_task_cache`](Self::export_task_cache)
+    /// and returns the number of imported entries. Entries that are already stored are skipped.
+    /// Conflicting entries are handled according to
+    /// [`BackingStorageOptions::task_cache_import_conflicts`]. The next free task id is moved
+    /// forward to the one of the export, so imported ids are never allocated again.
+    pub fn import_task_cache(&self, mut r: impl io::Read) -> Result<usize> {
+        let (next_free_task_id, entries) = task_cache_export::read(&mut r)?;
+        let _span = tracing::trace_span!("import task cache", items = entries.len()).entered();
+        let mut batch = self.write_batch()?;
+        let mut imported = Vec::new();
+        for (task_id, task_type_bytes) in entries {
+            let stored_task_id = batch
+                .get(KeySpace::ForwardTaskCache, &task_type_bytes)?
+                .map(as_u32)
+                .transpose()?;
+            let stored_task_type = batch
+                .get(KeySpace::ReverseTaskCache, IntKey::new(task_id).as_ref())?
+                .map(|bytes| bytes.borrow().to_vec());
+            let conflict = match (stored_task_id, stored_task_type) {
+                (None, None) => None,
+                (Some(stored_task_id), Some(stored_task_type))
+                    if stored_task_id == task_id && stored_task_type == task_type_bytes =>
+                {
+                    continue;
+                }
+                (Some(stored_task_id), _) if stored_task_id != task_id => Some(format!(
+                    "Task type {} is imported as task {task_id}, but is stored as task \
+                     {stored_task_id}",
+                    describe_task_type(&task_type_bytes)
+                )),
+                (_, Some(stored_task_type)) if stored_task_type != task_type_bytes => {
+                    Some(format!(
+                        "Task {task_id} is imported with task type {}, but is stored with task \
+                         type {}",
+                        describe_task_type(&task_type_bytes),
+                        describe_task_type(&stored_task_type)
+                    ))
+                }
+                // Only one direction is stored, the import repairs it
+                _ => None,
+            };
+            if let Some(message) = conflict {
+                match self.options.task_cache_import_conflicts {
+                    TaskCacheImportConflictPolicy::Error => bail!(message),
+                    TaskCacheImportConflictPolicy::KeepExisting => {
+                        tracing::warn!("{message}, keeping the stored task");
+                        continue;
+                    }
+                }
+            }
+            imported.push((task_type_bytes, TaskId::from(task_id)));
+        }
+        let items = imported.len();
+        write_task_cache(
+            &mut batch,
+            items,
+            imported.into_iter().map(Ok),
+            self.options.duplicate_task_ids,
+            &SnapshotProgress::new(None, items),
+        )?;
+        let key = IntKey::new(META_KEY_NEXT_FREE_TASK_ID);
+        let current = batch
+            .get(KeySpace::Infra, key.as_ref())?
+            .map(as_u32)
+            .transpose()?
+            .unwrap_or(1);
+        if next_free_task_id > current {
+            batch.put(
+                KeySpace::Infra,
+                Cow::Borrowed(key.as_ref()),
+                Cow::Borrowed(&next_free_task_id.to_le_bytes()),
+            )?;
+        }
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit imported task cache"))?;
+        Ok(items)
+    }
+
+    #[cfg(test)]
+    fn save_serialized_task_cache(
+        &self,
+        items: usize,
+        entries: impl IntoIterator<Item = Result<(Vec<u8>, TaskId)>>,
+    ) -> Result<()> {
+        let _span = tracing::trace_span!("save task cache", items).entered();
+        let progress = SnapshotProgress::new(None, items);
+        let mut batch = self.write_batch()?;
+        write_task_cache(
+            &mut batch,
+            items,
+            entries,
+            self.options.duplicate_task_ids,
+            &progress,
+        )?;
+        batch
+            .commit()
+            .with_context(|| anyhow!("Unable to commit task cache"))?;
+        Ok(())
+    }
+
+    fn scan_task_index_range(&self, range: Option<Range<u32>>) -> Result<Vec<TaskId>> {
+        let tx = self.database.begin_read_transaction()?;
+        scan_task_ids(&self.database, &tx, range)
+    }
+
+    /// Returns a consistent view of the storage at this point in time, which isn't affected by
+    /// later writes. This allows to scan the whole storage while it's written concurrently.
+    ///
+    /// The snapshot holds a read transaction until it's dropped. With LMDB this occupies a reader
+    /// slot and prevents reusing pages that are freed in the meantime, so the database grows
+    /// while a snapshot is held.
+    pub fn snapshot(&self) -> Result<StoreSnapshot<'_, T>> {
+        Ok(StoreSnapshot {
+            storage: self,
+            tx: Arc::new(SharedReadTransaction(Mutex::new(
+                self.database.begin_read_transaction()?,
+            ))),
+        })
+    }
+
+    /// Returns the tasks with persisted data and the keys of their data items, sorted by task id
+    /// and key, from a consistent snapshot. Values are left out, so the digest describes the shape
+    /// of the store independent of the value codec, e.g. for golden tests.
+    pub fn state_digest(&self) -> Result<Vec<(TaskId, Vec<CachedDataItemKey>)>> {
+        self.snapshot()?
+            .iter_tasks()?
+            .map(|task| {
+                let (task_id, data) = task?;
+                let mut keys = data.iter().map(|item| item.key()).collect::<Vec<_>>();
+                // Keys aren't ordered, but their debug representation is unique and stable
+                keys.sort_by_cached_key(|key| format!("{key:?}"));
+                Ok((task_id, keys))
+            })
+            .collect()
+    }
+
+    fn with_tx<R>(
+        &self,
+        tx: Option<&T::ReadTransaction<'_>>,
+        f: impl FnOnce(&T::ReadTransaction<'_>) -> Result<R>,
+    ) -> Result<R> {
+        if let Some(tx) = tx {
+            f(tx)
+        } else {
+            let tx = self.database.begin_read_transaction()?;
+            let r = f(&tx)?;
+            drop(tx);
+            Ok(r)
+        }
+    }
+}
+
+/// Deserializes stored task data. Data that the value codec can't read is retried as plain `pot`,
+/// which also reports the path of the item that failed.
+fn deserialize_task_data(
+    value_codec: ValueCodec,
+    task_id: TaskId,
+    bytes: &[u8],
+) -> Result<Vec<CachedDataItem>> {
+    match value_codec.deserialize(bytes) {
+        Ok(data) => Ok(data),
+        Err(_) => serde_path_to_error::deserialize(
+            &mut pot::de::SymbolList::new().deserializer_for_slice(bytes)?,
+        )
+        .with_context(|| anyhow!("Unable to deserialize old value of {task_id}: {bytes:?}")),
+    }
+}
+
+/// Replaces buffered task data with a delta against the baseline of the task. `generation` is the
+/// generation of the new delta, or `None` when the old data isn't delta encoded. A new baseline
+/// is written when there is none or after `interval` deltas. Returns the size of the written
+/// baseline.
+fn encode_delta<'a>(
+    batch: &mut impl WriteBatch<'a>,
+    task_id: u32,
+    generation: Option<u32>,
+    interval: u32,
+    value: &mut SerializedTaskData,
+) -> Result<usize> {
+    let baseline_key = task_baseline_key(task_id);
+    let SerializedTaskData::Buffered(bytes) = value else {
+        // Streamed data is written in full, so the baseline is no longer needed
+        if generation.is_some() {
+            batch.delete(KeySpace::DataBlob, Cow::Borrowed(&baseline_key))?;
+        }
+        return Ok(0);
+    };
+    if let Some(generation) = generation.filter(|&generation| generation <= interval) {
+        if let Some(baseline) = batch.get(KeySpace::DataBlob, &baseline_key)? {
+            *bytes = Delta::encode(generation, baseline.borrow(), bytes);
+            return Ok(0);
+        }
+    }
+    let baseline = take(bytes);
+    *bytes = Delta::encode(0, &baseline, &baseline);
+    let len = baseline.len();
+    batch.put(
+        KeySpace::DataBlob,
+        Cow::Borrowed(&baseline_key),
+        Cow::Owned(baseline),
+    )?;
+    Ok(len)
+}
+
+impl<T: KeyValueDatabase + 'static> KeyValueDatabaseBackingStorage<SwappableKeyValueDatabase<T>> {
+    /// Replaces the store with the store at `new_store_path`, e.g. one that was rebuilt offline.
+    /// The files of the new store are moved into the place of the current ones and the database
+    /// is reopened, so the new store is used after a restart as well. Snapshots and maintenance
+    /// wait until the swap is done. Transactions and [`StoreSnapshot`]s that began before keep
+    /// reading the previous store until they are dropped. Fails without replacing anything when
+    /// the new store uses a different value codec, or when another process has a reader
+    /// registered on the store. Other processes must not have the store open at all.
+    pub fn swap_in(&self, new_store_path: &Path) -> Result<()> {
+        let replace = self
+            .database
+            .replace_fn()
+            .context("The database doesn't support swapping in a store")?;
+        let _write_lock = self.write_lock.lock();
+        let database = replace(new_store_path, &|database: &T| {
+            let value_codec = get_infra_u32(database, META_KEY_VALUE_CODEC)
+                .map(ValueCodec::from_id)
+                .transpose()?
+                .unwrap_or(self.value_codec);
+            if value_codec != self.value_codec {
+                bail!(
+                    "The store at {} uses the value codec {value_codec:?}, but the current store \
+                     uses {:?}",
+                    new_store_path.display(),
+                    self.value_codec
+                );
+            }
+            Ok(())
+        })
+        .with_context(|| {
+            format!(
+                "Unable to swap in the store at {}",
+                new_store_path.display()
+            )
+        })?;
+        self.database.swap(database);
+        *self.cached_task_index.lock() = None;
+        self.access_stamps.clear();
+        self.clear_cached_data();
+        Ok(())
+    }
+}
+
+/// A read transaction shared by the clones of a [`StoreSnapshot`]. A transaction must not be used
+/// by multiple threads at the same time, so reads take turns.
+struct SharedReadTransaction<'a, T: KeyValueDatabase + 'a>(Mutex<T::ReadTransaction<'a>>);
+
+// Safety: It's safe to send RoTransaction between threads, but the types don't allow that. The
+// mutex ensures that only one thread uses the transaction at a time.
+unsafe impl<T: KeyValueDatabase> Send for SharedReadTransaction<'_, T> {}
+unsafe impl<T: KeyValueDatabase> Sync for SharedReadTransaction<'_, T> {}
+
+/// A consistent view of a storage, see [`KeyValueDatabaseBackingStorage::snapshot`].
+///
+/// Cloning is cheap and all clones observe the same data, so work can be split by task id range
+/// (see [`StoreSnapshot::task_ids_in`]) across threads. Reads of the clones are serialized, only
+/// the processing of the data runs in parallel. Clones share the read transaction, so they don't
+/// occupy additional reader slots, but the transaction is held until the last clone is dropped.
+pub struct StoreSnapshot<'a, T: KeyValueDatabase + 'a> {
+    storage: &'a KeyValueDatabaseBackingStorage<T>,
+    tx: Arc<SharedReadTransaction<'a, T>>,
+}
+
+impl<T: KeyValueDatabase> Clone for StoreSnapshot<'_, T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage,
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T: KeyValueDatabase> StoreSnapshot<'_, T> {
+    pub fn lookup_data(
+        &self,
+        task_id: TaskId,
+        category: TaskDataCategory,
+    ) -> Result<Vec<CachedDataItem>> {
+        lookup_task_data(
+            &self.storage.database,
+            self.storage.value_codec,
+            self.storage.options.skip_invalid_items,
+            &self.tx.0.lock(),
+            task_id,
+            category,
+        )
+    }
+
+    /// Like [`KeyValueDatabaseBackingStorage::lookup_raw`], but reads from the snapshot.
+    pub fn lookup_raw<R>(&self, task_id: TaskId, f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>> {
+        with_task_data(
+            &self.storage.database,
+            &self.tx.0.lock(),
+            KeySpace::TaskData,
+            task_id,
+            |bytes| Ok(f(bytes)),
+        )
+    }
+
+    /// Returns the ids of all tasks with persisted data, sorted ascending.
+    pub fn task_ids(&self) -> Result<Vec<TaskId>> {
+        scan_task_ids(&self.storage.database, &self.tx.0.lock(), None)
+    }
+
+    /// Returns the ids of the tasks with persisted data in `range`, sorted ascending.
+    pub fn task_ids_in(&self, range: Range<u32>) -> Result<Vec<TaskId>> {
+        scan_task_ids(&self.storage.database, &self.tx.0.lock(), Some(range))
+    }
+
+    /// Iterates the meta data and data of all tasks with persisted data, sorted by task id. The
+    /// task ids are scanned upfront, the data is read lazily. Storages with different LMDB
+    /// namespaces in a shared environment use separate databases, so this only returns the tasks
+    /// of this storage.
+    pub fn iter_tasks(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(TaskId, Vec<CachedDataItem>)>> + '_> {
+        Ok(self.read_tasks(self.task_ids()?))
+    }
+
+    /// Like [`StoreSnapshot::iter_tasks`], but only for the tasks in `range`.
+    pub fn iter_tasks_in(
+        &self,
+        range: Range<u32>,
+    ) -> Result<impl Iterator<Item = Result<(TaskId, Vec<CachedDataItem>)>> + '_> {
+        Ok(self.read_tasks(self.task_ids_in(range)?))
+    }
+
+    fn read_tasks(
+        &self,
+        task_ids: Vec<TaskId>,
+    ) -> impl Iterator<Item = Result<(TaskId, Vec<CachedDataItem>)>> + '_ {
+        task_ids.into_iter().map(|task_id| {
+            let mut data = self.lookup_data(task_id, TaskDataCategory::Meta)?;
+            data.extend(self.lookup_data(task_id, TaskDataCategory::Data)?);
+            Ok((task_id, data))
+        })
+    }
+}
+
+fn scan_task_ids<D: KeyValueDatabase>(
+    database: &D,
+    tx: &D::ReadTransaction<'_>,
+    range: Option<Range<u32>>,
+) -> Result<Vec<TaskId>> {
+    let mut task_ids = Vec::new();
+    let start = range.as_ref().map(|range| IntKey::new(range.start));
+    for key_space in [KeySpace::TaskMeta, KeySpace::TaskData] {
+        database.iterate(
+            tx,
+            key_space,
+            start.as_ref().map(|key| key.as_ref()),
+            &mut |key: &[u8], _: &[u8]| {
+                let task_id = decode_task_id(key_space, key, key)?;
+                if let Some(range) = &range {
+                    if task_id >= range.end {
+                        return Ok(false);
+                    }
+                }
+                task_ids.push(TaskId::from(task_id));
+                Ok(true)
+            },
+        )?;
+    }
+    task_ids.sort_unstable();
+    task_ids.dedup();
+    Ok(task_ids)
+}
+
+fn lookup_task_data<D: KeyValueDatabase>(
+    database: &D,
+    value_codec: ValueCodec,
+    skip_invalid_items: bool,
+    tx: &D::ReadTransaction<'_>,
+    task_id: TaskId,
+    category: TaskDataCategory,
+) -> Result<Vec<CachedDataItem>> {
+    let key_space = match category {
+        TaskDataCategory::Meta => KeySpace::TaskMeta,
+        TaskDataCategory::Data => KeySpace::TaskData,
+        TaskDataCategory::All => unreachable!(),
+    };
+    let result = with_task_data(database, tx, key_space, task_id, |bytes| match value_codec
+        .deserialize::<Vec<CachedDataItem>>(bytes)
+    {
+        Err(err) if skip_invalid_items => {
+            let Ok((items, skipped)) = value_codec.deserialize_elements(bytes) else {
+                return Err(err);
+            };
+            for (index, err) in skipped {
+                tracing::warn!("Skipping data item {index} of {task_id}: {err:?}");
+            }
+            Ok(items)
+        }
+        result => result,
+    })?;
+    Ok(result.unwrap_or_default())
+}
+
+/// Calls `f` with the stored task data of `task_id`. References to deduplicated data blobs and
+/// deltas are resolved and compressed data is decompressed.
+fn with_task_data<D: KeyValueDatabase, R>(
+    database: &D,
+    tx: &D::ReadTransaction<'_>,
+    key_space: KeySpace,
+    task_id: TaskId,
+    f: impl FnOnce(&[u8]) -> Result<R>,
+) -> Result<Option<R>> {
+    let Some(bytes) = database.get(tx, key_space, IntKey::new(*task_id).as_ref())? else {
+        return Ok(None);
+    };
+    let bytes: &[u8] = bytes.borrow();
+    if !is_encoded(bytes) {
+        return f(bytes).map(Some);
+    }
+    let blob;
+    let bytes: &[u8] = match blob_reference(bytes) {
+        Some(hash) => {
+            blob = database
+                .get(tx, KeySpace::DataBlob, &blob_content_key(hash))?
+                .with_context(|| {
+                    anyhow!("Data of {task_id} references the missing blob {hash:032x}")
+                })?;
+            blob.borrow()
+        }
+        None => bytes,
+    };
+    let call = |bytes: &[u8]| {
+        let (data, writer) = unframe(bytes)?;
+        match writer {
+            Some(writer) => f(data)
+                .with_context(|| anyhow!("The data of {task_id} was written by version {writer}")),
+            None => f(data),
+        }
+    };
+    let Some(delta) = Delta::decode(bytes) else {
+        return call(&decompress(bytes)?).map(Some);
+    };
+    let baseline = database
+        .get(tx, KeySpace::DataBlob, &task_baseline_key(*task_id))?
+        .with_context(|| {
+            anyhow!("The baseline of the delta encoded data of {task_id} is missing")
+        })?;
+    call(&decompress(&delta.apply(baseline.borrow())?)?).map(Some)
+}
+
+fn lookup_task_id<D: KeyValueDatabase>(
+    database: &D,
+    tx: &D::ReadTransaction<'_>,
+    task_type: &[u8],
+) -> Result<Option<TaskId>> {
+    let Some(bytes) = database.get(tx, KeySpace::ForwardTaskCache, task_type)? else {
+        return Ok(None);
+    };
+    let id = decode_task_id(KeySpace::ForwardTaskCache, task_type, bytes.borrow())?;
+    Ok(Some(TaskId::from(id)))
+}
+
+/// Maps the serialized task type into the persistent task id range. The hash is stable across
+/// architectures, so stores with content addressed ids are portable.
+fn content_addressed_task_id(task_type: &[u8]) -> TaskId {
+    let hash = stable_hash(task_type);
+    TaskId::from((hash % (TRANSIENT_TASK_BIT as u64 - 1)) as u32 + 1)
+}
+
+fn get_infra_u64(database: &impl KeyValueDatabase, key: u32) -> Option<u64> {
+    let tx = database.begin_read_transaction().ok()?;
+    let value = database
+        .get(&tx, KeySpace::Infra, IntKey::new(key).as_ref())
+        .ok()?
+        .map(as_u64)?
+        .ok()?;
+    Some(value)
+}
+
+/// Adds `n` to the little-endian u64 at the infra `key` in the batch.
+fn add_infra_u64<'a>(batch: &mut impl WriteBatch<'a>, key: u32, n: u64) -> Result<()> {
+    let value = batch
+        .get(KeySpace::Infra, IntKey::new(key).as_ref())?
+        .map(as_u64)
+        .transpose()?
+        .unwrap_or(0);
+    batch.put(
+        KeySpace::Infra,
+        Cow::Borrowed(IntKey::new(key).as_ref()),
+        Cow::Borrowed(&(value + n).to_le_bytes()),
+    )
+}
+
+fn get_infra_u32(database: &impl KeyValueDatabase, key: u32) -> Option<u32> {
+    let tx = database.begin_read_transaction().ok()?;
+    let value = database
+        .get(&tx, KeySpace::Infra, IntKey::new(key).as_ref())
+        .ok()?
+        .map(as_u32)?
+        .ok()?;
+    Some(value)
+}
+
+impl<T: KeyValueDatabase + Send + Sync + 'static> BackingStorage
+    for KeyValueDatabaseBackingStorage<T>
+{
+    type ReadTransaction<'l> = T::ReadTransaction<'l>;
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        T::lower_read_transaction(tx)
+    }
+
+    fn next_free_task_id(&self) -> TaskId {
+        TaskId::from(get_infra_u32(&self.database, META_KEY_NEXT_FREE_TASK_ID).unwrap_or(1))
+    }
+
+    fn content_addressed_task_id(&self, task_type: &CachedTaskType) -> Option<TaskId> {
+        if self.options.task_id_allocation != TaskIdAllocation::ContentAddressed {
+            return None;
+        }
+        let task_type = forward_cache_key_bytes(task_type)
+            .inspect_err(|err| {
+                tracing::warn!("Serializing task type {task_type:?} failed: {err:?}")
+            })
+            .ok()?;
+        Some(content_addressed_task_id(&task_type))
+    }
+
+    fn next_session_id(&self) -> SessionId {
+        SessionId::from(get_infra_u32(&self.database, META_KEY_SESSION_ID).unwrap_or(0) + 1)
+    }
+
+    fn uncompleted_operations(&self) -> Vec<AnyOperation> {
+        fn get(
+            database: &impl KeyValueDatabase,
+            value_codec: ValueCodec,
+        ) -> Result<Vec<AnyOperation>> {
+            let tx = database.begin_read_transaction()?;
+            let Some(operations) = read_chunked(
+                database,
+                &tx,
+                META_KEY_OPERATIONS,
+                META_KEY_OPERATIONS_CHUNKS,
+            )?
+            else {
+                return Ok(Vec::new());
+            };
+            let operations = value_codec.deserialize(&operations)?;
+            Ok(operations)
+        }
+        get(&self.database, self.value_codec).unwrap_or_default()
+    }
+
+    fn save_snapshot(
+        &self,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+        data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+    ) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        if self.options.skip_empty_snapshots
+            && operations.is_empty()
+            && task_cache_updates.iter().all(|m| m.is_empty())
+            && meta_updates
+                .iter()
+                .chain(data_updates.iter())
+                .all(|m| m.is_empty())
+            && !self.has_stored_operations()?
+        {
+            self.skipped_empty_snapshots.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let _write_lock = self.lock_for_snapshot()?;
+        let span = tracing::trace_span!("save snapshot", session_id = ?session_id, operations = operations.len(), db_operation_count = tracing::field::Empty);
+        let start = Instant::now();
+        let mut op_count = 0;
+        // Data updates are counted when they are organized by task and again when they are
+        // merged into the task data
+        let items = task_cache_updates.iter().map(|m| m.len()).sum::<usize>()
+            + 2 * meta_updates
+                .iter()
+                .chain(data_updates.iter())
+                .map(|m| m.len())
+                .sum::<usize>();
+        let progress = SnapshotProgress::new(self.options.progress.as_deref(), items);
+        if self.options.record_write_amplification {
+            for update in meta_updates
+                .iter()
+                .chain(&data_updates)
+                .flat_map(|m| m.iter())
+            {
+                self.record_logical_update(update);
+            }
+        }
+        // All key spaces are written in this one batch, so the snapshot is committed atomically
+        // and a failure before the commit leaves the previous snapshot untouched.
+        let mut batch = self
+            .write_batch()
+            .map_err(|err| self.handle_write_error(err))?;
+        let mut task_meta_items_result = Ok(Vec::new());
+        let mut task_data_items_result = Ok(Vec::new());
+        let failures = SerializationFailures::new(
+            self.options.serialization_failure_log_limit,
+            self.options.strict_serialization,
+        );
+        let pool = self.serialization_pool()?;
+
+        let result = turbo_tasks::scope(|s| {
+            // Start organizing the updates in parallel
+            s.spawn(|_| {
+                task_meta_items_result = process_task_data(
+                    &self.database,
+                    pool,
+                    self.value_codec,
+                    self.options.streaming_threshold,
+                    KeySpace::TaskMeta,
+                    meta_updates,
+                    &progress,
+                    &failures,
+                );
+            });
+            s.spawn(|_| {
+                task_data_items_result = process_task_data(
+                    &self.database,
+                    pool,
+                    self.value_codec,
+                    self.options.streaming_threshold,
+                    KeySpace::TaskData,
+                    data_updates,
+                    &progress,
+                    &failures,
+                );
+            });
+
+            op_count += self.write_snapshot_infra(
+                &mut batch,
+                session_id,
+                operations,
+                task_cache_updates,
+                &progress,
+            )?;
+
+            anyhow::Ok(())
+        });
+        failures.finish(&self.skipped_optional_items, &self.failed_required_items);
+        result?;
+
+        let mut blobs = BlobUpdates::default();
+        let mut written_tasks = 0;
+
+        let task_meta_items = task_meta_items_result?;
+        let task_data_items = task_data_items_result?;
+        let written_bytes = task_meta_items
+            .iter()
+            .chain(task_data_items.iter())
+            .flatten()
+            .map(|(_, value)| value.len())
+            .sum::<usize>();
+        self.record_task_sizes(
+            &task_data_items
+                .iter()
+                .flatten()
+                .map(|(task_id, value)| (*task_id, value.len()))
+                .collect::<Vec<_>>(),
+        );
+        let commit_chunk_size = self.options.commit_chunk_size.filter(|_| {
+            self.options.data_deduplication_threshold.is_none()
+                && self.options.data_delta_baseline_interval.is_none()
+        });
+        // The number of tasks written to the current transaction once the snapshot was split
+        let mut split_tasks = None;
+        let mut written_task_ids = Vec::new();
+        for (key_space, task_items) in [
+            (KeySpace::TaskMeta, task_meta_items),
+            (KeySpace::TaskData, task_data_items),
+        ] {
+            {
+                let _span =
+                    tracing::trace_span!("update task data", tasks = task_items.len()).entered();
+                for (task_id, value) in task_items.into_iter().flatten() {
+                    if let (Some(chunk_size), Some(tasks)) = (commit_chunk_size, &mut split_tasks) {
+                        if *tasks >= chunk_size {
+                            batch = self.commit_split_snapshot(batch)?;
+                            *tasks = 0;
+                        }
+                    }
+                    let value =
+                        self.encode_task_data(&mut batch, &mut blobs, key_space, task_id, value)?;
+                    match self.put_task_data(&mut batch, key_space, task_id, &value) {
+                        Ok(()) => {}
+                        // Only a rejected write leaves the transaction committable, LMDB's own
+                        // limit aborts it
+                        Err(err)
+                            if commit_chunk_size.is_some()
+                                && split_tasks.is_none()
+                                && err
+                                    .downcast_ref::<TransactionFull>()
+                                    .is_some_and(|full| full.committable) =>
+                        {
+                            tracing::warn!(
+                                "The snapshot doesn't fit into one transaction, committing it in \
+                                 transactions of {} tasks",
+                                commit_chunk_size.unwrap_or_default()
+                            );
+                            batch = self.commit_split_snapshot(batch)?;
+                            self.put_task_data(&mut batch, key_space, task_id, &value)
+                                .map_err(|err| transaction_full_error(err, commit_chunk_size))?;
+                            split_tasks = Some(0);
+                        }
+                        Err(err) => return Err(transaction_full_error(err, commit_chunk_size)),
+                    }
+                    if let Some(tasks) = &mut split_tasks {
+                        *tasks += 1;
+                    }
+                    if self.data_cache.is_some() {
+                        written_task_ids.push(task_id);
+                    }
+                    op_count += 1;
+                    written_tasks += 1;
+                }
+            }
+        }
+        blobs
+            .write(&mut batch)
+            .map_err(|err| transaction_full_error(err, commit_chunk_size))
+            .with_context(|| anyhow!("Unable to write data blobs"))?;
+        {
+            let _span = tracing::trace_span!("commit").entered();
+            batch
+                .commit()
+                .map_err(|err| self.handle_write_error(err))
+                .map_err(|err| transaction_full_error(err, commit_chunk_size))
+                .with_context(|| anyhow!("Unable to commit operations"))?;
+        }
+        self.snapshot_infra_committed();
+        if split_tasks.is_some() {
+            self.split_snapshots.fetch_add(1, Ordering::Relaxed);
+        }
+        self.committed_snapshots.fetch_add(1, Ordering::Relaxed);
+        self.cached_task_index.lock().take();
+        self.invalidate_cached_data(written_task_ids);
+        self.snapshot_cost
+            .lock()
+            .record(items, written_bytes, start.elapsed());
+        span.record("db_operation_count", op_count);
+        self.notify_commit(op_count, written_tasks);
+        Ok(())
+    }
+
+    fn estimate_snapshot_cost(
+        &self,
+        task_cache_len: usize,
+        data_updates_len: usize,
+    ) -> SnapshotCostEstimate {
+        self.snapshot_cost
+            .lock()
+            .estimate(task_cache_len + data_updates_len)
+    }
+
+    fn start_read_transaction(&self) -> Option<Self::ReadTransaction<'_>> {
+        self.database.begin_read_transaction().ok()
+    }
+
+    unsafe fn forward_lookup_task_cache(
+        &self,
+        tx: Option<&T::ReadTransaction<'_>>,
+        task_type: &CachedTaskType,
+    ) -> Option<TaskId> {
+        let id = self
+            .with_tx(tx, |tx| {
+                lookup_task_id(&self.database, tx, &forward_cache_key_bytes(task_type)?)
+            })
+            .inspect_err(|err| {
+                self.record_restore_error();
+                println!("Looking up task id for {task_type:?} failed: {err:?}")
+            })
+            .ok();
+        match id {
+            Some(Some(_)) => self.record_restored_cache_entry(),
+            Some(None) => self.log_new_task(task_type),
+            None => {}
+        }
+        let id = id.flatten();
+        if self.options.lookup_events {
+            match id {
+                Some(id) => tracing::trace!(
+                    lookup = "forward",
+                    hit = true,
+                    task_id = *id,
+                    task_type = %task_type.get_name(),
+                    "task cache lookup"
+                ),
+                None => tracing::trace!(
+                    lookup = "forward",
+                    hit = false,
+                    task_type = %task_type.get_name(),
+                    "task cache lookup"
+                ),
+            }
+        }
+        id
+    }
+
+    unsafe fn reverse_lookup_task_cache(
+        &self,
+        tx: Option<&T::ReadTransaction<'_>>,
+        task_id: TaskId,
+    ) -> Option<Arc<CachedTaskType>> {
+        fn lookup<D: KeyValueDatabase>(
+            database: &D,
+            tx: &D::ReadTransaction<'_>,
+            task_id: TaskId,
+        ) -> Result<Option<Arc<CachedTaskType>>> {
+            let Some(bytes) = database.get(
+                tx,
+                KeySpace::ReverseTaskCache,
+                IntKey::new(*task_id).as_ref(),
+            )?
+            else {
+                return Ok(None);
+            };
+            Ok(Some(pot::from_slice(bytes.borrow())?))
+        }
+        let result = self
+            .with_tx(tx, |tx| lookup(&self.database, tx, task_id))
+            .inspect_err(|err| {
+                self.record_restore_error();
+                println!("Looking up task type for {task_id} failed: {err:?}")
+            })
+            .ok()
+            .flatten();
+        if self.options.lookup_events {
+            match &result {
+                Some(task_type) => tracing::trace!(
+                    lookup = "reverse",
+                    hit = true,
+                    task_id = *task_id,
+                    task_type = %task_type.get_name(),
+                    "task cache lookup"
+                ),
+                None => tracing::trace!(
+                    lookup = "reverse",
+                    hit = false,
+                    task_id = *task_id,
+                    "task cache lookup"
+                ),
+            }
+        }
+        result
+    }
+
+    unsafe fn lookup_data(
+        &self,
+        tx: Option<&T::ReadTransaction<'_>>,
+        task_id: TaskId,
+        category: TaskDataCategory,
+    ) -> Arc<Vec<CachedDataItem>> {
+        self.touch(task_id);
+        let data = self
+            .lookup_shared_data(tx, task_id, category)
+            .inspect_err(|err| {
+                self.record_restore_error();
+                println!("Looking up data for {task_id} failed: {err:?}")
+            })
+            .unwrap_or_default();
+        if !data.is_empty() {
+            self.record_restored_task();
+        }
+        if self.options.lookup_events {
+            tracing::trace!(
+                lookup = "data",
+                hit = !data.is_empty(),
+                task_id = *task_id,
+                category = ?category,
+                items = data.len(),
+                "task data lookup"
+            );
+        }
+        data
+    }
+
+    fn contains_tasks(&self, task_ids: &[TaskId]) -> Vec<bool> {
+        let contains = |tx: &T::ReadTransaction<'_>, task_id: TaskId| -> Result<bool> {
+            let key = IntKey::new(*task_id);
+            Ok(self
+                .database
+                .get(tx, KeySpace::TaskMeta, key.as_ref())?
+                .is_some()
+                || self
+                    .database
+                    .get(tx, KeySpace::TaskData, key.as_ref())?
+                    .is_some())
+        };
+        self.database
+            .begin_read_transaction()
+            .and_then(|tx| {
+                task_ids
+                    .iter()
+                    .map(|&task_id| contains(&tx, task_id))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .unwrap_or_else(|err| {
+                tracing::error!("Checking whether tasks are stored failed: {err:?}");
+                vec![false; task_ids.len()]
+            })
+    }
+}
+
+/// Reports the progress of a `save_snapshot` call. It can be advanced from multiple threads, but
+/// the callback always observes monotonically increasing values. The callback is called by one
+/// thread at a time, but not under a lock, so a slow callback doesn't block other threads. Their
+/// progress is reported by the calling thread once the callback returns.
+struct SnapshotProgress<'a> {
+    callback: Option<&'a (dyn Fn(usize, usize) + Send + Sync)>,
+    processed: AtomicUsize,
+    /// The last value that was passed to the callback.
+    reported: AtomicUsize,
+    /// Set while a thread calls the callback.
+    reporting: AtomicBool,
+    total: usize,
+}
+
+impl<'a> SnapshotProgress<'a> {
+    fn new(callback: Option<&'a (dyn Fn(usize, usize) + Send + Sync)>, total: usize) -> Self {
+        Self {
+            callback,
+            processed: AtomicUsize::new(0),
+            reported: AtomicUsize::new(0),
+            reporting: AtomicBool::new(false),
+            total,
+        }
+    }
+
+    fn advance(&self, items: usize) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+        let before = self.processed.fetch_add(items, Ordering::SeqCst);
+        if !self.needs_report(before, before + items) {
+            return;
+        }
+        while self
+            .reporting
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            let processed = self.processed.load(Ordering::SeqCst);
+            let reported = self.reported.load(Ordering::Relaxed);
+            if processed > reported {
+                self.reported.store(processed, Ordering::Relaxed);
+                callback(processed, self.total);
+            }
+            self.reporting.store(false, Ordering::SeqCst);
+            let reported = processed.max(reported);
+            if !self.needs_report(reported, self.processed.load(Ordering::SeqCst)) {
+                break;
+            }
+        }
+    }
+
+    /// Whether advancing from `from` to `to` processed items needs to be reported.
+    fn needs_report(&self, from: usize, to: usize) -> bool {
+        to / PROGRESS_INTERVAL != from / PROGRESS_INTERVAL || (to == self.total && from != to)
+    }
+}
+
+/// The weight of the latest snapshot in the rolling averages of [`SnapshotCostModel`].
+const SNAPSHOT_COST_WEIGHT: f64 = 0.3;
+
+/// Rolling averages of the cost per item of recent snapshots, which are used to estimate the cost
+/// of the next one.
+#[derive(Default)]
+struct SnapshotCostModel {
+    bytes_per_item: f64,
+    nanos_per_item: f64,
+    samples: u64,
+}
+
+impl SnapshotCostModel {
+    fn record(&mut self, items: usize, bytes: usize, duration: Duration) {
+        if items == 0 {
+            return;
+        }
+        let bytes_per_item = bytes as f64 / items as f64;
+        let nanos_per_item = duration.as_nanos() as f64 / items as f64;
+        if self.samples == 0 {
+            self.bytes_per_item = bytes_per_item;
+            self.nanos_per_item = nanos_per_item;
+        } else {
+            self.bytes_per_item += SNAPSHOT_COST_WEIGHT * (bytes_per_item - self.bytes_per_item);
+            self.nanos_per_item += SNAPSHOT_COST_WEIGHT * (nanos_per_item - self.nanos_per_item);
+        }
+        self.samples += 1;
+    }
+
+    fn estimate(&self, items: usize) -> SnapshotCostEstimate {
+        SnapshotCostEstimate {
+            bytes: (self.bytes_per_item * items as f64) as u64,
+            duration: Duration::from_nanos((self.nanos_per_item * items as f64) as u64),
+        }
+    }
+}
+
+/// Counts the data items that failed to serialize during a `save_snapshot` call and logs the
+/// first few of them.
+struct SerializationFailures {
+    log_limit: usize,
+    /// Whether failures of optional items fail the snapshot, too.
+    strict: bool,
+    logged: AtomicUsize,
+    skipped_optional_items: AtomicU64,
+    failed_required_items: AtomicU64,
+}
+
+impl SerializationFailures {
+    fn new(log_limit: usize, strict: bool) -> Self {
+        Self {
+            log_limit,
+            strict,
+            logged: AtomicUsize::new(0),
+            skipped_optional_items: AtomicU64::new(0),
+            failed_required_items: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a failure and logs it unless the log limit is reached. Returns whether it was
+    /// logged.
+    fn record(
+        &self,
+        task: TaskId,
+        optional: bool,
+        item: &dyn std::fmt::Debug,
+        err: &dyn std::fmt::Display,
+    ) -> bool {
+        if optional {
+            self.skipped_optional_items.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed_required_items.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.logged.fetch_add(1, Ordering::Relaxed) >= self.log_limit {
+            return false;
+        }
+        tracing::warn!(
+            task = %task,
+            optional,
+            item = ?item,
+            "Unable to serialize data item: {err}"
+        );
+        true
+    }
+
+    /// Logs a summary if failures were not logged individually and adds the counts to `stats`.
+    fn finish(self, skipped_optional_items: &AtomicU64, failed_required_items: &AtomicU64) {
+        let skipped = self.skipped_optional_items.into_inner();
+        let failed = self.failed_required_items.into_inner();
+        if skipped + failed > self.log_limit as u64 {
+            tracing::warn!(
+                skipped_optional_items = skipped,
+                failed_required_items = failed,
+                "{} data items failed to serialize, only the first {} were logged",
+                skipped + failed,
+                self.log_limit
+            );
+        }
+        skipped_optional_items.fetch_add(skipped, Ordering::Relaxed);
+        failed_required_items.fetch_add(failed, Ordering::Relaxed);
+    }
+}
+
+/// Returns the key the forward task cache stores `task_type` under. Database implementations might
+/// store long keys differently, e.g. LMDB uses [`raw_key_layout`](crate::database::raw_key_layout).
+pub fn forward_cache_key_bytes(task_type: &CachedTaskType) -> Result<Vec<u8>> {
+    pot::to_vec(task_type)
+        .with_context(|| anyhow!("Unable to serialize task cache key {task_type:?}"))
+}
+
+/// Serializes a task type for the task cache. With `verify` the task type is deserialized again
+/// and compared with the original.
+fn serialize_task_type(task_type: &CachedTaskType, verify: bool) -> Result<Vec<u8>> {
+    let task_type_bytes = forward_cache_key_bytes(task_type)?;
+    #[cfg(feature = "verify_serialization")]
+    {
+        let deserialize: Result<CachedTaskType, _> = serde_path_to_error::deserialize(
+            &mut pot::de::SymbolList::new().deserializer_for_slice(&task_type_bytes)?,
+        );
+        if let Err(err) = deserialize {
+            println!("Task type would not be deserializable: {err:?}\n{task_type:#?}");
+            panic!("Task type would not be deserializable: {err:?}");
+        }
+    }
+    if verify {
+        let deserialized: CachedTaskType = serde_path_to_error::deserialize(
+            &mut pot::de::SymbolList::new().deserializer_for_slice(&task_type_bytes)?,
+        )
+        .with_context(|| anyhow!("Task type {task_type} would not be deserializable"))?;
+        if deserialized != *task_type {
+            bail!("Task type {task_type} changes in a serialization round trip: {deserialized:?}");
+        }
+    }
+    Ok(task_type_bytes)
+}
+
+/// Writes task cache entries of serialized task types and the next free task id. Returns the
+/// number of database operations.
+fn write_task_cache<'a>(
+    batch: &mut impl WriteBatch<'a>,
+    items: usize,
+    entries: impl IntoIterator<Item = Result<(Vec<u8>, TaskId)>>,
+    duplicate_task_ids: DuplicateTaskIdPolicy,
+    progress: &SnapshotProgress<'_>,
+) -> Result<usize> {
+    let _span = tracing::trace_span!("update task cache", items).entered();
+    let mut op_count = 0;
+    let mut next_task_id = match batch.get(
+        KeySpace::Infra,
+        IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref(),
+    )? {
+        Some(bytes) => u32::from_le_bytes(bytes.borrow().try_into()?),
+        None => 1,
+    };
+    // The content hashes of the task types of the task ids written by this call
+    let mut written = FxHashMap::<u32, u128>::default();
+    for entry in entries {
+        let (task_type_bytes, task_id) = entry?;
+        let task_id = *task_id;
+        let hash = hash_xxh3_hash128(&task_type_bytes[..]);
+        if let Some(previous) = written.insert(task_id, hash) {
+            if previous != hash {
+                let previous_type = batch
+                    .get(KeySpace::ReverseTaskCache, IntKey::new(task_id).as_ref())?
+                    .map(|bytes| describe_task_type(bytes.borrow()));
+                let message = format!(
+                    "Task {task_id} is mapped to different task types in one snapshot: {} and {}",
+                    previous_type.as_deref().unwrap_or("<missing>"),
+                    describe_task_type(&task_type_bytes)
+                );
+                match duplicate_task_ids {
+                    DuplicateTaskIdPolicy::Error => bail!(message),
+                    DuplicateTaskIdPolicy::LastWins => {
+                        tracing::error!("{message}, keeping the last")
+                    }
+                    DuplicateTaskIdPolicy::FirstWins => {
+                        tracing::error!("{message}, keeping the first");
+                        written.insert(task_id, previous);
+                        progress.advance(1);
+                        continue;
+                    }
+                }
+            }
+        }
+        batch
+            .put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(&task_type_bytes),
+                Cow::Borrowed(&task_id.to_le_bytes()),
+            )
+            .with_context(|| anyhow!("Unable to write forward task cache for {task_id}"))?;
+        batch
+            .put(
+                KeySpace::ReverseTaskCache,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(&task_type_bytes),
+            )
+            .with_context(|| anyhow!("Unable to write reverse task cache for {task_id}"))?;
+        if cfg!(debug_assertions) {
+            verify_task_cache_entry(&*batch, &task_type_bytes, task_id)?;
+        }
+        op_count += 2;
+        next_task_id = next_task_id.max(next_task_id_after(task_id)?);
+        progress.advance(1);
+    }
+    batch
+        .put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref()),
+            Cow::Borrowed(&next_task_id.to_le_bytes()),
+        )
+        .with_context(|| anyhow!("Unable to write next free task id"))?;
+    Ok(op_count)
+}
+
+/// Formats a serialized task type for error messages.
+fn describe_task_type(task_type_bytes: &[u8]) -> String {
+    match pot::from_slice::<CachedTaskType>(task_type_bytes) {
+        Ok(task_type) => task_type.to_string(),
+        Err(_) => format!("<{} bytes>", task_type_bytes.len()),
+    }
+}
+
+/// Checks that the forward and reverse task cache entries of `task_id` point at each other.
+fn verify_task_cache_entry<'a>(
+    batch: &impl WriteBatch<'a>,
+    task_type_bytes: &[u8],
+    task_id: u32,
+) -> Result<()> {
+    let forward = batch
+        .get(KeySpace::ForwardTaskCache, task_type_bytes)?
+        .map(|bytes| bytes.borrow().to_vec());
+    let reverse = batch
+        .get(KeySpace::ReverseTaskCache, IntKey::new(task_id).as_ref())?
+        .map(|bytes| bytes.borrow().to_vec());
+    if forward.as_deref() != Some(&task_id.to_le_bytes()[..])
+        || reverse.as_deref() != Some(task_type_bytes)
+    {
+        bail!(
+            "Forward and reverse task cache entries of {task_id} are out of sync: forward points \
+             to {forward:?}, reverse points to {reverse:?}"
+        );
+    }
+    Ok(())
+}
+
+/// Explains how to avoid [`TransactionFull`] errors when `err` is one.
+fn transaction_full_error(err: anyhow::Error, commit_chunk_size: Option<usize>) -> anyhow::Error {
+    let Some(full) = err.downcast_ref::<TransactionFull>() else {
+        return err;
+    };
+    if !full.committable {
+        return err.context(
+            "The snapshot doesn't fit into one transaction and LMDB aborted the transaction, so \
+             it can't be split. Set LmdbOptions::max_transaction_bytes below LMDB's limit, so \
+             snapshots are split before it's reached with \
+             BackingStorageOptions::commit_chunk_size.",
+        );
+    }
+    match commit_chunk_size {
+        Some(chunk_size) => err.context(format!(
+            "The snapshot doesn't fit into one transaction even when split into transactions of \
+             {chunk_size} tasks. Set a smaller BackingStorageOptions::commit_chunk_size."
+        )),
+        None => err.context(
+            "The snapshot doesn't fit into one transaction. Set \
+             BackingStorageOptions::commit_chunk_size to commit it in smaller transactions.",
+        ),
+    }
+}
+
+#[derive(Clone)]
+enum SerializedTaskData {
+    Buffered(Vec<u8>),
+    /// The data is serialized into the database when it's written.
+    Streamed {
+        len: usize,
+        data: Vec<CachedDataItem>,
+    },
+}
+
+impl SerializedTaskData {
+    fn len(&self) -> usize {
+        match self {
+            SerializedTaskData::Buffered(value) => value.len(),
+            SerializedTaskData::Streamed { len, .. } => *len,
+        }
+    }
+}
+
+type SerializedTasks = Vec<Vec<(TaskId, SerializedTaskData)>>;
+
+fn process_task_data(
     database: &(impl KeyValueDatabase + Sync),
+    pool: &rayon::ThreadPool,
+    value_codec: ValueCodec,
+    streaming_threshold: Option<usize>,
     key_space: KeySpace,
     updates: Vec<ChunkedVec<CachedDataUpdate>>,
+    progress: &SnapshotProgress<'_>,
+    failures: &SerializationFailures,
 ) -> Result<SerializedTasks> {
     let span = Span::current();
     let turbo_tasks = turbo_tasks::turbo_tasks();
     let handle = tokio::runtime::Handle::current();
-    updates
-        .into_par_iter()
-        .map(|updates| {
-            let _span = span.clone().entered();
-            let _guard = handle.clone().enter();
-            turbo_tasks_scope(turbo_tasks.clone(), || {
-                type TaskUpdates = FxHashMap<
-                    TaskId,
-                    FxHashMap<
-                        CachedDataItemKey,
-                        (Option<CachedDataItemValue>, Option<CachedDataItemValue>),
-                    >,
-                >;
-
-                let mut task_updates: TaskUpdates =
-                    FxHashMap::with_capacity_and_hasher(updates.len(), Default::default());
+    pool.install(|| {
+        updates
+            .into_par_iter()
+            .map(|updates| {
+                let _span = span.clone().entered();
+                let _guard = handle.clone().enter();
+                turbo_tasks_scope(turbo_tasks.clone(), || {
+                    type TaskUpdates = FxHashMap<
+                        TaskId,
+                        FxHashMap<
+                            CachedDataItemKey,
+                            (Option<CachedDataItemValue>, Option<CachedDataItemValue>),
+                        >,
+                    >;
+
+                    let update_count = updates.len();
+                    let mut task_updates: TaskUpdates =
+                        FxHashMap::with_capacity_and_hasher(updates.len(), Default::default());
+
+                    {
+                        let span = tracing::trace_span!(
+                            "organize updates",
+                            updates = updates.len(),
+                            tasks = tracing::field::Empty
+                        )
+                        .entered();
+
+                        // Organize the updates by task
+                        for CachedDataUpdate {
+                            task,
+                            key,
+                            value,
+                            old_value,
+                        } in updates.into_iter()
+                        {
+                            let data = task_updates.entry(task).or_default();
+                            match data.entry(key) {
+                                Entry::Occupied(mut entry) => {
+                                    entry.get_mut().1 = value;
+                                }
+                                Entry::Vacant(entry) => {
+                                    entry.insert((old_value, value));
+                                }
+                            }
+                            progress.advance(1);
+                        }
+
+                        span.record("tasks", task_updates.len());
+                    }
+
+                    {
+                        let span = tracing::trace_span!(
+                            "dedupe updates",
+                            before = task_updates.len(),
+                            after = tracing::field::Empty
+                        )
+                        .entered();
+
+                        // Remove no-op task updates (so we have less tasks to restore)
+                        task_updates.retain(|_, data| {
+                            data.retain(|_, (old_value, value)| *old_value != *value);
+                            !data.is_empty()
+                        });
+
+                        span.record("after", task_updates.len());
+                    }
+
+                    let tx = database.begin_read_transaction()?;
+
+                    let span = tracing::trace_span!(
+                        "restore, update and serialize",
+                        tasks = task_updates.len(),
+                        restored_tasks = tracing::field::Empty
+                    )
+                    .entered();
+                    let mut restored_tasks = 0;
+                    let mut merged_updates = 0;
+
+                    // Restore the old task data, apply the updates and serialize the new data
+                    let mut tasks = Vec::with_capacity(task_updates.len());
+                    let mut map = FxHashMap::with_capacity_and_hasher(128, Default::default());
+                    for (task, updates) in task_updates {
+                        let task_update_count = updates.len();
+                        // Restore the old task data
+                        if restore_task_data(database, &tx, value_codec, key_space, task, &mut map)?
+                        {
+                            restored_tasks += 1;
+                        }
+
+                        // Apply update
+                        for (key, (_, value)) in updates {
+                            if let Some(value) = value {
+                                map.insert(key, value);
+                            } else {
+                                map.remove(&key);
+                            }
+                        }
+
+                        // Get new data
+                        let data = map
+                            .drain()
+                            .map(|(key, value)| CachedDataItem::from_key_and_value(key, value))
+                            .collect::<Vec<_>>();
+
+                        // Serialize new data
+                        let value = serialize_task_data(
+                            task,
+                            data,
+                            value_codec,
+                            streaming_threshold,
+                            failures,
+                        )?;
+
+                        // Store the new task data
+                        tasks.push((task, value));
+                        merged_updates += task_update_count;
+                        progress.advance(task_update_count);
+                    }
+                    // Updates that were combined or dropped as no-ops are done as well
+                    progress.advance(update_count - merged_updates);
+
+                    span.record("restored_tasks", restored_tasks);
+                    Ok(tasks)
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+}
+
+/// Restores the stored data of `task` into `map`. Returns whether the task had stored data.
+fn restore_task_data<D: KeyValueDatabase>(
+    database: &D,
+    tx: &D::ReadTransaction<'_>,
+    value_codec: ValueCodec,
+    key_space: KeySpace,
+    task: TaskId,
+    map: &mut FxHashMap<CachedDataItemKey, CachedDataItemValue>,
+) -> Result<bool> {
+    let Some(old_data) = with_task_data(database, tx, key_space, task, |old_data| {
+        deserialize_task_data(value_codec, task, old_data)
+    })?
+    else {
+        return Ok(false);
+    };
+    map.extend(old_data.into_iter().map(|item| item.into_key_and_value()));
+    Ok(true)
+}
+
+/// Serializes the new data of `task`. Data that reaches the streaming threshold is serialized
+/// when it's written instead.
+fn serialize_task_data(
+    task: TaskId,
+    data: Vec<CachedDataItem>,
+    value_codec: ValueCodec,
+    streaming_threshold: Option<usize>,
+    failures: &SerializationFailures,
+) -> Result<SerializedTaskData> {
+    let streamed_len = streaming_threshold
+        .filter(|_| !cfg!(feature = "verify_serialization"))
+        .and_then(|threshold| {
+            value_codec
+                .serialized_size(&data)
+                .ok()
+                .filter(|len| *len >= threshold)
+        });
+    Ok(match streamed_len {
+        Some(len) => SerializedTaskData::Streamed { len, data },
+        None => SerializedTaskData::Buffered(serialize(task, data, value_codec, failures)?),
+    })
+}
+
+fn serialize(
+    task: TaskId,
+    mut data: Vec<CachedDataItem>,
+    value_codec: ValueCodec,
+    failures: &SerializationFailures,
+) -> Result<Vec<u8>> {
+    Ok(match value_codec.serialize(&data) {
+        #[cfg(not(feature = "verify_serialization"))]
+        Ok(value) => value,
+        _ => {
+            let mut error = Ok(());
+            data.retain(|item| {
+                let mut buf = Vec::<u8>::new();
+                let mut symbol_map = pot::ser::SymbolMap::new();
+                let mut serializer = symbol_map.serializer_for(&mut buf).unwrap();
+                if let Err(err) = serde_path_to_error::serialize(item, &mut serializer) {
+                    failures.record(task, item.is_optional(), item, &err);
+                    if !item.is_optional() || failures.strict {
+                        error = Err(err).context({
+                            anyhow!("Unable to serialize data item for {task}: {item:#?}")
+                        });
+                    }
+                    false
+                } else {
+                    #[cfg(feature = "verify_serialization")]
+                    {
+                        let deserialize: Result<CachedDataItem, _> =
+                            serde_path_to_error::deserialize(
+                                &mut pot::de::SymbolList::new()
+                                    .deserializer_for_slice(&buf)
+                                    .unwrap(),
+                            );
+                        if let Err(err) = deserialize {
+                            println!(
+                                "Data item would not be deserializable {task}: {err:?}\n{item:#?}"
+                            );
+                            return false;
+                        }
+                    }
+                    true
+                }
+            });
+            error?;
+
+            value_codec
+                .serialize(&data)
+                .with_context(|| anyhow!("Unable to serialize data items for {task}: {data:#?}"))?
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use std::{
+        collections::BTreeMap,
+        fmt::{self, Debug},
+        hash::Hash,
+        sync::OnceLock,
+    };
+
+    use anyhow::Result;
+    use parking_lot::{const_mutex, Mutex};
+    use serde::{Deserialize, Serialize};
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+    use turbo_tasks::{
+        backend::CachedTaskType, registry, CellId, RawVc, SessionId, SharedReference, TaskId,
+        TraitType, TransientInstance, ValueType, ValueTypeId,
+    };
+
+    use super::{BackingStorageOptions, KeyValueDatabaseBackingStorage};
+    #[cfg(feature = "lmdb")]
+    use crate::database::LmbdKeyValueDatabase;
+    use crate::{
+        backing_storage::BackingStorage,
+        data::{CachedDataItem, CachedDataItemKey, CachedDataItemValue, CachedDataUpdate},
+        database::key_value_database::KeyValueDatabase,
+        utils::chunked_vec::ChunkedVec,
+    };
+
+    /// Runs `f` with a turbo tasks context, which is needed by `save_snapshot`.
+    pub fn with_turbo_tasks<R>(f: impl FnOnce() -> R) -> R {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(turbo_tasks_testing::VcStorage::with(async { f() }))
+    }
+
+    /// Opens a storage with `options` on an LMDB database in a new temporary directory. The
+    /// directory is removed when it's dropped, so it needs to outlive the storage.
+    #[cfg(feature = "lmdb")]
+    pub fn lmdb_storage(
+        options: BackingStorageOptions,
+    ) -> Result<(
+        tempfile::TempDir,
+        KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>,
+    )> {
+        let dir = tempfile::tempdir()?;
+        let storage = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            options,
+        )?;
+        Ok((dir, storage))
+    }
+
+    /// Saves a snapshot of `session` that only contains the task data `updates`.
+    pub fn save_updates<T: KeyValueDatabase + Send + Sync + 'static>(
+        storage: &KeyValueDatabaseBackingStorage<T>,
+        session: u32,
+        updates: ChunkedVec<CachedDataUpdate>,
+    ) -> Result<()> {
+        with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(session),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                vec![updates],
+            )
+        })
+    }
+
+    /// Returns an update that sets the children count of `task` to `value`.
+    pub fn children_count_update(task: u32, value: u32) -> CachedDataUpdate {
+        CachedDataUpdate {
+            task: TaskId::from(task),
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value }),
+            old_value: None,
+        }
+    }
+
+    /// Returns cell data of a value type without serialization, like `serialization = "none"`
+    /// values. It's an optional item that fails to serialize.
+    pub fn unserializable_cell_data(index: u32) -> CachedDataItem {
+        static VALUE_TYPE: OnceLock<ValueTypeId> = OnceLock::new();
+
+        let type_id = *VALUE_TYPE.get_or_init(|| {
+            let value_type: &'static ValueType =
+                Box::leak(Box::new(ValueType::new::<turbo_tasks::Completion>()));
+            registry::register_value_type("turbo-tasks-backend::tests::Unserializable", value_type);
+            registry::get_value_type_id(value_type)
+        });
+        CachedDataItem::CellData {
+            cell: CellId { type_id, index },
+            value: SharedReference::from(TransientInstance::new(())).into_typed(type_id),
+        }
+    }
+
+    /// Returns a `ResolveTrait` task type calling `method` of the trait `name` on the output of
+    /// the task `this`. The trait is registered on first use.
+    pub fn test_task_type(name: &'static str, this: u32) -> CachedTaskType {
+        test_task_type_with_arg(name, this, ())
+    }
+
+    /// Like [`test_task_type`], but with a custom argument. All task types of a trait need to use
+    /// the same argument type.
+    pub fn test_task_type_with_arg<A>(name: &'static str, this: u32, arg: A) -> CachedTaskType
+    where
+        A: Serialize + for<'de> Deserialize<'de> + Debug + Eq + Hash + Send + Sync + 'static,
+    {
+        static REGISTER: Mutex<()> = const_mutex(());
+
+        let global_name = format!("turbo-tasks-backend::tests::{name}");
+        let trait_type = {
+            let _lock = REGISTER.lock();
+            registry::get_trait_type_id_by_global_name(&global_name).unwrap_or_else(|| {
+                let mut trait_type = TraitType::new(name.to_string());
+                trait_type.register_trait_method::<A>("method".into());
+                let trait_type = Box::leak(Box::new(trait_type));
+                registry::register_trait_type(global_name.leak(), trait_type);
+                registry::get_trait_type_id(trait_type)
+            })
+        };
+        CachedTaskType::ResolveTrait {
+            trait_type,
+            method_name: "method".into(),
+            this: RawVc::TaskOutput(TaskId::from(this)),
+            arg: Box::new(arg),
+        }
+    }
+
+    /// Runs `f` with a subscriber that captures the fields of all tracing events, and returns
+    /// them in the order they were emitted.
+    pub fn capture_events(f: impl FnOnce()) -> Vec<BTreeMap<&'static str, String>> {
+        let capture: &'static Capture = Box::leak(Box::default());
+        tracing::subscriber::with_default(capture, f);
+        capture.0.lock().clone()
+    }
+
+    #[derive(Default)]
+    struct Capture(Mutex<Vec<BTreeMap<&'static str, String>>>);
+
+    struct Fields<'a>(&'a mut BTreeMap<&'static str, String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+    }
+
+    impl Subscriber for &'static Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = BTreeMap::new();
+            event.record(&mut Fields(&mut fields));
+            self.0.lock().push(fields);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_progress_is_monotonic() {
+        const TOTAL: usize = 100_000;
+        const THREADS: usize = 8;
+
+        let reports = Mutex::new(Vec::new());
+        let callback = |processed: usize, total: usize| reports.lock().push((processed, total));
+        let progress = SnapshotProgress::new(Some(&callback), TOTAL);
+        scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..TOTAL / THREADS {
+                        progress.advance(1);
+                    }
+                });
+            }
+        });
+
+        let reports = reports.into_inner();
+        assert!(!reports.is_empty());
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(reports.iter().all(|&(_, total)| total == TOTAL));
+        assert_eq!(reports.last(), Some(&(TOTAL, TOTAL)));
+    }
+
+    #[test]
+    fn stats_render_as_prometheus_text() {
+        let output = BackingStorageStats {
+            skipped_optional_items: 3,
+            failed_required_items: 1,
+            ..Default::default()
+        }
+        .to_prometheus();
+
+        let mut samples = Vec::new();
+        for line in output.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let (kind, rest) = comment.split_once(' ').unwrap();
+                assert!(kind == "HELP" || kind == "TYPE");
+                assert!(!rest.is_empty());
+                continue;
+            }
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'));
+            samples.push((name, value.parse::<f64>().unwrap()));
+        }
+        assert_eq!(
+            samples,
+            vec![
+                ("turbo_tasks_backend_skipped_optional_items_total", 3.0),
+                ("turbo_tasks_backend_failed_required_items_total", 1.0),
+                ("turbo_tasks_backend_outlier_tasks_total", 0.0),
+                ("turbo_tasks_backend_committed_snapshots_total", 0.0),
+                ("turbo_tasks_backend_skipped_empty_snapshots_total", 0.0),
+                ("turbo_tasks_backend_split_snapshots_total", 0.0),
+                ("turbo_tasks_backend_data_cache_hits_total", 0.0),
+                ("turbo_tasks_backend_write_lock_waits_total", 0.0),
+                (
+                    "turbo_tasks_backend_write_lock_wait_microseconds_total",
+                    0.0
+                ),
+                ("turbo_tasks_backend_restore_errors_total", 0.0),
+                ("turbo_tasks_backend_restored_tasks_total", 0.0),
+                ("turbo_tasks_backend_restored_cache_entries_total", 0.0),
+                ("turbo_tasks_backend_lifetime_restored_tasks_total", 0.0),
+                (
+                    "turbo_tasks_backend_lifetime_restored_cache_entries_total",
+                    0.0
+                ),
+                ("turbo_tasks_backend_task_index_scans_total", 0.0),
+                ("turbo_tasks_backend_task_index_cache_hits_total", 0.0),
+                ("turbo_tasks_backend_logical_update_bytes_total", 0.0),
+                ("turbo_tasks_backend_written_task_bytes_total", 0.0),
+                ("turbo_tasks_backend_degraded", 0.0),
+            ]
+        );
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn rebuild_caches_restores_forward_cache() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let mut batch = storage.database.write_batch()?;
+        for task_id in [3u32, 7] {
+            batch.put(
+                KeySpace::ReverseTaskCache,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Owned(format!("task type {task_id}").into_bytes()),
+            )?;
+        }
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(12).as_ref()),
+            Cow::Borrowed(b"data"),
+        )?;
+        // Corrupt forward cache and next free task id
+        batch.put(
+            KeySpace::ForwardTaskCache,
+            Cow::Borrowed(b"garbage"),
+            Cow::Borrowed(&99u32.to_le_bytes()),
+        )?;
+        batch.put(
+            KeySpace::ForwardTaskCache,
+            Cow::Borrowed(b"task type 3"),
+            Cow::Borrowed(&7u32.to_le_bytes()),
+        )?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref()),
+            Cow::Borrowed(&1u32.to_le_bytes()),
+        )?;
+        batch.commit()?;
+
+        storage.rebuild_caches()?;
+
+        assert_eq!(*storage.next_free_task_id(), 13);
+        let tx = storage.database.begin_read_transaction()?;
+        let lookup = |key: &[u8]| -> Result<Option<u32>> {
+            storage
+                .database
+                .get(&tx, KeySpace::ForwardTaskCache, key)?
+                .map(as_u32)
+                .transpose()
+        };
+        assert_eq!(lookup(b"task type 3")?, Some(3));
+        assert_eq!(lookup(b"task type 7")?, Some(7));
+        assert_eq!(lookup(b"garbage")?, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn parallel_task_index_scan_matches_serial_scan() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        for task_id in (1..1000u32).filter(|id| id % 3 != 0) {
+            let key_space = if task_id % 2 == 0 {
+                KeySpace::TaskData
+            } else {
+                KeySpace::TaskMeta
+            };
+            batch.put(
+                key_space,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(b"data"),
+            )?;
+        }
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref()),
+            Cow::Borrowed(&1000u32.to_le_bytes()),
+        )?;
+        batch.commit()?;
+
+        let serial = KeyValueDatabaseBackingStorage::new(database)?.scan_task_index()?;
+        let parallel = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            BackingStorageOptions {
+                startup_parallelism: 4,
+                ..Default::default()
+            },
+        )?
+        .scan_task_index()?;
+        assert_eq!(serial.len(), 666);
+        assert_eq!(serial, parallel);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn value_codec_is_selected_from_database() -> Result<()> {
+        use crate::{
+            data::{CachedDataItemKey, CachedDataItemValue},
+            database::LmbdKeyValueDatabase,
+        };
+
+        let dir = tempfile::tempdir()?;
+        let open = |value_codec| {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                BackingStorageOptions {
+                    value_codec,
+                    ..Default::default()
+                },
+            )
+        };
+        let task = TaskId::from(1);
+        let storage = open(ValueCodec::PotV4)?;
+        assert_eq!(storage.value_codec(), ValueCodec::PotV4);
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 3 }),
+            old_value: None,
+        });
+        test_utils::save_updates(&storage, 1, updates)?;
+        drop(storage);
+
+        // The default changed, but the database keeps using the codec it was written with
+        let storage = open(ValueCodec::Pot)?;
+        assert_eq!(storage.value_codec(), ValueCodec::PotV4);
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) };
+        assert!(matches!(
+            data[..],
+            [CachedDataItem::ChildrenCount { value: 3 }]
+        ));
+        drop(storage);
+
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_VALUE_CODEC).as_ref()),
+            Cow::Borrowed(&99u32.to_le_bytes()),
+        )?;
+        batch.commit()?;
+        drop(database);
+        assert!(open(ValueCodec::Pot).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn stores_without_a_value_codec_use_pot() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let open = || {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                BackingStorageOptions {
+                    value_codec: ValueCodec::PotV4,
+                    ..Default::default()
+                },
+            )
+        };
+        // A new store uses the configured codec
+        assert_eq!(open()?.value_codec(), ValueCodec::PotV4);
+
+        // A store written before the codec was recorded
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_SESSION_ID).as_ref()),
+            Cow::Borrowed(&1u32.to_le_bytes()),
+        )?;
+        batch.commit()?;
+        drop(database);
+        assert_eq!(open()?.value_codec(), ValueCodec::Pot);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn other_schema_versions_are_rejected() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let (dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let mut updates = ChunkedVec::new();
+        updates.push(test_utils::children_count_update(1, 1));
+        test_utils::save_updates(&storage, 1, updates)?;
+        drop(storage);
+        assert_eq!(
+            get_infra_u32(
+                &LmbdKeyValueDatabase::new(dir.path())?,
+                META_KEY_SCHEMA_VERSION
+            ),
+            Some(SCHEMA_VERSION)
+        );
+
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_SCHEMA_VERSION).as_ref()),
+            Cow::Borrowed(&(SCHEMA_VERSION + 1).to_le_bytes()),
+        )?;
+        batch.commit()?;
+        drop(database);
+        let err = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("schema version"), "{err}");
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn streamed_task_data_is_byte_identical() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            streaming_threshold: Some(0),
+            ..Default::default()
+        })?;
+        let task = TaskId::from(1);
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        test_utils::save_updates(&storage, 1, updates)?;
+
+        let expected = storage
+            .value_codec
+            .serialize(&vec![CachedDataItem::ChildrenCount { value: 7 }])?;
+        let tx = storage.database.begin_read_transaction()?;
+        let stored = storage
+            .database
+            .get(&tx, KeySpace::TaskData, IntKey::new(*task).as_ref())?
+            .context("task data is missing")?;
+        assert_eq!(stored, &expected[..]);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn streamed_task_data_replaces_startup_cache_entries() -> Result<()> {
+        use crate::database::{LmbdKeyValueDatabase, StartupCacheLayer};
+
+        let dir = tempfile::tempdir()?;
+        let open = |streaming_threshold| {
+            let database = StartupCacheLayer::new(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                dir.path().join("startup.cache"),
+                false,
+            )?;
+            KeyValueDatabaseBackingStorage::with_options(
+                database,
+                BackingStorageOptions {
+                    streaming_threshold,
+                    ..Default::default()
+                },
+            )
+        };
+        let save = |storage: &KeyValueDatabaseBackingStorage<_>, session, value| {
+            let mut updates = ChunkedVec::new();
+            updates.push(test_utils::children_count_update(1, value));
+            test_utils::save_updates(storage, session, updates)
+        };
+        let task = TaskId::from(1);
+        let read = |storage: &KeyValueDatabaseBackingStorage<_>| {
+            // Safety: No transaction is passed.
+            let data = unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) };
+            match &data[..] {
+                [CachedDataItem::ChildrenCount { value }] => *value,
+                data => panic!("unexpected task data {data:?}"),
+            }
+        };
+
+        // The value is cached and persisted to the startup cache
+        let storage = open(None)?;
+        save(&storage, 1, 7)?;
+        assert_eq!(read(&storage), 7);
+        drop(storage);
+
+        // Streaming the value doesn't copy it into the cache, but invalidates the restored entry
+        let storage = open(Some(0))?;
+        save(&storage, 2, 8)?;
+        drop(storage);
+
+        let storage = open(Some(0))?;
+        assert_eq!(read(&storage), 8);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn task_cache_only_snapshot_leaves_data_untouched() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let mut batch = storage.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(3).as_ref()),
+            Cow::Borrowed(b"data"),
+        )?;
+        batch.put(
+            KeySpace::Infra,
+            Cow::Borrowed(IntKey::new(META_KEY_NEXT_FREE_TASK_ID).as_ref()),
+            Cow::Borrowed(&4u32.to_le_bytes()),
+        )?;
+        batch.commit()?;
+
+        storage.save_serialized_task_cache(
+            2,
+            [(b"task a", 4), (b"task b", 8)]
+                .map(|(task_type, task_id)| Ok((task_type.to_vec(), TaskId::from(task_id)))),
+        )?;
+
+        assert_eq!(*storage.next_free_task_id(), 9);
+        let tx = storage.database.begin_read_transaction()?;
+        assert_eq!(
+            storage
+                .database
+                .get(&tx, KeySpace::ForwardTaskCache, b"task b")?
+                .map(as_u32)
+                .transpose()?,
+            Some(8)
+        );
+        assert_eq!(
+            storage
+                .database
+                .get(&tx, KeySpace::ReverseTaskCache, IntKey::new(4).as_ref())?,
+            Some(&b"task a"[..])
+        );
+        assert_eq!(
+            storage
+                .database
+                .get(&tx, KeySpace::TaskData, IntKey::new(3).as_ref())?,
+            Some(&b"data"[..])
+        );
+        assert_eq!(storage.scan_task_index()?, vec![TaskId::from(3)]);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn iterating_tasks_stays_in_the_namespace() -> Result<()> {
+        use crate::database::{LmbdKeyValueDatabase, LmdbOptions};
+
+        let dir = tempfile::tempdir()?;
+        let open = |namespace| -> Result<_> {
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::with_options(
+                dir.path(),
+                LmdbOptions {
+                    namespace: Some(namespace),
+                    ..Default::default()
+                },
+            )?)
+        };
+        let first = open("first")?;
+        let second = open("second")?;
+        for (storage, tasks) in [(&first, 1..4), (&second, 3..6)] {
+            let mut updates = ChunkedVec::new();
+            updates.extend(tasks.map(|task| CachedDataUpdate {
+                task: TaskId::from(task),
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value: task }),
+                old_value: None,
+            }));
+            test_utils::save_updates(&storage, 1, updates)?;
+        }
+
+        let snapshot = second.snapshot()?;
+        let tasks = snapshot.iter_tasks()?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            tasks
+                .iter()
+                .map(|(task_id, _)| **task_id)
+                .collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        for (task_id, data) in tasks {
+            assert!(
+                matches!(&data[..], [CachedDataItem::ChildrenCount { value }] if *value == *task_id),
+                "{data:?}"
+            );
+        }
+        Ok(())
+    }
+
+    // The `verify_serialization` feature panics on such task types
+    #[cfg(all(feature = "lmdb", not(feature = "verify_serialization")))]
+    #[test]
+    fn task_types_that_do_not_round_trip_are_rejected() -> Result<()> {
+        use serde::{de, Deserializer, Serializer};
+
+        /// Serializes as a string, but deserializes from a number.
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Asymmetric;
+
+        impl Serialize for Asymmetric {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str("asymmetric")
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Asymmetric {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                u32::deserialize(deserializer)?;
+                Err(de::Error::custom("Asymmetric can't be deserialized"))
+            }
+        }
+
+        let task_type = || {
+            Arc::new(test_utils::test_task_type_with_arg(
+                "RoundTrip",
+                1,
+                Asymmetric,
+            ))
+        };
+
+        for verify_task_types in [true, false] {
+            let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+                verify_task_types,
+                ..Default::default()
+            })?;
+            let mut updates = ChunkedVec::new();
+            updates.push((task_type(), TaskId::from(2)));
+            let result = storage.save_task_cache_only(updates);
+            assert_eq!(result.is_err(), verify_task_types);
+            let tx = storage.database.begin_read_transaction()?;
+            let stored = storage
+                .database
+                .get(&tx, KeySpace::ReverseTaskCache, IntKey::new(2).as_ref())?
+                .is_some();
+            assert_eq!(stored, !verify_task_types);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn task_labels_are_stored_with_the_task_cache() -> Result<()> {
+        let task_type = Arc::new(test_utils::test_task_type("Labelled", 1));
+
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            store_task_labels: true,
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        updates.push((task_type, TaskId::from(2)));
+        storage.save_task_cache_only(updates)?;
+        assert_eq!(
+            storage.task_label(TaskId::from(2))?.as_deref(),
+            Some("*Labelled::method")
+        );
+        assert_eq!(storage.task_label(TaskId::from(3))?, None);
+
+        storage.delete_tasks(vec![TaskId::from(2)])?;
+        assert_eq!(storage.task_label(TaskId::from(2))?, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn copied_tasks_keep_their_id() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let source_dir = tempfile::tempdir()?;
+        let source =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(source_dir.path())?)?;
+        source.save_serialized_task_cache(
+            3,
+            [(b"task a", 4), (b"task b", 5), (b"task c", 6)]
+                .map(|(task_type, task_id)| Ok((task_type.to_vec(), TaskId::from(task_id)))),
+        )?;
+        let mut updates = ChunkedVec::new();
+        updates.extend([4, 6].map(|task| CachedDataUpdate {
+            task: TaskId::from(task),
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: task }),
+            old_value: None,
+        }));
+        test_utils::save_updates(&source, 1, updates)?;
+
+        let dst_dir = tempfile::tempdir()?;
+        let dst = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dst_dir.path())?)?;
+        source.copy_task_to(TaskId::from(4), &dst)?;
+        // A task with a task cache entry, but no data
+        source.copy_task_to(TaskId::from(5), &dst)?;
+        assert!(source.copy_task_to(TaskId::from(7), &dst).is_err());
+
+        let source_tx = source.database.begin_read_transaction()?;
+        let dst_tx = dst.database.begin_read_transaction()?;
+        for (task_type, task_id) in [(&b"task a"[..], 4), (&b"task b"[..], 5)] {
+            let key = IntKey::new(task_id);
+            assert_eq!(
+                dst.database
+                    .get(&dst_tx, KeySpace::ForwardTaskCache, task_type)?,
+                source
+                    .database
+                    .get(&source_tx, KeySpace::ForwardTaskCache, task_type)?
+            );
+            assert_eq!(
+                dst.database
+                    .get(&dst_tx, KeySpace::ReverseTaskCache, key.as_ref())?,
+                Some(task_type)
+            );
+            assert_eq!(
+                dst.lookup_raw(TaskId::from(task_id), |bytes| bytes.to_vec())?,
+                source.lookup_raw(TaskId::from(task_id), |bytes| bytes.to_vec())?
+            );
+        }
+        assert!(dst.lookup_raw(TaskId::from(4), |_| ())?.is_some());
+        assert_eq!(
+            dst.database
+                .get(&dst_tx, KeySpace::ForwardTaskCache, b"task c")?,
+            None
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn exported_task_cache_is_imported() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let source_dir = tempfile::tempdir()?;
+        let source =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(source_dir.path())?)?;
+        source.save_serialized_task_cache(
+            2,
+            [(b"task a", 4), (b"task b", 8)]
+                .map(|(task_type, task_id)| Ok((task_type.to_vec(), TaskId::from(task_id)))),
+        )?;
+        source.set_next_free_task_id(TaskId::from(20), false)?;
+        let mut export = Vec::new();
+        source.export_task_cache(&mut export)?;
+
+        let target_dir = tempfile::tempdir()?;
+        let target =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(target_dir.path())?)?;
+        assert_eq!(target.import_task_cache(&export[..])?, 2);
+        assert_eq!(*target.next_free_task_id(), 20);
+        let source_tx = source.database.begin_read_transaction()?;
+        let target_tx = target.database.begin_read_transaction()?;
+        for (task_type, task_id) in [(&b"task a"[..], 4), (&b"task b"[..], 8)] {
+            let key = IntKey::new(task_id);
+            assert_eq!(
+                target
+                    .database
+                    .get(&target_tx, KeySpace::ForwardTaskCache, task_type)?,
+                source
+                    .database
+                    .get(&source_tx, KeySpace::ForwardTaskCache, task_type)?
+            );
+            assert_eq!(
+                target
+                    .database
+                    .get(&target_tx, KeySpace::ReverseTaskCache, key.as_ref())?,
+                Some(task_type)
+            );
+        }
+        drop(target_tx);
+        // Importing again is a no-op
+        assert_eq!(target.import_task_cache(&export[..])?, 0);
+
+        // A store that has `task b` under another id conflicts
+        let conflict_dir = tempfile::tempdir()?;
+        let conflicting = |policy| -> Result<_> {
+            let storage = KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(conflict_dir.path())?,
+                BackingStorageOptions {
+                    task_cache_import_conflicts: policy,
+                    ..Default::default()
+                },
+            )?;
+            storage.save_serialized_task_cache(1, [Ok((b"task b".to_vec(), TaskId::from(5)))])?;
+            Ok(storage)
+        };
+        let storage = conflicting(TaskCacheImportConflictPolicy::Error)?;
+        assert!(storage.import_task_cache(&export[..]).is_err());
+        assert_eq!(*storage.next_free_task_id(), 6);
+        drop(storage);
+        let storage = conflicting(TaskCacheImportConflictPolicy::KeepExisting)?;
+        assert_eq!(storage.import_task_cache(&export[..])?, 1);
+        let tx = storage.database.begin_read_transaction()?;
+        assert_eq!(
+            storage
+                .database
+                .get(&tx, KeySpace::ForwardTaskCache, b"task b")?
+                .map(as_u32)
+                .transpose()?,
+            Some(5)
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lookup_raw_passes_stored_bytes() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let value = storage
+            .value_codec
+            .serialize(&vec![CachedDataItem::ChildrenCount { value: 2 }])?;
+        let mut batch = storage.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(1).as_ref()),
+            Cow::Borrowed(&value),
+        )?;
+        batch.commit()?;
+
+        let raw = storage.lookup_raw(TaskId::from(1), |bytes| bytes.to_vec())?;
+        let tx = storage.database.begin_read_transaction()?;
+        let stored = storage
+            .database
+            .get(&tx, KeySpace::TaskData, IntKey::new(1).as_ref())?
+            .map(|bytes| bytes.to_vec());
+        assert_eq!(raw, stored);
+        assert_eq!(raw.as_deref(), Some(&value[..]));
+        assert_eq!(storage.lookup_raw(TaskId::from(2), |_| ())?, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn delete_task_range_deletes_only_the_range() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let mut batch = storage.database.write_batch()?;
+        for task_id in 1u32..20 {
+            let key = IntKey::new(task_id);
+            let task_type = format!("task {task_id}").into_bytes();
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(key.as_ref()),
+                Cow::Borrowed(b"data"),
+            )?;
+            batch.put(
+                KeySpace::ReverseTaskCache,
+                Cow::Borrowed(key.as_ref()),
+                Cow::Borrowed(&task_type),
+            )?;
+            batch.put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(&task_type),
+                Cow::Borrowed(key.as_ref()),
+            )?;
+        }
+        batch.commit()?;
+
+        let deleted = storage.delete_task_range(TaskId::from(5), TaskId::from(10))?;
+        assert_eq!(deleted, 5);
+
+        let expected = (1u32..5)
+            .chain(10..20)
+            .map(TaskId::from)
+            .collect::<Vec<_>>();
+        assert_eq!(storage.scan_task_index()?, expected);
+        let tx = storage.database.begin_read_transaction()?;
+        for task_id in 1u32..20 {
+            let deleted = (5..10).contains(&task_id);
+            let key = IntKey::new(task_id);
+            let task_type = format!("task {task_id}").into_bytes();
+            assert_eq!(
+                storage
+                    .database
+                    .get(&tx, KeySpace::ReverseTaskCache, key.as_ref())?
+                    .is_none(),
+                deleted
+            );
+            assert_eq!(
+                storage
+                    .database
+                    .get(&tx, KeySpace::ForwardTaskCache, &task_type)?
+                    .is_none(),
+                deleted
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn sharded_snapshot_lookups_resolve_across_shards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = crate::lmdb_sharded_backing_storage(
+            dir.path(),
+            4,
+            Default::default(),
+            Default::default(),
+        )?;
+        let mut updates = ChunkedVec::new();
+        for task in 1..=20 {
+            updates.push(test_utils::children_count_update(task, task));
+        }
+        test_utils::save_updates(&storage, 1, updates)?;
+
+        assert_eq!(storage.next_session_id(), SessionId::from(2));
+        for task in 1..=20 {
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(task), TaskDataCategory::Data) };
+            assert!(matches!(data[..], [CachedDataItem::ChildrenCount { value }] if value == task));
+        }
+        // Every shard received a part of the tasks
+        for shard in storage.database.shards() {
+            let tx = shard.begin_read_transaction()?;
+            let mut tasks = 0;
+            shard.iterate(&tx, KeySpace::TaskData, None, &mut |_: &[u8], _: &[u8]| {
+                tasks += 1;
+                Ok(true)
+            })?;
+            assert_eq!(tasks, 5);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn only_large_task_data_is_compressed() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            compress_min_bytes: Some(256),
+            ..Default::default()
+        })?;
+        let (small, large) = (TaskId::from(1), TaskId::from(2));
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task: small,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        for child in 1..=200 {
+            updates.push(CachedDataUpdate {
+                task: large,
+                key: CachedDataItemKey::Child {
+                    task: TaskId::from(child),
+                },
+                value: Some(CachedDataItemValue::Child { value: () }),
+                old_value: None,
+            });
+        }
+        test_utils::save_updates(&storage, 1, updates)?;
+
+        let tx = storage.database.begin_read_transaction()?;
+        let stored = |task: TaskId| -> Result<Vec<u8>> {
+            Ok(storage
+                .database
+                .get(&tx, KeySpace::TaskData, IntKey::new(*task).as_ref())?
+                .unwrap()
+                .to_vec())
+        };
+        let serialized = |task| storage.lookup_raw(task, |bytes| bytes.to_vec());
+        // Small data is stored as is
+        assert_eq!(Some(stored(small)?), serialized(small)?);
+        // Large data is stored compressed
+        let large_serialized = serialized(large)?.unwrap();
+        assert!(large_serialized.len() >= 256);
+        assert!(stored(large)?.len() < large_serialized.len());
+
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, small, TaskDataCategory::Data) };
+        assert!(matches!(
+            data[..],
+            [CachedDataItem::ChildrenCount { value: 7 }]
+        ));
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, large, TaskDataCategory::Data) };
+        assert_eq!(data.len(), 200);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn snapshot_cost_is_estimated_from_recent_snapshots() -> Result<()> {
+        const TASKS: u32 = 100;
+
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        assert_eq!(
+            storage.estimate_snapshot_cost(0, TASKS as usize),
+            SnapshotCostEstimate::default()
+        );
+        let save = |snapshot: u32| -> Result<Duration> {
+            let mut updates = ChunkedVec::new();
+            for task in snapshot * TASKS + 1..=(snapshot + 1) * TASKS {
+                updates.push(test_utils::children_count_update(task, task));
+            }
+            let start = Instant::now();
+            test_utils::save_updates(&storage, 1, updates)?;
+            Ok(start.elapsed())
+        };
+        for snapshot in 0..3 {
+            save(snapshot)?;
+        }
+
+        let estimate = storage.estimate_snapshot_cost(0, TASKS as usize);
+        let stored_before = storage.stored_task_bytes()?;
+        let duration = save(3)?;
+        // The stored bytes include the keys
+        let bytes = storage.stored_task_bytes()? - stored_before - TASKS as u64 * 4;
+        assert!(
+            estimate.bytes >= bytes / 2 && estimate.bytes <= bytes * 2,
+            "estimated {} bytes, but {bytes} bytes were written",
+            estimate.bytes
+        );
+        // Timings are noisy, so only the order of magnitude is checked
+        assert!(
+            estimate.duration > duration / 20 && estimate.duration < duration * 20,
+            "estimated {:?}, but the snapshot took {duration:?}",
+            estimate.duration
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn meta_entries_list_builtin_keys() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let mut updates = ChunkedVec::new();
+        updates.push(test_utils::children_count_update(1, 7));
+        test_utils::save_updates(&storage, 1, updates)?;
+
+        let entries = storage.meta_entries()?;
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        for key in [
+            META_KEY_OPERATIONS,
+            META_KEY_NEXT_FREE_TASK_ID,
+            META_KEY_SESSION_ID,
+            META_KEY_VALUE_CODEC,
+            META_KEY_MANIFEST,
+            META_KEY_SCHEMA_VERSION,
+            META_KEY_GENERATION,
+            META_KEY_LIFETIME_RESTORED_TASKS,
+            META_KEY_LIFETIME_RESTORED_CACHE_ENTRIES,
+            META_KEY_WRITE_COUNT,
+        ] {
+            assert!(
+                entries.iter().any(|&(k, len)| k == key && len > 0),
+                "missing meta key {key} in {entries:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn truncated_framed_task_data_is_reported() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            frame_values: true,
+            ..Default::default()
+        })?;
+        let task = TaskId::from(1);
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        test_utils::save_updates(&storage, 1, updates)?;
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) };
+        assert!(matches!(
+            data[..],
+            [CachedDataItem::ChildrenCount { value: 7 }]
+        ));
+
+        let key = IntKey::new(*task);
+        let stored = {
+            let tx = storage.database.begin_read_transaction()?;
+            storage
+                .database
+                .get(&tx, KeySpace::TaskData, key.as_ref())?
+                .unwrap()
+                .to_vec()
+        };
+        let mut batch = storage.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(key.as_ref()),
+            Cow::Borrowed(&stored[..stored.len() - 1]),
+        )?;
+        batch.commit()?;
+
+        let tx = storage.database.begin_read_transaction()?;
+        let err = with_task_data(&storage.database, &tx, KeySpace::TaskData, task, |bytes| {
+            deserialize_task_data(storage.value_codec, task, bytes)
+        })
+        .unwrap_err();
+        assert!(err.to_string().starts_with("Truncated value"), "{err}");
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn identical_task_data_shares_one_blob() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            data_deduplication_threshold: Some(0),
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        for task in [1, 2] {
+            updates.push(test_utils::children_count_update(task, 7));
+        }
+        test_utils::save_updates(&storage, 1, updates)?;
+        let blob_entries = || -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+            let tx = storage.database.begin_read_transaction()?;
+            let mut entries = Vec::new();
+            storage
+                .database
+                .iterate(&tx, KeySpace::DataBlob, None, &mut |key, value| {
+                    entries.push((key.to_vec(), value.to_vec()));
+                    Ok(true)
+                })?;
+            Ok(entries)
+        };
+
+        // The content and a reference count of 2
+        let entries = blob_entries()?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries
+            .iter()
+            .any(|(_, value)| value == &2u32.to_le_bytes()));
+
+        storage.delete_task_range(TaskId::from(1), TaskId::from(2))?;
+        assert_eq!(blob_entries()?.len(), 2);
+        let expected = storage
+            .value_codec
+            .serialize(&vec![CachedDataItem::ChildrenCount { value: 7 }])?;
+        assert_eq!(
+            storage.lookup_raw(TaskId::from(2), |bytes| bytes.to_vec())?,
+            Some(expected)
+        );
+
+        storage.delete_task_range(TaskId::from(2), TaskId::from(3))?;
+        assert_eq!(blob_entries()?, Vec::new());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn blobs_are_released_after_disabling_deduplication() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let save = |storage: &KeyValueDatabaseBackingStorage<_>, value, old_value| {
+            let mut updates = ChunkedVec::new();
+            for task in [1, 2] {
+                updates.push(CachedDataUpdate {
+                    task: TaskId::from(task),
+                    key: CachedDataItemKey::ChildrenCount {},
+                    value: Some(CachedDataItemValue::ChildrenCount { value }),
+                    old_value: old_value.map(|value| CachedDataItemValue::ChildrenCount { value }),
+                });
+            }
+            test_utils::save_updates(storage, 1, updates)
+        };
+        let blob_entries = |storage: &KeyValueDatabaseBackingStorage<_>| -> Result<usize> {
+            let tx = storage.database.begin_read_transaction()?;
+            let mut entries = 0;
+            storage
+                .database
+                .iterate(&tx, KeySpace::DataBlob, None, &mut |_, _| {
+                    entries += 1;
+                    Ok(true)
+                })?;
+            Ok(entries)
+        };
+
+        let storage = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            BackingStorageOptions {
+                data_deduplication_threshold: Some(0),
+                ..Default::default()
+            },
+        )?;
+        save(&storage, 7, None)?;
+        assert_eq!(blob_entries(&storage)?, 2);
+        drop(storage);
+
+        let storage = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            BackingStorageOptions::default(),
+        )?;
+        save(&storage, 8, Some(7))?;
+        assert_eq!(blob_entries(&storage)?, 0);
+        let expected = storage
+            .value_codec
+            .serialize(&vec![CachedDataItem::ChildrenCount { value: 8 }])?;
+        assert_eq!(
+            storage.lookup_raw(TaskId::from(1), |bytes| bytes.to_vec())?,
+            Some(expected)
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn delta_encoded_task_data_round_trips() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            data_delta_baseline_interval: Some(2),
+            ..Default::default()
+        })?;
+        let task = TaskId::from(1);
+        for value in 1..=5 {
+            let mut updates = ChunkedVec::new();
+            updates.push(CachedDataUpdate {
+                task,
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value }),
+                old_value: None,
+            });
+            test_utils::save_updates(&storage, 1, updates)?;
+
+            let tx = storage.database.begin_read_transaction()?;
+            let stored = storage
+                .database
+                .get(&tx, KeySpace::TaskData, IntKey::new(*task).as_ref())?
+                .context("task data is missing")?;
+            let delta = Delta::decode(stored).context("task data isn't delta encoded")?;
+            // A new baseline is written every third snapshot
+            assert_eq!(delta.generation, (value - 1) % 3);
+            drop(tx);
+            // Safety: No transaction is passed.
+            let data = unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) };
+            assert_eq!(data.len(), 1);
+            assert!(matches!(data[0], CachedDataItem::ChildrenCount { value: v } if v == value));
+        }
+
+        storage.delete_task_range(task, TaskId::from(2))?;
+        let tx = storage.database.begin_read_transaction()?;
+        assert_eq!(
+            storage
+                .database
+                .get(&tx, KeySpace::DataBlob, &task_baseline_key(*task))?,
+            None
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn small_updates_of_large_tasks_are_amplified() -> Result<()> {
+        let task = TaskId::from(1);
+        let amplification = |data_delta_baseline_interval| -> Result<f64> {
+            let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+                data_delta_baseline_interval,
+                record_write_amplification: true,
+                ..Default::default()
+            })?;
+            let save = |updates: Vec<CachedDataUpdate>| {
+                let mut chunked = ChunkedVec::new();
+                chunked.extend(updates);
+                test_utils::save_updates(&storage, 1, chunked)
+            };
+            save(
+                (2..1000)
+                    .map(|child| CachedDataUpdate {
+                        task,
+                        key: CachedDataItemKey::Child {
+                            task: TaskId::from(child),
+                        },
+                        value: Some(CachedDataItemValue::Child { value: () }),
+                        old_value: None,
+                    })
+                    .chain([CachedDataUpdate {
+                        task,
+                        key: CachedDataItemKey::ChildrenCount {},
+                        value: Some(CachedDataItemValue::ChildrenCount { value: 0 }),
+                        old_value: None,
+                    }])
+                    .collect(),
+            )?;
+            let initial = storage.stats();
+            // Single field updates of the large task
+            for value in 1..=20 {
+                save(vec![CachedDataUpdate {
+                    task,
+                    key: CachedDataItemKey::ChildrenCount {},
+                    value: Some(CachedDataItemValue::ChildrenCount { value }),
+                    old_value: None,
+                }])?;
+            }
+            let stats = storage.stats();
+            BackingStorageStats {
+                logical_update_bytes: stats.logical_update_bytes - initial.logical_update_bytes,
+                written_task_bytes: stats.written_task_bytes - initial.written_task_bytes,
+                ..stats
+            }
+            .write_amplification()
+            .context("No updates were recorded")
+        };
+        let whole_task = amplification(None)?;
+        let delta_encoded = amplification(Some(100))?;
+        assert!(whole_task > 50.0, "{whole_task}");
+        assert!(delta_encoded < 10.0, "{delta_encoded}");
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn snapshots_are_serialized_on_the_configured_pool() -> Result<()> {
+        use std::sync::mpsc::channel;
+
+        let stored_data = |serialization_pool: Option<Arc<rayon::ThreadPool>>| -> Result<_> {
+            let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+                serialization_pool: serialization_pool.clone(),
+                ..Default::default()
+            })?;
+            let workers = storage.serialization_pool()?.current_num_threads();
+            if let Some(pool) = &serialization_pool {
+                assert_eq!(workers, pool.current_num_threads());
+            } else {
+                assert_eq!(workers, available_parallelism()?.get());
+            }
+            let updates = (1..100)
+                .map(|task| {
+                    let mut chunked = ChunkedVec::new();
+                    chunked.extend((0..task).map(|child| CachedDataUpdate {
+                        task: TaskId::from(task),
+                        key: CachedDataItemKey::Child {
+                            task: TaskId::from(1000 + child),
+                        },
+                        value: Some(CachedDataItemValue::Child { value: () }),
+                        old_value: None,
+                    }));
+                    chunked
+                })
+                .collect();
+            test_utils::with_turbo_tasks(|| {
+                storage.save_snapshot(
+                    SessionId::from(1),
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    updates,
+                )
+            })?;
+            let tx = storage.database.begin_read_transaction()?;
+            (1..100)
+                .map(|task| {
+                    Ok(storage
+                        .database
+                        .get(&tx, KeySpace::TaskData, IntKey::new(task).as_ref())?
+                        .context("task data is missing")?
+                        .to_vec())
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let sequential = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build()?);
+        let parallel = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(4).build()?);
+        let expected = stored_data(Some(parallel))?;
+        assert_eq!(stored_data(None)?, expected);
+
+        // While the only worker of the pool is blocked, the snapshot can't be serialized
+        let (release, blocked) = channel::<()>();
+        sequential.spawn(move || {
+            let _ = blocked.recv();
+        });
+        let (done, finished) = channel();
+        scope(|s| {
+            s.spawn(|| done.send(stored_data(Some(sequential.clone()))));
+            std::thread::sleep(Duration::from_millis(200));
+            assert!(finished.try_recv().is_err());
+            release.send(())?;
+            assert_eq!(finished.recv()??, expected);
+            anyhow::Ok(())
+        })
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn orphan_cache_entries_have_no_data() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        storage.save_serialized_task_cache(
+            2,
+            [(b"task a", 1), (b"task b", 2)]
+                .map(|(task_type, task_id)| Ok((task_type.to_vec(), TaskId::from(task_id)))),
+        )?;
+        let mut batch = storage.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(1).as_ref()),
+            Cow::Borrowed(b"data"),
+        )?;
+        batch.commit()?;
+
+        assert_eq!(storage.orphan_cache_entries()?, vec![TaskId::from(2)]);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn pinned_tasks_survive_deletion() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        storage.save_serialized_task_cache(
+            3,
+            [(b"task a", 1), (b"task b", 2), (b"task c", 3)]
+                .map(|(task_type, task_id)| Ok((task_type.to_vec(), TaskId::from(task_id)))),
+        )?;
+        let mut batch = storage.database.write_batch()?;
+        for task_id in 1..=3 {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(b"data"),
+            )?;
+        }
+        batch.commit()?;
+
+        storage.pin_task(TaskId::from(2))?;
+        storage.pin_task(TaskId::from(3))?;
+        storage.unpin_task(TaskId::from(3))?;
+        assert_eq!(storage.pinned_tasks()?, vec![TaskId::from(2)]);
+
+        assert_eq!(
+            storage.delete_task_range(TaskId::from(1), TaskId::from(4))?,
+            2
+        );
+        assert_eq!(storage.scan_task_index()?, vec![TaskId::from(2)]);
+        let tx = storage.database.begin_read_transaction()?;
+        assert_eq!(
+            storage
+                .database
+                .get(&tx, KeySpace::ForwardTaskCache, b"task b")?
+                .map(as_u32)
+                .transpose()?,
+            Some(2)
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn tagged_tasks_are_deleted_together() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        storage.save_serialized_task_cache(
+            4,
+            [
+                (b"task a", 1),
+                (b"task b", 2),
+                (b"task c", 3),
+                (b"task d", 4),
+            ]
+            .map(|(task_type, task_id)| Ok((task_type.to_vec(), TaskId::from(task_id)))),
+        )?;
+        let mut batch = storage.database.write_batch()?;
+        for task_id in 1..=4 {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(b"data"),
+            )?;
+        }
+        batch.commit()?;
+
+        for task_id in [3, 1, 2, 4] {
+            storage.tag_task(TaskId::from(task_id), "route /a")?;
+        }
+        storage.tag_task(TaskId::from(4), "route /b")?;
+        storage.untag_task(TaskId::from(4), "route /a")?;
+        storage.pin_task(TaskId::from(2))?;
+        assert_eq!(
+            storage.tasks_with_tag("route /a")?,
+            [1, 2, 3].map(TaskId::from)
+        );
+
+        assert_eq!(storage.delete_tasks_with_tag("route /a")?, 2);
+        assert_eq!(
+            storage.scan_task_index()?,
+            vec![TaskId::from(2), TaskId::from(4)]
+        );
+        assert_eq!(storage.tasks_with_tag("route /a")?, vec![TaskId::from(2)]);
+        assert_eq!(storage.tasks_with_tag("route /b")?, vec![TaskId::from(4)]);
+        assert!(storage.tasks_with_tag("route /c")?.is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn salvage_copies_readable_tasks() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let src_dir = tempfile::tempdir()?;
+        let dst_dir = tempfile::tempdir()?;
+        let src = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(src_dir.path())?)?;
+        let dst = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dst_dir.path())?)?;
+        let mut updates = ChunkedVec::new();
+        for task in 1..=3 {
+            updates.push(test_utils::children_count_update(task, task));
+        }
+        test_utils::save_updates(&src, 1, updates)?;
+        let mut batch = src.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(4).as_ref()),
+            Cow::Borrowed(b"corrupt"),
+        )?;
+        batch.commit()?;
+
+        let report = test_utils::with_turbo_tasks(|| src.salvage_into(&dst))?;
+        assert_eq!(report.recovered_entries, 3);
+        assert_eq!(report.dropped_entries, 1);
+        assert_eq!(
+            dst.scan_task_index()?,
+            (1..=3).map(TaskId::from).collect::<Vec<_>>()
+        );
+        for task in 1..=3 {
+            // Safety: No transaction is passed.
+            let data = unsafe { dst.lookup_data(None, TaskId::from(task), TaskDataCategory::Data) };
+            assert!(matches!(data[..], [CachedDataItem::ChildrenCount { value }] if value == task));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn oversized_task_data_is_reported() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            outlier_size_factor: Some(10),
+            ..Default::default()
+        })?;
+        let mut tasks = (1..=200)
+            .map(|task| (TaskId::from(task), 100))
+            .collect::<Vec<_>>();
+        tasks.push((TaskId::from(201), 100_000));
+        storage.record_task_sizes(&tasks);
+
+        let stats = storage.stats();
+        assert_eq!(stats.outlier_tasks, 1);
+        assert_eq!(stats.size_histogram[size_bucket(100)], 200);
+        assert_eq!(stats.size_histogram[size_bucket(100_000)], 1);
+        assert_eq!(stats.size_percentile(99), Some(128));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn snapshot_doesnt_see_later_writes() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let write = |task_id: u32, value: &[u8]| -> Result<()> {
+            let mut batch = storage.database.write_batch()?;
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(value),
+            )?;
+            batch.commit()
+        };
+        write(1, b"old")?;
+
+        let snapshot = storage.snapshot()?;
+        write(1, b"new")?;
+        write(2, b"new")?;
+
+        assert_eq!(
+            snapshot.lookup_raw(TaskId::from(1), |bytes| bytes.to_vec())?,
+            Some(b"old".to_vec())
+        );
+        assert_eq!(snapshot.lookup_raw(TaskId::from(2), |_| ())?, None);
+        assert_eq!(snapshot.task_ids()?, vec![TaskId::from(1)]);
+        drop(snapshot);
+        assert_eq!(
+            storage.lookup_raw(TaskId::from(1), |bytes| bytes.to_vec())?,
+            Some(b"new".to_vec())
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn enforce_budget_keeps_recently_accessed_tasks() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let mut storage =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?)?;
+        let mut batch = storage.database.write_batch()?;
+        for task_id in 1..=10 {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(&[0; 96]),
+            )?;
+        }
+        batch.commit()?;
+        let total = storage.stored_task_bytes()?;
+        assert_eq!(total, 1000);
+        storage.options.max_store_bytes = Some(total / 2);
+
+        for task_id in [9, 10] {
+            storage.lookup_raw(TaskId::from(task_id), |_| ())?;
+        }
+        assert_eq!(storage.enforce_budget()?, 5);
+
+        assert!(storage.stored_task_bytes()? <= total / 2);
+        assert_eq!(
+            storage.scan_task_index()?,
+            (6..=10).map(TaskId::from).collect::<Vec<_>>()
+        );
+        assert_eq!(storage.enforce_budget()?, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn next_free_task_id_only_moves_backward_with_force() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        storage.set_next_free_task_id(TaskId::from(100), false)?;
+        assert_eq!(*storage.next_free_task_id(), 100);
+
+        assert!(storage
+            .set_next_free_task_id(TaskId::from(50), false)
+            .is_err());
+        assert_eq!(*storage.next_free_task_id(), 100);
+
+        storage.set_next_free_task_id(TaskId::from(50), true)?;
+        assert_eq!(*storage.next_free_task_id(), 50);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn manifest_is_written_with_the_first_snapshot() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let save = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>| {
+            let mut updates = ChunkedVec::new();
+            updates.push(test_utils::children_count_update(1, 1));
+            test_utils::save_updates(storage, 1, updates)
+        };
+
+        let (dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let created = storage.manifest()?;
+        assert_eq!(created.created_at, created.last_opened_at);
+        assert_eq!(created.created_by_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(created.options.value_codec, ValueCodec::Pot);
+        // Opening the store doesn't write to it
+        assert_eq!(storage.read_manifest()?, None);
+        save(&storage)?;
+        assert_eq!(storage.read_manifest()?, Some(created.clone()));
+        drop(storage);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let storage = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            BackingStorageOptions {
+                startup_parallelism: 4,
+                ..Default::default()
+            },
+        )?;
+        let reopened = storage.manifest()?;
+        assert_eq!(reopened.created_at, created.created_at);
+        assert!(reopened.last_opened_at > created.last_opened_at);
+        assert_eq!(reopened.options.startup_parallelism, 4);
+        assert_eq!(storage.read_manifest()?, Some(created));
+        save(&storage)?;
+        assert_eq!(storage.read_manifest()?, Some(reopened));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn serialization_failure_logs_are_capped() -> Result<()> {
+        use std::collections::BTreeMap;
+
+        let mut stats = None;
+        let events = test_utils::capture_events(|| {
+            // The items are serialized on the pool, which needs to report to the capturing
+            // subscriber as well
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .start_handler(move |_| {
+                    std::mem::forget(tracing::dispatcher::set_default(&dispatch))
+                })
+                .build()
+                .unwrap();
+            let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+                serialization_failure_log_limit: 3,
+                serialization_pool: Some(Arc::new(pool)),
+                ..Default::default()
+            })
+            .unwrap();
+            let mut updates = ChunkedVec::new();
+            for task in 1..=10 {
+                let (key, value) = test_utils::unserializable_cell_data(0).into_key_and_value();
+                updates.push(CachedDataUpdate {
+                    task: TaskId::from(task),
+                    key,
+                    value: Some(value),
+                    old_value: None,
+                });
+            }
+            stats = Some(test_utils::save_updates(&storage, 1, updates).map(|()| storage.stats()));
+        });
+        let stats = stats.unwrap()?;
+        assert_eq!(stats.skipped_optional_items, 10);
+        assert_eq!(stats.failed_required_items, 0);
+
+        let message = |event: &BTreeMap<&str, String>| event.get("message").cloned();
+        let logged = events
+            .iter()
+            .filter_map(message)
+            .filter(|message| message.starts_with("Unable to serialize data item"))
+            .count();
+        assert_eq!(logged, 3);
+        let summaries = events
+            .iter()
+            .filter(|event| {
+                message(event).is_some_and(|m| m.contains("data items failed to serialize"))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0]["skipped_optional_items"], "10");
+        assert_eq!(summaries[0]["failed_required_items"], "0");
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn malformed_task_id_is_reported_as_corrupt() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        batch.put(
+            KeySpace::ForwardTaskCache,
+            Cow::Borrowed(b"task type"),
+            Cow::Borrowed(&[1, 2, 3]),
+        )?;
+        batch.commit()?;
+
+        let tx = database.begin_read_transaction()?;
+        let err = lookup_task_id(&database, &tx, b"task type").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Corrupt>(),
+            Some(&Corrupt {
+                key_space: KeySpace::ForwardTaskCache,
+                key: b"task type".to_vec(),
+                len: 3,
+            })
+        );
+        assert!(err.to_string().contains("expected a 4 byte task id"));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn streaming_snapshot_matches_in_memory_snapshot() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        fn update(task: u32, key: u32, value: Option<u32>) -> CachedDataUpdate {
+            CachedDataUpdate {
+                task: TaskId::from(task),
+                key: CachedDataItemKey::Child {
+                    task: TaskId::from(key),
+                },
+                value: value.map(|_| CachedDataItemValue::Child { value: () }),
+                old_value: None,
+            }
+        }
+        // Sorted by task id. Later updates of a key overwrite earlier ones.
+        let snapshots = [
+            (1..=20)
+                .flat_map(|task| (1..=3).map(move |key| update(task, key, Some(key))))
+                .collect::<Vec<_>>(),
+            (5..=15)
+                .flat_map(|task| [update(task, 1, None), update(task, 4, Some(4))])
+                .chain([update(16, 5, Some(5)), update(16, 5, None)])
+                .collect::<Vec<_>>(),
+        ];
+
+        let in_memory_dir = tempfile::tempdir()?;
+        let in_memory =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(in_memory_dir.path())?)?;
+        let streaming_dir = tempfile::tempdir()?;
+        let streaming =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(streaming_dir.path())?)?;
+        for (session, updates) in snapshots.into_iter().enumerate() {
+            let session_id = SessionId::from(session as u32 + 1);
+            test_utils::with_turbo_tasks(|| {
+                let mut chunked = ChunkedVec::new();
+                for update in updates.iter() {
+                    chunked.push(update.clone());
+                }
+                in_memory.save_snapshot(
+                    session_id,
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    vec![chunked],
+                )?;
+                streaming.save_snapshot_streaming(session_id, Vec::new(), Vec::new(), [], updates)
+            })?;
+        }
+
+        for task in 1..=20 {
+            let task = TaskId::from(task);
+            let lookup = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>| {
+                // Safety: No transaction is passed.
+                let mut data = unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) }
+                    .iter()
+                    .map(|item| match item {
+                        CachedDataItem::Child { task, .. } => **task,
+                        item => panic!("unexpected item {item:?}"),
+                    })
+                    .collect::<Vec<_>>();
+                data.sort_unstable();
+                data
+            };
+            assert_eq!(lookup(&streaming), lookup(&in_memory));
+        }
+
+        let unsorted = [update(2, 1, Some(1)), update(1, 1, Some(1))];
+        let err = test_utils::with_turbo_tasks(|| {
+            streaming.save_snapshot_streaming(
+                SessionId::from(3),
+                Vec::new(),
+                Vec::new(),
+                [],
+                unsorted,
+            )
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("must be sorted by task id"));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn mismatched_task_cache_entries_are_caught() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::new(dir.path())?;
+        let mut batch = database.write_batch()?;
+        for (task_id, forward_key, reverse_value) in
+            [(1u32, b"task a", b"task a"), (2, b"task b", b"task c")]
+        {
+            batch.put(
+                KeySpace::ForwardTaskCache,
+                Cow::Borrowed(forward_key),
+                Cow::Borrowed(&task_id.to_le_bytes()),
+            )?;
+            batch.put(
+                KeySpace::ReverseTaskCache,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(reverse_value),
+            )?;
+        }
+        verify_task_cache_entry(&batch, b"task a", 1)?;
+        let err = verify_task_cache_entry(&batch, b"task b", 2).unwrap_err();
+        assert!(err.to_string().contains("out of sync"));
+        // An entry pointing to another task is caught as well
+        assert!(verify_task_cache_entry(&batch, b"task a", 2).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn warm_start_loads_the_task_index_cache() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let cache_path = dir.path().join("task_index");
+        let open = || {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(&dir.path().join("db"))?,
+                BackingStorageOptions {
+                    task_index_cache: Some(cache_path.clone()),
+                    ..Default::default()
+                },
+            )
+        };
+        let save = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>, task: u32| {
+            let mut updates = ChunkedVec::new();
+            updates.push(test_utils::children_count_update(task, task));
+            test_utils::save_updates(storage, 1, updates)
+        };
+
+        let storage = open()?;
+        save(&storage, 3)?;
+        save(&storage, 5)?;
+        storage.shutdown()?;
+        assert!(cache_path.exists());
+        drop(storage);
+
+        let storage = open()?;
+        assert_eq!(
+            storage.scan_task_index()?,
+            vec![TaskId::from(3), TaskId::from(5)]
+        );
+        let stats = storage.stats();
+        assert_eq!(stats.task_index_cache_hits, 1);
+        assert_eq!(stats.task_index_scans, 0);
+        drop(storage);
+
+        // The cache is consumed when it's loaded, so an unclean shutdown leads to a scan
+        let storage = open()?;
+        assert_eq!(
+            storage.scan_task_index()?,
+            vec![TaskId::from(3), TaskId::from(5)]
+        );
+        assert_eq!(storage.stats().task_index_scans, 1);
+
+        // A write after loading the cache leads to a scan as well
+        storage.shutdown()?;
+        drop(storage);
+        let storage = open()?;
+        save(&storage, 7)?;
+        assert_eq!(
+            storage.scan_task_index()?,
+            vec![TaskId::from(3), TaskId::from(5), TaskId::from(7)]
+        );
+        let stats = storage.stats();
+        assert_eq!(stats.task_index_cache_hits, 0);
+        assert_eq!(stats.task_index_scans, 1);
+
+        // So does a write after the shutdown that keeps the session and the next free task id
+        storage.shutdown()?;
+        storage.delete_task_range(TaskId::from(5), TaskId::from(6))?;
+        drop(storage);
+        let storage = open()?;
+        assert_eq!(
+            storage.scan_task_index()?,
+            vec![TaskId::from(3), TaskId::from(7)]
+        );
+        let stats = storage.stats();
+        assert_eq!(stats.task_index_cache_hits, 0);
+        assert_eq!(stats.task_index_scans, 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn duplicate_task_ids_follow_the_policy() -> Result<()> {
+        for policy in [
+            DuplicateTaskIdPolicy::Error,
+            DuplicateTaskIdPolicy::LastWins,
+            DuplicateTaskIdPolicy::FirstWins,
+        ] {
+            let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+                duplicate_task_ids: policy,
+                ..Default::default()
+            })?;
+            let result = storage.save_serialized_task_cache(
+                3,
+                [
+                    Ok((b"task a".to_vec(), TaskId::from(1))),
+                    Ok((b"task a".to_vec(), TaskId::from(1))),
+                    Ok((b"task b".to_vec(), TaskId::from(1))),
+                ],
+            );
+
+            let tx = storage.database.begin_read_transaction()?;
+            let reverse = storage
+                .database
+                .get(&tx, KeySpace::ReverseTaskCache, IntKey::new(1).as_ref())?
+                .map(|bytes| bytes.to_vec());
+            let forward = |task_type: &[u8]| lookup_task_id(&storage.database, &tx, task_type);
+            match policy {
+                DuplicateTaskIdPolicy::Error => {
+                    let err = result.unwrap_err();
+                    assert!(err
+                        .to_string()
+                        .contains("Task 1 is mapped to different task types"));
+                    // Nothing was committed
+                    assert_eq!(reverse, None);
+                }
+                DuplicateTaskIdPolicy::LastWins => {
+                    result?;
+                    assert_eq!(reverse.as_deref(), Some(&b"task b"[..]));
+                    assert_eq!(forward(b"task a")?, Some(TaskId::from(1)));
+                    assert_eq!(forward(b"task b")?, Some(TaskId::from(1)));
+                }
+                DuplicateTaskIdPolicy::FirstWins => {
+                    result?;
+                    assert_eq!(reverse.as_deref(), Some(&b"task a"[..]));
+                    assert_eq!(forward(b"task a")?, Some(TaskId::from(1)));
+                    assert_eq!(forward(b"task b")?, None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn exhausted_task_id_space_is_reported() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        storage.set_next_free_task_id(TaskId::from(u32::MAX - 1), false)?;
+        storage.save_serialized_task_cache(
+            1,
+            [Ok((b"task a".to_vec(), TaskId::from(u32::MAX - 1)))],
+        )?;
+        assert_eq!(*storage.next_free_task_id(), u32::MAX);
+
+        let err = storage
+            .save_serialized_task_cache(1, [Ok((b"task b".to_vec(), TaskId::from(u32::MAX)))])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TaskIdSpaceExhausted>(),
+            Some(&TaskIdSpaceExhausted { task_id: u32::MAX })
+        );
+        // Nothing of the failed batch is committed
+        assert_eq!(*storage.next_free_task_id(), u32::MAX);
+        let tx = storage.database.begin_read_transaction()?;
+        assert!(lookup_task_id(&storage.database, &tx, b"task b")?.is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn empty_snapshots_are_skipped() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        for skip_empty_snapshots in [true, false] {
+            let dir = tempfile::tempdir()?;
+            let open = || {
+                KeyValueDatabaseBackingStorage::with_options(
+                    LmbdKeyValueDatabase::new(dir.path())?,
+                    BackingStorageOptions {
+                        skip_empty_snapshots,
+                        ..Default::default()
+                    },
+                )
+            };
+            let storage = open()?;
+            test_utils::with_turbo_tasks(|| {
+                storage.save_snapshot(
+                    SessionId::from(1),
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                )
+            })?;
+            let stats = storage.stats();
+            assert_eq!(stats.skipped_empty_snapshots, skip_empty_snapshots as u64);
+            assert_eq!(stats.committed_snapshots, !skip_empty_snapshots as u64);
+            drop(storage);
+
+            // The session id is only persisted by a committed snapshot
+            let expected = if skip_empty_snapshots { 1 } else { 2 };
+            assert_eq!(open()?.next_session_id(), SessionId::from(expected));
+        }
+        Ok(())
+    }
+
+    /// Fails write batches with [`ReadOnlyFilesystem`] once `read_only` is set, like a
+    /// filesystem that is remounted read-only.
+    #[cfg(feature = "lmdb")]
+    struct ReadOnlyRemount<T> {
+        database: T,
+        read_only: AtomicBool,
+    }
+
+    #[cfg(feature = "lmdb")]
+    impl<T: KeyValueDatabase> KeyValueDatabase for ReadOnlyRemount<T> {
+        type ReadTransaction<'l>
+            = T::ReadTransaction<'l>
+        where
+            Self: 'l;
+
+        fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+            tx: &'r Self::ReadTransaction<'l>,
+        ) -> &'r Self::ReadTransaction<'i> {
+            T::lower_read_transaction(tx)
+        }
+
+        fn begin_read_transaction(&self) -> Result<Self::ReadTransaction<'_>> {
+            self.database.begin_read_transaction()
+        }
+
+        type ValueBuffer<'l>
+            = T::ValueBuffer<'l>
+        where
+            Self: 'l;
+
+        fn get<'l, 'db: 'l>(
+            &'l self,
+            transaction: &'l Self::ReadTransaction<'db>,
+            key_space: KeySpace,
+            key: &[u8],
+        ) -> Result<Option<Self::ValueBuffer<'l>>> {
+            self.database.get(transaction, key_space, key)
+        }
+
+        fn iterate<'l, 'db: 'l>(
+            &'l self,
+            transaction: &'l Self::ReadTransaction<'db>,
+            key_space: KeySpace,
+            start: Option<&[u8]>,
+            f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>,
+        ) -> Result<()> {
+            self.database.iterate(transaction, key_space, start, f)
+        }
+
+        type WriteBatch<'l>
+            = T::WriteBatch<'l>
+        where
+            Self: 'l;
+
+        fn write_batch(&self) -> Result<Self::WriteBatch<'_>> {
+            if self.read_only.load(Ordering::Relaxed) {
+                return Err(anyhow!(ReadOnlyFilesystem));
+            }
+            self.database.write_batch()
+        }
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn read_only_filesystem_follows_the_policy() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        for policy in [
+            ReadOnlyFilesystemPolicy::Fail,
+            ReadOnlyFilesystemPolicy::Downgrade,
+        ] {
+            let dir = tempfile::tempdir()?;
+            let storage = KeyValueDatabaseBackingStorage::with_options(
+                ReadOnlyRemount {
+                    database: LmbdKeyValueDatabase::new(dir.path())?,
+                    read_only: AtomicBool::new(false),
+                },
+                BackingStorageOptions {
+                    skip_empty_snapshots: false,
+                    read_only_filesystem: policy,
+                    ..Default::default()
+                },
+            )?;
+            let save = |session_id: u32| {
+                test_utils::with_turbo_tasks(|| {
+                    storage.save_snapshot(
+                        SessionId::from(session_id),
+                        Vec::new(),
+                        Vec::new(),
+                        Vec::new(),
+                        Vec::new(),
+                    )
+                })
+            };
+            save(1)?;
+            storage.database.read_only.store(true, Ordering::Relaxed);
+            assert!(save(2).unwrap_err().is::<ReadOnlyFilesystem>());
+            let downgraded = policy == ReadOnlyFilesystemPolicy::Downgrade;
+            assert_eq!(storage.is_read_only(), downgraded);
+            // A downgraded store skips following snapshots instead of failing them
+            assert_eq!(save(3).is_ok(), downgraded);
+            assert_eq!(storage.stats().committed_snapshots, 1);
+        }
+        Ok(())
+    }
 
-                {
-                    let span = tracing::trace_span!(
-                        "organize updates",
-                        updates = updates.len(),
-                        tasks = tracing::field::Empty
-                    )
-                    .entered();
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn commit_hook_fires_after_committed_snapshots() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
 
-                    // Organize the updates by task
-                    for CachedDataUpdate {
-                        task,
-                        key,
-                        value,
-                        old_value,
-                    } in updates.into_iter()
-                    {
-                        let data = task_updates.entry(task).or_default();
-                        match data.entry(key) {
-                            Entry::Occupied(mut entry) => {
-                                entry.get_mut().1 = value;
-                            }
-                            Entry::Vacant(entry) => {
-                                entry.insert((old_value, value));
-                            }
+        let dir = tempfile::tempdir()?;
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let storage = KeyValueDatabaseBackingStorage::with_options(
+            ReadOnlyRemount {
+                database: LmbdKeyValueDatabase::new(dir.path())?,
+                read_only: AtomicBool::new(false),
+            },
+            BackingStorageOptions {
+                skip_empty_snapshots: false,
+                on_commit: Some(Arc::new({
+                    let commits = commits.clone();
+                    move |info: &CommitInfo| commits.lock().push(*info)
+                })),
+                ..Default::default()
+            },
+        )?;
+        storage.set_next_free_task_id(TaskId::from(10), false)?;
+        let updates = |tasks: Range<u32>| {
+            tasks
+                .map(|task| CachedDataUpdate {
+                    task: TaskId::from(task),
+                    key: CachedDataItemKey::ChildrenCount {},
+                    value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+                    old_value: None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let save = |tasks: Range<u32>| {
+            let mut chunked = ChunkedVec::new();
+            chunked.extend(updates(tasks));
+            test_utils::save_updates(&storage, 1, chunked)
+        };
+
+        save(1..4)?;
+        storage.save_snapshot_streaming(
+            SessionId::from(2),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            updates(4..6),
+        )?;
+        let expected = |written_tasks| CommitInfo {
+            op_count: 0,
+            written_tasks,
+            next_free_task_id: TaskId::from(10),
+        };
+        let infos = commits.lock().clone();
+        assert_eq!(infos.len(), 2);
+        for (info, written_tasks) in infos.iter().zip([3, 2]) {
+            // The session id, the value codec, the next free task id and the operations are
+            // written, too
+            assert!(info.op_count > written_tasks, "{info:?}");
+            assert_eq!(
+                CommitInfo {
+                    op_count: 0,
+                    ..*info
+                },
+                expected(written_tasks)
+            );
+        }
+
+        storage.database.read_only.store(true, Ordering::Relaxed);
+        assert!(save(6..8).is_err());
+        assert_eq!(commits.lock().len(), 2);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn snapshot_progress_covers_merging_the_updates() -> Result<()> {
+        const TASKS: u32 = 5000;
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            progress: Some(Arc::new({
+                let reports = reports.clone();
+                move |processed, total| reports.lock().push((processed, total))
+            })),
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        for task in 1..=TASKS {
+            updates.push(test_utils::children_count_update(task, task));
+        }
+        test_utils::save_updates(&storage, 1, updates)?;
+
+        // Every update is counted when it's organized by task and when it's merged
+        let total = 2 * TASKS as usize;
+        let reports = reports.lock().clone();
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0), "{reports:?}");
+        assert!(reports.iter().all(|&(_, t)| t == total), "{reports:?}");
+        assert!(
+            reports
+                .iter()
+                .any(|&(processed, _)| processed > TASKS as usize && processed < total),
+            "{reports:?}"
+        );
+        assert_eq!(reports.last(), Some(&(total, total)));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn panic_during_snapshot_leaves_store_unchanged() -> Result<()> {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let panic_on_progress = Arc::new(AtomicBool::new(true));
+        let storage = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(dir.path())?,
+            BackingStorageOptions {
+                progress: Some(Arc::new({
+                    let panic_on_progress = panic_on_progress.clone();
+                    move |_, _| {
+                        if panic_on_progress.load(Ordering::Relaxed) {
+                            panic!("progress callback panicked");
                         }
                     }
+                })),
+                ..Default::default()
+            },
+        )?;
+        let task = TaskId::from(1);
+        let save = || {
+            let mut updates = ChunkedVec::new();
+            updates.push(CachedDataUpdate {
+                task,
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+                old_value: None,
+            });
+            test_utils::save_updates(&storage, 1, updates)
+        };
 
-                    span.record("tasks", task_updates.len());
-                }
+        // The progress callback panics while the data updates are merged
+        assert!(catch_unwind(AssertUnwindSafe(save)).is_err());
+        assert_eq!(storage.next_session_id(), SessionId::from(1));
+        // Safety: No transaction is passed.
+        assert!(unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) }.is_empty());
 
-                {
-                    let span = tracing::trace_span!(
-                        "dedupe updates",
-                        before = task_updates.len(),
-                        after = tracing::field::Empty
-                    )
-                    .entered();
+        // The environment is still usable
+        panic_on_progress.store(false, Ordering::Relaxed);
+        save()?;
+        assert_eq!(storage.next_session_id(), SessionId::from(2));
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) };
+        assert!(matches!(
+            data[..],
+            [CachedDataItem::ChildrenCount { value: 7 }]
+        ));
+        Ok(())
+    }
 
-                    // Remove no-op task updates (so we have less tasks to restore)
-                    task_updates.retain(|_, data| {
-                        data.retain(|_, (old_value, value)| *old_value != *value);
-                        !data.is_empty()
-                    });
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn large_operations_are_split_into_chunks() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
 
-                    span.record("after", task_updates.len());
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            operations_chunk_size: Some(1024),
+            ..Default::default()
+        })?;
+        let chunks = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>| {
+            let tx = storage.database.begin_read_transaction().unwrap();
+            (0..)
+                .take_while(|chunk| {
+                    storage
+                        .database
+                        .get(
+                            &tx,
+                            KeySpace::Infra,
+                            IntKey::new(META_KEY_OPERATIONS_CHUNKS + chunk).as_ref(),
+                        )
+                        .unwrap()
+                        .is_some()
+                })
+                .count()
+        };
+        let save = |operations| {
+            test_utils::with_turbo_tasks(|| {
+                storage.save_snapshot(
+                    SessionId::from(1),
+                    operations,
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                )
+            })
+        };
+
+        let operations = (0..1000)
+            .map(|i| {
+                Arc::new(AnyOperation::Nested(vec![
+                    AnyOperation::Nested(Vec::new());
+                    i % 7
+                ]))
+            })
+            .collect::<Vec<_>>();
+        let serialized = pot::to_vec(&operations)?;
+        save(operations)?;
+        assert_eq!(chunks(&storage), serialized.len().div_ceil(1024));
+        assert!(chunks(&storage) > 1);
+        assert_eq!(pot::to_vec(&storage.uncompleted_operations())?, serialized);
+
+        // Chunks of the previous operations are removed
+        let operations = vec![Arc::new(AnyOperation::Nested(Vec::new()))];
+        let serialized = pot::to_vec(&operations)?;
+        save(operations)?;
+        assert_eq!(chunks(&storage), 0);
+        assert_eq!(pot::to_vec(&storage.uncompleted_operations())?, serialized);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn independent_stores_assign_the_same_content_addressed_ids() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let task_types =
+            ["First", "Second", "Third"].map(|name| Arc::new(test_utils::test_task_type(name, 1)));
+        let open = |dir: &Path| {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir)?,
+                BackingStorageOptions {
+                    task_id_allocation: TaskIdAllocation::ContentAddressed,
+                    ..Default::default()
+                },
+            )
+        };
+        // The stores discover the task types in different orders
+        let assign = |order: &[usize]| -> Result<Vec<Option<TaskId>>> {
+            let dir = tempfile::tempdir()?;
+            let storage = open(dir.path())?;
+            let mut updates = ChunkedVec::new();
+            test_utils::with_turbo_tasks(|| {
+                for &index in order {
+                    let task_type = &task_types[index];
+                    let task_id = storage.content_addressed_task_id(task_type).unwrap();
+                    updates.push((task_type.clone(), task_id));
                 }
+            });
+            storage.save_task_cache_only(updates)?;
+            drop(storage);
+
+            let storage = open(dir.path())?;
+            Ok(test_utils::with_turbo_tasks(|| {
+                task_types
+                    .iter()
+                    // Safety: No transaction is passed.
+                    .map(|task_type| unsafe { storage.forward_lookup_task_cache(None, task_type) })
+                    .collect()
+            }))
+        };
+        let ids = assign(&[0, 1, 2])?;
+        assert!(ids.iter().all(Option::is_some));
+        assert_eq!(assign(&[2, 0, 1])?, ids);
+        assert_eq!(assign(&[1, 2, 0])?, ids);
+        Ok(())
+    }
 
-                let tx = database.begin_read_transaction()?;
+    #[test]
+    fn content_addressed_task_ids_are_stable() {
+        for task_type in [&b"a"[..], b"some task type", &[0; 1024]] {
+            let id = content_addressed_task_id(task_type);
+            assert_eq!(id, content_addressed_task_id(task_type));
+            assert!(*id >= 1 && *id < TRANSIENT_TASK_BIT);
+        }
+        assert_ne!(
+            content_addressed_task_id(b"task a"),
+            content_addressed_task_id(b"task b")
+        );
+        // Pinned, so stores stay valid across architectures and versions
+        assert_eq!(*content_addressed_task_id(b"a"), 1814213519);
+        assert_eq!(*content_addressed_task_id(b"some task type"), 928168699);
+        assert_eq!(*content_addressed_task_id(&[0; 200]), 1112447957);
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lookups_emit_events() -> Result<()> {
+        let task_type = |this| test_utils::test_task_type("Traced", this);
+
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            lookup_events: true,
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        updates.push((Arc::new(task_type(1)), TaskId::from(2)));
+        storage.save_task_cache_only(updates)?;
+        let mut updates = ChunkedVec::new();
+        updates.push(test_utils::children_count_update(2, 1));
+        test_utils::save_updates(&storage, 1, updates)?;
+
+        let events = test_utils::capture_events(|| {
+            test_utils::with_turbo_tasks(|| unsafe {
+                assert_eq!(
+                    storage.forward_lookup_task_cache(None, &task_type(1)),
+                    Some(TaskId::from(2))
+                );
+                assert_eq!(storage.forward_lookup_task_cache(None, &task_type(3)), None);
+                assert!(storage
+                    .reverse_lookup_task_cache(None, TaskId::from(2))
+                    .is_some());
+                assert!(storage
+                    .reverse_lookup_task_cache(None, TaskId::from(3))
+                    .is_none());
+                assert_eq!(
+                    storage
+                        .lookup_data(None, TaskId::from(2), TaskDataCategory::Data)
+                        .len(),
+                    1
+                );
+                assert!(storage
+                    .lookup_data(None, TaskId::from(3), TaskDataCategory::Data)
+                    .is_empty());
+            })
+        });
 
-                let span = tracing::trace_span!(
-                    "restore, update and serialize",
-                    tasks = task_updates.len(),
-                    restored_tasks = tracing::field::Empty
+        let summary = events
+            .iter()
+            .filter(|fields| fields.contains_key("lookup"))
+            .map(|fields| {
+                (
+                    fields["lookup"].as_str(),
+                    fields["hit"].as_str(),
+                    fields.get("task_id").map(String::as_str),
+                    fields.get("task_type").map(String::as_str),
                 )
-                .entered();
-                let mut restored_tasks = 0;
-
-                // Restore the old task data, apply the updates and serialize the new data
-                let mut tasks = Vec::with_capacity(task_updates.len());
-                let mut map = FxHashMap::with_capacity_and_hasher(128, Default::default());
-                for (task, updates) in task_updates {
-                    // Restore the old task data
-                    if let Some(old_data) =
-                        database.get(&tx, key_space, IntKey::new(*task).as_ref())?
-                    {
-                        let old_data: Vec<CachedDataItem> = match pot::from_slice(old_data.borrow())
-                        {
-                            Ok(d) => d,
-                            Err(_) => serde_path_to_error::deserialize(
-                                &mut pot::de::SymbolList::new()
-                                    .deserializer_for_slice(old_data.borrow())?,
-                            )
-                            .with_context(|| {
-                                let old_data: &[u8] = old_data.borrow();
-                                anyhow!("Unable to deserialize old value of {task}: {old_data:?}")
-                            })?,
-                        };
-                        map.extend(old_data.into_iter().map(|item| item.into_key_and_value()));
-                        restored_tasks += 1;
-                    }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("forward", "true", Some("2"), Some("*Traced::method")),
+                ("forward", "false", None, Some("*Traced::method")),
+                ("reverse", "true", Some("2"), Some("*Traced::method")),
+                ("reverse", "false", Some("3"), None),
+                ("data", "true", Some("2"), None),
+                ("data", "false", Some("3"), None),
+            ]
+        );
+        Ok(())
+    }
 
-                    // Apply update
-                    for (key, (_, value)) in updates {
-                        if let Some(value) = value {
-                            map.insert(key, value);
-                        } else {
-                            map.remove(&key);
-                        }
-                    }
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn full_transactions_are_split() -> Result<()> {
+        use crate::database::{LmbdKeyValueDatabase, LmdbOptions, TransactionFull};
 
-                    // Get new data
-                    let data = map
-                        .drain()
-                        .map(|(key, value)| CachedDataItem::from_key_and_value(key, value))
-                        .collect::<Vec<_>>();
+        const TASKS: u32 = 100;
 
-                    // Serialize new data
-                    let value = serialize(task, data)?;
+        let save = |commit_chunk_size| -> Result<(_, Result<()>)> {
+            let dir = tempfile::tempdir()?;
+            let storage = KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::with_options(
+                    dir.path(),
+                    LmdbOptions {
+                        max_transaction_bytes: Some(600),
+                        ..Default::default()
+                    },
+                )?,
+                BackingStorageOptions {
+                    commit_chunk_size,
+                    ..Default::default()
+                },
+            )?;
+            let mut updates = ChunkedVec::new();
+            updates.extend((1..=TASKS).map(|task| CachedDataUpdate {
+                task: TaskId::from(task),
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value: task }),
+                old_value: None,
+            }));
+            let result = test_utils::with_turbo_tasks(|| {
+                storage.save_snapshot(
+                    SessionId::from(1),
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    vec![updates],
+                )
+            });
+            Ok(((dir, storage), result))
+        };
+
+        let ((_dir, storage), result) = save(Some(10))?;
+        result?;
+        let stats = storage.stats();
+        assert_eq!(stats.committed_snapshots, 1);
+        assert_eq!(stats.split_snapshots, 1);
+        let snapshot = storage.snapshot()?;
+        for task in 1..=TASKS {
+            let data = snapshot.lookup_data(TaskId::from(task), TaskDataCategory::Data)?;
+            assert!(
+                matches!(&data[..], [CachedDataItem::ChildrenCount { value }] if *value == task),
+                "{task}"
+            );
+        }
+
+        // Without splitting, nothing is written
+        let ((_dir, storage), result) = save(None)?;
+        let err = result.unwrap_err();
+        assert!(err.is::<TransactionFull>());
+        assert!(format!("{err:#}").contains("commit_chunk_size"));
+        assert!(storage.lookup_raw(TaskId::from(1), |_| ())?.is_none());
+
+        // The chunks don't fit either
+        let ((_dir, storage), result) = save(Some(TASKS as usize))?;
+        let err = result.unwrap_err();
+        assert!(err.is::<TransactionFull>());
+        assert!(format!("{err:#}").contains("smaller"));
+        assert_eq!(storage.stats().committed_snapshots, 0);
+
+        // LMDB's own limit aborts the transaction, so it's not split
+        let err = transaction_full_error(
+            anyhow::Error::new(TransactionFull { committable: false }),
+            Some(10),
+        );
+        assert!(format!("{err:#}").contains("max_transaction_bytes"));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn contains_tasks_preserves_the_order() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let update = |task: u32| {
+            let mut updates = ChunkedVec::new();
+            updates.push(test_utils::children_count_update(task, task));
+            updates
+        };
+        test_utils::with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                Vec::new(),
+                Vec::new(),
+                // A task with only meta
+                vec![update(4)],
+                vec![update(2)],
+            )
+        })?;
+
+        let task_ids = [5, 2, 3, 4, 2, 1].map(TaskId::from);
+        assert_eq!(
+            storage.contains_tasks(&task_ids),
+            [false, true, false, true, true, false]
+        );
+        assert!(storage.contains_tasks(&[]).is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn cached_data_is_invalidated_by_writes() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            data_cache_capacity: Some(16),
+            ..Default::default()
+        })?;
+        let save = |updates: &[(u32, u32)]| {
+            let mut chunk = ChunkedVec::new();
+            chunk.extend(updates.iter().map(|&(task, value)| CachedDataUpdate {
+                task: TaskId::from(task),
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value }),
+                old_value: None,
+            }));
+            test_utils::save_updates(&storage, 1, chunk)
+        };
+        let lookup = |task: u32| {
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(task), TaskDataCategory::Data) };
+            match &data[..] {
+                [CachedDataItem::ChildrenCount { value }] => Some(*value),
+                [] => None,
+                _ => panic!("unexpected data of {task}"),
+            }
+        };
+        let hits = || storage.stats().data_cache_hits;
+
+        save(&[(2, 1), (3, 10)])?;
+        assert_eq!(lookup(2), Some(1));
+        assert_eq!(lookup(3), Some(10));
+        assert_eq!(hits(), 0);
+        assert_eq!(lookup(2), Some(1));
+        assert_eq!(hits(), 1);
+        // Hits share the cached data instead of copying it
+        let lookup_shared = || {
+            // Safety: No transaction is passed.
+            unsafe { storage.lookup_data(None, TaskId::from(2), TaskDataCategory::Data) }
+        };
+        assert!(Arc::ptr_eq(&lookup_shared(), &lookup_shared()));
+        assert_eq!(hits(), 3);
+
+        save(&[(2, 2)])?;
+        assert_eq!(lookup(2), Some(2));
+        assert_eq!(hits(), 3);
+        // The data of the unrelated task is still cached
+        assert_eq!(lookup(3), Some(10));
+        assert_eq!(hits(), 4);
+
+        storage.delete_tasks(vec![TaskId::from(2)])?;
+        assert_eq!(lookup(2), None);
+        assert_eq!(hits(), 4);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn maintenance_runs_until_dropped() -> Result<()> {
+        use std::sync::mpsc::channel;
 
-                    // Store the new task data
-                    tasks.push((task, value));
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let storage = Arc::new(KeyValueDatabaseBackingStorage::new(
+            LmbdKeyValueDatabase::new(dir.path())?,
+        )?);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(sender);
+        let check_readers: MaintenanceTask<LmbdKeyValueDatabase> = Arc::new({
+            let runs = runs.clone();
+            move |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>| {
+                let stale_readers = storage.database().check_stale_readers()?;
+                runs.fetch_add(1, Ordering::SeqCst);
+                let _ = sender.lock().send(stale_readers);
+                Ok(())
+            }
+        });
+        let maintenance =
+            storage.start_maintenance(Duration::from_millis(10), vec![check_readers])?;
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10))?, 0);
+
+        drop(maintenance);
+        let stopped_runs = runs.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), stopped_runs);
+        // The thread doesn't keep the storage alive
+        assert_eq!(Arc::strong_count(&storage), 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn state_digest_ignores_values() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let save = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>,
+                    items: Vec<CachedDataItem>|
+         -> Result<()> {
+            let mut updates = ChunkedVec::new();
+            updates.extend(items.into_iter().map(|item| {
+                let (key, value) = item.into_key_and_value();
+                CachedDataUpdate {
+                    task: TaskId::from(4),
+                    key,
+                    value: Some(value),
+                    old_value: None,
                 }
+            }));
+            test_utils::save_updates(storage, 1, updates)
+        };
+        let child = |task: u32| CachedDataItem::Child {
+            task: TaskId::from(task),
+            value: (),
+        };
 
-                span.record("restored_tasks", restored_tasks);
-                Ok(tasks)
-            })
-        })
-        .collect::<Result<Vec<_>>>()
-}
+        let first_dir = tempfile::tempdir()?;
+        let first =
+            KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(first_dir.path())?)?;
+        save(
+            &first,
+            vec![
+                child(7),
+                child(5),
+                CachedDataItem::ChildrenCount { value: 2 },
+            ],
+        )?;
+        let second_dir = tempfile::tempdir()?;
+        let second = KeyValueDatabaseBackingStorage::with_options(
+            LmbdKeyValueDatabase::new(second_dir.path())?,
+            BackingStorageOptions {
+                value_codec: ValueCodec::PotV4,
+                ..Default::default()
+            },
+        )?;
+        save(
+            &second,
+            vec![
+                CachedDataItem::ChildrenCount { value: 3 },
+                child(5),
+                child(7),
+            ],
+        )?;
+        let digest = first.state_digest()?;
+        assert_eq!(digest, second.state_digest()?);
+        assert_eq!(
+            digest,
+            vec![(
+                TaskId::from(4),
+                vec![
+                    CachedDataItemKey::Child {
+                        task: TaskId::from(5)
+                    },
+                    CachedDataItemKey::Child {
+                        task: TaskId::from(7)
+                    },
+                    CachedDataItemKey::ChildrenCount {},
+                ]
+            )]
+        );
 
-fn serialize(task: TaskId, mut data: Vec<CachedDataItem>) -> Result<Vec<u8>> {
-    Ok(match pot::to_vec(&data) {
-        #[cfg(not(feature = "verify_serialization"))]
-        Ok(value) => value,
-        _ => {
-            let mut error = Ok(());
-            data.retain(|item| {
-                let mut buf = Vec::<u8>::new();
-                let mut symbol_map = pot::ser::SymbolMap::new();
-                let mut serializer = symbol_map.serializer_for(&mut buf).unwrap();
-                if let Err(err) = serde_path_to_error::serialize(item, &mut serializer) {
-                    if item.is_optional() {
-                        #[cfg(feature = "verify_serialization")]
-                        println!("Skipping non-serializable optional item: {item:?}");
-                    } else {
-                        error = Err(err).context({
-                            anyhow!("Unable to serialize data item for {task}: {item:#?}")
-                        });
-                    }
-                    false
-                } else {
-                    #[cfg(feature = "verify_serialization")]
-                    {
-                        let deserialize: Result<CachedDataItem, _> =
-                            serde_path_to_error::deserialize(
-                                &mut pot::de::SymbolList::new()
-                                    .deserializer_for_slice(&buf)
-                                    .unwrap(),
-                            );
-                        if let Err(err) = deserialize {
-                            println!(
-                                "Data item would not be deserializable {task}: {err:?}\n{item:#?}"
-                            );
-                            return false;
-                        }
-                    }
-                    true
+        save(&second, vec![child(6)])?;
+        assert_ne!(digest, second.state_digest()?);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn invalid_items_are_skipped() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let open = |skip_invalid_items| {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                BackingStorageOptions {
+                    skip_invalid_items,
+                    ..Default::default()
+                },
+            )
+        };
+        let strict = open(false)?;
+        // The item in the middle isn't a valid data item
+        let items = vec![
+            pot::Value::from_serialize(CachedDataItem::ChildrenCount { value: 3 })?,
+            pot::Value::from_serialize("NoLongerAnItem")?,
+            pot::Value::from_serialize(CachedDataItem::Child {
+                task: TaskId::from(5),
+                value: (),
+            })?,
+        ];
+        let mut batch = strict.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(4).as_ref()),
+            Cow::Owned(strict.value_codec.serialize(&items)?),
+        )?;
+        batch.commit()?;
+
+        assert!(strict
+            .snapshot()?
+            .lookup_data(TaskId::from(4), TaskDataCategory::Data)
+            .is_err());
+        let lenient = open(true)?;
+        let data = lenient
+            .snapshot()?
+            .lookup_data(TaskId::from(4), TaskDataCategory::Data)?;
+        assert!(matches!(
+            &data[..],
+            [
+                CachedDataItem::ChildrenCount { value: 3 },
+                CachedDataItem::Child { task, .. },
+            ] if *task == TaskId::from(5)
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn cloned_snapshots_share_the_same_view() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let write = |task_ids: Range<u32>, value: &[u8]| -> Result<()> {
+            let mut batch = storage.database.write_batch()?;
+            for task_id in task_ids {
+                batch.put(
+                    KeySpace::TaskData,
+                    Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                    Cow::Borrowed(value),
+                )?;
+            }
+            batch.commit()
+        };
+        write(1..101, b"old")?;
+
+        let snapshot = storage.snapshot()?;
+        let scan = |snapshot: StoreSnapshot<'_, LmbdKeyValueDatabase>, range: Range<u32>| {
+            snapshot
+                .task_ids_in(range)?
+                .into_iter()
+                .map(|task_id| {
+                    let value = snapshot.lookup_raw(task_id, |bytes| bytes.to_vec())?;
+                    Ok((
+                        task_id,
+                        value.context("task disappeared from the snapshot")?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let (low, high) = std::thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                for task_id in (1..201).step_by(10) {
+                    write(task_id..task_id + 10, b"new")?;
                 }
+                anyhow::Ok(())
             });
-            error?;
+            let low = scope.spawn({
+                let snapshot = snapshot.clone();
+                move || scan(snapshot, 0..50)
+            });
+            let high = scope.spawn({
+                let snapshot = snapshot.clone();
+                move || scan(snapshot, 50..u32::MAX)
+            });
+            writer.join().unwrap()?;
+            anyhow::Ok((low.join().unwrap()?, high.join().unwrap()?))
+        })?;
 
-            pot::to_vec(&data)
-                .with_context(|| anyhow!("Unable to serialize data items for {task}: {data:#?}"))?
+        assert_eq!(low.len(), 49);
+        assert_eq!(
+            low.into_iter().chain(high).collect::<Vec<_>>(),
+            (1..101)
+                .map(|task_id| (TaskId::from(task_id), b"old".to_vec()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(snapshot.task_ids()?.len(), 100);
+        drop(snapshot);
+        assert_eq!(storage.scan_task_index()?.len(), 200);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn strict_serialization_fails_on_optional_items() -> Result<()> {
+        let cell_data = test_utils::unserializable_cell_data(0);
+
+        let save = |strict_serialization| {
+            let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+                strict_serialization,
+                ..Default::default()
+            })?;
+            let mut chunk = ChunkedVec::new();
+            for item in [
+                CachedDataItem::ChildrenCount { value: 3 },
+                cell_data.clone(),
+            ] {
+                let (key, value) = item.into_key_and_value();
+                chunk.push(CachedDataUpdate {
+                    task: TaskId::from(1),
+                    key,
+                    value: Some(value),
+                    old_value: None,
+                });
+            }
+            test_utils::save_updates(&storage, 1, chunk)?;
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(1), TaskDataCategory::Data) };
+            anyhow::Ok((data, storage.stats().skipped_optional_items))
+        };
+
+        let err = save(true).unwrap_err();
+        assert!(format!("{err:?}").contains("Unable to serialize data item for"));
+        let (data, skipped) = save(false)?;
+        assert!(matches!(
+            &data[..],
+            [CachedDataItem::ChildrenCount { value: 3 }]
+        ));
+        assert_eq!(skipped, 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn operations_are_cleared_without_data() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        let mut chunk = ChunkedVec::new();
+        chunk.push(CachedDataUpdate {
+            task: TaskId::from(1),
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 3 }),
+            old_value: None,
+        });
+        let operations = vec![Arc::new(AnyOperation::Nested(Vec::new())); 3];
+        test_utils::with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                operations,
+                Vec::new(),
+                Vec::new(),
+                vec![chunk],
+            )
+        })?;
+        assert_eq!(storage.operations_len()?, 3);
+
+        storage.clear_operations()?;
+        assert_eq!(storage.operations_len()?, 0);
+        assert!(storage.uncompleted_operations().is_empty());
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, TaskId::from(1), TaskDataCategory::Data) };
+        assert!(matches!(
+            &data[..],
+            [CachedDataItem::ChildrenCount { value: 3 }]
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn generation_counts_snapshots() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let open = || KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?);
+        let save = |storage: &KeyValueDatabaseBackingStorage<_>, value| {
+            let mut chunk = ChunkedVec::new();
+            chunk.push(CachedDataUpdate {
+                task: TaskId::from(1),
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value }),
+                old_value: None,
+            });
+            test_utils::save_updates(storage, 1, chunk)
+        };
+
+        let storage = open()?;
+        assert_eq!(storage.current_generation()?, 0);
+        for value in 1..=3 {
+            save(&storage, value)?;
+            assert_eq!(storage.current_generation()?, value as u64);
         }
-    })
+        drop(storage);
+
+        let storage = open()?;
+        assert_eq!(storage.current_generation()?, 3);
+        save(&storage, 4)?;
+        assert_eq!(storage.current_generation()?, 4);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn concurrent_snapshots_are_serialized() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let open = |concurrent_snapshots| {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                BackingStorageOptions {
+                    concurrent_snapshots,
+                    ..Default::default()
+                },
+            )
+        };
+        let save = |storage: &KeyValueDatabaseBackingStorage<_>, task: u32| {
+            let mut chunk = ChunkedVec::new();
+            chunk.push(CachedDataUpdate {
+                task: TaskId::from(task),
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value: task }),
+                old_value: None,
+            });
+            test_utils::save_updates(storage, 1, chunk)
+        };
+
+        let storage = open(ConcurrentSnapshotPolicy::Wait)?;
+        std::thread::scope(|scope| {
+            // Both snapshots find the lock taken and wait for it
+            let storage = &storage;
+            let lock = storage.write_lock.lock();
+            let snapshots = [1, 2].map(|task| scope.spawn(move || save(storage, task)));
+            std::thread::sleep(Duration::from_millis(100));
+            drop(lock);
+            for snapshot in snapshots {
+                snapshot.join().unwrap()?;
+            }
+            anyhow::Ok(())
+        })?;
+        let stats = storage.stats();
+        assert_eq!(stats.write_lock_waits, 2);
+        assert!(stats.write_lock_wait_micros > 0);
+        assert_eq!(stats.committed_snapshots, 2);
+        assert_eq!(storage.current_generation()?, 2);
+        for task in [1, 2] {
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(task), TaskDataCategory::Data) };
+            assert!(matches!(
+                &data[..],
+                [CachedDataItem::ChildrenCount { value }] if *value == task
+            ));
+        }
+        drop(storage);
+
+        let storage = open(ConcurrentSnapshotPolicy::Fail)?;
+        let lock = storage.write_lock.lock();
+        assert!(save(&storage, 3).unwrap_err().is::<SnapshotInProgress>());
+        drop(lock);
+        save(&storage, 3)?;
+        assert_eq!(storage.current_generation()?, 3);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn shared_lookups_reuse_cached_data() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let open = |data_cache_capacity| {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                BackingStorageOptions {
+                    data_cache_capacity,
+                    ..Default::default()
+                },
+            )
+        };
+        let storage = open(Some(16))?;
+        let mut chunk = ChunkedVec::new();
+        chunk.push(CachedDataUpdate {
+            task: TaskId::from(1),
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 3 }),
+            old_value: None,
+        });
+        test_utils::save_updates(&storage, 1, chunk)?;
+
+        let first = storage.lookup_data_arc(TaskId::from(1), TaskDataCategory::Data)?;
+        let second = storage.lookup_data_arc(TaskId::from(1), TaskDataCategory::Data)?;
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(matches!(
+            &first[..],
+            [CachedDataItem::ChildrenCount { value: 3 }]
+        ));
+        assert_eq!(storage.stats().data_cache_hits, 1);
+        drop(storage);
+
+        // Without the cache every lookup deserializes the data
+        let storage = open(None)?;
+        let first = storage.lookup_data_arc(TaskId::from(1), TaskDataCategory::Data)?;
+        let second = storage.lookup_data_arc(TaskId::from(1), TaskDataCategory::Data)?;
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(first.len(), second.len());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn writer_version_is_reported_on_decode_errors() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            writer_version: Some(4),
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        updates.push(test_utils::children_count_update(1, 7));
+        test_utils::save_updates(&storage, 1, updates)?;
+        let snapshot = storage.snapshot()?;
+        assert!(matches!(
+            snapshot.lookup_data(TaskId::from(1), TaskDataCategory::Data)?[..],
+            [CachedDataItem::ChildrenCount { value: 7 }]
+        ));
+        drop(snapshot);
+        let stored = {
+            let tx = storage.database.begin_read_transaction()?;
+            storage
+                .database
+                .get(&tx, KeySpace::TaskData, IntKey::new(1).as_ref())?
+                .unwrap()
+                .to_vec()
+        };
+        assert_eq!(unframe(&stored)?.1, Some(4));
+
+        // Data that another version wrote in a format this version can't read
+        let items = vec![pot::Value::from_serialize("NoLongerAnItem")?];
+        let mut batch = storage.database.write_batch()?;
+        batch.put(
+            KeySpace::TaskData,
+            Cow::Borrowed(IntKey::new(2).as_ref()),
+            Cow::Owned(frame(storage.value_codec.serialize(&items)?, Some(3), None)),
+        )?;
+        batch.commit()?;
+        let err = storage
+            .snapshot()?
+            .lookup_data(TaskId::from(2), TaskDataCategory::Data)
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("The data of 2 was written by version 3"));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn failed_lookups_degrade_the_storage() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            max_restore_errors: Some(2),
+            ..Default::default()
+        })?;
+        let mut batch = storage.database.write_batch()?;
+        for task_id in 1..=3 {
+            batch.put(
+                KeySpace::TaskData,
+                Cow::Borrowed(IntKey::new(task_id).as_ref()),
+                Cow::Borrowed(b"not task data"),
+            )?;
+        }
+        batch.commit()?;
+
+        for task_id in 1..=3 {
+            assert!(!storage.stats().degraded);
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(task_id), TaskDataCategory::Data) };
+            assert!(data.is_empty());
+        }
+        let stats = storage.stats();
+        assert_eq!(stats.restore_errors, 3);
+        assert!(stats.degraded);
+        assert!(stats
+            .to_prometheus()
+            .contains("\nturbo_tasks_backend_degraded 1\n"));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn padded_values_round_trip() -> Result<()> {
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            pad_values_to: NonZeroUsize::new(128),
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        for task_id in 1..=3 {
+            updates.push(test_utils::children_count_update(task_id, task_id));
+        }
+        test_utils::save_updates(&storage, 1, updates)?;
+        for task_id in 1..=3 {
+            let stored = {
+                let tx = storage.database.begin_read_transaction()?;
+                storage
+                    .database
+                    .get(&tx, KeySpace::TaskData, IntKey::new(task_id).as_ref())?
+                    .unwrap()
+                    .to_vec()
+            };
+            assert_eq!(stored.len() % 128, 0);
+            assert!(unframe(&stored)?.0.len() < stored.len());
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(task_id), TaskDataCategory::Data) };
+            assert!(matches!(
+                data[..],
+                [CachedDataItem::ChildrenCount { value }] if value == task_id
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lifetime_restore_counts_survive_reopening() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let save = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>,
+                    task_id: u32|
+         -> Result<()> {
+            let mut updates = ChunkedVec::new();
+            updates.push(test_utils::children_count_update(task_id, 7));
+            test_utils::save_updates(storage, 1, updates)
+        };
+        let restore = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>| {
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(1), TaskDataCategory::Data) };
+            assert_eq!(data.len(), 1);
+        };
+
+        let storage = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?)?;
+        save(&storage, 1)?;
+        restore(&storage);
+        restore(&storage);
+        let stats = storage.stats();
+        assert_eq!(stats.restored_tasks, 2);
+        assert_eq!(stats.lifetime_restored_tasks, 2);
+        save(&storage, 2)?;
+        assert_eq!(storage.stats().lifetime_restored_tasks, 2);
+        drop(storage);
+
+        let storage = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?)?;
+        let stats = storage.stats();
+        assert_eq!(stats.restored_tasks, 0);
+        assert_eq!(stats.lifetime_restored_tasks, 2);
+        restore(&storage);
+        let stats = storage.stats();
+        assert_eq!(stats.restored_tasks, 1);
+        assert_eq!(stats.lifetime_restored_tasks, 3);
+        save(&storage, 3)?;
+        drop(storage);
+
+        let storage = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?)?;
+        assert_eq!(storage.stats().lifetime_restored_tasks, 3);
+        assert_eq!(storage.stats().lifetime_restored_cache_entries, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn lifetime_restore_counts_survive_failed_snapshots() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let (dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            strict_serialization: true,
+            ..Default::default()
+        })?;
+        let save = |items: Vec<CachedDataItem>| {
+            let mut updates = ChunkedVec::new();
+            updates.extend(items.into_iter().map(|item| {
+                let (key, value) = item.into_key_and_value();
+                CachedDataUpdate {
+                    task: TaskId::from(1),
+                    key,
+                    value: Some(value),
+                    old_value: None,
+                }
+            }));
+            test_utils::save_updates(&storage, 1, updates)
+        };
+        save(vec![CachedDataItem::ChildrenCount { value: 7 }])?;
+        // Safety: No transaction is passed.
+        unsafe { storage.lookup_data(None, TaskId::from(1), TaskDataCategory::Data) };
+        assert_eq!(storage.stats().lifetime_restored_tasks, 1);
+
+        // The snapshot writes the count and fails afterwards
+        assert!(save(vec![test_utils::unserializable_cell_data(1)]).is_err());
+        assert_eq!(storage.stats().lifetime_restored_tasks, 1);
+        save(vec![CachedDataItem::ChildrenCount { value: 8 }])?;
+        assert_eq!(storage.stats().lifetime_restored_tasks, 1);
+        drop(storage);
+
+        let storage = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(dir.path())?)?;
+        assert_eq!(storage.stats().lifetime_restored_tasks, 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn swap_in_replaces_the_store() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let updates = |task_id, value| {
+            let mut updates = ChunkedVec::new();
+            updates.push(test_utils::children_count_update(task_id, value));
+            updates
+        };
+        let dir = tempfile::tempdir()?;
+        let live = dir.path().join("live");
+        let new = dir.path().join("new");
+        let storage =
+            KeyValueDatabaseBackingStorage::new(SwappableKeyValueDatabase::with_replace(
+                crate::open_lmdb_database(&live, Default::default())?,
+                {
+                    let live = live.clone();
+                    move |new, validate| {
+                        crate::replace_lmdb_database(&live, new, Default::default(), validate)
+                    }
+                },
+            ))?;
+        test_utils::save_updates(&storage, 1, updates(1, 1))?;
+        {
+            let rebuilt = KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(&new)?)?;
+            test_utils::save_updates(&rebuilt, 1, updates(1, 2))?;
+            test_utils::save_updates(&rebuilt, 1, updates(2, 3))?;
+        }
+
+        let children_count = |data: &[CachedDataItem]| match data {
+            [CachedDataItem::ChildrenCount { value }] => Some(*value),
+            _ => None,
+        };
+        let old = storage.snapshot()?;
+        storage.swap_in(&new)?;
+        // Safety: No transaction is passed.
+        let data = unsafe { storage.lookup_data(None, TaskId::from(1), TaskDataCategory::Data) };
+        assert_eq!(children_count(&data), Some(2));
+        assert_eq!(
+            storage.snapshot()?.task_ids()?,
+            vec![TaskId::from(1), TaskId::from(2)]
+        );
+        // The earlier snapshot still reads the previous store
+        assert_eq!(
+            children_count(&old.lookup_data(TaskId::from(1), TaskDataCategory::Data)?),
+            Some(1)
+        );
+        assert_eq!(old.task_ids()?, vec![TaskId::from(1)]);
+        drop(old);
+
+        // Writes go to the new store
+        test_utils::save_updates(&storage, 1, updates(3, 4))?;
+        assert_eq!(storage.snapshot()?.task_ids()?.len(), 3);
+        assert!(!new.join("data.mdb").exists());
+        assert!(storage.swap_in(&new).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn operations_use_the_value_codec() -> Result<()> {
+        use crate::database::LmbdKeyValueDatabase;
+
+        let dir = tempfile::tempdir()?;
+        let open = || {
+            KeyValueDatabaseBackingStorage::with_options(
+                LmbdKeyValueDatabase::new(dir.path())?,
+                BackingStorageOptions {
+                    value_codec: ValueCodec::PotV4,
+                    ..Default::default()
+                },
+            )
+        };
+        let operations = (0..10)
+            .map(|i| {
+                Arc::new(AnyOperation::Nested(vec![
+                    AnyOperation::Nested(Vec::new());
+                    i % 3
+                ]))
+            })
+            .collect::<Vec<_>>();
+        let storage = open()?;
+        test_utils::with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                operations.clone(),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+            )
+        })?;
+        let stored = {
+            let tx = storage.database.begin_read_transaction()?;
+            read_chunked(
+                &storage.database,
+                &tx,
+                META_KEY_OPERATIONS,
+                META_KEY_OPERATIONS_CHUNKS,
+            )?
+            .unwrap()
+        };
+        assert_eq!(stored, ValueCodec::PotV4.serialize(&operations)?);
+        drop(storage);
+
+        let storage = open()?;
+        assert_eq!(storage.value_codec(), ValueCodec::PotV4);
+        assert_eq!(storage.operations_len()?, 10);
+        assert_eq!(
+            ValueCodec::PotV4.serialize(&storage.uncompleted_operations())?,
+            ValueCodec::PotV4.serialize(&operations)?
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn health_check_reports_capacity() -> Result<()> {
+        use crate::database::{LmbdKeyValueDatabase, LmdbOptions};
+
+        let (dir, storage) = test_utils::lmdb_storage(Default::default())?;
+        assert_eq!(storage.health_check()?, HealthStatus::Healthy);
+        drop(storage);
+
+        let dir = tempfile::tempdir()?;
+        let database = LmbdKeyValueDatabase::with_options(
+            dir.path(),
+            LmdbOptions {
+                map_size: 1024 * 1024,
+                ..Default::default()
+            },
+        )?;
+        let value = vec![1; 4000];
+        let mut key = 0;
+        while database.used_capacity()? < Some(0.92) {
+            let mut batch = database.write_batch()?;
+            for _ in 0..10 {
+                key += 1;
+                batch.put(
+                    KeySpace::TaskData,
+                    Cow::Owned(IntKey::new(key).as_ref().to_vec()),
+                    Cow::Borrowed(&value),
+                )?;
+            }
+            batch.commit()?;
+        }
+        assert!(database.used_capacity()? < Some(0.98));
+        let storage = KeyValueDatabaseBackingStorage::new(database)?;
+        assert!(matches!(storage.health_check()?, HealthStatus::Degraded(_)));
+        Ok(())
+    }
+
+    #[cfg(feature = "lmdb")]
+    #[test]
+    fn new_tasks_are_logged_up_to_the_limit() -> Result<()> {
+        let task_type = |this| test_utils::test_task_type("NewTasks", this);
+
+        let (_dir, storage) = test_utils::lmdb_storage(BackingStorageOptions {
+            log_new_tasks_limit: 3,
+            ..Default::default()
+        })?;
+        let mut updates = ChunkedVec::new();
+        updates.push((Arc::new(task_type(1)), TaskId::from(2)));
+        storage.save_task_cache_only(updates)?;
+
+        let events = test_utils::capture_events(|| {
+            test_utils::with_turbo_tasks(|| unsafe {
+                assert_eq!(
+                    storage.forward_lookup_task_cache(None, &task_type(1)),
+                    Some(TaskId::from(2))
+                );
+                for this in 3..8 {
+                    assert_eq!(
+                        storage.forward_lookup_task_cache(None, &task_type(this)),
+                        None
+                    );
+                }
+            })
+        });
+        let new_tasks = events
+            .iter()
+            .filter(|fields| fields.contains_key("new_task"))
+            .count();
+        assert_eq!(new_tasks, 3);
+        Ok(())
+    }
 }
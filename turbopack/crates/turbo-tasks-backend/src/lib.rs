@@ -1,38 +1,179 @@
 #![feature(anonymous_lifetime_in_impl_trait)]
 
+mod any_backing_storage;
 mod backend;
 mod backing_storage;
+mod chunked_value;
 mod data;
+mod data_blob;
+mod data_compression;
+mod data_delta;
+mod data_framing;
 pub mod database;
 mod kv_backing_storage;
+mod maintenance;
+mod manifest;
+mod mirrored_backing_storage;
+#[cfg(feature = "lmdb")]
+mod process_isolated_backing_storage;
+#[cfg(all(unix, feature = "fuse"))]
+mod store_view;
+mod task_cache_export;
+mod task_index_cache;
 mod utils;
+mod value_codec;
 
 use std::path::Path;
 
 use anyhow::Result;
 
-pub use self::{backend::TurboTasksBackend, kv_backing_storage::KeyValueDatabaseBackingStorage};
+#[cfg(feature = "lmdb")]
+pub use self::process_isolated_backing_storage::{
+    run_storage_helper_if_requested, ProcessIsolatedBackingStorage,
+};
+#[cfg(all(unix, feature = "fuse"))]
+pub use self::store_view::mount_store_view;
+pub use self::{
+    any_backing_storage::{open_backing_storage, AnyBackingStorage, BackingStorageKind},
+    backend::TurboTasksBackend,
+    kv_backing_storage::{
+        forward_cache_key_bytes, BackingStorageOptions, BackingStorageStats, CommitHook,
+        CommitInfo, ConcurrentSnapshotPolicy, Corrupt, DuplicateTaskIdPolicy, HealthStatus,
+        KeyValueDatabaseBackingStorage, MaintenanceTask, ProgressCallback,
+        ReadOnlyFilesystemPolicy, SalvageReport, SnapshotInProgress, StoreSnapshot,
+        TaskCacheImportConflictPolicy, TaskIdAllocation, TaskIdSpaceExhausted,
+    },
+    maintenance::MaintenanceThread,
+    manifest::{Manifest, ManifestOptions},
+    mirrored_backing_storage::{MirrorMode, MirroredBackingStorage},
+    value_codec::ValueCodec,
+};
 use crate::database::NoopKvDb;
 
 #[cfg(feature = "lmdb")]
-pub type LmdbBackingStorage = KeyValueDatabaseBackingStorage<
-    crate::database::ReadTransactionCache<
-        crate::database::StartupCacheLayer<
-            crate::database::FreshDbOptimization<crate::database::LmbdKeyValueDatabase>,
+pub type LmdbBackingStorage =
+    KeyValueDatabaseBackingStorage<crate::database::SwappableKeyValueDatabase<LmdbDatabase>>;
+
+/// The database layers of [`LmdbBackingStorage`], which can be swapped, see
+/// [`KeyValueDatabaseBackingStorage::swap_in`].
+#[cfg(feature = "lmdb")]
+pub type LmdbDatabase = crate::database::ReadTransactionCache<
+    crate::database::StartupCacheLayer<
+        crate::database::FreshDbOptimization<
+            crate::database::OperationTimeout<crate::database::LmbdKeyValueDatabase>,
         >,
     >,
 >;
 
 #[cfg(feature = "lmdb")]
 pub fn lmdb_backing_storage(path: &Path) -> Result<LmdbBackingStorage> {
+    lmdb_backing_storage_with_options(path, Default::default(), Default::default())
+}
+
+#[cfg(feature = "lmdb")]
+pub fn lmdb_backing_storage_with_options(
+    path: &Path,
+    lmdb_options: crate::database::LmdbOptions,
+    options: BackingStorageOptions,
+) -> Result<LmdbBackingStorage> {
     let path = crate::database::handle_db_versioning(path)?;
-    let fresh_db = crate::database::is_fresh(&path);
-    let database = crate::database::LmbdKeyValueDatabase::new(&path)?;
+    let database = open_lmdb_database(&path, lmdb_options)?;
+    let database =
+        crate::database::SwappableKeyValueDatabase::with_replace(database, move |new, validate| {
+            replace_lmdb_database(&path, new, lmdb_options, validate)
+        });
+    KeyValueDatabaseBackingStorage::with_options(database, options)
+}
+
+#[cfg(feature = "lmdb")]
+fn open_lmdb_database(
+    path: &Path,
+    lmdb_options: crate::database::LmdbOptions,
+) -> Result<LmdbDatabase> {
+    let fresh_db = crate::database::is_fresh(path);
+    let database = crate::database::LmbdKeyValueDatabase::with_options(path, lmdb_options)?;
+    let database = crate::database::OperationTimeout::new(database, lmdb_options.operation_timeout);
     let database = crate::database::FreshDbOptimization::new(database, fresh_db);
     let database =
         crate::database::StartupCacheLayer::new(database, path.join("startup.cache"), fresh_db)?;
-    let database = crate::database::ReadTransactionCache::new(database);
-    Ok(KeyValueDatabaseBackingStorage::new(database))
+    Ok(crate::database::ReadTransactionCache::new(database))
+}
+
+/// Moves the files of the database in the directory `new` into the directory `path` and opens
+/// the result, see [`KeyValueDatabaseBackingStorage::swap_in`]. `new` is the directory of the
+/// database itself, e.g. the versioned directory that
+/// [`lmdb_backing_storage_with_options`] uses. The environment that is open at `path` keeps
+/// using the previous files, which are deleted once it's closed.
+#[cfg(feature = "lmdb")]
+fn replace_lmdb_database(
+    path: &Path,
+    new: &Path,
+    lmdb_options: crate::database::LmdbOptions,
+    validate: &dyn Fn(&LmdbDatabase) -> Result<()>,
+) -> Result<LmdbDatabase> {
+    use anyhow::{bail, Context};
+
+    if !new.join("data.mdb").is_file() {
+        bail!("There is no database at {}", new.display());
+    }
+    validate(&open_lmdb_database(new, lmdb_options)?)?;
+    // Other processes would keep using the removed lock file and miss the writes of this one
+    crate::database::LmbdKeyValueDatabase::ensure_no_other_process_readers(path)?;
+    // The startup cache holds values of the previous store, and the one of the new store isn't
+    // worth moving
+    for file in ["startup.cache", "lock.mdb"] {
+        match std::fs::remove_file(path.join(file)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).with_context(|| format!("Unable to remove {file}"));
+            }
+            _ => {}
+        }
+    }
+    crate::database::LmbdKeyValueDatabase::forget_environment(path)?;
+    std::fs::rename(new.join("data.mdb"), path.join("data.mdb"))
+        .context("Unable to move the data file")?;
+    open_lmdb_database(path, lmdb_options)
+}
+
+#[cfg(feature = "lmdb")]
+pub type LmdbShardedBackingStorage = KeyValueDatabaseBackingStorage<
+    crate::database::ShardedKeyValueDatabase<crate::database::LmbdKeyValueDatabase>,
+>;
+
+/// Opens an LMDB backing storage with one environment per shard in the directories `shard-<n>`.
+/// Snapshots write the shards in parallel, but aren't atomic across shards, see
+/// [`ShardedKeyValueDatabase`](crate::database::ShardedKeyValueDatabase). The number of shards
+/// must not change for an existing database.
+#[cfg(feature = "lmdb")]
+pub fn lmdb_sharded_backing_storage(
+    path: &Path,
+    shards: usize,
+    lmdb_options: crate::database::LmdbOptions,
+    options: BackingStorageOptions,
+) -> Result<LmdbShardedBackingStorage> {
+    let path = crate::database::handle_db_versioning(path)?;
+    let shards = (0..shards)
+        .map(|shard| {
+            crate::database::LmbdKeyValueDatabase::with_options(
+                &path.join(format!("shard-{shard}")),
+                lmdb_options,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let database = crate::database::ShardedKeyValueDatabase::new(shards)?;
+    KeyValueDatabaseBackingStorage::with_options(database, options)
+}
+
+/// Copies all readable task data of the LMDB database in the directory `src` into a new database
+/// in the directory `dst`. `src` is opened read-only and isn't modified.
+#[cfg(feature = "lmdb")]
+pub fn lmdb_salvage(src: &Path, dst: &Path) -> Result<SalvageReport> {
+    let src = KeyValueDatabaseBackingStorage::new(
+        crate::database::LmbdKeyValueDatabase::open_immutable(src)?,
+    )?;
+    let dst =
+        KeyValueDatabaseBackingStorage::new(crate::database::LmbdKeyValueDatabase::new(dst)?)?;
+    src.salvage_into(&dst)
 }
 
 #[cfg(feature = "rocksdb")]
@@ -43,13 +184,13 @@ pub type RocksDBBackingStorage =
 pub fn rocksdb_backing_storage(path: &Path) -> Result<RocksDBBackingStorage> {
     let path = crate::database::handle_db_versioning(path)?;
     let database = crate::database::RocksDbKeyValueDatabase::new(&path)?;
-    Ok(KeyValueDatabaseBackingStorage::new(database))
+    KeyValueDatabaseBackingStorage::new(database)
 }
 
 pub type NoopBackingStorage = KeyValueDatabaseBackingStorage<NoopKvDb>;
 
 pub fn noop_backing_storage(_path: &Path) -> Result<NoopBackingStorage> {
-    Ok(KeyValueDatabaseBackingStorage::new(NoopKvDb))
+    KeyValueDatabaseBackingStorage::new(NoopKvDb)
 }
 
 #[cfg(feature = "rocksdb")]
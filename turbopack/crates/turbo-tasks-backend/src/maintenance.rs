@@ -0,0 +1,65 @@
+use std::{
+    sync::Arc,
+    thread::{Builder, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+
+/// A background thread that runs periodic housekeeping of a storage, see
+/// [`KeyValueDatabaseBackingStorage::start_maintenance`](crate::KeyValueDatabaseBackingStorage::start_maintenance).
+/// Dropping it stops the thread and waits for a running maintenance to finish.
+pub struct MaintenanceThread {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MaintenanceThread {
+    /// Calls `run` every `interval` until the thread is dropped or `run` returns `false`.
+    pub(crate) fn spawn(
+        interval: Duration,
+        mut run: impl FnMut() -> bool + Send + 'static,
+    ) -> Result<Self> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = Builder::new()
+            .name("turbo-tasks-maintenance".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || loop {
+                    {
+                        let (stopped, condvar) = &*stop;
+                        let deadline = Instant::now() + interval;
+                        let mut stopped = stopped.lock();
+                        // parking_lot's condition variables don't wake up spuriously
+                        if !*stopped {
+                            condvar.wait_until(&mut stopped, deadline);
+                        }
+                        if *stopped {
+                            return;
+                        }
+                    }
+                    if !run() {
+                        return;
+                    }
+                }
+            })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for MaintenanceThread {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                tracing::error!("The maintenance thread panicked");
+            }
+        }
+    }
+}
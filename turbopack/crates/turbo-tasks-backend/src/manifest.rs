@@ -0,0 +1,65 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kv_backing_storage::{BackingStorageOptions, TaskIdAllocation},
+    value_codec::ValueCodec,
+};
+
+/// Metadata about a persisted store, meant to help understanding a cache found on disk. It's
+/// stored as JSON next to the other infra values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Milliseconds since the unix epoch when the store was created.
+    pub created_at: u64,
+    /// Version of this crate that created the store.
+    pub created_by_version: String,
+    /// Milliseconds since the unix epoch when the store was opened the last time.
+    pub last_opened_at: u64,
+    /// Version of this crate that opened the store the last time.
+    pub last_opened_by_version: String,
+    /// The options the store was opened with the last time.
+    pub options: ManifestOptions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestOptions {
+    pub task_id_allocation: TaskIdAllocation,
+    pub startup_parallelism: usize,
+    pub value_codec: ValueCodec,
+}
+
+impl Manifest {
+    /// Returns the manifest for opening the store now. `previous` is the stored manifest, if
+    /// any. Stores created before manifests were introduced get a creation time of now.
+    pub(crate) fn opened(
+        previous: Option<Manifest>,
+        options: &BackingStorageOptions,
+        value_codec: ValueCodec,
+    ) -> Self {
+        let now = now();
+        let version = env!("CARGO_PKG_VERSION").to_string();
+        let (created_at, created_by_version) = match previous {
+            Some(previous) => (previous.created_at, previous.created_by_version),
+            None => (now, version.clone()),
+        };
+        Self {
+            created_at,
+            created_by_version,
+            last_opened_at: now,
+            last_opened_by_version: version,
+            options: ManifestOptions {
+                task_id_allocation: options.task_id_allocation,
+                startup_parallelism: options.startup_parallelism,
+                value_codec,
+            },
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
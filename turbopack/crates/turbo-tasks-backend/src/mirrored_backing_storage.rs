@@ -0,0 +1,495 @@
+use std::{
+    fmt::Debug,
+    num::NonZeroU64,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{Builder, JoinHandle},
+};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use turbo_tasks::{backend::CachedTaskType, turbo_tasks, turbo_tasks_scope, SessionId, TaskId};
+
+use crate::{
+    backend::{AnyOperation, TaskDataCategory},
+    backing_storage::{BackingStorage, SnapshotCostEstimate},
+    data::{CachedDataItem, CachedDataUpdate},
+    utils::chunked_vec::ChunkedVec,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorMode {
+    /// A snapshot fails when writing to either storage fails.
+    Sync,
+    /// The secondary storage is written on a background thread, and its failures are logged, but
+    /// don't fail the snapshot. A snapshot waits for the write of the previous snapshot to the
+    /// secondary storage, so at most one write is pending, see
+    /// [`MirroredBackingStorage::flush`].
+    BestEffort,
+}
+
+/// A [`BackingStorage`] that reads from the `primary` storage and writes every snapshot to both
+/// storages, so the `secondary` storage can be used as failover copy.
+pub struct MirroredBackingStorage<P: BackingStorage, S: BackingStorage> {
+    primary: P,
+    secondary: Arc<S>,
+    mode: MirrorMode,
+    /// The pending write to the secondary storage in [`MirrorMode::BestEffort`].
+    pending_write: Mutex<Option<JoinHandle<()>>>,
+    /// See [`MirroredBackingStorage::with_read_validation`].
+    validation_interval: Option<NonZeroU64>,
+    reads: AtomicU64,
+    validated_reads: AtomicU64,
+    read_discrepancies: AtomicU64,
+}
+
+impl<P: BackingStorage, S: BackingStorage> MirroredBackingStorage<P, S> {
+    pub fn new(primary: P, secondary: S, mode: MirrorMode) -> Self {
+        Self {
+            primary,
+            secondary: Arc::new(secondary),
+            mode,
+            pending_write: Mutex::new(None),
+            validation_interval: None,
+            reads: AtomicU64::new(0),
+            validated_reads: AtomicU64::new(0),
+            read_discrepancies: AtomicU64::new(0),
+        }
+    }
+
+    /// Repeats every `interval`th read on the secondary storage and logs and counts results that
+    /// differ from the primary storage, e.g. to validate a new storage before switching to it.
+    /// Reads are still served from the primary storage. Data items are compared by their debug
+    /// representation, which doesn't include the contents of cells.
+    pub fn with_read_validation(mut self, interval: NonZeroU64) -> Self {
+        self.validation_interval = Some(interval);
+        self
+    }
+
+    /// The number of reads that were repeated on the secondary storage.
+    pub fn validated_reads(&self) -> u64 {
+        self.validated_reads.load(Ordering::Relaxed)
+    }
+
+    /// The number of validated reads where the secondary storage returned a different result.
+    pub fn read_discrepancies(&self) -> u64 {
+        self.read_discrepancies.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the current read is sampled for validation.
+    fn sample_read(&self) -> bool {
+        let Some(interval) = self.validation_interval else {
+            return false;
+        };
+        self.reads.fetch_add(1, Ordering::Relaxed) % interval.get() == 0
+    }
+
+    /// Compares the result of a read from both storages and returns the primary result.
+    fn validate_read<R: PartialEq + Debug>(
+        &self,
+        read: impl Debug,
+        primary: R,
+        secondary: impl FnOnce() -> R,
+    ) -> R {
+        self.validated_reads.fetch_add(1, Ordering::Relaxed);
+        let secondary = secondary();
+        if primary != secondary {
+            self.read_discrepancies.fetch_add(1, Ordering::Relaxed);
+            tracing::error!(
+                "Reading {read:?} from the secondary storage returned {secondary:?} instead of \
+                 {primary:?}"
+            );
+        }
+        primary
+    }
+
+    /// Waits for the pending write to the secondary storage in [`MirrorMode::BestEffort`].
+    pub fn flush(&self) {
+        let Some(write) = self.pending_write.lock().take() else {
+            return;
+        };
+        if write.join().is_err() {
+            tracing::error!("Writing snapshot to secondary storage panicked");
+        }
+    }
+
+    /// Writes the snapshot to the secondary storage on a background thread.
+    fn spawn_secondary_write(
+        &self,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+        data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+    ) {
+        // Snapshots must be written in order
+        self.flush();
+        let secondary = self.secondary.clone();
+        let tt = turbo_tasks();
+        let write = Builder::new()
+            .name("turbo-tasks-mirror".to_string())
+            .spawn(move || {
+                let result = turbo_tasks_scope(tt, || {
+                    secondary.save_snapshot(
+                        session_id,
+                        operations,
+                        task_cache_updates,
+                        meta_updates,
+                        data_updates,
+                    )
+                });
+                if let Err(err) = result {
+                    tracing::error!("Writing snapshot to secondary storage failed: {err:?}");
+                }
+            });
+        match write {
+            Ok(write) => *self.pending_write.lock() = Some(write),
+            Err(err) => {
+                tracing::error!("Unable to spawn the secondary storage write: {err:?}");
+            }
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+}
+
+impl<P: BackingStorage, S: BackingStorage> BackingStorage for MirroredBackingStorage<P, S> {
+    type ReadTransaction<'l> = P::ReadTransaction<'l>;
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        P::lower_read_transaction(tx)
+    }
+
+    fn next_free_task_id(&self) -> TaskId {
+        self.primary.next_free_task_id()
+    }
+
+    fn content_addressed_task_id(&self, task_type: &CachedTaskType) -> Option<TaskId> {
+        self.primary.content_addressed_task_id(task_type)
+    }
+
+    fn next_session_id(&self) -> SessionId {
+        self.primary.next_session_id()
+    }
+
+    fn uncompleted_operations(&self) -> Vec<AnyOperation> {
+        self.primary.uncompleted_operations()
+    }
+
+    fn save_snapshot(
+        &self,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+        data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+    ) -> Result<()> {
+        if self.mode == MirrorMode::BestEffort {
+            self.spawn_secondary_write(
+                session_id,
+                operations.clone(),
+                task_cache_updates.clone(),
+                meta_updates.clone(),
+                data_updates.clone(),
+            );
+            return self
+                .primary
+                .save_snapshot(
+                    session_id,
+                    operations,
+                    task_cache_updates,
+                    meta_updates,
+                    data_updates,
+                )
+                .context("Writing snapshot to primary storage failed");
+        }
+        let secondary_operations = operations.clone();
+        let secondary_task_cache_updates = task_cache_updates.clone();
+        let secondary_meta_updates = meta_updates.clone();
+        let secondary_data_updates = data_updates.clone();
+        let mut secondary_result = Ok(());
+        let primary_result = turbo_tasks::scope(|s| {
+            s.spawn(|_| {
+                secondary_result = self.secondary.save_snapshot(
+                    session_id,
+                    secondary_operations,
+                    secondary_task_cache_updates,
+                    secondary_meta_updates,
+                    secondary_data_updates,
+                );
+            });
+            self.primary.save_snapshot(
+                session_id,
+                operations,
+                task_cache_updates,
+                meta_updates,
+                data_updates,
+            )
+        });
+        primary_result.context("Writing snapshot to primary storage failed")?;
+        secondary_result.context("Writing snapshot to secondary storage failed")
+    }
+
+    fn estimate_snapshot_cost(
+        &self,
+        task_cache_len: usize,
+        data_updates_len: usize,
+    ) -> SnapshotCostEstimate {
+        // Both storages are written in parallel, also in best effort mode where the snapshot
+        // waits for the previous write to the secondary storage
+        let primary = self
+            .primary
+            .estimate_snapshot_cost(task_cache_len, data_updates_len);
+        let secondary = self
+            .secondary
+            .estimate_snapshot_cost(task_cache_len, data_updates_len);
+        SnapshotCostEstimate {
+            bytes: primary.bytes + secondary.bytes,
+            duration: primary.duration.max(secondary.duration),
+        }
+    }
+
+    fn start_read_transaction(&self) -> Option<Self::ReadTransaction<'_>> {
+        self.primary.start_read_transaction()
+    }
+
+    unsafe fn forward_lookup_task_cache(
+        &self,
+        tx: Option<&Self::ReadTransaction<'_>>,
+        key: &CachedTaskType,
+    ) -> Option<TaskId> {
+        // Safety: The transaction is a transaction of the primary storage.
+        let task_id = unsafe { self.primary.forward_lookup_task_cache(tx, key) };
+        if !self.sample_read() {
+            return task_id;
+        }
+        self.validate_read(key, task_id, || {
+            // Safety: No transaction is passed.
+            unsafe { self.secondary.forward_lookup_task_cache(None, key) }
+        })
+    }
+
+    unsafe fn reverse_lookup_task_cache(
+        &self,
+        tx: Option<&Self::ReadTransaction<'_>>,
+        task_id: TaskId,
+    ) -> Option<Arc<CachedTaskType>> {
+        // Safety: The transaction is a transaction of the primary storage.
+        let task_type = unsafe { self.primary.reverse_lookup_task_cache(tx, task_id) };
+        if !self.sample_read() {
+            return task_type;
+        }
+        self.validate_read(task_id, task_type, || {
+            // Safety: No transaction is passed.
+            unsafe { self.secondary.reverse_lookup_task_cache(None, task_id) }
+        })
+    }
+
+    unsafe fn lookup_data(
+        &self,
+        tx: Option<&Self::ReadTransaction<'_>>,
+        task_id: TaskId,
+        category: TaskDataCategory,
+    ) -> Arc<Vec<CachedDataItem>> {
+        // Safety: The transaction is a transaction of the primary storage.
+        let data = unsafe { self.primary.lookup_data(tx, task_id, category) };
+        if !self.sample_read() {
+            return data;
+        }
+        // Data items can't be compared, and the order of the items doesn't matter
+        let describe = |data: &[CachedDataItem]| {
+            let mut items = data
+                .iter()
+                .map(|item| format!("{item:?}"))
+                .collect::<Vec<_>>();
+            items.sort_unstable();
+            items
+        };
+        self.validate_read((task_id, category), describe(&data), || {
+            // Safety: No transaction is passed.
+            describe(&unsafe { self.secondary.lookup_data(None, task_id, category) })
+        });
+        data
+    }
+
+    fn contains_tasks(&self, task_ids: &[TaskId]) -> Vec<bool> {
+        let contained = self.primary.contains_tasks(task_ids);
+        if !self.sample_read() {
+            return contained;
+        }
+        self.validate_read(task_ids, contained, || {
+            self.secondary.contains_tasks(task_ids)
+        })
+    }
+}
+
+impl<P: BackingStorage, S: BackingStorage> Drop for MirroredBackingStorage<P, S> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(all(test, feature = "lmdb"))]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        data::{CachedDataItemKey, CachedDataItemValue},
+        database::LmbdKeyValueDatabase,
+        kv_backing_storage::{test_utils::with_turbo_tasks, KeyValueDatabaseBackingStorage},
+    };
+
+    fn open_storage(path: &Path) -> Result<KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>> {
+        KeyValueDatabaseBackingStorage::new(LmbdKeyValueDatabase::new(path)?)
+    }
+
+    #[test]
+    fn writes_propagate_to_both_storages() -> Result<()> {
+        let primary_dir = tempfile::tempdir()?;
+        let secondary_dir = tempfile::tempdir()?;
+        let storage = MirroredBackingStorage::new(
+            open_storage(primary_dir.path())?,
+            open_storage(secondary_dir.path())?,
+            MirrorMode::Sync,
+        );
+        let task = TaskId::from(42);
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                vec![updates],
+            )
+        })?;
+
+        let read = |storage: &dyn Fn(TaskId) -> Arc<Vec<CachedDataItem>>| {
+            let data = storage(task);
+            assert_eq!(data.len(), 1);
+            assert!(matches!(
+                data[0],
+                CachedDataItem::ChildrenCount { value: 7 }
+            ));
+        };
+        // Safety: No transaction is passed.
+        read(&|task| unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) });
+        read(&|task| unsafe {
+            storage
+                .secondary()
+                .lookup_data(None, task, TaskDataCategory::Data)
+        });
+        assert_eq!(storage.secondary().next_session_id(), SessionId::from(2));
+
+        drop(storage);
+        let secondary = open_storage(secondary_dir.path())?;
+        // Safety: No transaction is passed.
+        read(&|task| unsafe { secondary.lookup_data(None, task, TaskDataCategory::Data) });
+        Ok(())
+    }
+
+    #[test]
+    fn best_effort_writes_the_secondary_storage_in_the_background() -> Result<()> {
+        let primary_dir = tempfile::tempdir()?;
+        let secondary_dir = tempfile::tempdir()?;
+        let storage = MirroredBackingStorage::new(
+            open_storage(primary_dir.path())?,
+            open_storage(secondary_dir.path())?,
+            MirrorMode::BestEffort,
+        );
+        for session in 1..=2 {
+            let mut updates = ChunkedVec::new();
+            updates.push(CachedDataUpdate {
+                task: TaskId::from(session),
+                key: CachedDataItemKey::ChildrenCount {},
+                value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+                old_value: None,
+            });
+            with_turbo_tasks(|| {
+                storage.save_snapshot(
+                    SessionId::from(session),
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    vec![updates],
+                )
+            })?;
+        }
+        assert!(storage.pending_write.lock().is_some());
+        storage.flush();
+        assert!(storage.pending_write.lock().is_none());
+
+        for task in [1, 2] {
+            // Safety: No transaction is passed.
+            let data = unsafe {
+                storage
+                    .secondary()
+                    .lookup_data(None, TaskId::from(task), TaskDataCategory::Data)
+            };
+            assert_eq!(data.len(), 1);
+        }
+        assert_eq!(storage.secondary().next_session_id(), SessionId::from(3));
+        Ok(())
+    }
+
+    #[test]
+    fn read_validation_reports_discrepancies() -> Result<()> {
+        let primary_dir = tempfile::tempdir()?;
+        let secondary_dir = tempfile::tempdir()?;
+        let primary = open_storage(primary_dir.path())?;
+        let secondary = open_storage(secondary_dir.path())?;
+        let save = |storage: &KeyValueDatabaseBackingStorage<LmbdKeyValueDatabase>,
+                    tasks: &[u32]| {
+            let mut updates = ChunkedVec::new();
+            for &task in tasks {
+                updates.push(CachedDataUpdate {
+                    task: TaskId::from(task),
+                    key: CachedDataItemKey::ChildrenCount {},
+                    value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+                    old_value: None,
+                });
+            }
+            with_turbo_tasks(|| {
+                storage.save_snapshot(
+                    SessionId::from(1),
+                    Vec::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    vec![updates],
+                )
+            })
+        };
+        // The secondary storage misses the data of task 2
+        save(&primary, &[1, 2])?;
+        save(&secondary, &[1])?;
+
+        let storage = MirroredBackingStorage::new(primary, secondary, MirrorMode::Sync)
+            .with_read_validation(NonZeroU64::MIN);
+        for task in [1, 2, 3] {
+            // Safety: No transaction is passed.
+            let data =
+                unsafe { storage.lookup_data(None, TaskId::from(task), TaskDataCategory::Data) };
+            assert_eq!(data.len(), usize::from(task != 3));
+        }
+        assert_eq!(storage.validated_reads(), 3);
+        assert_eq!(storage.read_discrepancies(), 1);
+        Ok(())
+    }
+}
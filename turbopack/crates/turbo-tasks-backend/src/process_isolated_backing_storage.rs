@@ -0,0 +1,795 @@
+#[cfg(unix)]
+use std::os::{
+    fd::{AsRawFd, FromRawFd, RawFd},
+    unix::{net::UnixStream, process::CommandExt},
+};
+use std::{
+    env,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::Shutdown,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+#[cfg(not(unix))]
+use std::{
+    net::{TcpListener, TcpStream},
+    thread::sleep,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use turbo_tasks::{
+    backend::CachedTaskType, turbo_tasks_scope, SessionId, TaskId, TurboTasks, TurboTasksApi,
+};
+
+use crate::{
+    backend::{AnyOperation, TaskDataCategory},
+    backing_storage::{BackingStorage, SnapshotCostEstimate},
+    data::{CachedDataItem, CachedDataUpdate},
+    noop_backing_storage,
+    utils::chunked_vec::ChunkedVec,
+    LmdbBackingStorage, TurboTasksBackend,
+};
+
+/// The file descriptor of the socket the helper process inherits.
+#[cfg(unix)]
+const HELPER_FD_ENV: &str = "TURBO_TASKS_STORAGE_HELPER_FD";
+/// The address the helper process connects to.
+#[cfg(not(unix))]
+const HELPER_ADDRESS_ENV: &str = "TURBO_TASKS_STORAGE_HELPER_ADDRESS";
+/// The token the helper process authenticates with.
+#[cfg(not(unix))]
+const HELPER_TOKEN_ENV: &str = "TURBO_TASKS_STORAGE_HELPER_TOKEN";
+/// The path of the store the helper process opens.
+const HELPER_PATH_ENV: &str = "TURBO_TASKS_STORAGE_HELPER_PATH";
+
+/// How long to wait for the helper process to connect.
+#[cfg(not(unix))]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum length of a message. Longer messages are rejected before they are read, so a
+/// broken peer can't make the other process allocate arbitrary amounts of memory.
+const MAX_MESSAGE_LEN: usize = 1 << 30;
+
+/// The connection to the helper process. On unix it's a socket pair that the helper process
+/// inherits, elsewhere a loopback TCP connection that is authenticated with a random token.
+#[cfg(unix)]
+type Stream = UnixStream;
+#[cfg(not(unix))]
+type Stream = TcpStream;
+
+/// A [`BackingStorage`] that runs an [`LmdbBackingStorage`] in a helper process, so a crash of
+/// the database doesn't take down the build. Every call is forwarded to the helper process.
+///
+/// When the helper process dies or the connection breaks, the storage is degraded for good and
+/// behaves like an empty store that can't be written: lookups return nothing, task and session
+/// ids start at 1 and snapshots fail. Calls are sent one at a time, and reads don't share a read
+/// transaction.
+pub struct ProcessIsolatedBackingStorage {
+    connection: Mutex<Connection>,
+    child: Mutex<Child>,
+    /// Set when a request failed to be sent or received, see [`Self::is_degraded`].
+    degraded: AtomicBool,
+}
+
+struct Connection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+impl ProcessIsolatedBackingStorage {
+    /// Spawns `command` as helper process that opens the store at `path`. The helper process
+    /// must call [`run_storage_helper_if_requested`] on startup.
+    pub fn spawn(mut command: Command, path: &Path) -> Result<Self> {
+        command.env(HELPER_PATH_ENV, path);
+        let (child, stream) = connect_helper(&mut command)?;
+        Ok(Self {
+            connection: Mutex::new(Connection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: BufWriter::new(stream),
+            }),
+            child: Mutex::new(child),
+            degraded: AtomicBool::new(false),
+        })
+    }
+
+    /// Spawns the current executable as helper process, see [`Self::spawn`].
+    pub fn spawn_current_exe(path: &Path) -> Result<Self> {
+        Self::spawn(Command::new(env::current_exe()?), path)
+    }
+
+    /// Whether the connection to the helper process was lost. All following calls fail or
+    /// return nothing.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    fn request(&self, request: &Request) -> Result<Response> {
+        if self.is_degraded() {
+            bail!("The connection to the storage helper process was lost");
+        }
+        let mut connection = self.connection.lock();
+        let result = write_message(&mut connection.writer, request).and_then(|()| {
+            read_message(&mut connection.reader)?
+                .context("The storage helper process closed the connection")
+        });
+        if result.is_err() && !self.degraded.swap(true, Ordering::Relaxed) {
+            tracing::error!("The storage helper process can't be reached, the storage is degraded");
+        }
+        result
+    }
+}
+
+impl Drop for ProcessIsolatedBackingStorage {
+    fn drop(&mut self) {
+        // The helper process exits when the connection is closed
+        let _ = self
+            .connection
+            .get_mut()
+            .writer
+            .get_ref()
+            .shutdown(Shutdown::Both);
+        let _ = self.child.get_mut().wait();
+    }
+}
+
+/// Spawns the helper process with one end of a socket pair, which it inherits.
+#[cfg(unix)]
+fn connect_helper(command: &mut Command) -> Result<(Child, Stream)> {
+    let (stream, helper_stream) = UnixStream::pair()?;
+    let fd = helper_stream.as_raw_fd();
+    command.env(HELPER_FD_ENV, fd.to_string());
+    // Safety: Only `fcntl` is called between fork and exec, which is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            // Sockets are created with close-on-exec, so it's cleared for the helper process
+            nix::fcntl::fcntl(
+                fd,
+                nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()),
+            )?;
+            Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .context("Unable to spawn the storage helper process")?;
+    // Only the helper process holds its end now, so the connection closes when it exits
+    drop(helper_stream);
+    Ok((child, stream))
+}
+
+/// Spawns the helper process and waits for it to connect to a loopback port.
+#[cfg(not(unix))]
+fn connect_helper(command: &mut Command) -> Result<(Child, Stream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let token = format!("{:016x}", rand::random::<u64>());
+    let mut child = command
+        .env(HELPER_ADDRESS_ENV, listener.local_addr()?.to_string())
+        .env(HELPER_TOKEN_ENV, &token)
+        .spawn()
+        .context("Unable to spawn the storage helper process")?;
+    match accept_helper(&listener, &mut child, &token) {
+        Ok(stream) => Ok((child, stream)),
+        Err(err) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(err)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn accept_helper(listener: &TcpListener, child: &mut Child, token: &str) -> Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    let start = Instant::now();
+    let mut stream = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(status) = child.try_wait()? {
+                    bail!("The storage helper process exited with {status} before connecting");
+                }
+                if start.elapsed() > CONNECT_TIMEOUT {
+                    bail!("The storage helper process didn't connect within {CONNECT_TIMEOUT:?}");
+                }
+                sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let mut received = vec![0; token.len()];
+    stream.read_exact(&mut received)?;
+    if received != token.as_bytes() {
+        bail!("The storage helper process sent an invalid token");
+    }
+    Ok(stream)
+}
+
+/// Returns the connection to the parent process when the current process was spawned as
+/// storage helper process.
+#[cfg(unix)]
+fn connect_parent() -> Result<Option<Stream>> {
+    let Ok(fd) = env::var(HELPER_FD_ENV) else {
+        return Ok(None);
+    };
+    let fd: RawFd = fd
+        .parse()
+        .context("Invalid storage helper file descriptor")?;
+    // Safety: The parent process passes its end of the socket pair, which isn't owned by
+    // anything else in this process.
+    Ok(Some(unsafe { UnixStream::from_raw_fd(fd) }))
+}
+
+/// Returns the connection to the parent process when the current process was spawned as
+/// storage helper process.
+#[cfg(not(unix))]
+fn connect_parent() -> Result<Option<Stream>> {
+    let Ok(address) = env::var(HELPER_ADDRESS_ENV) else {
+        return Ok(None);
+    };
+    let token = env::var(HELPER_TOKEN_ENV).context("Missing storage helper token")?;
+    let mut stream = TcpStream::connect(&address)?;
+    stream.set_nodelay(true)?;
+    stream.write_all(token.as_bytes())?;
+    Ok(Some(stream))
+}
+
+/// Serves a [`ProcessIsolatedBackingStorage`] when the current process was spawned as its
+/// helper process. Returns `false` immediately otherwise. The process should exit when this
+/// returns `true`.
+pub fn run_storage_helper_if_requested() -> Result<bool> {
+    let Some(stream) = connect_parent()? else {
+        return Ok(false);
+    };
+    let path = PathBuf::from(env::var_os(HELPER_PATH_ENV).context("Missing storage path")?);
+    let storage = crate::lmdb_backing_storage(&path)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    // Task types and task data can only be (de)serialized with a turbo tasks context. The
+    // helper doesn't execute tasks, so a backend without persistence is enough.
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let _runtime = runtime.enter();
+    let turbo_tasks: Arc<dyn TurboTasksApi> =
+        TurboTasks::new(TurboTasksBackend::new(noop_backing_storage(&path)?));
+    turbo_tasks_scope(turbo_tasks, || {
+        while let Some(request) = read_message(&mut reader)? {
+            let response = handle_request(&storage, request);
+            write_message(&mut writer, &response)?;
+        }
+        anyhow::Ok(())
+    })?;
+    Ok(true)
+}
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    NextFreeTaskId,
+    /// Task types are sent serialized, since they can't be cloned into a request.
+    ContentAddressedTaskId(Vec<u8>),
+    NextSessionId,
+    UncompletedOperations,
+    SaveSnapshot {
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<(Arc<CachedTaskType>, TaskId)>,
+        meta_updates: Vec<Update>,
+        data_updates: Vec<Update>,
+    },
+    EstimateSnapshotCost {
+        task_cache_len: usize,
+        data_updates_len: usize,
+    },
+    ForwardLookupTaskCache(Vec<u8>),
+    ReverseLookupTaskCache(TaskId),
+    LookupData(TaskId, Category),
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response {
+    TaskId(Option<TaskId>),
+    SessionId(SessionId),
+    Operations(Vec<AnyOperation>),
+    Snapshot(Result<(), String>),
+    SnapshotCost { bytes: u64, duration: Duration },
+    TaskType(Option<Arc<CachedTaskType>>),
+    Data(Vec<CachedDataItem>),
+    Error(String),
+}
+
+/// A [`CachedDataUpdate`] with the key stored in the items, since keys alone can't be
+/// serialized.
+#[derive(Serialize, Deserialize)]
+struct Update {
+    task: TaskId,
+    value: Option<CachedDataItem>,
+    old_value: Option<CachedDataItem>,
+}
+
+impl Update {
+    /// Returns `None` for updates without a value, which don't change the stored data.
+    fn new(update: CachedDataUpdate) -> Option<Self> {
+        let CachedDataUpdate {
+            task,
+            key,
+            value,
+            old_value,
+        } = update;
+        if value.is_none() && old_value.is_none() {
+            return None;
+        }
+        Some(Self {
+            task,
+            value: value.map(|value| CachedDataItem::from_key_and_value(key.clone(), value)),
+            old_value: old_value.map(|value| CachedDataItem::from_key_and_value(key, value)),
+        })
+    }
+
+    fn into_update(self) -> Result<CachedDataUpdate> {
+        let (key, value) = match self.value {
+            Some(item) => {
+                let (key, value) = item.into_key_and_value();
+                (key, Some(value))
+            }
+            None => (
+                self.old_value
+                    .as_ref()
+                    .context("Update without a value")?
+                    .key(),
+                None,
+            ),
+        };
+        Ok(CachedDataUpdate {
+            task: self.task,
+            key,
+            value,
+            old_value: self.old_value.map(|item| item.into_key_and_value().1),
+        })
+    }
+}
+
+/// Serializes the `updates` one by one, so optional items that can't be serialized are skipped
+/// like [`KeyValueDatabaseBackingStorage`](crate::KeyValueDatabaseBackingStorage) does.
+fn serialize_updates(updates: Vec<ChunkedVec<CachedDataUpdate>>) -> Result<Vec<Update>> {
+    let mut result = Vec::new();
+    for update in updates.into_iter().flatten() {
+        let Some(update) = Update::new(update) else {
+            continue;
+        };
+        if let Err(err) = pot::to_vec(&update) {
+            let optional = [&update.value, &update.old_value]
+                .into_iter()
+                .flatten()
+                .all(|item| item.is_optional());
+            if !optional {
+                return Err(anyhow!(err).context(format!(
+                    "Unable to serialize an update of task {}",
+                    update.task
+                )));
+            }
+            continue;
+        }
+        result.push(update);
+    }
+    Ok(result)
+}
+
+fn deserialize_updates(updates: Vec<Update>) -> Result<Vec<ChunkedVec<CachedDataUpdate>>> {
+    let mut result = ChunkedVec::new();
+    for update in updates {
+        result.push(update.into_update()?);
+    }
+    Ok(vec![result])
+}
+
+#[derive(Serialize, Deserialize)]
+enum Category {
+    Meta,
+    Data,
+    All,
+}
+
+impl From<TaskDataCategory> for Category {
+    fn from(category: TaskDataCategory) -> Self {
+        match category {
+            TaskDataCategory::Meta => Category::Meta,
+            TaskDataCategory::Data => Category::Data,
+            TaskDataCategory::All => Category::All,
+        }
+    }
+}
+
+impl From<Category> for TaskDataCategory {
+    fn from(category: Category) -> Self {
+        match category {
+            Category::Meta => TaskDataCategory::Meta,
+            Category::Data => TaskDataCategory::Data,
+            Category::All => TaskDataCategory::All,
+        }
+    }
+}
+
+fn handle_request(storage: &LmdbBackingStorage, request: Request) -> Response {
+    match try_handle_request(storage, request) {
+        Ok(response) => response,
+        Err(err) => Response::Error(format!("{err:?}")),
+    }
+}
+
+fn try_handle_request(storage: &LmdbBackingStorage, request: Request) -> Result<Response> {
+    Ok(match request {
+        Request::NextFreeTaskId => Response::TaskId(Some(storage.next_free_task_id())),
+        Request::ContentAddressedTaskId(task_type) => {
+            let task_type: CachedTaskType = pot::from_slice(&task_type)?;
+            Response::TaskId(storage.content_addressed_task_id(&task_type))
+        }
+        Request::NextSessionId => Response::SessionId(storage.next_session_id()),
+        Request::UncompletedOperations => Response::Operations(storage.uncompleted_operations()),
+        Request::SaveSnapshot {
+            session_id,
+            operations,
+            task_cache_updates,
+            meta_updates,
+            data_updates,
+        } => {
+            let result = (|| {
+                let mut task_cache = ChunkedVec::new();
+                task_cache.extend(task_cache_updates);
+                storage.save_snapshot(
+                    session_id,
+                    operations,
+                    vec![task_cache],
+                    deserialize_updates(meta_updates)?,
+                    deserialize_updates(data_updates)?,
+                )
+            })();
+            Response::Snapshot(result.map_err(|err| format!("{err:?}")))
+        }
+        Request::EstimateSnapshotCost {
+            task_cache_len,
+            data_updates_len,
+        } => {
+            let SnapshotCostEstimate { bytes, duration } =
+                storage.estimate_snapshot_cost(task_cache_len, data_updates_len);
+            Response::SnapshotCost { bytes, duration }
+        }
+        Request::ForwardLookupTaskCache(task_type) => {
+            let task_type: CachedTaskType = pot::from_slice(&task_type)?;
+            let tx = storage.start_read_transaction();
+            // Safety: The transaction is a transaction of the storage.
+            Response::TaskId(unsafe { storage.forward_lookup_task_cache(tx.as_ref(), &task_type) })
+        }
+        Request::ReverseLookupTaskCache(task_id) => {
+            let tx = storage.start_read_transaction();
+            // Safety: The transaction is a transaction of the storage.
+            Response::TaskType(unsafe { storage.reverse_lookup_task_cache(tx.as_ref(), task_id) })
+        }
+        Request::LookupData(task_id, category) => {
+            let tx = storage.start_read_transaction();
+            // Safety: The transaction is a transaction of the storage.
+            Response::Data(Arc::unwrap_or_clone(unsafe {
+                storage.lookup_data(tx.as_ref(), task_id, category.into())
+            }))
+        }
+    })
+}
+
+/// Writes `message` prefixed with its length as little-endian u32.
+fn write_message(writer: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    let bytes = pot::to_vec(message)?;
+    if bytes.len() > MAX_MESSAGE_LEN {
+        bail!(
+            "The message is {} bytes long, which exceeds the limit of {MAX_MESSAGE_LEN} bytes",
+            bytes.len()
+        );
+    }
+    writer.write_all(&u32::try_from(bytes.len())?.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a message written by [`write_message`]. Returns `None` when the connection was closed.
+fn read_message<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        bail!(
+            "Received a message of {len} bytes, which exceeds the limit of {MAX_MESSAGE_LEN} bytes"
+        );
+    }
+    // The buffer grows with the received data instead of trusting the length
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(pot::from_slice(&bytes)?))
+}
+
+impl BackingStorage for ProcessIsolatedBackingStorage {
+    type ReadTransaction<'l> = ();
+
+    fn lower_read_transaction<'l: 'i + 'r, 'i: 'r, 'r>(
+        tx: &'r Self::ReadTransaction<'l>,
+    ) -> &'r Self::ReadTransaction<'i> {
+        tx
+    }
+
+    fn next_free_task_id(&self) -> TaskId {
+        match self.request(&Request::NextFreeTaskId) {
+            Ok(Response::TaskId(Some(task_id))) => task_id,
+            result => {
+                tracing::error!(
+                    "Unable to get the next free task id from the storage helper process: {}",
+                    unexpected(result)
+                );
+                // Like an empty store, which the degraded storage behaves like
+                self.degraded.store(true, Ordering::Relaxed);
+                TaskId::from(1)
+            }
+        }
+    }
+
+    fn content_addressed_task_id(&self, task_type: &CachedTaskType) -> Option<TaskId> {
+        let request = pot::to_vec(task_type).map(Request::ContentAddressedTaskId);
+        match request
+            .map_err(anyhow::Error::from)
+            .and_then(|request| self.request(&request))
+        {
+            Ok(Response::TaskId(task_id)) => task_id,
+            result => {
+                tracing::error!(
+                    "Content addressed task id lookup failed: {}",
+                    unexpected(result)
+                );
+                None
+            }
+        }
+    }
+
+    fn next_session_id(&self) -> SessionId {
+        match self.request(&Request::NextSessionId) {
+            Ok(Response::SessionId(session_id)) => session_id,
+            result => {
+                tracing::error!(
+                    "Unable to get the next session id from the storage helper process: {}",
+                    unexpected(result)
+                );
+                self.degraded.store(true, Ordering::Relaxed);
+                SessionId::from(1)
+            }
+        }
+    }
+
+    fn uncompleted_operations(&self) -> Vec<AnyOperation> {
+        match self.request(&Request::UncompletedOperations) {
+            Ok(Response::Operations(operations)) => operations,
+            result => {
+                tracing::error!(
+                    "Looking up uncompleted operations failed: {}",
+                    unexpected(result)
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    fn save_snapshot(
+        &self,
+        session_id: SessionId,
+        operations: Vec<Arc<AnyOperation>>,
+        task_cache_updates: Vec<ChunkedVec<(Arc<CachedTaskType>, TaskId)>>,
+        meta_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+        data_updates: Vec<ChunkedVec<CachedDataUpdate>>,
+    ) -> Result<()> {
+        let request = Request::SaveSnapshot {
+            session_id,
+            operations,
+            task_cache_updates: task_cache_updates.into_iter().flatten().collect(),
+            meta_updates: serialize_updates(meta_updates)?,
+            data_updates: serialize_updates(data_updates)?,
+        };
+        match self.request(&request)? {
+            Response::Snapshot(result) => result.map_err(|err| anyhow!(err)),
+            Response::Error(err) => Err(anyhow!(err)),
+            _ => bail!("Unexpected response of the storage helper process"),
+        }
+    }
+
+    fn estimate_snapshot_cost(
+        &self,
+        task_cache_len: usize,
+        data_updates_len: usize,
+    ) -> SnapshotCostEstimate {
+        match self.request(&Request::EstimateSnapshotCost {
+            task_cache_len,
+            data_updates_len,
+        }) {
+            Ok(Response::SnapshotCost { bytes, duration }) => {
+                SnapshotCostEstimate { bytes, duration }
+            }
+            _ => SnapshotCostEstimate::default(),
+        }
+    }
+
+    fn start_read_transaction(&self) -> Option<Self::ReadTransaction<'_>> {
+        None
+    }
+
+    unsafe fn forward_lookup_task_cache(
+        &self,
+        _tx: Option<&Self::ReadTransaction<'_>>,
+        key: &CachedTaskType,
+    ) -> Option<TaskId> {
+        let request = pot::to_vec(key).map(Request::ForwardLookupTaskCache);
+        match request
+            .map_err(anyhow::Error::from)
+            .and_then(|request| self.request(&request))
+        {
+            Ok(Response::TaskId(task_id)) => task_id,
+            result => {
+                tracing::error!(
+                    "Looking up task id for {key:?} failed: {}",
+                    unexpected(result)
+                );
+                None
+            }
+        }
+    }
+
+    unsafe fn reverse_lookup_task_cache(
+        &self,
+        _tx: Option<&Self::ReadTransaction<'_>>,
+        task_id: TaskId,
+    ) -> Option<Arc<CachedTaskType>> {
+        match self.request(&Request::ReverseLookupTaskCache(task_id)) {
+            Ok(Response::TaskType(task_type)) => task_type,
+            result => {
+                tracing::error!(
+                    "Looking up task type for {task_id} failed: {}",
+                    unexpected(result)
+                );
+                None
+            }
+        }
+    }
+
+    unsafe fn lookup_data(
+        &self,
+        _tx: Option<&Self::ReadTransaction<'_>>,
+        task_id: TaskId,
+        category: TaskDataCategory,
+    ) -> Arc<Vec<CachedDataItem>> {
+        match self.request(&Request::LookupData(task_id, category.into())) {
+            Ok(Response::Data(data)) => Arc::new(data),
+            result => {
+                tracing::error!(
+                    "Looking up data for {task_id} failed: {}",
+                    unexpected(result)
+                );
+                Default::default()
+            }
+        }
+    }
+}
+
+/// Describes a failed request or a response of the wrong kind.
+fn unexpected(result: Result<Response>) -> String {
+    match result {
+        Ok(Response::Error(err)) => err,
+        Ok(_) => "unexpected response".to_string(),
+        Err(err) => format!("{err:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use super::*;
+    use crate::{
+        data::{CachedDataItemKey, CachedDataItemValue},
+        kv_backing_storage::test_utils::with_turbo_tasks,
+    };
+
+    /// Serves the store when spawned by another test and does nothing otherwise.
+    #[test]
+    fn storage_helper() -> Result<()> {
+        run_storage_helper_if_requested()?;
+        Ok(())
+    }
+
+    fn spawn_helper(path: &Path) -> Result<ProcessIsolatedBackingStorage> {
+        let mut command = Command::new(env::current_exe()?);
+        command
+            .args([
+                "--exact",
+                "process_isolated_backing_storage::tests::storage_helper",
+            ])
+            .stdout(Stdio::null());
+        ProcessIsolatedBackingStorage::spawn(command, path)
+    }
+
+    #[test]
+    fn snapshots_and_lookups_are_forwarded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let task = TaskId::from(1);
+        let storage = spawn_helper(dir.path())?;
+        assert_eq!(storage.next_free_task_id(), TaskId::from(1));
+        assert_eq!(storage.next_session_id(), SessionId::from(1));
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task,
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                vec![updates],
+            )
+        })?;
+        let lookup = |storage: &ProcessIsolatedBackingStorage| {
+            // Safety: This storage has no read transactions.
+            with_turbo_tasks(|| unsafe { storage.lookup_data(None, task, TaskDataCategory::Data) })
+        };
+        let data = lookup(&storage);
+        assert_eq!(data.len(), 1);
+        assert!(matches!(
+            data[0],
+            CachedDataItem::ChildrenCount { value: 7 }
+        ));
+        assert!(storage.uncompleted_operations().is_empty());
+        // Safety: This storage has no read transactions.
+        assert!(unsafe { storage.reverse_lookup_task_cache(None, task) }.is_none());
+
+        // A crash of the helper process doesn't take down this process
+        storage.child.lock().kill()?;
+        assert!(lookup(&storage).is_empty());
+        assert!(with_turbo_tasks(|| storage.save_snapshot(
+            SessionId::from(2),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        ))
+        .is_err());
+        assert!(storage.is_degraded());
+        assert_eq!(storage.next_session_id(), SessionId::from(1));
+        drop(storage);
+
+        let storage = spawn_helper(dir.path())?;
+        assert_eq!(storage.next_session_id(), SessionId::from(2));
+        assert_eq!(lookup(&storage).len(), 1);
+        Ok(())
+    }
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let mut message = u32::MAX.to_le_bytes().to_vec();
+        message.extend_from_slice(&[0; 16]);
+        assert!(read_message::<Request>(&mut &message[..]).is_err());
+
+        // A message that is shorter than its length
+        let mut message = 100u32.to_le_bytes().to_vec();
+        message.extend_from_slice(&[0; 16]);
+        assert!(read_message::<Request>(&mut &message[..]).is_err());
+    }
+}
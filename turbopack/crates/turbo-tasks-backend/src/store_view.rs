@@ -0,0 +1,331 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, Request,
+};
+use nix::errno::Errno;
+use turbo_tasks::TaskId;
+
+use crate::{
+    backend::TaskDataCategory, data::CachedDataItem,
+    database::key_value_database::KeyValueDatabase, KeyValueDatabaseBackingStorage,
+};
+
+const ROOT_INO: u64 = 1;
+/// The store can change while it's mounted, so the kernel shouldn't cache anything for long.
+const TTL: Duration = Duration::from_secs(1);
+
+/// A read-only filesystem with one directory per task, named by the task id, and one file per
+/// item of the task, named by the index and key of the item. Files contain the debug output of
+/// the item. The task directory's inode is the task id shifted by 32 bits, the inodes of its
+/// files add the index of the item plus one. Every call reads the store again with
+/// [`KeyValueDatabaseBackingStorage::lookup_data_arc`].
+struct StoreView<T: KeyValueDatabase> {
+    storage: Arc<KeyValueDatabaseBackingStorage<T>>,
+}
+
+impl<T: KeyValueDatabase> StoreView<T> {
+    fn task_ids(&self) -> Result<Vec<TaskId>> {
+        self.storage.snapshot()?.task_ids()
+    }
+
+    /// Returns the meta data and data items of the task, in this order.
+    fn items(&self, task_id: TaskId) -> Result<Vec<CachedDataItem>> {
+        let mut items = Vec::new();
+        for category in [TaskDataCategory::Meta, TaskDataCategory::Data] {
+            items.extend(
+                self.storage
+                    .lookup_data_arc(task_id, category)?
+                    .iter()
+                    .cloned(),
+            );
+        }
+        Ok(items)
+    }
+
+    fn item(&self, ino: u64) -> Result<Option<Vec<u8>>> {
+        let (task_id, Some(index)) = split_ino(ino) else {
+            return Ok(None);
+        };
+        Ok(self
+            .items(task_id)?
+            .get(index)
+            .map(|item| format!("{item:#?}\n").into_bytes()))
+    }
+}
+
+fn task_ino(task_id: TaskId) -> u64 {
+    (*task_id as u64) << 32
+}
+
+fn item_ino(task_id: TaskId, index: usize) -> u64 {
+    task_ino(task_id) + index as u64 + 1
+}
+
+/// Returns the task id of the inode and the index of the item for files.
+fn split_ino(ino: u64) -> (TaskId, Option<usize>) {
+    let task_id = TaskId::from((ino >> 32) as u32);
+    let index = (ino as u32).checked_sub(1).map(|index| index as usize);
+    (task_id, index)
+}
+
+fn item_name(index: usize, item: &CachedDataItem) -> String {
+    let key = format!("{:?}", item.key()).replace(['/', '\0'], "_");
+    format!("{index:04} {key}")
+}
+
+fn attr(ino: u64, kind: FileType, size: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind,
+        perm: if kind == FileType::Directory {
+            0o555
+        } else {
+            0o444
+        },
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
+        rdev: 0,
+        blksize: 512,
+        flags: 0,
+    }
+}
+
+/// Logs a failed read of the store and returns the errno for it.
+fn read_error(err: anyhow::Error) -> i32 {
+    tracing::warn!("Failed to read the store for the store view: {err:?}");
+    Errno::EIO as i32
+}
+
+impl<T: KeyValueDatabase> Filesystem for StoreView<T> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(name) = name.to_str() else {
+            return reply.error(Errno::ENOENT as i32);
+        };
+        if parent == ROOT_INO {
+            let Ok(task_id) = name.parse::<u32>() else {
+                return reply.error(Errno::ENOENT as i32);
+            };
+            match self.task_ids() {
+                Ok(task_ids) if task_id != 0 && task_ids.contains(&TaskId::from(task_id)) => {
+                    let ino = task_ino(TaskId::from(task_id));
+                    reply.entry(&TTL, &attr(ino, FileType::Directory, 0), 0)
+                }
+                Ok(_) => reply.error(Errno::ENOENT as i32),
+                Err(err) => reply.error(read_error(err)),
+            }
+        } else {
+            let (task_id, None) = split_ino(parent) else {
+                return reply.error(Errno::ENOTDIR as i32);
+            };
+            match self.items(task_id) {
+                Ok(items) => {
+                    let found = items
+                        .iter()
+                        .enumerate()
+                        .find(|(index, item)| item_name(*index, item) == name);
+                    match found {
+                        Some((index, item)) => {
+                            let size = format!("{item:#?}\n").len() as u64;
+                            let ino = item_ino(task_id, index);
+                            reply.entry(&TTL, &attr(ino, FileType::RegularFile, size), 0)
+                        }
+                        None => reply.error(Errno::ENOENT as i32),
+                    }
+                }
+                Err(err) => reply.error(read_error(err)),
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        if ino == ROOT_INO {
+            return reply.attr(&TTL, &attr(ino, FileType::Directory, 0));
+        }
+        match split_ino(ino) {
+            (_, None) => reply.attr(&TTL, &attr(ino, FileType::Directory, 0)),
+            (_, Some(_)) => match self.item(ino) {
+                Ok(Some(content)) => reply.attr(
+                    &TTL,
+                    &attr(ino, FileType::RegularFile, content.len() as u64),
+                ),
+                Ok(None) => reply.error(Errno::ENOENT as i32),
+                Err(err) => reply.error(read_error(err)),
+            },
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.item(ino) {
+            Ok(Some(content)) => {
+                let start = (offset.max(0) as usize).min(content.len());
+                let end = (start + size as usize).min(content.len());
+                reply.data(&content[start..end])
+            }
+            Ok(None) => reply.error(Errno::ENOENT as i32),
+            Err(err) => reply.error(read_error(err)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = if ino == ROOT_INO {
+            self.task_ids().map(|task_ids| {
+                task_ids
+                    .into_iter()
+                    .map(|task_id| (task_ino(task_id), FileType::Directory, task_id.to_string()))
+                    .collect::<Vec<_>>()
+            })
+        } else {
+            let (task_id, None) = split_ino(ino) else {
+                return reply.error(Errno::ENOTDIR as i32);
+            };
+            self.items(task_id).map(|items| {
+                items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        (
+                            item_ino(task_id, index),
+                            FileType::RegularFile,
+                            item_name(index, item),
+                        )
+                    })
+                    .collect()
+            })
+        };
+        match entries {
+            Ok(entries) => {
+                let dots = [
+                    (ino, FileType::Directory, ".".to_string()),
+                    (ROOT_INO, FileType::Directory, "..".to_string()),
+                ];
+                for (i, (ino, kind, name)) in dots
+                    .into_iter()
+                    .chain(entries)
+                    .enumerate()
+                    .skip(offset as usize)
+                {
+                    // The offset is the one of the next entry
+                    if reply.add(ino, i as i64 + 1, kind, name) {
+                        break;
+                    }
+                }
+                reply.ok()
+            }
+            Err(err) => reply.error(read_error(err)),
+        }
+    }
+}
+
+/// Mounts a read-only view of the store at `mountpoint` for debugging, so it can be explored with
+/// normal file tools. There is a directory per task, named by the task id, with a file per item
+/// of the task. Files contain the debug output of the item and are named by the index and key of
+/// the item, meta data items come first. The view reads the store on every access, so it shows
+/// the latest snapshot. It's unmounted when the returned session is dropped.
+pub fn mount_store_view<T>(
+    storage: Arc<KeyValueDatabaseBackingStorage<T>>,
+    mountpoint: &Path,
+) -> Result<BackgroundSession>
+where
+    T: KeyValueDatabase + Send + Sync + 'static,
+{
+    fuser::spawn_mount2(
+        StoreView { storage },
+        mountpoint,
+        &[
+            MountOption::RO,
+            MountOption::FSName("turbo-tasks-store".to_string()),
+        ],
+    )
+    .with_context(|| format!("Unable to mount the store view at {}", mountpoint.display()))
+}
+
+#[cfg(all(test, target_os = "linux", feature = "lmdb"))]
+mod tests {
+    use turbo_tasks::SessionId;
+
+    use super::*;
+    use crate::{
+        backing_storage::BackingStorage,
+        data::{CachedDataItemKey, CachedDataItemValue, CachedDataUpdate},
+        database::LmbdKeyValueDatabase,
+        kv_backing_storage::test_utils,
+        utils::chunked_vec::ChunkedVec,
+    };
+
+    #[test]
+    fn items_are_readable_as_files() -> Result<()> {
+        if !Path::new("/dev/fuse").exists() {
+            // FUSE isn't available, e.g. in containers
+            return Ok(());
+        }
+        let dir = tempfile::tempdir()?;
+        let mountpoint = tempfile::tempdir()?;
+        let storage = Arc::new(KeyValueDatabaseBackingStorage::new(
+            LmbdKeyValueDatabase::new(dir.path())?,
+        )?);
+        let mut updates = ChunkedVec::new();
+        updates.push(CachedDataUpdate {
+            task: TaskId::from(3),
+            key: CachedDataItemKey::ChildrenCount {},
+            value: Some(CachedDataItemValue::ChildrenCount { value: 7 }),
+            old_value: None,
+        });
+        test_utils::with_turbo_tasks(|| {
+            storage.save_snapshot(
+                SessionId::from(1),
+                Vec::new(),
+                Vec::new(),
+                Vec::new(),
+                vec![updates],
+            )
+        })?;
+
+        let session = mount_store_view(storage.clone(), mountpoint.path())?;
+        let tasks = std::fs::read_dir(mountpoint.path())?
+            .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tasks, ["3"]);
+        let items = std::fs::read_dir(mountpoint.path().join("3"))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(items.len(), 1);
+        assert!(items[0].ends_with("0000 ChildrenCount"));
+        let content = std::fs::read_to_string(&items[0])?;
+        assert!(content.contains("ChildrenCount"));
+        assert!(content.contains("7"));
+        drop(session);
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+/// Identifies the file format.
+const MAGIC: &[u8] = b"TTTC\x01";
+
+/// Writes the header of a task cache export. The file is the magic, the next free task id as
+/// little-endian u32 and the entries. Each entry is the task id and the length of the serialized
+/// task type as little-endian u32 followed by the serialized task type. A zero task id ends the
+/// file.
+pub(crate) fn write_header(w: &mut impl Write, next_free_task_id: u32) -> Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&next_free_task_id.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn write_entry(w: &mut impl Write, task_id: u32, task_type_bytes: &[u8]) -> Result<()> {
+    debug_assert_ne!(task_id, 0);
+    w.write_all(&task_id.to_le_bytes())?;
+    w.write_all(&u32::try_from(task_type_bytes.len())?.to_le_bytes())?;
+    w.write_all(task_type_bytes)?;
+    Ok(())
+}
+
+pub(crate) fn write_end(w: &mut impl Write) -> Result<()> {
+    w.write_all(&0u32.to_le_bytes())?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Reads a task cache export and returns the next free task id and the entries.
+pub(crate) fn read(r: &mut impl Read) -> Result<(u32, Vec<(u32, Vec<u8>)>)> {
+    let mut magic = [0; MAGIC.len()];
+    r.read_exact(&mut magic)
+        .context("Truncated task cache export")?;
+    if magic != MAGIC {
+        bail!("Unknown task cache export format");
+    }
+    let next_free_task_id = read_u32(r)?;
+    let mut entries = Vec::new();
+    loop {
+        let task_id = read_u32(r)?;
+        if task_id == 0 {
+            return Ok((next_free_task_id, entries));
+        }
+        let len = read_u32(r)? as usize;
+        let mut task_type_bytes = Vec::new();
+        r.take(len as u64).read_to_end(&mut task_type_bytes)?;
+        if task_type_bytes.len() != len {
+            bail!("Truncated task cache export: the task type of {task_id} is incomplete");
+        }
+        entries.push((task_id, task_type_bytes));
+    }
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)
+        .context("Truncated task cache export")?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_exports_are_rejected() -> Result<()> {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, 8)?;
+        write_entry(&mut bytes, 3, b"task type")?;
+        write_entry(&mut bytes, 7, b"")?;
+        write_end(&mut bytes)?;
+        let (next_free_task_id, entries) = read(&mut &bytes[..])?;
+        assert_eq!(next_free_task_id, 8);
+        assert_eq!(entries, vec![(3, b"task type".to_vec()), (7, Vec::new())]);
+
+        for len in 0..bytes.len() {
+            assert!(read(&mut &bytes[..len]).is_err());
+        }
+        Ok(())
+    }
+}
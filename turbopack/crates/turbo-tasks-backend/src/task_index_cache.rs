@@ -0,0 +1,98 @@
+use anyhow::{bail, Result};
+use turbo_tasks::TaskId;
+use turbo_tasks_hash::hash_xxh3_hash128;
+
+/// Identifies the file format.
+const MAGIC: &[u8] = b"TTIX\x02";
+const GENERATION_SIZE: usize = 16;
+const HEADER_SIZE: usize = MAGIC.len() + 16 + GENERATION_SIZE;
+
+/// The state of the store a task index cache was written for. The cache is stale when the store
+/// has a different generation. The write count changes with every write to the store, the other
+/// fields make a cache of another store less likely to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Generation {
+    pub session_id: u32,
+    pub next_free_task_id: u32,
+    pub write_count: u64,
+}
+
+/// Serializes the sorted task index of a store with the `generation`. The file is the magic, a
+/// 128 bit hash of the rest, the generation as two little-endian u32 and a little-endian u64, and
+/// the task ids as little-endian u32.
+pub(crate) fn encode(generation: Generation, task_ids: &[TaskId]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(GENERATION_SIZE + task_ids.len() * 4);
+    body.extend_from_slice(&generation.session_id.to_le_bytes());
+    body.extend_from_slice(&generation.next_free_task_id.to_le_bytes());
+    body.extend_from_slice(&generation.write_count.to_le_bytes());
+    for task_id in task_ids {
+        body.extend_from_slice(&task_id.to_le_bytes());
+    }
+    let mut result = Vec::with_capacity(HEADER_SIZE + body.len());
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&hash_xxh3_hash128(&body[..]).to_le_bytes());
+    result.extend_from_slice(&body);
+    result
+}
+
+/// Reads a task index cache. Fails when it's corrupted or was written for another generation.
+pub(crate) fn decode(bytes: &[u8], generation: Generation) -> Result<Vec<TaskId>> {
+    let Some(bytes) = bytes.strip_prefix(MAGIC) else {
+        bail!("Unknown task index cache format");
+    };
+    if bytes.len() < 16 + GENERATION_SIZE || (bytes.len() - 16 - GENERATION_SIZE) % 4 != 0 {
+        bail!("Truncated task index cache");
+    }
+    let (hash, body) = bytes.split_at(16);
+    if u128::from_le_bytes(hash.try_into()?) != hash_xxh3_hash128(body) {
+        bail!("Corrupted task index cache");
+    }
+    let (generation_bytes, task_ids) = body.split_at(GENERATION_SIZE);
+    let read_u32 =
+        |pos: usize| u32::from_le_bytes(generation_bytes[pos..pos + 4].try_into().unwrap());
+    let cached = Generation {
+        session_id: read_u32(0),
+        next_free_task_id: read_u32(4),
+        write_count: u64::from_le_bytes(generation_bytes[8..].try_into()?),
+    };
+    if cached != generation {
+        bail!("Stale task index cache for {cached:?}, but the store is at {generation:?}");
+    }
+    Ok(task_ids
+        .chunks_exact(4)
+        .map(|word| TaskId::from(u32::from_le_bytes(word.try_into().unwrap())))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_and_corrupted_caches_are_rejected() -> Result<()> {
+        let generation = Generation {
+            session_id: 3,
+            next_free_task_id: 10,
+            write_count: 7,
+        };
+        let task_ids = vec![TaskId::from(1), TaskId::from(4), TaskId::from(9)];
+        let encoded = encode(generation, &task_ids);
+        assert_eq!(decode(&encoded, generation)?, task_ids);
+
+        let newer = Generation {
+            session_id: 4,
+            ..generation
+        };
+        assert!(decode(&encoded, newer).is_err());
+        let written = Generation {
+            write_count: 8,
+            ..generation
+        };
+        assert!(decode(&encoded, written).is_err());
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(decode(&corrupted, generation).is_err());
+        assert!(decode(&encoded[..encoded.len() - 2], generation).is_err());
+        Ok(())
+    }
+}
@@ -55,4 +55,21 @@ where
             }
         }
     }
+
+    /// Like [`BiMap::try_insert`], but also fails when the value is already mapped to another key.
+    /// In that case `Err(None)` is returned.
+    pub fn try_insert_unique(&self, key: K, value: V) -> Result<(), Option<V>> {
+        match self.forward.entry(key) {
+            Entry::Occupied(e) => Err(Some(e.get().clone())),
+            Entry::Vacant(e) => match self.reverse.entry(value.clone()) {
+                Entry::Occupied(_) => Err(None),
+                Entry::Vacant(reverse_entry) => {
+                    let e = e.insert_entry(value);
+                    reverse_entry.insert(e.key().clone());
+                    drop(e);
+                    Ok(())
+                }
+            },
+        }
+    }
 }
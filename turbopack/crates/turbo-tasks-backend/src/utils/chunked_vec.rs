@@ -8,6 +8,15 @@ impl<T> Default for ChunkedVec<T> {
     }
 }
 
+impl<T: Clone> Clone for ChunkedVec<T> {
+    fn clone(&self) -> Self {
+        // Chunk capacities are significant, so we can't derive Clone
+        let mut new = Self::new();
+        new.extend(self.iter().cloned());
+        new
+    }
+}
+
 impl<T> ChunkedVec<T> {
     pub fn new() -> Self {
         Self { chunks: Vec::new() }
@@ -4,3 +4,4 @@ pub mod dash_map_multi;
 pub mod deque_set;
 pub mod ptr_eq_arc;
 pub mod sharded;
+pub mod stable_hash;
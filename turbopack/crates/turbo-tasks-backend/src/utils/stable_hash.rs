@@ -0,0 +1,26 @@
+use turbo_tasks_hash::{DeterministicHasher, Xxh3Hash64Hasher};
+
+/// Hashes `bytes` with the 64 bit xxh3 algorithm and seed 0. Unlike `FxHasher` and the `Hash`
+/// impl of slices, the result doesn't depend on the endianness or the pointer width, so it can be
+/// persisted and stores are portable between architectures.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = Xxh3Hash64Hasher::new();
+    hasher.write_bytes(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stable_hash;
+
+    #[test]
+    fn digests_are_pinned() {
+        // Reference values of XXH3_64bits, changing them breaks existing stores
+        assert_eq!(stable_hash(b""), 0x2d06800538d394c2);
+        assert_eq!(stable_hash(b"a"), 0xe6c632b61e964e1f);
+        assert_eq!(stable_hash(b"some task type"), 0x46cf4e57a9b41e4a);
+        assert_eq!(stable_hash(&[0; 200]), 0x8f8c9188233578c2);
+        let bytes = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(stable_hash(&bytes), 0xf42a8864feaf0703);
+    }
+}
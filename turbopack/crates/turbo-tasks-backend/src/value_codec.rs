@@ -0,0 +1,143 @@
+use std::io::{self, Write};
+
+use anyhow::{bail, Result};
+use pot::Compatibility;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The first byte of stored values that are encoded, e.g. compressed, instead of containing the
+/// serialized data directly. An encoded value starts with this byte followed by the name of the
+/// encoding, e.g. `\0lz4`, and the encoding specific header. Values serialized by a
+/// [`ValueCodec`] never start with a zero byte, so plain and encoded values can be told apart
+/// without any other metadata. This keeps every encoding readable when the option that enables it
+/// is disabled later, and allows a store to contain values written with different options.
+///
+/// Task data is encoded in this order when writing and decoded in the reverse order:
+/// 1. Framing with the length, the writer version and padding, see
+///    [`frame`](crate::data_framing::frame).
+/// 2. Compression, see [`compress`](crate::data_compression::compress).
+/// 3. Either a delta against the baseline of the task, see [`Delta`](crate::data_delta::Delta), or
+///    a reference to a deduplicated blob, see [`BlobUpdates`](crate::data_blob::BlobUpdates).
+///
+/// Task data that is streamed into the database is written as plain serialized data. Infra values
+/// that are larger than the chunk size are split into chunks, see
+/// [`write_chunked`](crate::chunked_value::write_chunked).
+pub(crate) const ENCODED_VALUE_MARKER: u8 = 0;
+
+/// Returns whether `value` is encoded, see [`ENCODED_VALUE_MARKER`].
+pub(crate) fn is_encoded(value: &[u8]) -> bool {
+    value.first() == Some(&ENCODED_VALUE_MARKER)
+}
+
+/// The format used to serialize task data values. The codec of a database is recorded when the
+/// database is created, so values are always read with the codec they were written with, even
+/// when the default changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueCodec {
+    /// `pot` in a format that is compatible with all `pot` versions.
+    #[default]
+    Pot,
+    /// `pot` in the more compact V4 format.
+    PotV4,
+}
+
+impl ValueCodec {
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            ValueCodec::Pot => 1,
+            ValueCodec::PotV4 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Result<Self> {
+        Ok(match id {
+            1 => ValueCodec::Pot,
+            2 => ValueCodec::PotV4,
+            _ => bail!(
+                "The database was written with an unknown value codec (id {id}). It was likely \
+                 created by a newer version and can't be read."
+            ),
+        })
+    }
+
+    fn config(self) -> pot::Config {
+        pot::Config::default().compatibility(match self {
+            ValueCodec::Pot => Compatibility::Full,
+            ValueCodec::PotV4 => Compatibility::V4,
+        })
+    }
+
+    pub(crate) fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        Ok(self.config().serialize(value)?)
+    }
+
+    /// Computes the length of the serialized `value` without buffering it.
+    pub(crate) fn serialized_size<T: Serialize>(self, value: &T) -> Result<usize> {
+        let mut counter = ByteCounter(0);
+        self.config().serialize_into(value, &mut counter)?;
+        Ok(counter.0)
+    }
+
+    /// Serializes `value` into `buffer`, which must have exactly the length returned by
+    /// [`ValueCodec::serialized_size`].
+    pub(crate) fn serialize_into<T: Serialize>(self, value: &T, buffer: &mut [u8]) -> Result<()> {
+        let mut remaining = &mut buffer[..];
+        self.config().serialize_into(value, &mut remaining)?;
+        if !remaining.is_empty() {
+            bail!(
+                "Serialized value is {} bytes shorter than the buffer",
+                remaining.len()
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn deserialize<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        Ok(self.config().deserialize(bytes)?)
+    }
+
+    /// Deserializes a list one element at a time. Returns the elements that could be deserialized
+    /// and the index and error of the others. Fails when the list itself can't be read.
+    pub(crate) fn deserialize_elements<T: DeserializeOwned>(
+        self,
+        bytes: &[u8],
+    ) -> Result<(Vec<T>, Vec<(usize, anyhow::Error)>)> {
+        let values: Vec<pot::Value<'_>> = self.config().deserialize(bytes)?;
+        let mut elements = Vec::with_capacity(values.len());
+        let mut errors = Vec::new();
+        for (index, value) in values.iter().enumerate() {
+            match value.deserialize_as() {
+                Ok(element) => elements.push(element),
+                Err(err) => errors.push((index, err.into())),
+            }
+        }
+        Ok((elements, errors))
+    }
+}
+
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialized_values_are_not_encoded() -> Result<()> {
+        for codec in [ValueCodec::Pot, ValueCodec::PotV4] {
+            for value in [codec.serialize(&())?, codec.serialize(&vec![0u8; 16])?] {
+                assert!(!is_encoded(&value), "{codec:?}: {value:?}");
+            }
+        }
+        Ok(())
+    }
+}